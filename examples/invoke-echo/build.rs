@@ -0,0 +1,23 @@
+fn main() {
+    // Same schemas as the host, compiled with `src_prefix` so the modules are just `echo_capnp`
+    // and `bulk_capnp` (which echo.capnp imports).
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set");
+    let schema_dir = std::path::Path::new(&manifest_dir)
+        .join("../../lib/cap")
+        .canonicalize()
+        .expect("failed to canonicalize schema dir");
+
+    for schema in ["echo.capnp", "bulk.capnp"] {
+        println!(
+            "cargo:rerun-if-changed={}",
+            schema_dir.join(schema).display()
+        );
+    }
+
+    capnpc::CompilerCommand::new()
+        .src_prefix(&schema_dir)
+        .file(schema_dir.join("echo.capnp"))
+        .file(schema_dir.join("bulk.capnp"))
+        .run()
+        .expect("schema compiler command");
+}
@@ -0,0 +1,56 @@
+//! A reactor guest that serves `Echoer.echo` through `wetware:guest/invoke`, without an RPC
+//! connection. Build it with `make build-invoke-guest` and run it with
+//! `cargo run -- --reactor --wasm examples/invoke-echo/target/wasm32-wasip2/release/invoke_echo.wasm`.
+//!
+//! Events are echoed back as their replies.
+
+use capnp::message::{self, ReaderOptions};
+use capnp::serialize;
+
+use echo_capnp::echoer;
+use exports::wetware::guest::{invoke, reactor};
+
+capnp::generated_code!(pub mod echo_capnp);
+capnp::generated_code!(pub mod bulk_capnp);
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "wetware:guest/invokable-guest",
+    generate_all,
+});
+
+struct InvokeEcho;
+
+impl reactor::Guest for InvokeEcho {
+    fn init() -> Result<(), String> {
+        Ok(())
+    }
+
+    fn handle_event(event: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(event)
+    }
+}
+
+impl invoke::Guest for InvokeEcho {
+    fn call(method: String, params: Vec<u8>) -> Result<Vec<u8>, String> {
+        match method.as_str() {
+            "Echoer.echo" => echo(&params).map_err(|e| e.to_string()),
+            _ => Err(format!("unknown method {method}")),
+        }
+    }
+}
+
+/// `Echoer.echo`: the reply is the message's bytes.
+fn echo(params: &[u8]) -> capnp::Result<Vec<u8>> {
+    let params = serialize::read_message(params, ReaderOptions::new())?;
+    let msg = params
+        .get_root::<echoer::echo_params::Reader>()?
+        .get_msg()?;
+    let mut results = message::Builder::new_default();
+    results
+        .init_root::<echoer::echo_results::Builder>()
+        .set_reply(msg.as_bytes());
+    Ok(serialize::write_message_to_words(&results))
+}
+
+export!(InvokeEcho);
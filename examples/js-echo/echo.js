@@ -0,0 +1,30 @@
+// Minimal JavaScript guest targeting the `wetware:guest` world. It doesn't speak Cap'n Proto
+// itself: it reaches the host's `Echoer` through the `capnp-bridge` resources instead.
+//
+// Build with `make build-js-guest` (requires jco), run with `make test-js`.
+
+import { bootstrap } from 'wetware:guest/capnp-bridge';
+import { ready } from 'wetware:guest/lifecycle';
+import { log } from 'wetware:guest/logging';
+
+const CALL_COUNT = 100;
+
+export const run = {
+  run() {
+    // `result` returns map to exceptions in jco: an `err` from the host throws here.
+    const echoer = bootstrap().echoer();
+    ready();
+
+    const decoder = new TextDecoder();
+    for (let i = 0; i < CALL_COUNT; i++) {
+      const msg = `Hello from JS! #${i}`;
+      const reply = decoder.decode(echoer.echo(msg));
+      if (reply !== msg) {
+        log('error', 'js-echo', `reply mismatch for ${i}: ${reply}`);
+        throw new Error(`reply mismatch for ${i}`);
+      }
+    }
+
+    log('info', 'js-echo', `${CALL_COUNT} bridged echoes matched`);
+  },
+};
@@ -1,9 +1,19 @@
 fn main() {
     // Re-run build script if the schema changes
     println!("cargo:rerun-if-changed=echo.capnp");
+    println!("cargo:rerun-if-changed=guest.capnp");
+    println!("cargo:rerun-if-changed=control.capnp");
+    println!("cargo:rerun-if-changed=oneway.capnp");
+    println!("cargo:rerun-if-changed=bulk.capnp");
+    println!("cargo:rerun-if-changed=capture.capnp");
 
     capnpc::CompilerCommand::new()
         .file("echo.capnp")
+        .file("guest.capnp")
+        .file("control.capnp")
+        .file("oneway.capnp")
+        .file("bulk.capnp")
+        .file("capture.capnp")
         .run()
         .expect("schema compiler command");
 }
@@ -0,0 +1,389 @@
+//! A circuit breaker for capabilities behind flaky transports.
+//!
+//! Calls through a [`CircuitBreaker`] are counted: after `failure_threshold` failures in a row
+//! the breaker trips open, and for `cool_down` every call fails at once with an `overloaded`
+//! error instead of waiting on a peer that is probably gone. Once the cool-down has passed, the
+//! next call goes through as a probe while the others keep failing fast. A successful probe
+//! closes the breaker; a failed one opens it for another cool-down. Probing is lazy: an idle
+//! breaker stays open until someone calls. [`is_circuit_open`] tells the breaker's errors apart
+//! from the peer's.
+//!
+//! [`BreakerEchoerProvider`] decorates an `EchoerProvider`, and the echoers it hands out, with one
+//! shared breaker. Other interfaces wrap their calls the same way: [`CircuitBreaker::admit`]
+//! before sending, [`Call::finish`] with the result.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use tracing::{info, warn};
+
+use crate::echo_capnp::{echoer, echoer_provider};
+use crate::layer::{self, Layer};
+use crate::proxy::{Forward, Method};
+use crate::trace;
+
+// Prefix of the errors an open breaker fails calls with.
+const OPEN_PREFIX: &str = "circuit open:";
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Consecutive failures that trip the breaker.
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before letting a probe through.
+    pub cool_down: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe is in flight.
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    name: String,
+    config: BreakerConfig,
+    state: Cell<BreakerState>,
+    trips: Cell<u64>,
+    rejected: Cell<u64>,
+}
+
+impl CircuitBreaker {
+    /// A closed breaker; `name` identifies it in logs and errors.
+    pub fn new(name: impl Into<String>, config: BreakerConfig) -> Rc<Self> {
+        Rc::new(Self {
+            name: name.into(),
+            config,
+            state: Cell::new(BreakerState::Closed { failures: 0 }),
+            trips: Cell::new(0),
+            rejected: Cell::new(0),
+        })
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state.get()
+    }
+
+    /// Times the breaker has tripped open.
+    pub fn trips(&self) -> u64 {
+        self.trips.get()
+    }
+
+    /// Calls failed fast while the breaker was open.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.get()
+    }
+
+    /// Let one call through, or fail it fast if the breaker is open. After the cool-down the
+    /// first call admitted is the probe.
+    pub fn admit(self: &Rc<Self>) -> Result<Call, capnp::Error> {
+        let now = Instant::now();
+        let probe = match self.state.get() {
+            BreakerState::Closed { .. } => false,
+            BreakerState::Open { until } if now >= until => {
+                self.state.set(BreakerState::HalfOpen);
+                true
+            }
+            BreakerState::Open { until } => {
+                return Err(self.reject(format!(
+                    "retry in {}ms",
+                    until.saturating_duration_since(now).as_millis()
+                )));
+            }
+            BreakerState::HalfOpen => return Err(self.reject("probe in flight".to_string())),
+        };
+        Ok(Call {
+            breaker: self.clone(),
+            probe,
+            finished: false,
+        })
+    }
+
+    fn reject(&self, detail: String) -> capnp::Error {
+        self.rejected.set(self.rejected.get() + 1);
+        capnp::Error::overloaded(format!("{OPEN_PREFIX} {} ({detail})", self.name))
+    }
+
+    fn record(&self, probe: bool, ok: bool) {
+        let state = self.state.get();
+        // Once the breaker has tripped, only the probe decides what happens next. A call
+        // admitted before it tripped can finish after, and changes nothing.
+        if !probe && !matches!(state, BreakerState::Closed { .. }) {
+            return;
+        }
+        if ok {
+            if probe {
+                info!(breaker = %self.name, "probe succeeded; closing circuit");
+            }
+            self.state.set(BreakerState::Closed { failures: 0 });
+            return;
+        }
+        let failures = match state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::Open { .. } | BreakerState::HalfOpen => self.config.failure_threshold,
+        };
+        if failures < self.config.failure_threshold {
+            self.state.set(BreakerState::Closed { failures });
+            return;
+        }
+        self.trips.set(self.trips.get() + 1);
+        self.state.set(BreakerState::Open {
+            until: Instant::now() + self.config.cool_down,
+        });
+        warn!(
+            breaker = %self.name,
+            failures,
+            cool_down = ?self.config.cool_down,
+            probe,
+            "circuit opened"
+        );
+    }
+}
+
+/// Whether `error` came from an open breaker rather than from the peer.
+pub fn is_circuit_open(error: &capnp::Error) -> bool {
+    error.kind == capnp::ErrorKind::Overloaded && error.extra.starts_with(OPEN_PREFIX)
+}
+
+/// A call let through by a [`CircuitBreaker`]. Dropped unfinished, e.g. when the call is
+/// canceled, it counts as a failure.
+pub struct Call {
+    breaker: Rc<CircuitBreaker>,
+    probe: bool,
+    finished: bool,
+}
+
+impl Call {
+    /// Record the call's result and pass it on.
+    pub fn finish<T>(mut self, result: capnp::Result<T>) -> capnp::Result<T> {
+        self.finished = true;
+        self.breaker.record(self.probe, result.is_ok());
+        result
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker.record(self.probe, false);
+        }
+    }
+}
+
+/// `EchoerProvider` whose calls, and those on the echoers it hands out, go through a breaker.
+pub struct BreakerEchoerProvider {
+    breaker: Rc<CircuitBreaker>,
+}
+
+impl BreakerEchoerProvider {
+    pub fn client(
+        inner: echoer_provider::Client,
+        breaker: Rc<CircuitBreaker>,
+    ) -> echoer_provider::Client {
+        layer::provider(inner, Self { breaker })
+    }
+}
+
+impl Layer for BreakerEchoerProvider {
+    fn around(
+        &self,
+        _method: Method,
+        forward: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let call = pry!(self.breaker.admit());
+        trace::promise(async move { call.finish(forward.send().await) })
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(BreakerEchoer {
+            inner,
+            breaker: self.breaker.clone(),
+        })
+    }
+}
+
+struct BreakerEchoer {
+    inner: echoer::Client,
+    breaker: Rc<CircuitBreaker>,
+}
+
+impl echoer::Server for BreakerEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        let call = pry!(self.breaker.admit());
+        trace::promise(async move {
+            let response = call.finish(request.send().promise.await)?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        let call = pry!(self.breaker.admit());
+        trace::promise(async move {
+            let response = call.finish(request.send().promise.await)?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn breaker(failure_threshold: u32, cool_down: Duration) -> Rc<CircuitBreaker> {
+        CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_threshold,
+                cool_down,
+            },
+        )
+    }
+
+    fn fail(breaker: &Rc<CircuitBreaker>) {
+        let call = breaker.admit().unwrap();
+        let _ = call.finish::<()>(Err(capnp::Error::failed("peer failed".into())));
+    }
+
+    fn succeed(breaker: &Rc<CircuitBreaker>) {
+        breaker.admit().unwrap().finish(Ok(())).unwrap();
+    }
+
+    #[test]
+    fn reaching_the_threshold_trips_the_breaker() {
+        let breaker = breaker(3, Duration::from_secs(60));
+        fail(&breaker);
+        fail(&breaker);
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 2 });
+        // A success in between starts the count over.
+        succeed(&breaker);
+        fail(&breaker);
+        fail(&breaker);
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 2 });
+        fail(&breaker);
+        assert!(matches!(breaker.state(), BreakerState::Open { .. }));
+        assert_eq!(breaker.trips(), 1);
+    }
+
+    #[test]
+    fn calls_fail_fast_while_open() {
+        let breaker = breaker(1, Duration::from_secs(60));
+        fail(&breaker);
+        for _ in 0..3 {
+            let Err(err) = breaker.admit() else {
+                panic!("an open breaker admitted a call");
+            };
+            assert!(is_circuit_open(&err), "{err}");
+        }
+        assert_eq!(breaker.rejected(), 3);
+        assert!(!is_circuit_open(&capnp::Error::overloaded("busy".into())));
+    }
+
+    #[test]
+    fn one_probe_is_let_through_after_the_cool_down() {
+        let breaker = breaker(1, Duration::ZERO);
+        fail(&breaker);
+        let probe = breaker.admit().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(is_circuit_open(&breaker.admit().err().unwrap()));
+        probe.finish(Ok(())).unwrap();
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn a_failed_probe_opens_the_breaker_again() {
+        let breaker = breaker(3, Duration::ZERO);
+        for _ in 0..3 {
+            fail(&breaker);
+        }
+        // The probe.
+        fail(&breaker);
+        assert!(matches!(breaker.state(), BreakerState::Open { .. }));
+        assert_eq!(breaker.trips(), 2);
+        // So does a probe dropped before it finished.
+        drop(breaker.admit().unwrap());
+        assert_eq!(breaker.trips(), 3);
+    }
+
+    #[test]
+    fn late_results_leave_an_open_breaker_open() {
+        let breaker = breaker(1, Duration::from_secs(60));
+        let slow_success = breaker.admit().unwrap();
+        let slow_failure = breaker.admit().unwrap();
+        fail(&breaker);
+        slow_success.finish(Ok(())).unwrap();
+        assert!(matches!(breaker.state(), BreakerState::Open { .. }));
+        let _ = slow_failure.finish::<()>(Err(capnp::Error::failed("late".into())));
+        assert_eq!(breaker.trips(), 1);
+    }
+
+    #[test]
+    fn late_results_leave_a_probe_in_charge() {
+        let breaker = breaker(1, Duration::ZERO);
+        let slow = breaker.admit().unwrap();
+        fail(&breaker);
+        let probe = breaker.admit().unwrap();
+        slow.finish(Ok(())).unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        let _ = probe.finish::<()>(Err(capnp::Error::failed("still down".into())));
+        assert!(matches!(breaker.state(), BreakerState::Open { .. }));
+    }
+
+    // A provider whose every call fails.
+    struct Down;
+
+    impl echoer_provider::Server for Down {}
+
+    #[test]
+    fn the_provider_wrapper_fails_fast_once_tripped() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        let provider = BreakerEchoerProvider::client(capnp_rpc::new_client(Down), breaker.clone());
+        let introspect = || {
+            block_on(provider.introspect_request().send().promise)
+                .err()
+                .unwrap()
+        };
+        assert!(!is_circuit_open(&introspect()));
+        assert!(!is_circuit_open(&introspect()));
+        assert!(is_circuit_open(&introspect()));
+        assert_eq!(breaker.trips(), 1);
+        assert_eq!(breaker.rejected(), 1);
+    }
+}
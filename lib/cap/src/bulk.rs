@@ -0,0 +1,588 @@
+//! Bulk transfers over a `ChunkSink` capability.
+//!
+//! A large payload goes out as a sequence of chunks of at most `chunk_size` bytes, each one a
+//! `write` call. [`send`] keeps at most `window` of those calls unanswered and waits for the
+//! oldest return, which acknowledges its chunk, before reading the next one. Neither side ever
+//! holds more than `window * chunk_size` bytes of the payload, however large it is: the sender
+//! buffers only unacknowledged chunks, and [`ChunkSinkServer`] hands each chunk to its
+//! [`ChunkWriter`] as it arrives. Calls on one capability are delivered in order, so chunks
+//! arrive in order too; the offsets only catch a confused sender.
+//!
+//! [`MemoryBlobStore`] serves `BlobStore` with these: uploads go through a `ChunkSinkServer`
+//! and downloads through [`send`].
+
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use futures::channel::oneshot;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::bulk_capnp::{blob_store, chunk_sink};
+use crate::state::Shared;
+use crate::trace;
+
+/// Chunking and windowing of a transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferOptions {
+    /// Largest chunk, in bytes.
+    pub chunk_size: usize,
+    /// Most chunks sent but not yet acknowledged.
+    pub window: usize,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 64 * 1024,
+            window: 8,
+        }
+    }
+}
+
+/// Send everything `reader` yields to `sink`, then end the transfer. Returns the payload's length
+/// once the sink has acknowledged all of it.
+pub async fn send<R: AsyncRead + Unpin>(
+    sink: &chunk_sink::Client,
+    mut reader: R,
+    options: TransferOptions,
+) -> capnp::Result<u64> {
+    if options.chunk_size == 0 || options.window == 0 {
+        return Err(capnp::Error::failed(
+            "chunk size and window must be nonzero".to_string(),
+        ));
+    }
+    let mut buf = vec![0u8; options.chunk_size];
+    let mut unacked = VecDeque::with_capacity(options.window);
+    let mut offset = 0u64;
+    loop {
+        let n = read_chunk(&mut reader, &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        if unacked.len() == options.window {
+            let ack: Promise<_, capnp::Error> = unacked.pop_front().unwrap();
+            ack.await?;
+        }
+        let mut request = sink.write_request();
+        request.get().set_offset(offset);
+        request.get().set_data(&buf[..n]);
+        unacked.push_back(request.send().promise);
+        offset += n as u64;
+    }
+    for ack in unacked {
+        ack.await?;
+    }
+    let mut request = sink.done_request();
+    request.get().set_length(offset);
+    request.send().promise.await?;
+    debug!(length = offset, "bulk transfer finished");
+    Ok(offset)
+}
+
+/// Fill `buf` from `reader`, short only at end of input.
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> capnp::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Where a [`ChunkSinkServer`] puts the chunks it receives. Its futures run on the RPC
+/// executor, between calls of every other capability, so they must not block: any
+/// `futures::AsyncWrite` is a writer, but a file needs an async one, not `std::fs::File`.
+pub trait ChunkWriter {
+    fn write_chunk(&mut self, data: &[u8]) -> impl Future<Output = capnp::Result<()>>;
+
+    /// The whole payload, `length` bytes, has been written.
+    fn finish(&mut self, length: u64) -> impl Future<Output = capnp::Result<()>>;
+}
+
+impl<W: AsyncWrite + Unpin> ChunkWriter for W {
+    async fn write_chunk(&mut self, data: &[u8]) -> capnp::Result<()> {
+        Ok(self.write_all(data).await?)
+    }
+
+    async fn finish(&mut self, _length: u64) -> capnp::Result<()> {
+        Ok(self.close().await?)
+    }
+}
+
+/// Receives one transfer into a [`ChunkWriter`]. Chunks are written one at a time, in the order
+/// their calls arrived, and each call returns once its own chunk is written. A chunk that fails
+/// to write, or whose call is canceled before it is, fails every call after it.
+pub struct ChunkSinkServer<W> {
+    // Out while a chunk is being written, and gone for good once one has failed.
+    writer: Shared<Option<W>>,
+    // Fires once the latest call has written its chunk; dropped unfired if it didn't.
+    last: Option<oneshot::Receiver<()>>,
+    received: u64,
+    done: bool,
+}
+
+enum Job {
+    Write(chunk_sink::WriteParams),
+    Finish(u64),
+}
+
+impl<W: ChunkWriter + 'static> ChunkSinkServer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Shared::new(Some(writer)),
+            last: None,
+            received: 0,
+            done: false,
+        }
+    }
+
+    /// A `ChunkSink` capability writing to `writer`.
+    pub fn client(writer: W) -> chunk_sink::Client {
+        capnp_rpc::new_client(Self::new(writer))
+    }
+
+    /// Run `job` on the writer once every earlier call has run its own.
+    fn in_turn(&mut self, job: Job) -> Promise<(), capnp::Error> {
+        let (turn, next) = oneshot::channel();
+        let previous = self.last.replace(next);
+        let writer = self.writer.clone();
+        trace::promise(async move {
+            if let Some(previous) = previous {
+                previous.await.map_err(|_| {
+                    capnp::Error::failed("an earlier chunk was not written".to_string())
+                })?;
+            }
+            let mut taken = writer
+                .with(Option::take)
+                .expect("the writer is put back before the next call's turn");
+            match job {
+                Job::Write(params) => taken.write_chunk(params.get()?.get_data()?).await?,
+                Job::Finish(length) => taken.finish(length).await?,
+            }
+            writer.with(|slot| *slot = Some(taken));
+            let _ = turn.send(());
+            Ok(())
+        })
+    }
+}
+
+impl<W: ChunkWriter + 'static> chunk_sink::Server for ChunkSinkServer<W> {
+    fn write(
+        &mut self,
+        params: chunk_sink::WriteParams,
+        _results: chunk_sink::WriteResults,
+    ) -> Promise<(), capnp::Error> {
+        if self.done {
+            return Promise::err(capnp::Error::failed(
+                "transfer already finished".to_string(),
+            ));
+        }
+        let chunk = pry!(params.get());
+        if chunk.get_offset() != self.received {
+            return Promise::err(capnp::Error::failed(format!(
+                "chunk at offset {} after {} bytes",
+                chunk.get_offset(),
+                self.received
+            )));
+        }
+        self.received += pry!(chunk.get_data()).len() as u64;
+        self.in_turn(Job::Write(params))
+    }
+
+    fn done(
+        &mut self,
+        params: chunk_sink::DoneParams,
+        mut results: chunk_sink::DoneResults,
+    ) -> Promise<(), capnp::Error> {
+        let length = pry!(params.get()).get_length();
+        if length != self.received {
+            return Promise::err(capnp::Error::failed(format!(
+                "transfer of {length} bytes ended after {} bytes",
+                self.received
+            )));
+        }
+        self.done = true;
+        let finished = self.in_turn(Job::Finish(length));
+        trace::promise(async move {
+            finished.await?;
+            results.get().set_length(length);
+            Ok(())
+        })
+    }
+}
+
+/// A `BlobStore` holding its blobs in memory. Each download is sent with `options`.
+pub struct MemoryBlobStore {
+    blobs: Shared<HashMap<String, Rc<[u8]>>>,
+    options: TransferOptions,
+}
+
+impl MemoryBlobStore {
+    pub fn new(options: TransferOptions) -> Self {
+        Self {
+            blobs: Shared::default(),
+            options,
+        }
+    }
+
+    /// A `BlobStore` capability for a new, empty store.
+    pub fn client(options: TransferOptions) -> blob_store::Client {
+        capnp_rpc::new_client(Self::new(options))
+    }
+
+    /// The blob stored under `name`, if its upload has finished.
+    pub fn blob(&self, name: &str) -> Option<Rc<[u8]>> {
+        self.blobs.with(|blobs| blobs.get(name).cloned())
+    }
+}
+
+impl blob_store::Server for MemoryBlobStore {
+    fn put(
+        &mut self,
+        params: blob_store::PutParams,
+        mut results: blob_store::PutResults,
+    ) -> Promise<(), capnp::Error> {
+        let name = pry!(pry!(pry!(params.get()).get_name()).to_str()).to_string();
+        results.get().set_sink(ChunkSinkServer::client(Upload {
+            name,
+            data: Vec::new(),
+            blobs: self.blobs.clone(),
+        }));
+        Promise::ok(())
+    }
+
+    fn get(
+        &mut self,
+        params: blob_store::GetParams,
+        mut results: blob_store::GetResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str());
+        let Some(blob) = self.blob(name) else {
+            return Promise::err(capnp::Error::failed(format!("no blob named {name:?}")));
+        };
+        let sink = pry!(params.get_sink());
+        let options = self.options;
+        trace::promise(async move {
+            let length = send(&sink, &blob[..], options).await?;
+            results.get().set_length(length);
+            Ok(())
+        })
+    }
+}
+
+/// One upload to a [`MemoryBlobStore`], stored under `name` once it is finished.
+struct Upload {
+    name: String,
+    data: Vec<u8>,
+    blobs: Shared<HashMap<String, Rc<[u8]>>>,
+}
+
+impl ChunkWriter for Upload {
+    async fn write_chunk(&mut self, data: &[u8]) -> capnp::Result<()> {
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn finish(&mut self, _length: u64) -> capnp::Result<()> {
+        let blob = std::mem::take(&mut self.data).into();
+        self.blobs
+            .with(|blobs| blobs.insert(self.name.clone(), blob));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use capnp_rpc::{RpcSystem, twoparty};
+    use futures::channel::mpsc;
+    use futures::executor::{LocalPool, LocalSpawner, block_on};
+    use futures::task::LocalSpawnExt;
+    use futures::{FutureExt, StreamExt, TryStreamExt};
+
+    use super::*;
+    use crate::proxy::ProxyBuilder;
+
+    const PAYLOAD: &[u8] = b"twenty bytes of data";
+    const OPTIONS: TransferOptions = TransferOptions {
+        chunk_size: 4,
+        window: 2,
+    };
+
+    // Keeps what it is given where the test can see it. With `permits`, it writes each chunk
+    // only once the test has let one through.
+    struct Collect {
+        permits: Option<mpsc::UnboundedReceiver<()>>,
+        written: Rc<RefCell<Vec<u8>>>,
+        finished: Rc<Cell<bool>>,
+    }
+
+    impl ChunkWriter for Collect {
+        async fn write_chunk(&mut self, data: &[u8]) -> capnp::Result<()> {
+            if let Some(permits) = &mut self.permits
+                && permits.next().await.is_none()
+            {
+                return Err(capnp::Error::failed("gate closed".to_string()));
+            }
+            self.written.borrow_mut().extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn finish(&mut self, _length: u64) -> capnp::Result<()> {
+            self.finished.set(true);
+            Ok(())
+        }
+    }
+
+    // A sink writing to a `Collect`, and what the test watches it through.
+    struct Sink {
+        sink: chunk_sink::Client,
+        gate: mpsc::UnboundedSender<()>,
+        // `write` calls that have reached the sink, written or not.
+        arrived: Rc<Cell<usize>>,
+        written: Rc<RefCell<Vec<u8>>>,
+        finished: Rc<Cell<bool>>,
+    }
+
+    fn gated_sink() -> Sink {
+        let (gate, permits) = mpsc::unbounded();
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let finished = Rc::new(Cell::new(false));
+        let arrived = Rc::new(Cell::new(0));
+        let counted = arrived.clone();
+        let sink = ProxyBuilder::new(ChunkSinkServer::client(Collect {
+            permits: Some(permits),
+            written: written.clone(),
+            finished: finished.clone(),
+        }))
+        .params(move |method, _| {
+            if method.is::<chunk_sink::Client>(0) {
+                counted.set(counted.get() + 1);
+            }
+            Ok(())
+        })
+        .build();
+        Sink {
+            sink,
+            gate,
+            arrived,
+            written,
+            finished,
+        }
+    }
+
+    // One direction of an in-memory connection.
+    struct Pipe(mpsc::UnboundedSender<io::Result<Vec<u8>>>);
+
+    impl AsyncWrite for Pipe {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            // A peer that has gone away doesn't read what is sent.
+            let _ = self.0.unbounded_send(Ok(buf.to_vec()));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.close_channel();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn pipe() -> (Pipe, impl AsyncRead + Unpin) {
+        let (tx, rx) = mpsc::unbounded();
+        (Pipe(tx), rx.into_async_read())
+    }
+
+    // Serve `sink` over a new in-memory connection and return the client's end. Calls on it reach
+    // the sink once sent, not once their promises are first polled as with a local capability,
+    // so a whole window of them can be out at once.
+    fn connect(spawner: &LocalSpawner, sink: &chunk_sink::Client) -> chunk_sink::Client {
+        let (to_client, from_server) = pipe();
+        let (to_server, from_client) = pipe();
+        let network =
+            twoparty::VatNetwork::new(from_client, to_client, Side::Server, Default::default());
+        let server = RpcSystem::new(Box::new(network), Some(sink.clone().client));
+        spawner.spawn_local(server.map(|_| ())).unwrap();
+        let network =
+            twoparty::VatNetwork::new(from_server, to_server, Side::Client, Default::default());
+        let mut client = RpcSystem::new(Box::new(network), None);
+        let bootstrap = client.bootstrap(Side::Server);
+        spawner.spawn_local(client.map(|_| ())).unwrap();
+        bootstrap
+    }
+
+    fn write(
+        sink: &chunk_sink::Client,
+        offset: u64,
+        data: &[u8],
+    ) -> Promise<capnp::capability::Response<chunk_sink::write_results::Owned>, capnp::Error> {
+        let mut request = sink.write_request();
+        request.get().set_offset(offset);
+        request.get().set_data(data);
+        request.send().promise
+    }
+
+    fn done(sink: &chunk_sink::Client, length: u64) -> capnp::Result<u64> {
+        let mut request = sink.done_request();
+        request.get().set_length(length);
+        Ok(block_on(request.send().promise)?.get()?.get_length())
+    }
+
+    #[test]
+    fn blobs_round_trip_through_the_store() {
+        let store = MemoryBlobStore::client(TransferOptions {
+            chunk_size: 3,
+            window: 2,
+        });
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let finished = Rc::new(Cell::new(false));
+        block_on(async {
+            let mut put = store.put_request();
+            put.get().set_name("data");
+            let upload = put.send().promise.await?.get()?.get_sink()?;
+            assert_eq!(send(&upload, PAYLOAD, OPTIONS).await?, 20);
+
+            let mut get = store.get_request();
+            get.get().set_name("data");
+            get.get().set_sink(ChunkSinkServer::client(Collect {
+                permits: None,
+                written: written.clone(),
+                finished: finished.clone(),
+            }));
+            assert_eq!(get.send().promise.await?.get()?.get_length(), 20);
+
+            let mut get = store.get_request();
+            get.get().set_name("missing");
+            get.get()
+                .set_sink(ChunkSinkServer::client(futures::io::sink()));
+            assert!(get.send().promise.await.is_err());
+            Ok::<_, capnp::Error>(())
+        })
+        .unwrap();
+        assert_eq!(&written.borrow()[..], PAYLOAD);
+        assert!(finished.get());
+    }
+
+    #[test]
+    fn a_full_window_holds_the_sender_back_until_a_chunk_is_written() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        let sink = gated_sink();
+        let remote = connect(&spawner, &sink.sink);
+        let sent = spawner
+            .spawn_local_with_handle(async move { send(&remote, PAYLOAD, OPTIONS).await })
+            .unwrap();
+
+        pool.run_until_stalled();
+        assert_eq!(sink.arrived.get(), 2);
+        assert!(sink.written.borrow().is_empty());
+
+        // Writing the first chunk acknowledges it, which lets one more out.
+        sink.gate.unbounded_send(()).unwrap();
+        pool.run_until_stalled();
+        assert_eq!(sink.arrived.get(), 3);
+        assert_eq!(&sink.written.borrow()[..], &PAYLOAD[..4]);
+
+        for _ in 0..4 {
+            sink.gate.unbounded_send(()).unwrap();
+        }
+        assert_eq!(pool.run_until(sent).unwrap(), 20);
+        assert_eq!(sink.arrived.get(), 5);
+        assert_eq!(&sink.written.borrow()[..], PAYLOAD);
+        assert!(sink.finished.get());
+    }
+
+    #[test]
+    fn a_transfer_dropped_midway_is_never_finished() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        let sink = gated_sink();
+        let remote = connect(&spawner, &sink.sink);
+        let sent = spawner
+            .spawn_local_with_handle(async move { send(&remote, PAYLOAD, OPTIONS).await })
+            .unwrap();
+        sink.gate.unbounded_send(()).unwrap();
+        pool.run_until_stalled();
+        assert_eq!(&sink.written.borrow()[..], &PAYLOAD[..4]);
+
+        drop(sent);
+        for _ in 0..4 {
+            sink.gate.unbounded_send(()).unwrap();
+        }
+        pool.run_until_stalled();
+        // Whatever got written is the start of the payload, and the sink never took it whole.
+        let written = sink.written.borrow().clone();
+        assert!(written.len() < PAYLOAD.len() && PAYLOAD.starts_with(&written));
+        assert!(!sink.finished.get());
+        assert!(done(&sink.sink, 20).is_err());
+    }
+
+    #[test]
+    fn a_canceled_write_fails_the_rest_of_the_transfer() {
+        let mut pool = LocalPool::new();
+        let sink = gated_sink();
+        let first = pool
+            .spawner()
+            .spawn_local_with_handle(write(&sink.sink, 0, &PAYLOAD[..4]))
+            .unwrap();
+        pool.run_until_stalled();
+        drop(first);
+
+        // Let a chunk through, which the canceled write must not take.
+        sink.gate.unbounded_send(()).unwrap();
+        let second = pool
+            .spawner()
+            .spawn_local_with_handle(write(&sink.sink, 4, &PAYLOAD[4..8]))
+            .unwrap();
+        pool.run_until_stalled();
+        assert!(matches!(second.now_or_never(), Some(Err(_))));
+        assert!(sink.written.borrow().is_empty());
+        assert!(done(&sink.sink, 8).is_err());
+        assert!(!sink.finished.get());
+    }
+
+    #[test]
+    fn out_of_order_and_late_chunks_are_refused() {
+        let sink = ChunkSinkServer::client(Vec::<u8>::new());
+        assert!(block_on(write(&sink, 4, &PAYLOAD[..4])).is_err());
+        block_on(write(&sink, 0, &PAYLOAD[..4])).unwrap();
+        assert!(done(&sink, 8).is_err());
+        assert_eq!(done(&sink, 4).unwrap(), 4);
+        assert!(block_on(write(&sink, 4, &PAYLOAD[4..8])).is_err());
+    }
+
+    #[test]
+    fn a_zero_chunk_size_or_window_is_an_error() {
+        let sink = ChunkSinkServer::client(Vec::<u8>::new());
+        for options in [
+            TransferOptions {
+                chunk_size: 0,
+                window: 2,
+            },
+            TransferOptions {
+                chunk_size: 4,
+                window: 0,
+            },
+        ] {
+            assert!(block_on(send(&sink, PAYLOAD, options)).is_err());
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Reading payload captures.
+//!
+//! The host's `--capture` file is a stream of `capture.capnp` records whose payloads are the
+//! params and results structs of the captured calls, stored as `AnyPointer`s. [`read`] walks the
+//! file and [`render`] prints a record, decoding its payload with the schema of the call's method,
+//! so the params and results appear as structs rather than bytes. Payloads of methods this crate
+//! has no schema for, and truncated ones, are printed as hex.
+
+use std::fmt::{Debug, Write};
+use std::io;
+
+use capnp::any_pointer;
+use capnp::message::{self, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+use capnp::traits::FromPointerReader;
+
+use crate::capture_capnp::{payload, record};
+use crate::echo_capnp::{echoer, echoer_provider};
+
+/// Read every record in a capture.
+pub fn read<R: io::Read>(
+    mut reader: R,
+) -> impl Iterator<Item = capnp::Result<message::Reader<OwnedSegments>>> {
+    // Payloads can be as large as the guest made them.
+    let options = *ReaderOptions::new().traversal_limit_in_words(None);
+    std::iter::from_fn(move || serialize::try_read_message(&mut reader, options).transpose())
+}
+
+/// One record as text: a header line, then the decoded payload or the error.
+pub fn render(record: record::Reader) -> capnp::Result<String> {
+    let method = record.get_method()?.to_str()?;
+    let mut out = format!(
+        "#{} +{:.6}s {method} ",
+        record.get_call_id(),
+        record.get_timestamp_ns() as f64 / 1e9
+    );
+    let (payload, results) = match record.which()? {
+        record::Params(payload) => (payload?, false),
+        record::Results(payload) => (payload?, true),
+        record::Error(error) => {
+            writeln!(out, "error\n  {}", error?.to_str()?).unwrap();
+            return Ok(out);
+        }
+    };
+    let side = if results { "results" } else { "params" };
+    write!(out, "{side} ({} bytes)", payload.get_size()).unwrap();
+    match payload.which()? {
+        payload::Full(value) => {
+            let text = match decode(method, results, value)? {
+                Some(text) => text,
+                None => hex(&serialize::write_message_to_words(&flat(value)?)),
+            };
+            out.push('\n');
+            for line in text.lines() {
+                writeln!(out, "  {line}").unwrap();
+            }
+        }
+        payload::Truncated(prefix) => {
+            writeln!(out, ", truncated\n  {}", hex(prefix?)).unwrap();
+        }
+        payload::Omitted(()) => writeln!(out, ", holds capabilities").unwrap(),
+    }
+    Ok(out)
+}
+
+/// Decode a payload with its method's schema; `None` if there is none.
+fn decode(
+    method: &str,
+    results: bool,
+    value: any_pointer::Reader,
+) -> capnp::Result<Option<String>> {
+    Ok(Some(match (method, results) {
+        ("Echoer.echo", false) => pretty::<echoer::echo_params::Reader>(value)?,
+        ("Echoer.echo", true) => pretty::<echoer::echo_results::Reader>(value)?,
+        ("EchoerProvider.echoer", false) => {
+            pretty::<echoer_provider::echoer_params::Reader>(value)?
+        }
+        ("EchoerProvider.budget", false) => {
+            pretty::<echoer_provider::budget_params::Reader>(value)?
+        }
+        ("EchoerProvider.budget", true) => {
+            pretty::<echoer_provider::budget_results::Reader>(value)?
+        }
+        ("EchoerProvider.progress", false) => {
+            pretty::<echoer_provider::progress_params::Reader>(value)?
+        }
+        ("EchoerProvider.logTail", false) => {
+            pretty::<echoer_provider::log_tail_params::Reader>(value)?
+        }
+        ("EchoerProvider.introspect", false) => {
+            pretty::<echoer_provider::introspect_params::Reader>(value)?
+        }
+        ("EchoerProvider.introspect", true) => {
+            pretty::<echoer_provider::introspect_results::Reader>(value)?
+        }
+        ("EchoerProvider.restore", false) => {
+            pretty::<echoer_provider::restore_params::Reader>(value)?
+        }
+        ("EchoerProvider.migration", false) => {
+            pretty::<echoer_provider::migration_params::Reader>(value)?
+        }
+        _ => return Ok(None),
+    }))
+}
+
+fn pretty<'a, T: FromPointerReader<'a> + Debug>(
+    value: any_pointer::Reader<'a>,
+) -> capnp::Result<String> {
+    Ok(format!("{:#?}", value.get_as::<T>()?))
+}
+
+/// A payload on its own, for printing raw.
+fn flat(value: any_pointer::Reader) -> capnp::Result<message::Builder<message::HeapAllocator>> {
+    let mut message = message::Builder::new_default();
+    message.set_root(value)?;
+    Ok(message)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
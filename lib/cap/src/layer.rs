@@ -0,0 +1,268 @@
+//! Layers over an `EchoerProvider`, built on [`ProxyBuilder`].
+//!
+//! The host stacks layers over each guest's provider: budgets, fault injection, capture, tracing
+//! and so on. Most treat every provider call alike, so a [`Layer`] is an `around` hook on a
+//! proxy over the provider below, and a provider method added to the schema needs no code in the
+//! layers. Calls on echoers are the exception. The echoer that `echoer()` or `restore()` answers
+//! with is a capability in the results, which the proxy passes on as it is, so [`provider`]
+//! answers those two itself: through the layer's `around` hook like any other call, but with the
+//! layer's own echoer over the one from below. A layer that answers a method itself, rather than
+//! passing it on, serves it with [`ProxyBuilder::serve`] on the builder from [`builder`].
+
+use std::rc::Rc;
+
+use capnp::any_pointer;
+use capnp::capability::{self, FromClientHook, Promise, Response};
+use capnp::traits::{HasTypeId, Owned, SetterInput};
+use capnp_rpc::pry;
+
+use crate::echo_capnp::{echoer, echoer_provider};
+use crate::proxy::{Forward, Method, ProxyBuilder};
+use crate::trace;
+
+// `EchoerProvider`'s method ordinals, as in echo.capnp.
+pub const ECHOER: u16 = 0;
+pub const BUDGET: u16 = 1;
+pub const CONTROL: u16 = 5;
+pub const RESTORE: u16 = 6;
+
+// Each `EchoerProvider` method by ordinal: its name, and whether its results are a capability.
+const PROVIDER_METHODS: [(&str, bool); 9] = [
+    ("EchoerProvider.echoer", true),
+    ("EchoerProvider.budget", false),
+    ("EchoerProvider.progress", true),
+    ("EchoerProvider.logTail", true),
+    ("EchoerProvider.introspect", false),
+    ("EchoerProvider.control", true),
+    ("EchoerProvider.restore", true),
+    ("EchoerProvider.migration", true),
+    ("EchoerProvider.blobs", true),
+];
+
+fn provider_method(method: Method) -> Option<(&'static str, bool)> {
+    if method.interface_id != echoer_provider::Client::TYPE_ID {
+        return None;
+    }
+    PROVIDER_METHODS.get(usize::from(method.method_id)).copied()
+}
+
+/// The provider's methods, as `Interface.method`.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PROVIDER_METHODS.iter().map(|(name, _)| *name)
+}
+
+/// `Interface.method`, e.g. `EchoerProvider.echoer`.
+pub fn name(method: Method) -> &'static str {
+    provider_method(method).map_or("EchoerProvider.unknown", |(name, _)| name)
+}
+
+/// The method's name without its interface, e.g. `echoer`.
+pub fn short_name(method: Method) -> &'static str {
+    let name = name(method);
+    name.split_once('.').map_or(name, |(_, method)| method)
+}
+
+/// Whether the method's results are a capability rather than data.
+pub fn returns_capability(method: Method) -> bool {
+    provider_method(method).is_some_and(|(_, capability)| capability)
+}
+
+/// What a layer does to the calls through it.
+pub trait Layer: 'static {
+    /// Make `call`, a call to `method` on the provider below, doing what the layer does to it.
+    /// Passes the call on unchanged by default.
+    fn around(
+        &self,
+        _method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        call.send()
+    }
+
+    /// The layer's echoer over `inner`, an echoer from the provider below.
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client;
+}
+
+/// `inner` behind `layer`.
+pub fn provider(inner: echoer_provider::Client, layer: impl Layer) -> echoer_provider::Client {
+    builder(inner, layer).build()
+}
+
+/// A proxy over `inner` with `layer` on it, for a layer that serves some methods itself.
+pub fn builder(inner: echoer_provider::Client, layer: impl Layer) -> ProxyBuilder {
+    let layer = Rc::new(layer);
+    let echoers = Echoers {
+        inner: capability::Client::new(inner.clone().into_client_hook()),
+        layer: layer.clone(),
+    };
+    ProxyBuilder::new(inner)
+        .around(move |method, call| layer.around(method, call))
+        .serve::<echoer_provider::Client, _>(&[ECHOER, RESTORE], echoers)
+}
+
+/// Answers `echoer()` and `restore()` through the layer, with the layer's echoer.
+///
+/// Copying the answer and swapping the echoer in it afterwards would leave the echoer below in
+/// the answer's capability table, where the guest could reach it around the layer, so the answer
+/// is built here field by field.
+struct Echoers<L> {
+    inner: capability::Client,
+    layer: Rc<L>,
+}
+
+impl<L: Layer> Echoers<L> {
+    /// Send `params` to `method_id` on the provider below, through the layer.
+    fn call<P: Owned>(
+        &self,
+        method_id: u16,
+        params: impl SetterInput<P>,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let method = Method {
+            interface_id: echoer_provider::Client::TYPE_ID,
+            method_id,
+        };
+        let mut request = self
+            .inner
+            .new_call::<any_pointer::Owned, any_pointer::Owned>(
+                method.interface_id,
+                method_id,
+                None,
+            );
+        pry!(request.get().set_as(params));
+        self.layer.around(method, Forward::new(method, request))
+    }
+}
+
+impl<L: Layer> echoer_provider::Server for Echoers<L> {
+    fn echoer(
+        &mut self,
+        params: echoer_provider::EchoerParams,
+        mut results: echoer_provider::EchoerResults,
+    ) -> Promise<(), capnp::Error> {
+        let response = self.call(ECHOER, pry!(params.get()));
+        let layer = self.layer.clone();
+        trace::promise(async move {
+            let response = response.await?;
+            let answer = response
+                .get()?
+                .get_as::<echoer_provider::echoer_results::Reader>()?;
+            results.get().set_echoer(layer.echoer(answer.get_echoer()?));
+            results.get().set_ref(answer.get_ref()?);
+            Ok(())
+        })
+    }
+
+    fn restore(
+        &mut self,
+        params: echoer_provider::RestoreParams,
+        mut results: echoer_provider::RestoreResults,
+    ) -> Promise<(), capnp::Error> {
+        let response = self.call(RESTORE, pry!(params.get()));
+        let layer = self.layer.clone();
+        trace::promise(async move {
+            let response = response.await?;
+            let answer = response
+                .get()?
+                .get_as::<echoer_provider::restore_results::Reader>()?;
+            results.get().set_echoer(layer.echoer(answer.get_echoer()?));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::EchoerProvider;
+
+    fn provider_call(method_id: u16) -> Method {
+        Method {
+            interface_id: echoer_provider::Client::TYPE_ID,
+            method_id,
+        }
+    }
+
+    #[test]
+    fn methods_are_named_from_the_table() {
+        assert_eq!(name(provider_call(ECHOER)), "EchoerProvider.echoer");
+        assert_eq!(name(provider_call(RESTORE)), "EchoerProvider.restore");
+        assert_eq!(short_name(provider_call(CONTROL)), "control");
+        assert_eq!(name(provider_call(99)), "EchoerProvider.unknown");
+        let echo = Method {
+            interface_id: echoer::Client::TYPE_ID,
+            method_id: 0,
+        };
+        assert_eq!(name(echo), "EchoerProvider.unknown");
+        assert!(returns_capability(provider_call(ECHOER)));
+        assert!(!returns_capability(provider_call(BUDGET)));
+        assert!(!returns_capability(echo));
+        assert_eq!(names().count(), PROVIDER_METHODS.len());
+    }
+
+    // Logs every provider call, and every echo on the echoers it hands out.
+    struct Logging {
+        log: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Layer for Logging {
+        fn around(
+            &self,
+            method: Method,
+            call: Forward,
+        ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+            self.log.borrow_mut().push(name(method));
+            call.send()
+        }
+
+        fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+            let log = self.log.clone();
+            ProxyBuilder::new(inner)
+                .params(move |_, _| {
+                    log.borrow_mut().push("Echoer.echo");
+                    Ok(())
+                })
+                .build()
+        }
+    }
+
+    #[test]
+    fn every_provider_call_and_handed_out_echoer_goes_through_the_layer() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let provider = provider(EchoerProvider::client(), Logging { log: log.clone() });
+        block_on(async {
+            let response = provider.echoer_request().send().promise.await.unwrap();
+            let echoer = response.get().unwrap().get_echoer().unwrap();
+            let sturdy = response.get().unwrap().get_ref().unwrap().to_vec();
+            assert!(!sturdy.is_empty());
+            echo(&echoer, "first").await;
+
+            provider.introspect_request().send().promise.await.unwrap();
+
+            let mut request = provider.restore_request();
+            request.get().set_ref(&sturdy[..]);
+            let response = request.send().promise.await.unwrap();
+            echo(&response.get().unwrap().get_echoer().unwrap(), "second").await;
+        });
+        assert_eq!(
+            *log.borrow(),
+            [
+                "EchoerProvider.echoer",
+                "Echoer.echo",
+                "EchoerProvider.introspect",
+                "EchoerProvider.restore",
+                "Echoer.echo",
+            ]
+        );
+    }
+
+    async fn echo(echoer: &echoer::Client, msg: &str) {
+        let mut request = echoer.echo_request();
+        request.get().set_msg(msg);
+        let response = request.send().promise.await.unwrap();
+        assert_eq!(response.get().unwrap().get_reply().unwrap(), msg.as_bytes());
+    }
+}
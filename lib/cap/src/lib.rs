@@ -1,10 +1,28 @@
 use capnp::capability::Promise;
 use capnp_rpc::pry;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 capnp::generated_code!(pub mod echo_capnp);
+capnp::generated_code!(pub mod guest_capnp);
+capnp::generated_code!(pub mod control_capnp);
+capnp::generated_code!(pub mod oneway_capnp);
+capnp::generated_code!(pub mod bulk_capnp);
+capnp::generated_code!(pub mod capture_capnp);
 
-use echo_capnp::{echoer, echoer_provider};
+pub mod breaker;
+pub mod bulk;
+pub mod capture;
+pub mod layer;
+pub mod logtail;
+pub mod mailbox;
+pub mod proxy;
+pub mod reconnect;
+pub mod reply;
+pub mod state;
+pub mod trace;
+
+use echo_capnp::{echoer, echoer_provider, log_tail, migration, progress};
+use state::Shared;
 
 pub struct Echoer;
 
@@ -12,22 +30,65 @@ impl echo_capnp::echoer::Server for Echoer {
     fn echo(
         &mut self,
         params: echoer::EchoParams,
-        mut results: echoer::EchoResults,
+        results: echoer::EchoResults,
     ) -> Promise<(), capnp::Error> {
-        debug!("Received echo request");
-        let msg = pry!(pry!(params.get()).get_msg());
-        let msg_bytes = msg.as_bytes();
+        echo_into(params, results)
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        echo_batch_into(params, results)
+    }
+}
+
+/// Write the message of `params` to `results` as the reply, copying it once (see [`reply`]).
+fn echo_into(
+    params: echoer::EchoParams,
+    mut results: echoer::EchoResults,
+) -> Promise<(), capnp::Error> {
+    debug!("Received echo request");
+    let msg = pry!(pry!(params.get()).get_msg());
+    let msg_bytes = msg.as_bytes();
+    // Checking the text is a pass over every byte; only pay for it when it gets logged.
+    if tracing::enabled!(tracing::Level::DEBUG) {
         let msg_str = std::str::from_utf8(msg_bytes);
         debug!(?msg_str, "Echoing message");
-        results.get().set_reply(msg_bytes);
-        debug!("Ended echo request");
-        Promise::ok(())
     }
+    pry!(reply::reflect(
+        msg_bytes,
+        results.get(),
+        echoer::echo_results::Builder::init_reply
+    ));
+    debug!("Ended echo request");
+    Promise::ok(())
+}
+
+/// Write the messages of `params` to `results` as the replies, in order.
+fn echo_batch_into(
+    params: echoer::EchoBatchParams,
+    mut results: echoer::EchoBatchResults,
+) -> Promise<(), capnp::Error> {
+    let msgs = pry!(pry!(params.get()).get_msgs());
+    debug!(messages = msgs.len(), "Echoing message batch");
+    pry!(results.get().set_replies(msgs));
+    Promise::ok(())
 }
 
 pub struct EchoerProvider {
     i: usize,
     echoers: Vec<echoer::Client>,
+    handouts: Handouts,
+    // Handed out by `progress()`; without one, guests can't report progress.
+    progress: Option<progress::Client>,
+    // Handed out by `logTail()`; without one, guests can't follow the host's log.
+    log_tail: Option<log_tail::Client>,
+    // Handed out by `migration()`; without one, guests can't be moved to a fresh instance.
+    migration: Option<migration::Client>,
+    // Handed out by `blobs()`, the same store to every caller.
+    blobs: bulk_capnp::blob_store::Client,
 }
 
 impl EchoerProvider {
@@ -39,7 +100,15 @@ impl EchoerProvider {
         }
         Self {
             i: 0,
+            handouts: Handouts(Shared::new(Tally {
+                counts: vec![0; echoers.len()],
+                live: 0,
+            })),
             echoers: echoers,
+            progress: None,
+            log_tail: None,
+            migration: None,
+            blobs: bulk::MemoryBlobStore::client(bulk::TransferOptions::default()),
         }
     }
 
@@ -47,24 +116,337 @@ impl EchoerProvider {
         let provider: echoer_provider::Client = capnp_rpc::new_client(EchoerProvider::new());
         provider
     }
+
+    /// A provider whose `progress()` hands out `progress`.
+    pub fn client_with_progress(progress: progress::Client) -> echoer_provider::Client {
+        capnp_rpc::new_client(EchoerProvider {
+            progress: Some(progress),
+            ..EchoerProvider::new()
+        })
+    }
+
+    /// A provider whose `progress()` hands out `progress`, and whose `logTail()` and
+    /// `migration()` hand out `log_tail` and `migration`, or fail as unimplemented without them.
+    pub fn with_services(
+        progress: progress::Client,
+        log_tail: Option<log_tail::Client>,
+        migration: Option<migration::Client>,
+    ) -> Self {
+        EchoerProvider {
+            progress: Some(progress),
+            log_tail,
+            migration,
+            ..EchoerProvider::new()
+        }
+    }
+
+    /// How often `echoer()` has handed out each echoer, readable after the provider has been
+    /// turned into a client.
+    pub fn handouts(&self) -> Handouts {
+        self.handouts.clone()
+    }
+}
+
+/// Per-echoer counts of an [`EchoerProvider`]'s `echoer()` answers, and how many of them are
+/// still held. Shared by the provider and its answers.
+#[derive(Clone)]
+pub struct Handouts(Shared<Tally>);
+
+struct Tally {
+    counts: Vec<u64>,
+    live: u32,
+}
+
+impl Handouts {
+    pub fn counts(&self) -> Vec<u64> {
+        self.0.with(|tally| tally.counts.clone())
+    }
+
+    /// Answers not yet released by whoever holds them.
+    pub fn live(&self) -> u32 {
+        self.0.with(|tally| tally.live)
+    }
+
+    /// Log the counts, and warn if the round robin has drifted: the counts of any two echoers
+    /// should differ by at most one, however many calls arrived at once.
+    pub fn log_summary(&self) {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        let min = counts.iter().copied().min().unwrap_or(0);
+        let max = counts.iter().copied().max().unwrap_or(0);
+        if max - min > 1 {
+            warn!(target: "provider", ?counts, "echoer round robin is unbalanced");
+        } else {
+            info!(target: "provider", total, min, max, "echoers handed out");
+        }
+    }
 }
 
 impl echoer_provider::Server for EchoerProvider {
     fn echoer(
         &mut self,
-    _params: echoer_provider::EchoerParams,
+        _params: echoer_provider::EchoerParams,
         mut results: echoer_provider::EchoerResults,
     ) -> Promise<(), capnp::Error> {
-    debug!("Received echoer request");
-        
+        debug!("Received echoer request");
+
         // Round-robin selection of an Echoer client without risking out-of-bounds.
         // Use modulo over the number of echoers, then bump the counter.
         let len = self.echoers.len();
         let idx = self.i % len;
         let ec = self.echoers[idx].clone();
         self.i = self.i.wrapping_add(1);
+        self.handouts.0.with(|tally| {
+            tally.counts[idx] += 1;
+            tally.live += 1;
+        });
+        // Each answer is a capability of its own, so `introspect()` can tell when it is released.
+        let ec: echoer::Client = capnp_rpc::new_client(HandedOut {
+            inner: ec,
+            handouts: self.handouts.clone(),
+        });
         results.get().set_echoer(ec);
+        results.get().set_ref(&sturdy_ref(idx)[..]);
         debug!("Ended echoer request");
         Promise::ok(())
     }
+
+    fn progress(
+        &mut self,
+        _params: echoer_provider::ProgressParams,
+        mut results: echoer_provider::ProgressResults,
+    ) -> Promise<(), capnp::Error> {
+        match &self.progress {
+            Some(progress) => {
+                results.get().set_progress(progress.clone());
+                Promise::ok(())
+            }
+            None => Promise::err(capnp::Error::unimplemented(
+                "progress reporting is not available".to_string(),
+            )),
+        }
+    }
+
+    fn log_tail(
+        &mut self,
+        _params: echoer_provider::LogTailParams,
+        mut results: echoer_provider::LogTailResults,
+    ) -> Promise<(), capnp::Error> {
+        match &self.log_tail {
+            Some(log_tail) => {
+                results.get().set_log_tail(log_tail.clone());
+                Promise::ok(())
+            }
+            None => Promise::err(capnp::Error::unimplemented(
+                "the host's log is not available".to_string(),
+            )),
+        }
+    }
+
+    fn introspect(
+        &mut self,
+        _params: echoer_provider::IntrospectParams,
+        mut results: echoer_provider::IntrospectResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut stats = results.get().init_stats();
+        stats.set_live_echoers(self.handouts.live());
+        stats.set_echoers_handed_out(self.handouts.counts().iter().sum());
+        Promise::ok(())
+    }
+
+    fn control(
+        &mut self,
+        _params: echoer_provider::ControlParams,
+        _results: echoer_provider::ControlResults,
+    ) -> Promise<(), capnp::Error> {
+        Promise::err(capnp::Error::unimplemented(
+            "test control is not enabled (run the host with --test-control)".to_string(),
+        ))
+    }
+
+    /// Answers the echoer in the slot `ref` names. Restores count as live answers, but not in
+    /// the round robin.
+    fn restore(
+        &mut self,
+        params: echoer_provider::RestoreParams,
+        mut results: echoer_provider::RestoreResults,
+    ) -> Promise<(), capnp::Error> {
+        let sturdy = pry!(pry!(params.get()).get_ref());
+        let Some(ec) = slot(sturdy).and_then(|idx| self.echoers.get(idx)) else {
+            return Promise::err(capnp::Error::failed(format!(
+                "unknown sturdy ref {sturdy:02x?}"
+            )));
+        };
+        self.handouts.0.with(|tally| tally.live += 1);
+        let ec: echoer::Client = capnp_rpc::new_client(HandedOut {
+            inner: ec.clone(),
+            handouts: self.handouts.clone(),
+        });
+        results.get().set_echoer(ec);
+        Promise::ok(())
+    }
+
+    fn migration(
+        &mut self,
+        _params: echoer_provider::MigrationParams,
+        mut results: echoer_provider::MigrationResults,
+    ) -> Promise<(), capnp::Error> {
+        match &self.migration {
+            Some(migration) => {
+                results.get().set_migration(migration.clone());
+                Promise::ok(())
+            }
+            None => Promise::err(capnp::Error::unimplemented(
+                "migration is not available (run the host with --control)".to_string(),
+            )),
+        }
+    }
+
+    fn blobs(
+        &mut self,
+        _params: echoer_provider::BlobsParams,
+        mut results: echoer_provider::BlobsResults,
+    ) -> Promise<(), capnp::Error> {
+        results.get().set_store(self.blobs.clone());
+        Promise::ok(())
+    }
+}
+
+/// The sturdy ref of the echoer in slot `idx`: the slot number, as a little-endian `u32`. It
+/// names the same echoer on every connection to a provider.
+fn sturdy_ref(idx: usize) -> [u8; 4] {
+    (idx as u32).to_le_bytes()
+}
+
+/// The slot a sturdy ref names, if it is one.
+fn slot(sturdy: &[u8]) -> Option<usize> {
+    Some(u32::from_le_bytes(sturdy.try_into().ok()?) as usize)
+}
+
+/// One `echoer()` answer, counted as live until it is released.
+struct HandedOut {
+    #[cfg_attr(feature = "reply-reuse", allow(dead_code))]
+    inner: echoer::Client,
+    handouts: Handouts,
+}
+
+impl echoer::Server for HandedOut {
+    /// With `reply-reuse`, answered here: the echoers behind handed-out ones are all plain
+    /// [`Echoer`]s, so the reply can go straight into this call's results.
+    #[cfg(feature = "reply-reuse")]
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        echo_into(params, results)
+    }
+
+    #[cfg(feature = "reply-reuse")]
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        echo_batch_into(params, results)
+    }
+
+    #[cfg(not(feature = "reply-reuse"))]
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        trace::promise(async move {
+            let response = request.send().promise.await?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    #[cfg(not(feature = "reply-reuse"))]
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        trace::promise(async move {
+            let response = request.send().promise.await?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
+
+impl Drop for HandedOut {
+    fn drop(&mut self) {
+        self.handouts.0.with(|tally| tally.live -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[test]
+    fn handouts_stay_consistent_under_concurrent_calls() {
+        let provider = EchoerProvider::new();
+        let handouts = provider.handouts();
+        let client: echoer_provider::Client = capnp_rpc::new_client(provider);
+        let mut pool = LocalPool::new();
+        // All in flight at once, as under the stress workload.
+        let calls = (0..1000).map(|_| {
+            let request = client.echoer_request();
+            async move {
+                let response = request.send().promise.await.unwrap();
+                response.get().unwrap().get_echoer().unwrap()
+            }
+        });
+        let echoers = pool.run_until(join_all(calls));
+        assert_eq!(handouts.counts(), vec![100; 10]);
+        assert_eq!(handouts.live(), 1000);
+        drop(echoers);
+        pool.run_until_stalled();
+        assert_eq!(handouts.live(), 0);
+        assert_eq!(handouts.counts().iter().sum::<u64>(), 1000);
+    }
+
+    #[test]
+    fn restore_answers_the_echoer_a_sturdy_ref_names() {
+        let provider = EchoerProvider::new();
+        let handouts = provider.handouts();
+        let client: echoer_provider::Client = capnp_rpc::new_client(provider);
+        let mut pool = LocalPool::new();
+        pool.run_until(async {
+            let response = client.echoer_request().send().promise.await.unwrap();
+            let sturdy = response.get().unwrap().get_ref().unwrap().to_vec();
+            assert_eq!(sturdy, sturdy_ref(0));
+
+            let mut restore = client.restore_request();
+            restore.get().set_ref(&sturdy[..]);
+            let response = restore.send().promise.await.unwrap();
+            let echoer = response.get().unwrap().get_echoer().unwrap();
+            let mut echo = echoer.echo_request();
+            echo.get().set_msg("restored");
+            let reply = echo.send().promise.await.unwrap();
+            assert_eq!(reply.get().unwrap().get_reply().unwrap(), b"restored");
+
+            let mut restore = client.restore_request();
+            restore.get().set_ref(&b"no such echoer"[..]);
+            assert!(restore.send().promise.await.is_err());
+        });
+        // The restore is a live answer, but not a turn of the round robin.
+        assert_eq!(handouts.counts().iter().sum::<u64>(), 1);
+    }
 }
@@ -0,0 +1,219 @@
+//! Following the host's log over RPC.
+//!
+//! A [`LogBook`] keeps the most recent log records in memory, numbered in order. Any thread can
+//! append to it. [`LogTailServer`] serves a `LogTail` capability over a book: `follow` pushes
+//! records to the caller's `LogSink` for as long as the call lasts, waiting for new records once
+//! it has caught up. The stream has no end of its own. The caller stops it by canceling the call,
+//! which drops the push loop along with its unanswered pushes, and a failed push ends it too.
+//!
+//! The sink sets the pace: at most `window` pushes are unanswered at once, and the loop waits for
+//! the oldest answer before sending more. The book itself never waits for a follower. A follower
+//! that falls more than the book's capacity behind skips the records that were dropped, and sees
+//! the gap in their sequence numbers.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use futures::future::{Either, select};
+use tracing::debug;
+
+use crate::echo_capnp::{log_sink, log_tail};
+use crate::trace;
+
+// Records per push.
+const BATCH: usize = 64;
+// Unanswered pushes a follower may ask for.
+const MAX_WINDOW: u32 = 64;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub seq: u64,
+    pub timestamp_ns: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+struct State {
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+    waiters: Vec<Waker>,
+}
+
+/// The most recent `capacity` log records.
+#[derive(Clone)]
+pub struct LogBook {
+    state: Arc<Mutex<State>>,
+    capacity: usize,
+    started: Instant,
+}
+
+impl LogBook {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                entries: VecDeque::with_capacity(capacity),
+                next_seq: 0,
+                waiters: Vec::new(),
+            })),
+            capacity,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn append(&self, level: &str, target: &str, message: String) {
+        let timestamp_ns = self.started.elapsed().as_nanos() as u64;
+        let waiters = {
+            let mut state = self.state.lock().unwrap();
+            if state.entries.len() == self.capacity {
+                state.entries.pop_front();
+            }
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.entries.push_back(Entry {
+                seq,
+                timestamp_ns,
+                level: level.to_string(),
+                target: target.to_string(),
+                message,
+            });
+            std::mem::take(&mut state.waiters)
+        };
+        // Woken outside the lock: a waker may log, which appends again.
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    /// Up to `max` records from `seq` on, or from the oldest one kept if `seq` was dropped.
+    pub fn read(&self, seq: u64, max: usize) -> Vec<Entry> {
+        let state = self.state.lock().unwrap();
+        let first = state.entries.front().map_or(0, |e| e.seq);
+        let skip = seq.saturating_sub(first) as usize;
+        state.entries.iter().skip(skip).take(max).cloned().collect()
+    }
+
+    /// Resolves once a record numbered `seq` or later has been appended.
+    pub fn appended(&self, seq: u64) -> Appended {
+        Appended {
+            book: self.clone(),
+            seq,
+        }
+    }
+}
+
+pub struct Appended {
+    book: LogBook,
+    seq: u64,
+}
+
+impl Future for Appended {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.book.state.lock().unwrap();
+        if state.next_seq > self.seq {
+            return Poll::Ready(());
+        }
+        if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            state.waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Serves a `LogTail` over a [`LogBook`].
+pub struct LogTailServer {
+    book: LogBook,
+}
+
+impl LogTailServer {
+    pub fn client(book: LogBook) -> log_tail::Client {
+        capnp_rpc::new_client(Self { book })
+    }
+}
+
+impl log_tail::Server for LogTailServer {
+    fn follow(
+        &mut self,
+        params: log_tail::FollowParams,
+        _results: log_tail::FollowResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let sink = pry!(params.get_sink());
+        let window = params.get_window().clamp(1, MAX_WINDOW) as usize;
+        let mut follower = Follower {
+            seq: params.get_from_seq(),
+            delivered: 0,
+        };
+        let book = self.book.clone();
+        trace::promise(async move {
+            let mut unanswered: VecDeque<Promise<_, capnp::Error>> =
+                VecDeque::with_capacity(window);
+            loop {
+                let entries = book.read(follower.seq, BATCH);
+                let Some(last) = entries.last() else {
+                    // Caught up. A push to a local sink only reaches it once its promise is
+                    // polled, so see to the oldest one while waiting for more records.
+                    let appended = book.appended(follower.seq);
+                    match unanswered.front_mut() {
+                        None => appended.await,
+                        Some(oldest) => {
+                            if let Either::Right((answer, _)) = select(appended, oldest).await {
+                                answer?;
+                                unanswered.pop_front();
+                            }
+                        }
+                    }
+                    continue;
+                };
+                follower.seq = last.seq + 1;
+                if unanswered.len() == window {
+                    unanswered.pop_front().unwrap().await?;
+                }
+                unanswered.push_back(push(&sink, &entries).send().promise);
+                follower.delivered += entries.len() as u64;
+            }
+        })
+    }
+}
+
+fn push(
+    sink: &log_sink::Client,
+    entries: &[Entry],
+) -> capnp::capability::Request<log_sink::push_params::Owned, log_sink::push_results::Owned> {
+    let mut request = sink.push_request();
+    let mut records = request.get().init_records(entries.len() as u32);
+    for (i, entry) in entries.iter().enumerate() {
+        let mut record = records.reborrow().get(i as u32);
+        record.set_seq(entry.seq);
+        record.set_timestamp_ns(entry.timestamp_ns);
+        record.set_level(entry.level.as_str());
+        record.set_target(entry.target.as_str());
+        record.set_message(entry.message.as_str());
+    }
+    request
+}
+
+/// Where a follower has got to; logged when the follow call ends, however it ends.
+struct Follower {
+    seq: u64,
+    delivered: u64,
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        debug!(
+            target: "logtail",
+            delivered = self.delivered,
+            next_seq = self.seq,
+            "log follower ended"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Blocking work off the RPC executor.
+//!
+//! A connection's servers all run on one thread, so a call that blocks, on a sqlite query or a
+//! file read, stalls every other call on the connection until it returns. A [`Mailbox`] owns a
+//! thread of its own and the state the blocking work needs, such as the database handle. Servers
+//! hand it jobs with [`Mailbox::call`], which returns a future for the job's result, and the RPC
+//! executor goes on with other calls meanwhile. The thread runs jobs one at a time, in the order
+//! they were sent, so the state needs no locking. A job runs in the span current when it was
+//! sent.
+//!
+//! ```ignore
+//! let value = self.store.call(move |db| db.get(&key));
+//! trace::promise(async move {
+//!     let value = value.await?;
+//!     results.get().set_value(&value);
+//!     Ok(())
+//! })
+//! ```
+//!
+//! The mailbox is unbounded: callers that can flood it bound their own calls in flight. The thread
+//! stops once every handle has been dropped and the jobs already sent have run.
+
+use std::sync::mpsc;
+use std::thread;
+
+use futures::channel::oneshot;
+use tracing::{Span, warn};
+
+type Job<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+/// A handle to a thread that runs jobs on state `S`. Clones send to the same thread.
+pub struct Mailbox<S> {
+    jobs: mpsc::Sender<Job<S>>,
+}
+
+impl<S: Send + 'static> Mailbox<S> {
+    /// Start a thread named `name` that owns `state`.
+    pub fn spawn(name: &str, state: S) -> std::io::Result<Self> {
+        let (jobs, inbox) = mpsc::channel::<Job<S>>();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let mut state = state;
+                for job in inbox {
+                    job(&mut state);
+                }
+            })?;
+        Ok(Self { jobs })
+    }
+
+    /// Run `job` on the mailbox's thread. The returned future resolves to what it returns, or
+    /// fails if the thread is gone, or panicked in the job.
+    pub fn call<R, F>(&self, job: F) -> impl Future<Output = Result<R, capnp::Error>> + 'static
+    where
+        F: FnOnce(&mut S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let span = Span::current();
+        let sent = self.jobs.send(Box::new(move |state: &mut S| {
+            let result = span.in_scope(|| job(state));
+            // The caller may have stopped waiting.
+            let _ = tx.send(result);
+        }));
+        async move {
+            sent.map_err(|_| stopped())?;
+            rx.await.map_err(|_| {
+                warn!(target: "mailbox", "mailbox job ended without a result");
+                stopped()
+            })
+        }
+    }
+}
+
+impl<S> Clone for Mailbox<S> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+fn stopped() -> capnp::Error {
+    capnp::Error::failed("mailbox thread stopped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[test]
+    fn call_resolves_to_the_jobs_result() {
+        let mailbox = Mailbox::spawn("test-mailbox", 40).unwrap();
+        let result = block_on(mailbox.call(|n: &mut i32| *n + 2)).unwrap();
+        assert_eq!(result, 42);
+    }
+
+    #[test]
+    fn jobs_run_in_the_order_they_were_sent() {
+        let mailbox = Mailbox::spawn("test-mailbox", Vec::new()).unwrap();
+        // Sent in order, awaited together.
+        let calls: Vec<_> = (0..100)
+            .map(|i| mailbox.call(move |seen: &mut Vec<i32>| seen.push(i)))
+            .collect();
+        block_on(join_all(calls));
+        let seen = block_on(mailbox.call(|seen: &mut Vec<i32>| seen.clone())).unwrap();
+        assert_eq!(seen, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_panicking_job_fails_its_call() {
+        let mailbox = Mailbox::spawn("test-mailbox", ()).unwrap();
+        let result = block_on(mailbox.call(|_: &mut ()| -> u32 { panic!("job failed") }));
+        assert!(result.is_err());
+        // The thread is gone with it, so later calls fail too rather than wait.
+        assert!(block_on(mailbox.call(|_: &mut ()| ())).is_err());
+    }
+
+    struct DropSignal(mpsc::Sender<()>);
+
+    impl Drop for DropSignal {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[test]
+    fn dropping_every_handle_stops_the_thread() {
+        let (tx, dropped) = mpsc::channel();
+        let mailbox = Mailbox::spawn("test-mailbox", DropSignal(tx)).unwrap();
+        let clone = mailbox.clone();
+        drop(mailbox);
+        // A handle is left, so the thread still owns its state.
+        assert!(dropped.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(clone);
+        // The state is dropped when the thread's loop ends.
+        dropped.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,400 @@
+//! A generic forwarding proxy with hooks to rewrite calls.
+//!
+//! [`ProxyBuilder`] wraps any capability in a proxy that forwards every call on it, whatever the
+//! interface, to the target. Hooks registered on the builder see each call's params after they
+//! are copied into the forwarded request, and its results after they are copied into the answer,
+//! and may change them in place: redact a field, stamp a tenant ID, swap an error for a default.
+//! A hook receives the [`Method`] being called and the payload as an `AnyPointer`, which it reads
+//! or rewrites as the method's own params or results type.
+//!
+//! An `around` hook makes the call itself. It gets the call as a [`Forward`], can wait or fail
+//! before passing it on with [`Forward::send`], and sees the response before it is copied into
+//! the answer. That is how the host's layers over the guest's provider count, time, trace or
+//! fail calls (see [`layer`](crate::layer)). Methods a typed server answers in place of the
+//! target are handed to it with `serve`; no hooks run on those.
+//!
+//! Capabilities in results are passed on as they are; they are not proxied in turn. Calls are
+//! answered once the target has answered, so promise pipelining stops at the proxy.
+
+use std::rc::Rc;
+
+use capnp::any_pointer;
+use capnp::capability::{
+    self, FromClientHook, FromServer, Params, Promise, Request, Response, Results,
+};
+use capnp::traits::HasTypeId;
+use capnp_rpc::pry;
+
+use crate::trace;
+
+/// The method a call is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Method {
+    pub interface_id: u64,
+    pub method_id: u16,
+}
+
+impl Method {
+    /// Whether this is method `method_id` of interface `C`, e.g.
+    /// `method.is::<echoer::Client>(0)` for `Echoer.echo`.
+    pub fn is<C: HasTypeId>(&self, method_id: u16) -> bool {
+        self.interface_id == C::TYPE_ID && self.method_id == method_id
+    }
+}
+
+/// Rewrites a call's params or results in place.
+pub type Hook = Rc<dyn Fn(Method, any_pointer::Builder) -> capnp::Result<()>>;
+
+/// Makes a call, passing it on with [`Forward::send`].
+pub type Around =
+    Rc<dyn Fn(Method, Forward) -> Promise<Response<any_pointer::Owned>, capnp::Error>>;
+
+/// A call on its way to the target, with the `around` hooks it has yet to go through.
+pub struct Forward {
+    method: Method,
+    request: Request<any_pointer::Owned, any_pointer::Owned>,
+    around: Rc<[Around]>,
+    next: usize,
+}
+
+impl Forward {
+    /// `request`, on its way straight to the target.
+    pub(crate) fn new(
+        method: Method,
+        request: Request<any_pointer::Owned, any_pointer::Owned>,
+    ) -> Self {
+        Self {
+            method,
+            request,
+            around: Rc::new([]),
+            next: 0,
+        }
+    }
+
+    /// The params the target will get.
+    pub fn params(&mut self) -> any_pointer::Reader<'_> {
+        self.request.get().into_reader()
+    }
+
+    /// Pass the call on to the next `around` hook, or to the target after the last one.
+    pub fn send(mut self) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let Some(hook) = self.around.get(self.next).cloned() else {
+            return self.request.send().promise;
+        };
+        self.next += 1;
+        hook(self.method, self)
+    }
+}
+
+pub struct ProxyBuilder {
+    target: capability::Client,
+    params: Vec<Hook>,
+    results: Vec<Hook>,
+    around: Vec<Around>,
+    served: Vec<Served>,
+}
+
+impl ProxyBuilder {
+    /// A proxy forwarding to `target` unchanged, until hooks are added.
+    pub fn new<C: FromClientHook>(target: C) -> Self {
+        Self {
+            target: capability::Client::new(target.into_client_hook()),
+            params: Vec::new(),
+            results: Vec::new(),
+            around: Vec::new(),
+            served: Vec::new(),
+        }
+    }
+
+    /// Rewrite params before they are forwarded. Hooks run in the order they were added.
+    pub fn params(
+        mut self,
+        hook: impl Fn(Method, any_pointer::Builder) -> capnp::Result<()> + 'static,
+    ) -> Self {
+        self.params.push(Rc::new(hook));
+        self
+    }
+
+    /// Rewrite results before they are returned. Hooks run in the order they were added.
+    pub fn results(
+        mut self,
+        hook: impl Fn(Method, any_pointer::Builder) -> capnp::Result<()> + 'static,
+    ) -> Self {
+        self.results.push(Rc::new(hook));
+        self
+    }
+
+    /// Make each call through `hook`, once its params have been rewritten. The first hook added
+    /// is the outermost.
+    pub fn around(
+        mut self,
+        hook: impl Fn(Method, Forward) -> Promise<Response<any_pointer::Owned>, capnp::Error> + 'static,
+    ) -> Self {
+        self.around.push(Rc::new(hook));
+        self
+    }
+
+    /// Answer `methods` of interface `C` with `server` rather than the target.
+    pub fn serve<C, S>(mut self, methods: &[u16], server: S) -> Self
+    where
+        C: FromServer<S> + HasTypeId,
+    {
+        self.served.push(Served {
+            interface_id: C::TYPE_ID,
+            methods: methods.to_vec(),
+            server: Box::new(C::from_server(server)),
+        });
+        self
+    }
+
+    /// The proxy, as a client of the target's interface.
+    pub fn build<C: FromClientHook>(self) -> C {
+        let proxy = Proxy {
+            target: self.target,
+            params: self.params.into(),
+            results: self.results.into(),
+            around: self.around.into(),
+            served: self.served,
+        };
+        C::new(Box::new(capnp_rpc::local::Client::new(proxy)))
+    }
+}
+
+/// Methods a typed server answers.
+struct Served {
+    interface_id: u64,
+    methods: Vec<u16>,
+    server: Box<dyn capability::Server>,
+}
+
+struct Proxy {
+    target: capability::Client,
+    params: Rc<[Hook]>,
+    results: Rc<[Hook]>,
+    around: Rc<[Around]>,
+    served: Vec<Served>,
+}
+
+impl capability::Server for Proxy {
+    fn dispatch_call(
+        &mut self,
+        interface_id: u64,
+        method_id: u16,
+        params: Params<any_pointer::Owned>,
+        mut results: Results<any_pointer::Owned>,
+    ) -> Promise<(), capnp::Error> {
+        if let Some(served) = self.served.iter_mut().find(|served| {
+            served.interface_id == interface_id && served.methods.contains(&method_id)
+        }) {
+            return served
+                .server
+                .dispatch_call(interface_id, method_id, params, results);
+        }
+        let method = Method {
+            interface_id,
+            method_id,
+        };
+        let mut request = self
+            .target
+            .new_call::<any_pointer::Owned, any_pointer::Owned>(interface_id, method_id, None);
+        pry!(request.get().set_as(pry!(params.get())));
+        for hook in self.params.iter() {
+            pry!(hook(method, request.get()));
+        }
+        let call = Forward {
+            method,
+            request,
+            around: self.around.clone(),
+            next: 0,
+        };
+        let hooks = self.results.clone();
+        trace::promise(async move {
+            let response = call.send().await?;
+            results.get().set_as(response.get()?)?;
+            for hook in hooks.iter() {
+                hook(method, results.get())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::Echoer;
+    use crate::echo_capnp::echoer;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // An echoer whose every call fails.
+    struct Failing;
+
+    impl echoer::Server for Failing {
+        fn echo(
+            &mut self,
+            _params: echoer::EchoParams,
+            _results: echoer::EchoResults,
+        ) -> Promise<(), capnp::Error> {
+            Promise::err(capnp::Error::failed("target failed".into()))
+        }
+    }
+
+    fn target() -> echoer::Client {
+        capnp_rpc::new_client(Echoer)
+    }
+
+    fn echo(echoer: &echoer::Client, msg: &str) -> capnp::Result<Vec<u8>> {
+        let mut request = echoer.echo_request();
+        request.get().set_msg(msg);
+        block_on(async move { Ok(request.send().promise.await?.get()?.get_reply()?.to_vec()) })
+    }
+
+    // A params or results hook that logs `name`.
+    fn logged(
+        log: &Log,
+        name: &'static str,
+    ) -> impl Fn(Method, any_pointer::Builder) -> capnp::Result<()> + 'static {
+        let log = log.clone();
+        move |_, _| {
+            log.borrow_mut().push(name.to_string());
+            Ok(())
+        }
+    }
+
+    // An `around` hook that logs `name` before and after passing the call on.
+    fn logged_around(
+        log: &Log,
+        name: &'static str,
+    ) -> impl Fn(Method, Forward) -> Promise<Response<any_pointer::Owned>, capnp::Error> + 'static
+    {
+        let log = log.clone();
+        move |_, call| {
+            log.borrow_mut().push(format!("{name} before"));
+            let response = call.send();
+            let log = log.clone();
+            Promise::from_future(async move {
+                let response = response.await;
+                log.borrow_mut().push(format!("{name} after"));
+                response
+            })
+        }
+    }
+
+    fn failing(
+        message: &'static str,
+    ) -> impl Fn(Method, any_pointer::Builder) -> capnp::Result<()> {
+        move |_, _| Err(capnp::Error::failed(message.into()))
+    }
+
+    #[test]
+    fn hooks_run_in_the_order_they_were_added() {
+        let log = Log::default();
+        let echoer: echoer::Client = ProxyBuilder::new(target())
+            .params(logged(&log, "params 1"))
+            .params(logged(&log, "params 2"))
+            .around(logged_around(&log, "around 1"))
+            .around(logged_around(&log, "around 2"))
+            .results(logged(&log, "results 1"))
+            .results(logged(&log, "results 2"))
+            .build();
+        assert_eq!(echo(&echoer, "hi").unwrap(), b"hi");
+        assert_eq!(
+            *log.borrow(),
+            [
+                "params 1",
+                "params 2",
+                "around 1 before",
+                "around 2 before",
+                "around 2 after",
+                "around 1 after",
+                "results 1",
+                "results 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn hooks_rewrite_params_and_results() {
+        let echoer: echoer::Client = ProxyBuilder::new(target())
+            .params(|method, params| {
+                assert!(method.is::<echoer::Client>(0));
+                params
+                    .get_as::<echoer::echo_params::Builder>()?
+                    .set_msg("rewritten");
+                Ok(())
+            })
+            .results(|_, results| {
+                let mut results = results.get_as::<echoer::echo_results::Builder>()?;
+                let reply = results.reborrow().get_reply()?.to_ascii_uppercase();
+                results.set_reply(&reply[..]);
+                Ok(())
+            })
+            .build();
+        assert_eq!(echo(&echoer, "hi").unwrap(), b"REWRITTEN");
+    }
+
+    #[test]
+    fn a_failing_params_hook_fails_the_call_before_it_is_forwarded() {
+        let log = Log::default();
+        let echoer: echoer::Client = ProxyBuilder::new(target())
+            .params(failing("bad params"))
+            .params(logged(&log, "params 2"))
+            .around(logged_around(&log, "around"))
+            .build();
+        let err = echo(&echoer, "hi").unwrap_err();
+        assert!(err.extra.contains("bad params"), "{err}");
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn an_around_hook_can_refuse_the_call() {
+        let log = Log::default();
+        let echoer: echoer::Client = ProxyBuilder::new(target())
+            .around(|_, _| Promise::err(capnp::Error::overloaded("refused".into())))
+            .around(logged_around(&log, "inner"))
+            .results(logged(&log, "results"))
+            .build();
+        let err = echo(&echoer, "hi").unwrap_err();
+        assert_eq!(err.kind, capnp::ErrorKind::Overloaded);
+        assert!(err.extra.contains("refused"), "{err}");
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn errors_from_the_target_and_results_hooks_reach_the_caller() {
+        let log = Log::default();
+        let echoer: echoer::Client =
+            ProxyBuilder::new(capnp_rpc::new_client::<echoer::Client, _>(Failing))
+                .around(logged_around(&log, "around"))
+                .results(logged(&log, "results"))
+                .build();
+        let err = echo(&echoer, "hi").unwrap_err();
+        assert!(err.extra.contains("target failed"), "{err}");
+        // The around hook sees the failure; results hooks don't run on it.
+        assert_eq!(*log.borrow(), ["around before", "around after"]);
+
+        let echoer: echoer::Client = ProxyBuilder::new(target())
+            .results(failing("bad results"))
+            .build();
+        let err = echo(&echoer, "hi").unwrap_err();
+        assert!(err.extra.contains("bad results"), "{err}");
+    }
+
+    #[test]
+    fn served_methods_skip_the_target_and_the_hooks() {
+        let log = Log::default();
+        let echoer: echoer::Client = ProxyBuilder::new(target())
+            .params(logged(&log, "params"))
+            .around(logged_around(&log, "around"))
+            .serve::<echoer::Client, _>(&[0], Failing)
+            .build();
+        let err = echo(&echoer, "hi").unwrap_err();
+        assert!(err.extra.contains("target failed"), "{err}");
+        assert!(log.borrow().is_empty());
+    }
+}
@@ -0,0 +1,432 @@
+//! Reconnecting after a transport reset.
+//!
+//! When a connection drops, every capability imported over it breaks: the calls in flight and
+//! every later call on it fail as `disconnected`. A [`Session`] holds a way to connect, a closure
+//! that sets up a fresh connection and returns the peer's bootstrap capability, and the bootstrap
+//! of the current connection. A call that fails as disconnected marks that connection broken, and
+//! the next use connects again. Calls that reset at the same time share one reconnect.
+//!
+//! A call in flight when the connection broke may or may not have run at the peer.
+//! [`Session::call`] returns that failure to the caller. [`Session::call_idempotent`] is for calls
+//! that are safe to run twice: it sends them again on a new connection, up to the session's
+//! attempts.
+//!
+//! Other capabilities come back through sturdy refs, names for them that outlive the connection,
+//! such as the `ref` that `EchoerProvider.echoer()` answers with. A [`Restorable`] holds the
+//! closure that restores one from the bootstrap, e.g. with `EchoerProvider.restore`. It restores
+//! the capability once on each new connection, when it is first used there.
+//!
+//! Nothing here waits between attempts. A `connect` closure that should back off sleeps before it
+//! connects. The guest SDK's `reconnect` module is the same for guests.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::rc::Rc;
+
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+use tracing::{info, warn};
+
+type Connecting<T> = Shared<LocalBoxFuture<'static, capnp::Result<T>>>;
+type Restore<B, C> = Box<dyn Fn(&B) -> LocalBoxFuture<'static, capnp::Result<C>>>;
+
+/// Whether `error` means the connection it was sent on is gone.
+pub fn is_disconnected(error: &capnp::Error) -> bool {
+    error.kind == capnp::ErrorKind::Disconnected
+}
+
+enum State<B> {
+    /// Connection `epoch` is broken, or none has been made yet (epoch 0).
+    Broken(u64),
+    /// Connection `epoch` is being made; everyone who needs it waits on the same attempt.
+    Connecting(u64, Connecting<B>),
+    /// The bootstrap of connection `epoch`.
+    Connected(u64, B),
+}
+
+struct Inner<B> {
+    connect: Box<dyn Fn() -> LocalBoxFuture<'static, capnp::Result<B>>>,
+    attempts: u32,
+    state: RefCell<State<B>>,
+    connections: Cell<u64>,
+}
+
+/// A connection to a peer that is made again when it breaks. Clones share the connection.
+pub struct Session<B> {
+    inner: Rc<Inner<B>>,
+}
+
+impl<B> Clone for Session<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: Clone + 'static> Session<B> {
+    /// A session that connects with `connect` when it is first used. Idempotent calls are sent
+    /// at most `attempts` times.
+    pub fn new<F, Fut>(attempts: u32, connect: F) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = capnp::Result<B>> + 'static,
+    {
+        Self {
+            inner: Rc::new(Inner {
+                connect: Box::new(move || connect().boxed_local()),
+                attempts: attempts.max(1),
+                state: RefCell::new(State::Broken(0)),
+                connections: Cell::new(0),
+            }),
+        }
+    }
+
+    /// Connections made so far.
+    pub fn connections(&self) -> u64 {
+        self.inner.connections.get()
+    }
+
+    /// The bootstrap of the current connection, and the connection's epoch. Connects first if
+    /// there is none.
+    async fn current(&self) -> capnp::Result<(u64, B)> {
+        let (epoch, connecting) = {
+            let mut state = self.inner.state.borrow_mut();
+            match &*state {
+                State::Connected(epoch, bootstrap) => return Ok((*epoch, bootstrap.clone())),
+                State::Connecting(epoch, connecting) => (*epoch, connecting.clone()),
+                State::Broken(broken) => {
+                    let epoch = broken + 1;
+                    let connecting = (self.inner.connect)().shared();
+                    *state = State::Connecting(epoch, connecting.clone());
+                    (epoch, connecting)
+                }
+            }
+        };
+        let result = connecting.await;
+        // The first caller back records the outcome for the others.
+        let mut state = self.inner.state.borrow_mut();
+        if matches!(&*state, State::Connecting(e, _) if *e == epoch) {
+            *state = match &result {
+                Ok(bootstrap) => {
+                    self.inner.connections.set(self.connections() + 1);
+                    if epoch > 1 {
+                        info!(target: "reconnect", epoch, "reconnected");
+                    }
+                    State::Connected(epoch, bootstrap.clone())
+                }
+                Err(e) => {
+                    warn!(target: "reconnect", epoch, error = %e, "connecting failed");
+                    State::Broken(epoch)
+                }
+            };
+        }
+        result.map(|bootstrap| (epoch, bootstrap))
+    }
+
+    /// Mark connection `epoch` broken, unless it has been replaced already.
+    fn broke(&self, epoch: u64) {
+        let mut state = self.inner.state.borrow_mut();
+        if matches!(&*state, State::Connected(e, _) if *e == epoch) {
+            warn!(target: "reconnect", epoch, "connection reset");
+            *state = State::Broken(epoch);
+        }
+    }
+
+    /// Run `call` on the bootstrap once. If it fails as disconnected, the next call reconnects.
+    pub async fn call<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: FnOnce(&B) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let (epoch, bootstrap) = self.current().await?;
+        let result = call(&bootstrap).await;
+        if let Err(e) = &result
+            && is_disconnected(e)
+        {
+            self.broke(epoch);
+        }
+        result
+    }
+
+    /// Run `call` on the bootstrap, and again on a new connection each time it fails as
+    /// disconnected, up to the session's attempts. A `connect` that fails as disconnected counts
+    /// as a failed attempt too.
+    pub async fn call_idempotent<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: Fn(&B) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.call(&call).await {
+                Err(e) if is_disconnected(&e) && attempt < self.inner.attempts => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// A capability restored from the bootstrap by `restore` on every connection it is used on.
+    pub fn restorable<C, F, Fut>(&self, restore: F) -> Restorable<B, C>
+    where
+        F: Fn(&B) -> Fut + 'static,
+        Fut: Future<Output = capnp::Result<C>> + 'static,
+    {
+        Restorable {
+            session: self.clone(),
+            restore: Box::new(move |bootstrap| restore(bootstrap).boxed_local()),
+            restored: RefCell::new(Cap::None),
+        }
+    }
+}
+
+enum Cap<C> {
+    None,
+    /// Being restored on connection `epoch`.
+    Restoring(u64, Connecting<C>),
+    /// Restored on connection `epoch`.
+    Restored(u64, C),
+}
+
+/// A capability that is restored from its sturdy ref on each new connection of a [`Session`].
+pub struct Restorable<B, C> {
+    session: Session<B>,
+    restore: Restore<B, C>,
+    restored: RefCell<Cap<C>>,
+}
+
+impl<B: Clone + 'static, C: Clone + 'static> Restorable<B, C> {
+    /// The capability on the current connection, and the connection's epoch. Restores it there
+    /// first if it hasn't been yet.
+    async fn current(&self) -> capnp::Result<(u64, C)> {
+        let (epoch, bootstrap) = self.session.current().await?;
+        let restoring = {
+            let mut restored = self.restored.borrow_mut();
+            match &*restored {
+                Cap::Restored(e, cap) if *e == epoch => return Ok((epoch, cap.clone())),
+                Cap::Restoring(e, restoring) if *e == epoch => restoring.clone(),
+                _ => {
+                    let restoring = (self.restore)(&bootstrap).shared();
+                    *restored = Cap::Restoring(epoch, restoring.clone());
+                    restoring
+                }
+            }
+        };
+        let result = restoring.await;
+        let mut restored = self.restored.borrow_mut();
+        if matches!(&*restored, Cap::Restoring(e, _) if *e == epoch) {
+            *restored = match &result {
+                Ok(cap) => Cap::Restored(epoch, cap.clone()),
+                Err(_) => Cap::None,
+            };
+        }
+        drop(restored);
+        match result {
+            Ok(cap) => Ok((epoch, cap)),
+            Err(e) => {
+                if is_disconnected(&e) {
+                    self.session.broke(epoch);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Run `call` on the capability once. If it fails as disconnected, the next call reconnects.
+    pub async fn call<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: FnOnce(&C) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let (epoch, cap) = self.current().await?;
+        let result = call(&cap).await;
+        if let Err(e) = &result
+            && is_disconnected(e)
+        {
+            self.session.broke(epoch);
+        }
+        result
+    }
+
+    /// Run `call` on the capability, and again on a new connection each time it, or restoring
+    /// the capability, fails as disconnected, up to the session's attempts.
+    pub async fn call_idempotent<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: Fn(&C) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.call(&call).await {
+                Err(e) if is_disconnected(&e) && attempt < self.session.inner.attempts => {
+                    attempt += 1
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use capnp_rpc::{RpcSystem, twoparty};
+    use futures::channel::mpsc;
+    use futures::executor::{LocalPool, LocalSpawner};
+    use futures::future::join_all;
+    use futures::task::LocalSpawnExt;
+    use futures::{AsyncRead, AsyncWrite, TryStreamExt};
+
+    use super::*;
+    use crate::EchoerProvider;
+    use crate::echo_capnp::{echoer, echoer_provider};
+
+    // The sending half of one direction of an in-memory connection. Once it has taken `left`
+    // bytes it breaks like a reset connection: the flush after them fails, and so does every
+    // later write. The peer reads whole messages and then the end of the stream.
+    struct Sender {
+        tx: mpsc::UnboundedSender<io::Result<Vec<u8>>>,
+        left: Option<usize>,
+        cut: bool,
+    }
+
+    impl AsyncWrite for Sender {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.cut {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if let Some(left) = &mut self.left {
+                *left = left.saturating_sub(buf.len());
+            }
+            // A peer that has gone away doesn't read what is sent.
+            let _ = self.tx.unbounded_send(Ok(buf.to_vec()));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            if self.left == Some(0) && !self.cut {
+                self.cut = true;
+                self.tx.close_channel();
+            }
+            if self.cut {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.tx.close_channel();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn pipe(cut_after: Option<usize>) -> (Sender, impl AsyncRead + Unpin) {
+        let (tx, rx) = mpsc::unbounded();
+        let sender = Sender {
+            tx,
+            left: cut_after,
+            cut: false,
+        };
+        (sender, rx.into_async_read())
+    }
+
+    // Serve `provider` over a new in-memory connection and return the client's bootstrap. The
+    // provider's side breaks after sending `cut_after` bytes.
+    fn connect(
+        spawner: &LocalSpawner,
+        provider: &echoer_provider::Client,
+        cut_after: Option<usize>,
+    ) -> echoer_provider::Client {
+        let (to_client, from_server) = pipe(cut_after);
+        let (to_server, from_client) = pipe(None);
+        let network =
+            twoparty::VatNetwork::new(from_client, to_client, Side::Server, Default::default());
+        let server = RpcSystem::new(Box::new(network), Some(provider.clone().client));
+        spawner.spawn_local(server.map(|_| ())).unwrap();
+        let network =
+            twoparty::VatNetwork::new(from_server, to_server, Side::Client, Default::default());
+        let mut client = RpcSystem::new(Box::new(network), None);
+        let bootstrap = client.bootstrap(Side::Server);
+        spawner.spawn_local(client.map(|_| ())).unwrap();
+        bootstrap
+    }
+
+    // A session with one provider behind every connection, whose first connection breaks after
+    // the provider has sent `cut_after` bytes.
+    fn session(pool: &LocalPool, cut_after: usize) -> Session<echoer_provider::Client> {
+        let spawner = pool.spawner();
+        let provider = EchoerProvider::client();
+        let made = Cell::new(0);
+        Session::new(3, move || {
+            let cut = (made.replace(made.get() + 1) == 0).then_some(cut_after);
+            let bootstrap = connect(&spawner, &provider, cut);
+            async move { Ok(bootstrap) }
+        })
+    }
+
+    #[test]
+    fn idempotent_calls_survive_a_reset_mid_batch() {
+        let mut pool = LocalPool::new();
+        // Past the first few replies of the batch, well before the last.
+        let session = session(&pool, 2048);
+        let restores = Rc::new(Cell::new(0));
+        pool.run_until(async {
+            let sturdy = session
+                .call(|provider| {
+                    let request = provider.echoer_request();
+                    async move {
+                        let response = request.send().promise.await?;
+                        Ok(response.get()?.get_ref()?.to_vec())
+                    }
+                })
+                .await
+                .unwrap();
+            let counted = restores.clone();
+            let echoer = session.restorable(move |provider: &echoer_provider::Client| {
+                counted.set(counted.get() + 1);
+                let mut request = provider.restore_request();
+                request.get().set_ref(&sturdy[..]);
+                async move { Ok(request.send().promise.await?.get()?.get_echoer()?) }
+            });
+
+            let calls = (0..100).map(|i| {
+                echoer.call_idempotent(move |echoer: &echoer::Client| {
+                    let mut request = echoer.echo_request();
+                    request.get().set_msg(format!("call {i}").as_str());
+                    async move { Ok(request.send().promise.await?.get()?.get_reply()?.to_vec()) }
+                })
+            });
+            for (i, reply) in join_all(calls).await.into_iter().enumerate() {
+                assert_eq!(reply.unwrap(), format!("call {i}").as_bytes());
+            }
+        });
+        // One reset, one reconnect, and the echoer restored on each connection once.
+        assert_eq!(session.connections(), 2);
+        assert_eq!(restores.get(), 2);
+    }
+
+    #[test]
+    fn a_call_cut_off_fails_and_the_next_one_reconnects() {
+        let mut pool = LocalPool::new();
+        // Broken before the provider answers anything.
+        let session = session(&pool, 0);
+        let introspect = |provider: &echoer_provider::Client| {
+            let request = provider.introspect_request();
+            async move { request.send().promise.await.map(drop) }
+        };
+        pool.run_until(async {
+            // It may or may not have run, so it isn't sent again.
+            let error = session.call(introspect).await.unwrap_err();
+            assert!(is_disconnected(&error));
+            session.call(introspect).await.unwrap();
+        });
+        assert_eq!(session.connections(), 2);
+    }
+}
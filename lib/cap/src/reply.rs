@@ -0,0 +1,41 @@
+//! Writing replies straight into a call's results.
+//!
+//! A call's params and its results are separate messages, so a reply made from the request is
+//! copied at least once, from the request's segments into the results'. The aim is to copy it only
+//! that once. Orphans don't save the copy: an orphan belongs to the message it was made in, and
+//! adopting one from the params into the results copies it as well.
+//!
+//! So an echo-like service takes the request's bytes as a borrowed reader and writes them to the
+//! reply's own space, with no owned `Vec` or `String` in between. [`reflect`] does that for a
+//! reply that repeats the request. A service that transforms the request uses [`fill_data`],
+//! which sizes the reply field and hands its space to the transform. A reply that is a whole
+//! struct or list of the request's (as `echoBatch` returns its `msgs`) is best passed to the
+//! field's setter as a reader, which copies it in one pass.
+
+use capnp::data;
+
+/// Size a `Data` field of `builder` for `len` bytes with `init` (the field's generated
+/// `init_*` method) and let `fill` write the reply into it. The field's space comes zeroed from
+/// the results message, so nothing is written twice.
+pub fn fill_data<'a, B>(
+    builder: B,
+    init: impl FnOnce(B, u32) -> data::Builder<'a>,
+    len: usize,
+    fill: impl FnOnce(&mut [u8]),
+) -> capnp::Result<()> {
+    let len = u32::try_from(len)
+        .map_err(|_| capnp::Error::failed(format!("reply of {len} bytes is too large")))?;
+    fill(init(builder, len));
+    Ok(())
+}
+
+/// Answer with `request` as it is, copied once into the field `init` sizes.
+pub fn reflect<'a, B>(
+    request: &[u8],
+    builder: B,
+    init: impl FnOnce(B, u32) -> data::Builder<'a>,
+) -> capnp::Result<()> {
+    fill_data(builder, init, request.len(), |reply| {
+        reply.copy_from_slice(request)
+    })
+}
@@ -0,0 +1,94 @@
+//! State shared between capability servers.
+//!
+//! Servers run on the connection's single-threaded executor, and each call gets `&mut self`, so
+//! state that one server owns belongs in its own fields: the calls can't overlap on it. State that
+//! several servers use, such as a store that the provider and every echoer it hands out read and
+//! write, or counters read after the provider has become a client, goes in a [`Shared`].
+//!
+//! A `Shared` is reached only through [`Shared::with`], whose closure isn't async. The borrow ends
+//! before the call's future can yield, so no call ever holds the state while another runs, and an
+//! update is never split across an await. A call that needs the state on both sides of an await
+//! takes it twice, and must not assume it unchanged in between. `Shared` is neither `Send` nor
+//! `Sync`, so it stays on the executor that serves the calls.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// State shared by the servers on one executor. Clones are handles to the same state.
+pub struct Shared<T>(Rc<RefCell<T>>);
+
+impl<T> Shared<T> {
+    pub fn new(state: T) -> Self {
+        Self(Rc::new(RefCell::new(state)))
+    }
+
+    /// Run `f` on the state and return what it returns.
+    ///
+    /// # Panics
+    ///
+    /// If `f` reaches the same state through `with` again.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut state = self
+            .0
+            .try_borrow_mut()
+            .expect("shared state reached again from within `Shared::with`");
+        f(&mut state)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_the_state() {
+        let a = Shared::new(1);
+        let b = a.clone();
+        b.with(|n| *n += 1);
+        assert_eq!(a.with(|n| *n), 2);
+    }
+
+    #[test]
+    fn with_returns_what_f_returns() {
+        let shared = Shared::new(vec![1, 2, 3]);
+        assert_eq!(shared.with(|v| v.pop()), Some(3));
+        assert_eq!(shared.with(|v| v.len()), 2);
+    }
+
+    #[test]
+    fn default_holds_the_default_state() {
+        let shared: Shared<Vec<u8>> = Shared::default();
+        assert!(shared.with(|v| v.is_empty()));
+    }
+
+    #[test]
+    #[should_panic(expected = "shared state reached again from within `Shared::with`")]
+    fn reaching_the_state_again_from_with_panics() {
+        let a = Shared::new(0);
+        let b = a.clone();
+        a.with(|_| b.with(|n| *n += 1));
+    }
+
+    #[test]
+    fn state_can_be_reached_again_after_a_panic_in_with() {
+        let shared = Shared::new(0);
+        let inner = shared.clone();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            inner.with(|_| panic!("in the closure"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(shared.with(|n| *n), 0);
+    }
+}
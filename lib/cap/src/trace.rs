@@ -0,0 +1,23 @@
+//! Span propagation for capability servers and host tasks.
+//!
+//! A server method returns a promise that capnp-rpc polls later, from whichever task drives the
+//! connection, so the span current when the call arrives is not the one current when its future
+//! runs. [`promise`] ties the future to the span current at the call. The same goes for every
+//! future the host runs: it carries its span with `.instrument(span)` or `.in_current_span()`
+//! ([`Instrument`]), and no span is entered with a guard held across an await, which would leave
+//! it entered for whatever else runs on the thread meanwhile. Embedders writing their own servers
+//! should return [`promise`] where they'd return `Promise::from_future`.
+
+use capnp::capability::Promise;
+
+pub use tracing::Instrument;
+
+/// A promise for `fut` that runs it in the span current when this is called.
+pub fn promise<T, E, F>(fut: F) -> Promise<T, E>
+where
+    F: Future<Output = Result<T, E>> + 'static,
+    T: 'static,
+    E: 'static,
+{
+    Promise::from_future(fut.in_current_span())
+}
@@ -0,0 +1,127 @@
+//! Allocation counting on the host (the `alloc-stats` feature).
+//!
+//! With the feature, a wrapping global allocator counts every allocation made on each thread.
+//! The CPU accounts in `cpu_time` read the count around each poll, as they do the CPU clock, so
+//! allocations are charged to the guest's side or the provider's. Without the feature the count
+//! stays at zero and the accounts report none.
+//!
+//! The same allocator serves blocks from the segment pool with the `segment-pool` feature (see
+//! `segment_pool`). Only allocations that reach the system are counted, so two hosts built with
+//! and without the pool show what it saves.
+
+use std::cell::Cell;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Allocations made on the calling thread so far.
+pub fn thread_allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// Allocations the segment pool served instead of the system, on every thread; always 0
+/// without `segment-pool`.
+pub fn pooled_allocations() -> u64 {
+    #[cfg(feature = "segment-pool")]
+    let pooled = crate::segment_pool::hits();
+    #[cfg(not(feature = "segment-pool"))]
+    let pooled = 0;
+    pooled
+}
+
+#[cfg(any(feature = "alloc-stats", feature = "segment-pool"))]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    #[cfg(feature = "segment-pool")]
+    use crate::segment_pool;
+
+    pub struct CountingAlloc;
+
+    fn count() {
+        // `try_with`: the thread's counter may already be gone while it exits.
+        #[cfg(feature = "alloc-stats")]
+        let _ = super::ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+    }
+
+    // A block served from the segment pool doesn't reach the system, so it isn't counted.
+    #[cfg(feature = "segment-pool")]
+    fn pooled(layout: Layout, zeroed: bool) -> Option<*mut u8> {
+        segment_pool::take(layout, zeroed)
+    }
+
+    #[cfg(not(feature = "segment-pool"))]
+    fn pooled(_layout: Layout, _zeroed: bool) -> Option<*mut u8> {
+        None
+    }
+
+    #[cfg(feature = "segment-pool")]
+    fn poolable(layout: Layout) -> bool {
+        segment_pool::class(layout).is_some()
+    }
+
+    #[cfg(not(feature = "segment-pool"))]
+    fn poolable(_layout: Layout) -> bool {
+        false
+    }
+
+    fn system_layout(layout: Layout) -> Layout {
+        #[cfg(feature = "segment-pool")]
+        let layout = segment_pool::system_layout(layout);
+        layout
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if let Some(block) = pooled(layout, false) {
+                return block;
+            }
+            count();
+            unsafe { System.alloc(system_layout(layout)) }
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            if let Some(block) = pooled(layout, true) {
+                return block;
+            }
+            count();
+            unsafe { System.alloc_zeroed(system_layout(layout)) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            #[cfg(feature = "segment-pool")]
+            if segment_pool::give(ptr, layout) {
+                return;
+            }
+            unsafe { System.dealloc(ptr, system_layout(layout)) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            // SAFETY: the caller guarantees `new_size`, rounded up to the alignment, doesn't
+            // overflow.
+            let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+            if poolable(layout) && system_layout(layout) == system_layout(new_layout) {
+                // Still within the block's size class.
+                return ptr;
+            }
+            if poolable(layout) || poolable(new_layout) {
+                // Into, out of or between size classes: move the block.
+                let moved = unsafe { self.alloc(new_layout) };
+                if !moved.is_null() {
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(ptr, moved, layout.size().min(new_size));
+                        self.dealloc(ptr, layout);
+                    }
+                }
+                return moved;
+            }
+            // As in the guest, a reallocation counts as a fresh allocation.
+            count();
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+}
@@ -0,0 +1,186 @@
+//! Blocking calls on the provider thread (`--blocking-threshold`).
+//!
+//! Every capability server on a connection runs on the provider's one thread, so a service that
+//! blocks, on a lock, a file or a busy loop, stalls every call sharing the thread until it
+//! returns. A [`BlockingWatch`] wraps the services themselves and times each stretch a call holds
+//! the thread: the method's own body, and each poll of the promise it returns. A stretch longer
+//! than `--blocking-threshold` is warned about under the `blocking` target, at most
+//! `--log-rate` times a second. A call that waits without blocking, however long, polls quickly
+//! and isn't flagged. The host's own wrappers and capnp-rpc sit outside the watch.
+
+use std::cell::Cell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use cap::trace;
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use tracing::{info, warn};
+
+use crate::log_limit::{LogLimit, LogRate};
+
+/// How long the services hold the provider thread, with a warning for each stretch too long.
+pub struct BlockingWatch {
+    threshold: Duration,
+    warnings: LogLimit,
+    stretches: Cell<u64>,
+    blocked: Cell<u64>,
+    longest: Cell<Duration>,
+}
+
+impl BlockingWatch {
+    pub fn new(threshold: Duration, log_rate: Option<LogRate>) -> Rc<Self> {
+        info!(target: "blocking", ?threshold, "watching for blocking calls");
+        Rc::new(Self {
+            threshold,
+            warnings: LogLimit::new("blocking", log_rate),
+            stretches: Cell::new(0),
+            blocked: Cell::new(0),
+            longest: Cell::new(Duration::ZERO),
+        })
+    }
+
+    /// Send a call to `method` with `send`, timing it and every poll of the promise it returns.
+    fn call<F: Future>(
+        self: &Rc<Self>,
+        method: &'static str,
+        send: impl FnOnce() -> F,
+    ) -> Watched<F> {
+        let started = Instant::now();
+        let inner = Box::pin(send());
+        self.record(method, started.elapsed());
+        Watched {
+            inner,
+            method,
+            watch: self.clone(),
+        }
+    }
+
+    fn record(&self, method: &'static str, held: Duration) {
+        self.stretches.set(self.stretches.get() + 1);
+        if held > self.longest.get() {
+            self.longest.set(held);
+        }
+        if held > self.threshold {
+            self.blocked.set(self.blocked.get() + 1);
+            if self.warnings.admit() {
+                warn!(
+                    target: "blocking",
+                    method,
+                    ?held,
+                    threshold = ?self.threshold,
+                    "call held the provider thread past the threshold; it may be blocking"
+                );
+            }
+        }
+    }
+
+    /// Log how often the services held the thread too long, and the longest stretch.
+    pub fn log_summary(&self) {
+        info!(
+            target: "blocking",
+            stretches = self.stretches.get(),
+            blocked = self.blocked.get(),
+            longest = ?self.longest.get(),
+            "time the services held the provider thread"
+        );
+    }
+}
+
+/// A call's promise, timed on every poll.
+struct Watched<F> {
+    inner: Pin<Box<F>>,
+    method: &'static str,
+    watch: Rc<BlockingWatch>,
+}
+
+impl<F: Future> Future for Watched<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let started = Instant::now();
+        let poll = self.inner.as_mut().poll(cx);
+        self.watch.record(self.method, started.elapsed());
+        poll
+    }
+}
+
+/// `EchoerProvider` that times its calls, and hands out echoers that time theirs.
+pub struct WatchedEchoerProvider {
+    watch: Rc<BlockingWatch>,
+}
+
+impl WatchedEchoerProvider {
+    pub fn client(
+        inner: echoer_provider::Client,
+        watch: Rc<BlockingWatch>,
+    ) -> echoer_provider::Client {
+        layer::provider(inner, Self { watch })
+    }
+}
+
+impl Layer for WatchedEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        trace::promise(self.watch.call(layer::name(method), || call.send()))
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(WatchedEchoer {
+            inner,
+            watch: self.watch.clone(),
+        })
+    }
+}
+
+struct WatchedEchoer {
+    inner: echoer::Client,
+    watch: Rc<BlockingWatch>,
+}
+
+impl echoer::Server for WatchedEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        let response = self.watch.call("Echoer.echo", || request.send().promise);
+        trace::promise(async move {
+            let response = response.await?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        let response = self
+            .watch
+            .call("Echoer.echoBatch", || request.send().promise);
+        trace::promise(async move {
+            let response = response.await?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,649 @@
+//! Bridge between Cap'n Proto capabilities and component-model resources.
+//!
+//! Capnp clients are `!Send` and live on the provider thread, while host functions imported by the
+//! guest run on the Wasm runtime. Bridged resources are therefore plain ids into a table owned by
+//! the provider thread, and every call is forwarded there over a channel. Each bridged interface
+//! is a resource type of its own (see `wit/capnp-bridge.wit`), all sharing the one table.
+//!
+//! A `log-sink` runs the other way: the guest serves it and host capabilities call it. The host
+//! can't call into the guest while the guest is running, so a `LogSink` server on the provider
+//! thread queues each push until the guest takes it with `next-push`, which answers the push.
+
+use std::collections::HashMap;
+
+use cap::echo_capnp::{echoer, echoer_provider, log_sink, log_tail};
+use cap::logtail::Entry;
+use cap::state::Shared;
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use futures::StreamExt;
+use futures::future::LocalBoxFuture;
+use futures::stream::FuturesUnordered;
+use tokio::sync::{mpsc, oneshot};
+use tracing::debug;
+use wasmtime::component::Resource;
+
+use crate::ComponentRunStates;
+use crate::world::capnp_bridge;
+
+/// Guest-side handle of a bridged `EchoerProvider`; the capability itself stays on the provider
+/// thread, as with the other bridged resources.
+pub struct BridgedEchoerProvider {
+    id: u64,
+}
+
+/// Guest-side handle of a bridged `Echoer`.
+pub struct BridgedEchoer {
+    id: u64,
+}
+
+/// Guest-side handle of a bridged `LogTail`.
+pub struct BridgedLogTail {
+    id: u64,
+}
+
+/// Guest-side handle of a `LogSink` the guest serves.
+pub struct BridgedLogSink {
+    id: u64,
+}
+
+type Reply<T> = oneshot::Sender<Result<T, String>>;
+
+enum BridgeRequest {
+    Bootstrap {
+        reply: Reply<u64>,
+    },
+    Echoer {
+        provider: u64,
+        reply: Reply<u64>,
+    },
+    LogTail {
+        provider: u64,
+        reply: Reply<u64>,
+    },
+    Echo {
+        echoer: u64,
+        msg: String,
+        reply: Reply<Vec<u8>>,
+    },
+    NewLogSink {
+        reply: Reply<u64>,
+    },
+    Follow {
+        log_tail: u64,
+        sink: u64,
+        from_seq: u64,
+        window: u32,
+        reply: Reply<()>,
+    },
+    NextPush {
+        sink: u64,
+        reply: Reply<Option<Vec<Entry>>>,
+    },
+    Drop {
+        id: u64,
+    },
+}
+
+/// Sending half of the bridge, held in the store data.
+#[derive(Clone)]
+pub struct BridgeHandle {
+    tx: mpsc::UnboundedSender<BridgeRequest>,
+}
+
+/// Receiving half of the bridge, served on the provider thread by [`serve`].
+pub struct BridgeServer {
+    rx: mpsc::UnboundedReceiver<BridgeRequest>,
+}
+
+pub fn channel() -> (BridgeHandle, BridgeServer) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (BridgeHandle { tx }, BridgeServer { rx })
+}
+
+impl BridgeHandle {
+    async fn call<T>(&self, request: impl FnOnce(Reply<T>) -> BridgeRequest) -> Result<T, String> {
+        let (reply, response) = oneshot::channel();
+        self.tx
+            .send(request(reply))
+            .map_err(|_| "capnp bridge is closed".to_string())?;
+        response
+            .await
+            .map_err(|_| "capnp bridge dropped the request".to_string())?
+    }
+
+    /// The id of the host's `EchoerProvider`.
+    pub async fn bootstrap(&self) -> Result<u64, String> {
+        self.call(|reply| BridgeRequest::Bootstrap { reply }).await
+    }
+
+    pub async fn echoer(&self, provider: u64) -> Result<u64, String> {
+        self.call(|reply| BridgeRequest::Echoer { provider, reply })
+            .await
+    }
+
+    pub async fn log_tail(&self, provider: u64) -> Result<u64, String> {
+        self.call(|reply| BridgeRequest::LogTail { provider, reply })
+            .await
+    }
+
+    pub async fn echo(&self, echoer: u64, msg: String) -> Result<Vec<u8>, String> {
+        self.call(|reply| BridgeRequest::Echo { echoer, msg, reply })
+            .await
+    }
+
+    pub async fn new_log_sink(&self) -> Result<u64, String> {
+        self.call(|reply| BridgeRequest::NewLogSink { reply }).await
+    }
+
+    pub async fn follow(
+        &self,
+        log_tail: u64,
+        sink: u64,
+        from_seq: u64,
+        window: u32,
+    ) -> Result<(), String> {
+        self.call(|reply| BridgeRequest::Follow {
+            log_tail,
+            sink,
+            from_seq,
+            window,
+            reply,
+        })
+        .await
+    }
+
+    /// The records of the next push to `sink`, or `None` once nothing follows into it.
+    pub async fn next_push(&self, sink: u64) -> Result<Option<Vec<Entry>>, String> {
+        self.call(|reply| BridgeRequest::NextPush { sink, reply })
+            .await
+    }
+
+    /// Let go of whatever `id` names.
+    pub fn drop_bridged(&self, id: u64) {
+        let _ = self.tx.send(BridgeRequest::Drop { id });
+    }
+}
+
+enum Bridged {
+    Provider(echoer_provider::Client),
+    Echoer(echoer::Client),
+    LogTail(log_tail::Client),
+    LogSink(GuestLogSink),
+}
+
+/// A `LogSink` served by the guest, as the provider thread keeps it.
+struct GuestLogSink {
+    client: log_sink::Client,
+    // Out while a `next-push` waits on it.
+    events: Option<mpsc::UnboundedReceiver<SinkEvent>>,
+    // Tells a waiting `next-push` that a `follow` call has ended.
+    ended: mpsc::UnboundedSender<SinkEvent>,
+    // `follow` calls pushing to the sink.
+    following: u32,
+}
+
+enum SinkEvent {
+    Push {
+        records: Vec<Entry>,
+        taken: oneshot::Sender<()>,
+    },
+    Ended,
+}
+
+#[derive(Default)]
+struct Table {
+    bridged: HashMap<u64, Bridged>,
+    next_id: u64,
+}
+
+impl Table {
+    fn insert(&mut self, bridged: Bridged) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.bridged.insert(id, bridged);
+        id
+    }
+
+    fn get(&mut self, id: u64) -> Result<&mut Bridged, String> {
+        self.bridged
+            .get_mut(&id)
+            .ok_or_else(|| format!("unknown bridged capability {id}"))
+    }
+
+    fn provider(&mut self, id: u64) -> Result<echoer_provider::Client, String> {
+        match self.get(id)? {
+            Bridged::Provider(client) => Ok(client.clone()),
+            _ => Err(format!("bridged capability {id} is not an EchoerProvider")),
+        }
+    }
+
+    fn echoer(&mut self, id: u64) -> Result<echoer::Client, String> {
+        match self.get(id)? {
+            Bridged::Echoer(client) => Ok(client.clone()),
+            _ => Err(format!("bridged capability {id} is not an Echoer")),
+        }
+    }
+
+    fn log_tail(&mut self, id: u64) -> Result<log_tail::Client, String> {
+        match self.get(id)? {
+            Bridged::LogTail(client) => Ok(client.clone()),
+            _ => Err(format!("bridged capability {id} is not a LogTail")),
+        }
+    }
+
+    fn log_sink(&mut self, id: u64) -> Result<&mut GuestLogSink, String> {
+        match self.get(id)? {
+            Bridged::LogSink(sink) => Ok(sink),
+            _ => Err(format!("bridged capability {id} is not a LogSink")),
+        }
+    }
+}
+
+/// Serve bridged calls against `provider` until every [`BridgeHandle`] is dropped.
+///
+/// The guest blocks on each bridged call, so there is never more than one in flight per store,
+/// but `follow` calls go on after they are answered, and a `next-push` waits on them. Both are
+/// driven here alongside new requests, and dropped once the bridge closes.
+pub async fn serve(mut server: BridgeServer, provider: echoer_provider::Client) {
+    let table = Shared::new(Table::default());
+    let mut calls = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            request = server.rx.recv() => match request {
+                Some(request) => calls.push(handle(&table, &provider, request)),
+                None => break,
+            },
+            Some(()) = calls.next(), if !calls.is_empty() => {}
+        }
+    }
+    debug!("capnp bridge closed");
+}
+
+fn handle(
+    table: &Shared<Table>,
+    provider: &echoer_provider::Client,
+    request: BridgeRequest,
+) -> LocalBoxFuture<'static, ()> {
+    let table = table.clone();
+    match request {
+        BridgeRequest::Bootstrap { reply } => {
+            let id = table.with(|table| table.insert(Bridged::Provider(provider.clone())));
+            let _ = reply.send(Ok(id));
+            Box::pin(async {})
+        }
+        BridgeRequest::Echoer { provider, reply } => Box::pin(async move {
+            let result = async {
+                let provider = table.with(|table| table.provider(provider))?;
+                let response = provider.echoer_request().send().promise.await;
+                let echoer = response
+                    .and_then(|response| response.get()?.get_echoer())
+                    .map_err(|e| e.to_string())?;
+                Ok(table.with(|table| table.insert(Bridged::Echoer(echoer))))
+            };
+            let _ = reply.send(result.await);
+        }),
+        BridgeRequest::LogTail { provider, reply } => Box::pin(async move {
+            let result = async {
+                let provider = table.with(|table| table.provider(provider))?;
+                let response = provider.log_tail_request().send().promise.await;
+                let log_tail = response
+                    .and_then(|response| response.get()?.get_log_tail())
+                    .map_err(|e| e.to_string())?;
+                Ok(table.with(|table| table.insert(Bridged::LogTail(log_tail))))
+            };
+            let _ = reply.send(result.await);
+        }),
+        BridgeRequest::Echo { echoer, msg, reply } => Box::pin(async move {
+            let result = async {
+                let client = table.with(|table| table.echoer(echoer))?;
+                let mut request = client.echo_request();
+                request.get().set_msg(msg.as_str());
+                let reply = async {
+                    let response = request.send().promise.await?;
+                    Ok::<_, capnp::Error>(response.get()?.get_reply()?.to_vec())
+                };
+                reply.await.map_err(|e| e.to_string())
+            };
+            let _ = reply.send(result.await);
+        }),
+        BridgeRequest::NewLogSink { reply } => {
+            let (ended, events) = mpsc::unbounded_channel();
+            let client = capnp_rpc::new_client(QueuedLogSink {
+                events: ended.clone(),
+            });
+            let id = table.with(|table| {
+                table.insert(Bridged::LogSink(GuestLogSink {
+                    client,
+                    events: Some(events),
+                    ended,
+                    following: 0,
+                }))
+            });
+            let _ = reply.send(Ok(id));
+            Box::pin(async {})
+        }
+        BridgeRequest::Follow {
+            log_tail,
+            sink,
+            from_seq,
+            window,
+            reply,
+        } => {
+            let started = table.with(|table| {
+                let log_tail = table.log_tail(log_tail)?;
+                let sink = table.log_sink(sink)?;
+                let mut request = log_tail.follow_request();
+                request.get().set_sink(sink.client.clone());
+                request.get().set_from_seq(from_seq);
+                request.get().set_window(window);
+                sink.following += 1;
+                Ok((request.send().promise, sink.ended.clone()))
+            });
+            let (call, ended) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                    return Box::pin(async {});
+                }
+            };
+            let _ = reply.send(Ok(()));
+            Box::pin(async move {
+                let result = call.await;
+                debug!(ok = result.is_ok(), "bridged log follower ended");
+                let _ = ended.send(SinkEvent::Ended);
+            })
+        }
+        BridgeRequest::NextPush { sink, reply } => Box::pin(async move {
+            let _ = reply.send(next_push(&table, sink).await);
+        }),
+        BridgeRequest::Drop { id } => {
+            table.with(|table| table.bridged.remove(&id));
+            Box::pin(async {})
+        }
+    }
+}
+
+/// Take the next push to guest sink `sink`, answering it; `None` once no `follow` call is pushing
+/// to the sink and every push has been taken.
+async fn next_push(table: &Shared<Table>, sink: u64) -> Result<Option<Vec<Entry>>, String> {
+    let mut events = table
+        .with(|table| table.log_sink(sink).map(|sink| sink.events.take()))?
+        .ok_or("another next-push is waiting on this log sink")?;
+    let taken = loop {
+        let following = table.with(|table| table.log_sink(sink).map(|sink| sink.following));
+        let event = match following {
+            Ok(0) => events.try_recv().ok(),
+            // Every sender is held by the sink or a `follow` call it is in.
+            Ok(_) => events.recv().await,
+            // The guest can't drop the sink while it waits on it.
+            Err(_) => None,
+        };
+        match event {
+            Some(SinkEvent::Push { records, taken }) => {
+                let _ = taken.send(());
+                break Some(records);
+            }
+            Some(SinkEvent::Ended) => {
+                table.with(|table| {
+                    if let Ok(sink) = table.log_sink(sink) {
+                        sink.following -= 1;
+                    }
+                });
+            }
+            None => break None,
+        }
+    };
+    table.with(|table| {
+        if let Ok(sink) = table.log_sink(sink) {
+            sink.events = Some(events);
+        }
+    });
+    Ok(taken)
+}
+
+/// The Cap'n Proto side of a guest's `log-sink`: each push waits until the guest takes it.
+struct QueuedLogSink {
+    events: mpsc::UnboundedSender<SinkEvent>,
+}
+
+impl log_sink::Server for QueuedLogSink {
+    fn push(
+        &mut self,
+        params: log_sink::PushParams,
+        _results: log_sink::PushResults,
+    ) -> Promise<(), capnp::Error> {
+        let records = pry!(pry!(params.get()).get_records());
+        let records = pry!(
+            records
+                .iter()
+                .map(|record| {
+                    Ok(Entry {
+                        seq: record.get_seq(),
+                        timestamp_ns: record.get_timestamp_ns(),
+                        level: record.get_level()?.to_str()?.to_string(),
+                        target: record.get_target()?.to_str()?.to_string(),
+                        message: record.get_message()?.to_str()?.to_string(),
+                    })
+                })
+                .collect::<capnp::Result<Vec<_>>>()
+        );
+        let (taken, answer) = oneshot::channel();
+        if self
+            .events
+            .send(SinkEvent::Push { records, taken })
+            .is_err()
+        {
+            return Promise::err(capnp::Error::failed(
+                "the guest dropped its log sink".to_string(),
+            ));
+        }
+        cap::trace::promise(async move {
+            answer.await.map_err(|_| {
+                capnp::Error::failed(
+                    "the guest dropped its log sink before taking the records".to_string(),
+                )
+            })
+        })
+    }
+}
+
+impl ComponentRunStates {
+    fn bridged_id<T: 'static>(
+        &self,
+        resource: &Resource<T>,
+        id: impl FnOnce(&T) -> u64,
+    ) -> Result<u64, String> {
+        self.resource_table
+            .get(resource)
+            .map(id)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl capnp_bridge::Host for ComponentRunStates {
+    async fn bootstrap(&mut self) -> Result<Resource<BridgedEchoerProvider>, String> {
+        if !self.grants.capnp_bridge {
+            return Err("capnp-bridge is not granted to this guest".to_string());
+        }
+        let id = self.bridge.bootstrap().await?;
+        self.resource_table
+            .push(BridgedEchoerProvider { id })
+            .map_err(|e| e.to_string())
+    }
+
+    async fn new_log_sink(&mut self) -> Result<Resource<BridgedLogSink>, String> {
+        if !self.grants.capnp_bridge {
+            return Err("capnp-bridge is not granted to this guest".to_string());
+        }
+        let id = self.bridge.new_log_sink().await?;
+        self.resource_table
+            .push(BridgedLogSink { id })
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl capnp_bridge::HostEchoerProvider for ComponentRunStates {
+    async fn echoer(
+        &mut self,
+        provider: Resource<BridgedEchoerProvider>,
+    ) -> Result<Resource<BridgedEchoer>, String> {
+        let provider = self.bridged_id(&provider, |provider| provider.id)?;
+        let id = self.bridge.echoer(provider).await?;
+        self.resource_table
+            .push(BridgedEchoer { id })
+            .map_err(|e| e.to_string())
+    }
+
+    async fn log_tail(
+        &mut self,
+        provider: Resource<BridgedEchoerProvider>,
+    ) -> Result<Resource<BridgedLogTail>, String> {
+        let provider = self.bridged_id(&provider, |provider| provider.id)?;
+        let id = self.bridge.log_tail(provider).await?;
+        self.resource_table
+            .push(BridgedLogTail { id })
+            .map_err(|e| e.to_string())
+    }
+
+    async fn drop(&mut self, provider: Resource<BridgedEchoerProvider>) -> wasmtime::Result<()> {
+        let BridgedEchoerProvider { id } = self.resource_table.delete(provider)?;
+        self.bridge.drop_bridged(id);
+        Ok(())
+    }
+}
+
+impl capnp_bridge::HostEchoer for ComponentRunStates {
+    async fn echo(
+        &mut self,
+        echoer: Resource<BridgedEchoer>,
+        msg: String,
+    ) -> Result<Vec<u8>, String> {
+        let id = self.bridged_id(&echoer, |echoer| echoer.id)?;
+        self.bridge.echo(id, msg).await
+    }
+
+    async fn drop(&mut self, echoer: Resource<BridgedEchoer>) -> wasmtime::Result<()> {
+        let BridgedEchoer { id } = self.resource_table.delete(echoer)?;
+        self.bridge.drop_bridged(id);
+        Ok(())
+    }
+}
+
+impl capnp_bridge::HostLogTail for ComponentRunStates {
+    async fn follow(
+        &mut self,
+        log_tail: Resource<BridgedLogTail>,
+        sink: Resource<BridgedLogSink>,
+        from_seq: u64,
+        window: u32,
+    ) -> Result<(), String> {
+        let log_tail = self.bridged_id(&log_tail, |log_tail| log_tail.id)?;
+        let sink = self.bridged_id(&sink, |sink| sink.id)?;
+        self.bridge.follow(log_tail, sink, from_seq, window).await
+    }
+
+    async fn drop(&mut self, log_tail: Resource<BridgedLogTail>) -> wasmtime::Result<()> {
+        let BridgedLogTail { id } = self.resource_table.delete(log_tail)?;
+        self.bridge.drop_bridged(id);
+        Ok(())
+    }
+}
+
+impl capnp_bridge::HostLogSink for ComponentRunStates {
+    async fn next_push(
+        &mut self,
+        sink: Resource<BridgedLogSink>,
+    ) -> Result<Option<Vec<capnp_bridge::LogRecord>>, String> {
+        let id = self.bridged_id(&sink, |sink| sink.id)?;
+        let records = self.bridge.next_push(id).await?;
+        Ok(records.map(|records| {
+            records
+                .into_iter()
+                .map(|entry| capnp_bridge::LogRecord {
+                    seq: entry.seq,
+                    timestamp_ns: entry.timestamp_ns,
+                    level: entry.level,
+                    target: entry.target,
+                    message: entry.message,
+                })
+                .collect()
+        }))
+    }
+
+    async fn drop(&mut self, sink: Resource<BridgedLogSink>) -> wasmtime::Result<()> {
+        let BridgedLogSink { id } = self.resource_table.delete(sink)?;
+        self.bridge.drop_bridged(id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap::EchoerProvider;
+    use cap::logtail::{LogBook, LogTailServer};
+
+    use super::*;
+    use crate::progress::ProgressTracker;
+
+    fn messages(records: Option<Vec<Entry>>) -> Vec<String> {
+        records
+            .expect("a push")
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn host_capabilities_are_called_through_the_bridge() {
+        let (bridge, server) = channel();
+        let guest = async move {
+            let provider = bridge.bootstrap().await.unwrap();
+            let echoer = bridge.echoer(provider).await.unwrap();
+            assert_eq!(
+                bridge.echo(echoer, "bridged".to_string()).await.unwrap(),
+                b"bridged"
+            );
+            // Each id names one kind of capability.
+            assert!(bridge.echo(provider, "wrong".to_string()).await.is_err());
+            assert!(bridge.echoer(echoer).await.is_err());
+            bridge.drop_bridged(echoer);
+            assert!(bridge.echo(echoer, "dropped".to_string()).await.is_err());
+            // `EchoerProvider::client` keeps no log for guests.
+            assert!(bridge.log_tail(provider).await.is_err());
+        };
+        tokio::join!(serve(server, EchoerProvider::client()), guest);
+    }
+
+    #[tokio::test]
+    async fn host_capabilities_push_to_a_sink_the_guest_serves() {
+        let book = LogBook::new(16);
+        book.append("info", "test", "first".to_string());
+        book.append("info", "test", "second".to_string());
+        let provider = capnp_rpc::new_client(EchoerProvider::with_services(
+            ProgressTracker::new().client(),
+            Some(LogTailServer::client(book.clone())),
+            None,
+        ));
+        let (bridge, server) = channel();
+        let guest = async move {
+            let provider = bridge.bootstrap().await.unwrap();
+            let log_tail = bridge.log_tail(provider).await.unwrap();
+            let sink = bridge.new_log_sink().await.unwrap();
+            // Nothing pushes to the sink yet.
+            assert_eq!(bridge.next_push(sink).await.unwrap().map(|r| r.len()), None);
+
+            bridge.follow(log_tail, sink, 0, 1).await.unwrap();
+            let records = bridge.next_push(sink).await.unwrap();
+            assert_eq!(messages(records), ["first", "second"]);
+            book.append("info", "test", "third".to_string());
+            let records = bridge.next_push(sink).await.unwrap();
+            assert_eq!(messages(records), ["third"]);
+
+            assert!(bridge.follow(sink, log_tail, 0, 1).await.is_err());
+            bridge.drop_bridged(sink);
+            assert!(bridge.next_push(sink).await.is_err());
+        };
+        tokio::join!(serve(server, provider), guest);
+    }
+}
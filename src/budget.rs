@@ -0,0 +1,191 @@
+//! Capability-call budgets.
+//!
+//! Every call a guest makes on a host capability is charged to a per-guest [`Ledger`]: a fixed
+//! weight per method plus the bytes moved. Once the budget is spent, further calls fail with a
+//! `budget-exhausted` error instead of reaching the capability. The metered wrappers here sit
+//! between the guest and the real capabilities on the provider thread.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use cap::trace;
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use tracing::{debug, warn};
+
+use crate::log_limit::{LogLimit, LogRate};
+
+/// Prefix of the error returned once a budget is spent; guests can match on it.
+pub const BUDGET_EXHAUSTED: &str = "budget-exhausted";
+
+// Per-method weights, in budget units.
+const ECHOER_COST: u64 = 10;
+const ECHO_COST: u64 = 1;
+// Bytes per additional unit charged for payloads, in either direction.
+const BYTES_PER_UNIT: u64 = 1024;
+
+/// Budget units spent by one guest, against an optional limit.
+pub struct Ledger {
+    limit: Option<u64>,
+    spent: Cell<u64>,
+    // Charges are logged once per call, so they are limited to `--log-rate`.
+    charges: LogLimit,
+}
+
+impl Ledger {
+    pub fn new(limit: Option<u64>, log_rate: Option<LogRate>) -> Rc<Self> {
+        Rc::new(Self {
+            limit,
+            spent: Cell::new(0),
+            charges: LogLimit::new("budget", log_rate),
+        })
+    }
+
+    pub fn spent(&self) -> u64 {
+        self.spent.get()
+    }
+
+    /// Charge `cost` units for a call to `method`, or fail without charging if that would exceed
+    /// the limit.
+    pub fn charge(&self, method: &str, cost: u64) -> Result<(), capnp::Error> {
+        let spent = self.spent.get().saturating_add(cost);
+        if let Some(limit) = self.limit
+            && spent > limit
+        {
+            warn!(
+                method,
+                cost,
+                spent = self.spent.get(),
+                limit,
+                "capability budget exhausted"
+            );
+            return Err(capnp::Error::failed(format!(
+                "{BUDGET_EXHAUSTED}: {method} costs {cost} units, {} of {limit} left",
+                limit - self.spent.get()
+            )));
+        }
+        self.spent.set(spent);
+        if self.charges.admit() {
+            debug!(method, cost, spent, "charged capability call");
+        }
+        Ok(())
+    }
+
+    /// Charge for `bytes` of payload.
+    pub fn charge_bytes(&self, method: &str, bytes: usize) -> Result<(), capnp::Error> {
+        self.charge(method, bytes as u64 / BYTES_PER_UNIT)
+    }
+}
+
+/// `EchoerProvider` that charges every call, and hands out metered echoers.
+///
+/// Only getting an echoer costs anything on the provider; reading the budget, reporting progress
+/// and the rest are free. The budget is read from the ledger here, not from the provider below.
+pub struct MeteredEchoerProvider {
+    ledger: Rc<Ledger>,
+}
+
+impl MeteredEchoerProvider {
+    pub fn client(inner: echoer_provider::Client, ledger: Rc<Ledger>) -> echoer_provider::Client {
+        let budget = LedgerProvider {
+            ledger: ledger.clone(),
+        };
+        layer::builder(inner, Self { ledger })
+            .serve::<echoer_provider::Client, _>(&[layer::BUDGET], budget)
+            .build()
+    }
+}
+
+impl Layer for MeteredEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        if method.is::<echoer_provider::Client>(layer::ECHOER)
+            || method.is::<echoer_provider::Client>(layer::RESTORE)
+        {
+            pry!(self.ledger.charge(layer::short_name(method), ECHOER_COST));
+        }
+        call.send()
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(MeteredEchoer {
+            inner,
+            ledger: self.ledger.clone(),
+        })
+    }
+}
+
+/// Answers `EchoerProvider.budget()` from the ledger.
+struct LedgerProvider {
+    ledger: Rc<Ledger>,
+}
+
+impl echoer_provider::Server for LedgerProvider {
+    fn budget(
+        &mut self,
+        _params: echoer_provider::BudgetParams,
+        mut results: echoer_provider::BudgetResults,
+    ) -> Promise<(), capnp::Error> {
+        // Reading the budget is free.
+        let mut usage = results.get().init_usage();
+        usage.set_spent(self.ledger.spent());
+        usage.set_limit(self.ledger.limit.unwrap_or(0));
+        Promise::ok(())
+    }
+}
+
+struct MeteredEchoer {
+    inner: echoer::Client,
+    ledger: Rc<Ledger>,
+}
+
+impl echoer::Server for MeteredEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let msg = pry!(params.get_msg());
+        pry!(self.ledger.charge("echo", ECHO_COST));
+        // The reply carries the same bytes back, so the payload is charged both ways up front.
+        pry!(self.ledger.charge_bytes("echo", 2 * msg.len()));
+        let mut request = self.inner.echo_request();
+        request.get().set_msg(msg);
+        request.get().set_call_id(params.get_call_id());
+        trace::promise(async move {
+            let response = request.send().promise.await?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    /// Charged as one echo per message, so batching saves round trips but not budget.
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let msgs = pry!(params.get_msgs());
+        let bytes: usize = msgs.iter().map(|msg| msg.map_or(0, <[u8]>::len)).sum();
+        let cost = ECHO_COST * msgs.len() as u64;
+        pry!(self.ledger.charge("echoBatch", cost));
+        pry!(self.ledger.charge_bytes("echoBatch", 2 * bytes));
+        let mut request = self.inner.echo_batch_request();
+        pry!(request.get().set_msgs(msgs));
+        request.get().set_call_id(params.get_call_id());
+        trace::promise(async move {
+            let response = request.send().promise.await?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,366 @@
+//! Sampled capture of call payloads.
+//!
+//! With `--capture <PATH>`, the host picks a share of the guest's calls on host capabilities and
+//! writes their params and results, or the error they failed with, to a file. Content-dependent
+//! bugs can then be reproduced from what was actually sent. Records follow `capture.capnp` and
+//! are written back to back with the standard stream framing; a payload over the size limit is
+//! kept as a prefix of its flat serialization. Which calls are sampled is derived from the run
+//! seed. The capturing wrappers sit outermost, so they see the calls as the guest made them,
+//! including those the layers behind them reject.
+//!
+//! Records are written from the provider thread as calls complete. The file is buffered, but a
+//! capture meant for a long run should keep the sample small.
+
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use cap::capture_capnp::{payload, record};
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use cap::trace;
+use capnp::capability::{Promise, Response};
+use capnp::message::{self, HeapAllocator};
+use capnp::traits::{Owned, SetterInput};
+use capnp::{any_pointer, serialize};
+use capnp_rpc::pry;
+use tracing::{info, warn};
+
+use crate::seed::SplitMix64;
+
+/// A standalone copy of a params or results struct.
+type Flat = message::Builder<HeapAllocator>;
+
+fn flat<T: Owned>(value: impl SetterInput<T>) -> capnp::Result<Flat> {
+    let mut message = message::Builder::new_default();
+    message.set_root(value)?;
+    Ok(message)
+}
+
+pub struct Capture {
+    writer: RefCell<BufWriter<File>>,
+    /// Share of calls captured, from 0 to 1.
+    sample: f64,
+    /// Largest payload kept whole, in bytes.
+    max_bytes: usize,
+    rolls: RefCell<SplitMix64>,
+    started: Instant,
+    next_call: Cell<u64>,
+    calls: Cell<u64>,
+    records: Cell<u64>,
+    write_failed: Cell<bool>,
+}
+
+impl Capture {
+    /// Capture into `file`, created up front so a bad path fails the run before it starts.
+    pub fn new(file: File, sample: f64, max_bytes: usize, seed: u64) -> Rc<Self> {
+        Rc::new(Self {
+            writer: RefCell::new(BufWriter::new(file)),
+            sample,
+            max_bytes,
+            rolls: RefCell::new(SplitMix64::new(seed)),
+            started: Instant::now(),
+            next_call: Cell::new(0),
+            calls: Cell::new(0),
+            records: Cell::new(0),
+            write_failed: Cell::new(false),
+        })
+    }
+
+    /// Decide whether to capture the next call; returns the sampled call's id.
+    fn sample(&self) -> Option<u64> {
+        let call = self.next_call.get();
+        self.next_call.set(call + 1);
+        if self.rolls.borrow_mut().next_f64() >= self.sample {
+            return None;
+        }
+        self.calls.set(self.calls.get() + 1);
+        Some(call)
+    }
+
+    fn params(&self, call: u64, method: &str, value: capnp::Result<Flat>) {
+        self.write(call, method, |mut record| match &value {
+            Ok(value) => self.set_payload(record.init_params(), Some(value)),
+            Err(e) => {
+                record.set_error(e.to_string().as_str());
+                Ok(())
+            }
+        });
+    }
+
+    /// Record a call's outcome; `Ok(None)` stands for results holding capabilities.
+    fn outcome(&self, call: u64, method: &str, outcome: capnp::Result<Option<Flat>>) {
+        self.write(call, method, |mut record| match &outcome {
+            Ok(value) => self.set_payload(record.init_results(), value.as_ref()),
+            Err(e) => {
+                record.set_error(e.to_string().as_str());
+                Ok(())
+            }
+        });
+    }
+
+    fn set_payload(
+        &self,
+        mut payload: payload::Builder,
+        value: Option<&Flat>,
+    ) -> capnp::Result<()> {
+        let Some(value) = value else {
+            payload.set_omitted(());
+            return Ok(());
+        };
+        let size = serialize::compute_serialized_size_in_words(value) * 8;
+        payload.set_size(size as u64);
+        if size <= self.max_bytes {
+            let root: any_pointer::Reader = value.get_root_as_reader()?;
+            payload.init_full().set_as(root)?;
+        } else {
+            let bytes = serialize::write_message_to_words(value);
+            payload.set_truncated(&bytes[..self.max_bytes]);
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        call: u64,
+        method: &str,
+        fill: impl FnOnce(record::Builder) -> capnp::Result<()>,
+    ) {
+        if self.write_failed.get() {
+            return;
+        }
+        let mut message = message::Builder::new_default();
+        let mut record = message.init_root::<record::Builder>();
+        record.set_call_id(call);
+        record.set_method(method);
+        record.set_timestamp_ns(self.started.elapsed().as_nanos() as u64);
+        let written = fill(record)
+            .and_then(|()| serialize::write_message(&mut *self.writer.borrow_mut(), &message));
+        match written {
+            Ok(()) => self.records.set(self.records.get() + 1),
+            Err(e) => {
+                // One failed write would leave the file unframed; stop capturing instead.
+                warn!(target: "capture", error = %e, "failed to write capture record; stopping");
+                self.write_failed.set(true);
+            }
+        }
+    }
+
+    /// Flush the file and log what was captured.
+    pub fn finish(&self) {
+        if let Err(e) = self.writer.borrow_mut().flush() {
+            warn!(target: "capture", error = %e, "failed to flush capture file");
+        }
+        info!(
+            target: "capture",
+            calls = self.next_call.get(),
+            sampled = self.calls.get(),
+            records = self.records.get(),
+            "payload capture finished"
+        );
+    }
+}
+
+/// `EchoerProvider` whose sampled calls, and those on the echoers it hands out, are captured.
+pub struct CapturingEchoerProvider {
+    capture: Rc<Capture>,
+}
+
+impl CapturingEchoerProvider {
+    pub fn client(inner: echoer_provider::Client, capture: Rc<Capture>) -> echoer_provider::Client {
+        layer::provider(inner, Self { capture })
+    }
+}
+
+impl Layer for CapturingEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        mut forward: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let name = layer::name(method);
+        let call = self.capture.sample();
+        if let Some(call) = call {
+            let params = flat::<any_pointer::Owned>(forward.params());
+            self.capture.params(call, name, params);
+        }
+        let capture = self.capture.clone();
+        trace::promise(async move {
+            let outcome = forward.send().await;
+            if let Some(call) = call {
+                let value = match &outcome {
+                    // The result is a capability.
+                    Ok(_) if layer::returns_capability(method) => Ok(None),
+                    Ok(response) => response
+                        .get()
+                        .and_then(flat::<any_pointer::Owned>)
+                        .map(Some),
+                    Err(e) => Err(e.clone()),
+                };
+                capture.outcome(call, name, value);
+            }
+            outcome
+        })
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(CapturingEchoer {
+            inner,
+            capture: self.capture.clone(),
+        })
+    }
+}
+
+struct CapturingEchoer {
+    inner: echoer::Client,
+    capture: Rc<Capture>,
+}
+
+impl echoer::Server for CapturingEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        const METHOD: &str = "Echoer.echo";
+        let params = pry!(params.get());
+        let call = self.capture.sample();
+        if let Some(call) = call {
+            self.capture.params(call, METHOD, flat(params));
+        }
+        let mut request = self.inner.echo_request();
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        let capture = self.capture.clone();
+        trace::promise(async move {
+            let outcome = request.send().promise.await;
+            if let Some(call) = call {
+                let value = match &outcome {
+                    Ok(response) => response.get().and_then(flat).map(Some),
+                    Err(e) => Err(e.clone()),
+                };
+                capture.outcome(call, METHOD, value);
+            }
+            results.get().set_reply(outcome?.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        const METHOD: &str = "Echoer.echoBatch";
+        let params = pry!(params.get());
+        let call = self.capture.sample();
+        if let Some(call) = call {
+            self.capture.params(call, METHOD, flat(params));
+        }
+        let mut request = self.inner.echo_batch_request();
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        let capture = self.capture.clone();
+        trace::promise(async move {
+            let outcome = request.send().promise.await;
+            if let Some(call) = call {
+                let value = match &outcome {
+                    Ok(response) => response.get().and_then(flat).map(Some),
+                    Err(e) => Err(e.clone()),
+                };
+                capture.outcome(call, METHOD, value);
+            }
+            results.get().set_replies(outcome?.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use capnp::message::ReaderOptions;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("capture-{name}-{}.bin", std::process::id()))
+    }
+
+    fn capture(path: &Path, sample: f64, max_bytes: usize, seed: u64) -> Rc<Capture> {
+        Capture::new(File::create(path).unwrap(), sample, max_bytes, seed)
+    }
+
+    /// Which of `calls` calls are sampled.
+    fn sampled(sample: f64, seed: u64, calls: usize) -> Vec<u64> {
+        let path = temp_path(&format!("sample-{seed}"));
+        let capture = capture(&path, sample, 4096, seed);
+        let sampled = (0..calls).filter_map(|_| capture.sample()).collect();
+        std::fs::remove_file(&path).unwrap();
+        sampled
+    }
+
+    #[test]
+    fn the_sample_decides_which_calls_are_captured() {
+        assert!(sampled(0.0, 1, 1000).is_empty());
+        assert_eq!(sampled(1.0, 1, 1000), (0..1000).collect::<Vec<_>>());
+        let half = sampled(0.5, 1, 1000);
+        assert!((400..600).contains(&half.len()), "{}", half.len());
+        // The same seed samples the same calls; another seed, others.
+        assert_eq!(sampled(0.5, 1, 1000), half);
+        assert_ne!(sampled(0.5, 2, 1000), half);
+    }
+
+    #[test]
+    fn payloads_over_the_limit_are_truncated() {
+        let path = temp_path("truncate");
+        let capture = capture(&path, 1.0, 64, 1);
+        for msg in ["short", &"long".repeat(100)] {
+            let mut params = message::Builder::new_default();
+            params
+                .init_root::<echoer::echo_params::Builder>()
+                .set_msg(msg);
+            let call = capture.sample().unwrap();
+            capture.params(call, "Echoer.echo", Ok(params));
+        }
+        capture.finish();
+
+        let mut file = std::io::BufReader::new(File::open(&path).unwrap());
+        let mut payloads = Vec::new();
+        while let Some(message) =
+            serialize::try_read_message(&mut file, ReaderOptions::new()).unwrap()
+        {
+            let record = message.get_root::<record::Reader>().unwrap();
+            assert_eq!(
+                record.get_method().unwrap().to_str().unwrap(),
+                "Echoer.echo"
+            );
+            let record::Params(payload) = record.which().unwrap() else {
+                panic!("expected params");
+            };
+            let payload = payload.unwrap();
+            let kept = match payload.which().unwrap() {
+                payload::Full(full) => {
+                    let params = full.get_as::<echoer::echo_params::Reader>().unwrap();
+                    params.get_msg().unwrap().len()
+                }
+                payload::Truncated(bytes) => bytes.unwrap().len(),
+                payload::Omitted(()) => panic!("params are never omitted"),
+            };
+            payloads.push((payload.get_size(), kept));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let [(short_size, short_kept), (long_size, long_kept)] = payloads[..] else {
+            panic!("expected two records, got {payloads:?}");
+        };
+        assert!(short_size <= 64);
+        assert_eq!(short_kept, "short".len());
+        assert!(long_size > 400);
+        assert_eq!(long_kept, 64);
+    }
+}
@@ -0,0 +1,620 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use serde::Deserialize;
+
+use crate::guest_env;
+use crate::guest_stderr::StderrMode;
+use crate::pooling::PoolingOptions;
+use crate::preopens::Preopen;
+use crate::question_alarm::QuestionAlarm;
+use crate::topology::{RuntimeFile, RuntimeOptions};
+use crate::verify::VerifyOptions;
+
+/// Host configuration, parsed from the command line.
+#[derive(Parser, Debug, Clone)]
+#[command(about = "Run a Wasm guest with Cap'n Proto capabilities over its stdio")]
+pub struct HostConfig {
+    /// Path of the guest Wasm component to run, in the binary or the WAT text format.
+    #[arg(long, default_value = "wasm/target/wasm32-wasip2/release/wasm.wasm")]
+    pub wasm: PathBuf,
+
+    /// Run the conformance suite against the guest instead of serving it: the guest must export
+    /// an `EchoerProvider` as its bootstrap capability when `WETWARE_CONFORMANCE` is set.
+    #[arg(long)]
+    pub conformance: bool,
+
+    /// Upper bound on each guest linear memory, in bytes. Memory64 guests may go past 4 GiB, so
+    /// the host doesn't rely on the 32-bit address space to cap them.
+    #[arg(long, default_value_t = DEFAULT_MAX_MEMORY)]
+    pub max_memory: usize,
+
+    /// Fuel budget for the guest; it traps once the budget is spent. Unmetered by default.
+    #[arg(long)]
+    pub fuel: Option<u64>,
+
+    /// Budget for the guest's calls on host capabilities, in ledger units (a weight per method
+    /// plus one unit per KiB moved). Calls beyond it fail with `budget-exhausted`.
+    #[arg(long)]
+    pub budget: Option<u64>,
+
+    /// Cap on questions the guest may have outstanding on its connection. Calls beyond it are
+    /// rejected with an `overloaded` backoff error; the cap is advertised to the guest in
+    /// `WETWARE_MAX_QUESTIONS`.
+    #[arg(long)]
+    pub max_questions: Option<usize>,
+
+    /// Warn under the `flow` target when more than this many questions are outstanding on the
+    /// guest's connection, an early sign of reply starvation.
+    #[arg(long, value_name = "QUESTIONS")]
+    pub question_alarm: Option<usize>,
+
+    /// Warn under the `flow` target when the guest's outstanding questions have grown without
+    /// falling for this long.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub question_growth: Option<Duration>,
+
+    /// End the run when `--question-alarm` or `--question-growth` goes off: the guest is
+    /// interrupted as at `--deadline`, and the run fails.
+    #[arg(long)]
+    pub question_alarm_abort: bool,
+
+    /// Throttle each direction of the RPC transport to this many bytes per second, to simulate
+    /// a slow link or consumer.
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u64).range(1..))]
+    pub throttle: Option<u64>,
+
+    /// Token-bucket size for `--throttle`: the most bytes that move at once after a pause.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 64 * 1024,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub throttle_burst: u64,
+
+    /// Emulate a wide-area link on the RPC transport with this round-trip time (e.g. `100ms`).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub wan_rtt: Option<Duration>,
+
+    /// Random deviation from the emulated link's one-way delay, either way.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "wan_rtt")]
+    pub wan_jitter: Option<Duration>,
+
+    /// Bandwidth of the emulated link, in bytes per second.
+    #[arg(long, value_name = "BYTES_PER_SEC", requires = "wan_rtt")]
+    pub wan_bandwidth: Option<u64>,
+
+    /// Time each host-side phase of every RPC call and write the totals to this file as folded
+    /// stacks, for flamegraph tools.
+    #[arg(long, value_name = "PATH")]
+    pub profile: Option<PathBuf>,
+
+    /// Measure the CPU time spent running the guest and serving its RPC calls, per thread, and
+    /// write it to this file as JSON.
+    #[arg(long, value_name = "PATH")]
+    pub cpu_report: Option<PathBuf>,
+
+    /// Also write spans and events, including every pipe transfer, to this file as a Chrome
+    /// trace (open it in Perfetto or chrome://tracing).
+    #[arg(long, value_name = "PATH")]
+    pub trace_chrome: Option<PathBuf>,
+
+    /// Capacity of each RPC pipe between host and guest, in bytes, unless `--stdin-buffer` or
+    /// `--stdout-buffer` sets it for one direction.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 32 * 1024 * 1024,
+        value_parser = parse_buffer
+    )]
+    pub pipe_buffer: usize,
+
+    /// Capacity of the pipe from the provider to the guest's stdin, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = parse_buffer)]
+    pub stdin_buffer: Option<usize>,
+
+    /// Capacity of the pipe from the guest's stdout to the provider, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = parse_buffer)]
+    pub stdout_buffer: Option<usize>,
+
+    /// Capacity of the guest's stderr pipe, in bytes. Once it is full, the guest's log writes
+    /// wait for the host to forward what is in it.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 32 * 1024 * 1024,
+        value_parser = parse_buffer
+    )]
+    pub stderr_buffer: usize,
+
+    /// Simulate a slow consumer: the host pauses this long (e.g. `5ms`) before every read from
+    /// the guest, so the guest's writes back up against the pipe bound.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub slow_consumer: Option<Duration>,
+
+    /// Delay or fail calls on host capabilities according to the rules in this TOML file, to test
+    /// how the guest copes with a slow or flaky dependency.
+    #[arg(long, value_name = "PATH")]
+    pub inject: Option<PathBuf>,
+
+    /// Withhold the echoers' replies and return them in a shuffled order, up to this many at a
+    /// time, to check that the guest copes with replies arriving out of order. The shuffle is
+    /// derived from the run seed.
+    #[arg(long, value_name = "REPLIES")]
+    pub reorder_replies: Option<usize>,
+
+    /// How long `--reorder-replies` withholds a reply at most, when too few calls are in flight
+    /// to fill a group.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "10ms")]
+    pub reorder_hold: Duration,
+
+    /// Warn when a capability service holds the provider thread longer than this (e.g. `10ms`)
+    /// in one stretch, a sign that it blocks.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub blocking_threshold: Option<Duration>,
+
+    /// Answer `EchoerProvider.echoer()` at once with a promise for the echoer, and resolve it only
+    /// this long after the call, to exercise pipelining on promised capabilities.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub resolve_delay: Option<Duration>,
+
+    /// For tests only: hand the guest a `Control` capability from `EchoerProvider.control()`,
+    /// with which it can have the host hang up on it after a given number of calls.
+    #[arg(long)]
+    pub test_control: bool,
+
+    /// Write the params and results of sampled calls on host capabilities to this file, as
+    /// `capture.capnp` records.
+    #[arg(long, value_name = "PATH")]
+    pub capture: Option<PathBuf>,
+
+    /// Share of calls `--capture` records, from 0 to 1.
+    #[arg(
+        long,
+        value_name = "SHARE",
+        default_value_t = 1.0,
+        value_parser = parse_share,
+        requires = "capture"
+    )]
+    pub capture_sample: f64,
+
+    /// Largest payload `--capture` keeps whole, in bytes; bigger ones are cut to this length.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 4096,
+        requires = "capture"
+    )]
+    pub capture_max_bytes: usize,
+
+    /// Queue the provider's outgoing messages in a channel of this many writes and write them to
+    /// the transport from a task of their own, coalescing bursts into one write.
+    #[arg(
+        long,
+        value_name = "WRITES",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub write_batch: Option<usize>,
+
+    /// Keep the host's last RECORDS log records for the guest to follow through
+    /// `EchoerProvider.logTail`, and ask the example guest to follow them.
+    #[arg(long, value_name = "RECORDS")]
+    pub log_tail: Option<usize>,
+
+    /// Ask the example guest to count and time the polls of its futures, and report them with
+    /// its resource usage; flags futures that wake themselves in a loop or poll for too long.
+    #[arg(long)]
+    pub poll_stats: bool,
+
+    /// Split the host's reads and writes on the RPC transport into pieces of 1 to this many
+    /// bytes, with random stalls, to exercise both sides' stream adapters with partial
+    /// transfers. The pattern is derived from the run seed.
+    #[arg(long, value_name = "BYTES")]
+    pub fragment: Option<usize>,
+
+    /// How to handle guest stderr: log each line as a `tracing` event, parse each line as a `json`
+    /// event, or copy lines to the host's stderr `raw`.
+    #[arg(long, value_enum, default_value_t = StderrMode::Tracing)]
+    pub guest_stderr: StderrMode,
+
+    /// Also append every guest stderr line, as written, to this file.
+    #[arg(long, value_name = "PATH")]
+    pub guest_stderr_tee: Option<PathBuf>,
+
+    /// Log at most LINES guest stderr lines a second, and as many of each per-call host message;
+    /// the rest are dropped and counted in "messages suppressed" summaries.
+    #[arg(long, value_name = "LINES")]
+    pub log_rate: Option<u32>,
+
+    /// Past `--log-rate`, still log one message in N.
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "log_rate")]
+    pub log_sample: u32,
+
+    /// Multiplex the RPC pipes into logical channels, each with its own flow control. The guest
+    /// must support it (the guest SDK's `mux` module); it is told through `WETWARE_MUX`.
+    #[arg(long, conflicts_with = "conformance")]
+    pub mux: bool,
+
+    /// Connect the guest's transport streams to the provider through a channel of whole
+    /// messages, up to this many each way, instead of byte pipes. Only for guests that use
+    /// `wetware:guest/transport`.
+    #[arg(
+        long,
+        value_name = "MESSAGES",
+        conflicts_with = "mux",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub message_channel: Option<usize>,
+
+    /// Run every tenant defined in this TOML file side by side, each in its own engine and store
+    /// with its own limits, instead of the single `--wasm` guest.
+    #[arg(long, conflicts_with_all = ["http", "reactor"])]
+    pub tenants: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub pooling: PoolingOptions,
+
+    #[command(flatten)]
+    pub verify: VerifyOptions,
+
+    #[command(flatten)]
+    pub runtime: RuntimeOptions,
+
+    /// Read settings from this TOML file; options given on the command line override it. Only
+    /// the `[runtime]` table is read for now (see `topology::RuntimeFile`).
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Serve the guest as a `wasi:http/proxy` component on this address instead of running its
+    /// `wasi:cli/run` export. The guest keeps its Cap'n Proto connection alongside.
+    #[arg(long)]
+    pub http: Option<SocketAddr>,
+
+    /// Serve a Cap'n Proto `GuestControl` capability on this address, to pause and resume the
+    /// guest while it runs.
+    #[arg(long, conflicts_with = "tenants")]
+    pub control: Option<SocketAddr>,
+
+    /// Hard limit on the whole guest run, RPC connection included. When it passes, the guest is
+    /// interrupted, the host logs what the transport looked like, and it exits with status 124.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub deadline: Option<Duration>,
+
+    /// Keep the host's last 4096 log events, debug ones included, and write them to PATH if
+    /// `--deadline` passes.
+    #[arg(long, value_name = "PATH")]
+    pub flight_recorder: Option<PathBuf>,
+
+    /// How long the provider gets to see the guest's transport close on its own once the guest
+    /// has exited; after that the host closes the provider's ends of the pipes itself.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "2s")]
+    pub teardown_grace: Duration,
+
+    /// How long an `--http` host keeps serving open connections after SIGTERM or ctrl-c.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "30s")]
+    pub drain_timeout: Duration,
+
+    /// Treat the guest as a reactor: instantiate it once, call its `wetware:guest/reactor`
+    /// `init` export, then call `handle-event` for every line read from stdin.
+    #[arg(long, conflicts_with = "http")]
+    pub reactor: bool,
+
+    /// Read the reactor's events from this file instead of stdin, e.g. to replay a recorded
+    /// session.
+    #[arg(long, value_name = "PATH", requires = "reactor")]
+    pub events: Option<PathBuf>,
+
+    /// Cache the compiled guest at this path and reuse it on later runs, skipping compilation.
+    /// A compile cache only, keyed by the SHA-256 of the guest bytes and the engine
+    /// configuration, and rebuilt whenever either changes.
+    #[arg(long)]
+    pub snapshot: Option<PathBuf>,
+
+    /// Root seed from which all host and guest randomness is derived. A random one is drawn and
+    /// logged when not given.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Back the guest's `wasi:random` and `wasi:clocks` with generators and virtual clocks
+    /// derived from the run seed, so runs are reproducible.
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Pass host environment variables matching this pattern (`*` is a wildcard) to the guest.
+    /// Repeatable. Nothing is passed by default.
+    #[arg(long, value_name = "PATTERN")]
+    pub pass_env: Vec<String>,
+
+    /// Rename a passed-through variable: `FROM=TO`. Repeatable.
+    #[arg(long, value_name = "FROM=TO", value_parser = guest_env::parse_pair)]
+    pub rename_env: Vec<(String, String)>,
+
+    /// Set a guest environment variable, overriding any passed-through value. Repeatable.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = guest_env::parse_pair)]
+    pub env: Vec<(String, String)>,
+
+    /// Preopen a host directory in the guest: `HOST:GUEST`, or `HOST:GUEST:ro` for read-only
+    /// access. Repeatable.
+    #[arg(long, value_name = "HOST:GUEST[:ro]", value_parser = Preopen::parse)]
+    pub dir: Vec<Preopen>,
+
+    /// Arguments for the guest, after `--`. The host's own arguments are never passed on.
+    #[arg(last = true)]
+    pub guest_args: Vec<String>,
+}
+
+/// 4 GiB: the full address space of a 32-bit guest.
+pub const DEFAULT_MAX_MEMORY: usize = 4 << 30;
+
+impl HostConfig {
+    /// Capacity of the pipe the guest reads its stdin from.
+    pub fn stdin_buffer(&self) -> usize {
+        self.stdin_buffer.unwrap_or(self.pipe_buffer)
+    }
+
+    /// Capacity of the pipe the guest writes its stdout to.
+    pub fn stdout_buffer(&self) -> usize {
+        self.stdout_buffer.unwrap_or(self.pipe_buffer)
+    }
+
+    /// The question alarm, if `--question-alarm` or `--question-growth` sets one.
+    pub fn question_alarm(&self) -> Option<QuestionAlarm> {
+        if self.question_alarm.is_none() && self.question_growth.is_none() {
+            return None;
+        }
+        Some(QuestionAlarm {
+            threshold: self.question_alarm,
+            growth: self.question_growth,
+            abort: self.question_alarm_abort,
+        })
+    }
+}
+
+/// A `--config` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    runtime: RuntimeFile,
+}
+
+impl HostConfig {
+    /// Parse the command line, exiting on bad arguments as `parse` does, then apply the
+    /// `--config` file under it.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_matches(&Self::command().get_matches())
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = Self::from_arg_matches(matches)?;
+        if let Some(path) = &config.config {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read config {}: {e}", path.display()))?;
+            let file: ConfigFile = toml::from_str(&text)
+                .map_err(|e| format!("invalid config {}: {e}", path.display()))?;
+            config
+                .runtime
+                .merge(file.runtime, matches)
+                .map_err(|e| format!("invalid config {}: {e}", path.display()))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Parse a pipe capacity in bytes. A pipe that can't hold a byte would never move one.
+fn parse_buffer(arg: &str) -> Result<usize, String> {
+    match arg.parse() {
+        Ok(0) => Err("a pipe needs room for at least one byte".to_string()),
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Err(format!("invalid size {arg:?}")),
+    }
+}
+
+/// Parse a share of calls, from 0 to 1.
+fn parse_share(arg: &str) -> Result<f64, String> {
+    match arg.parse() {
+        Ok(share) if (0.0..=1.0).contains(&share) => Ok(share),
+        Ok(_) => Err(format!("{arg} is not between 0 and 1")),
+        Err(_) => Err(format!("invalid share {arg:?}")),
+    }
+}
+
+/// Parse a duration such as `250ms`, `2s` or `1m`.
+pub fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let split = arg
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in {arg:?} (use ms, s or m)"))?;
+    let (value, unit) = arg.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration {arg:?}"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => value
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration {arg:?} is too long")),
+        _ => Err(format!("unknown unit {unit:?} in {arg:?} (use ms, s or m)")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::{Flavor, ProviderPlacement};
+
+    #[test]
+    fn throttle_rejects_zero() {
+        assert!(HostConfig::try_parse_from(["host", "--throttle", "0"]).is_err());
+        assert!(HostConfig::try_parse_from(["host", "--throttle-burst", "0"]).is_err());
+        let config = HostConfig::try_parse_from(["host", "--throttle", "1"]).unwrap();
+        assert_eq!(config.throttle, Some(1));
+    }
+
+    #[test]
+    fn worker_threads_rejects_zero() {
+        assert!(HostConfig::try_parse_from(["host", "--worker-threads", "0"]).is_err());
+        let config = HostConfig::try_parse_from(["host", "--worker-threads", "1"]).unwrap();
+        assert_eq!(config.runtime.worker_threads, 1);
+    }
+
+    #[test]
+    fn write_batch_rejects_zero() {
+        assert!(HostConfig::try_parse_from(["host", "--write-batch", "0"]).is_err());
+        let config = HostConfig::try_parse_from(["host", "--write-batch", "1"]).unwrap();
+        assert_eq!(config.write_batch, Some(1));
+    }
+
+    #[test]
+    fn message_channel_rejects_zero() {
+        assert!(HostConfig::try_parse_from(["host", "--message-channel", "0"]).is_err());
+        let config = HostConfig::try_parse_from(["host", "--message-channel", "1"]).unwrap();
+        assert_eq!(config.message_channel, Some(1));
+    }
+
+    #[test]
+    fn capture_sample_is_a_share() {
+        let sample = |share| {
+            HostConfig::try_parse_from([
+                "host",
+                "--capture",
+                "calls.bin",
+                "--capture-sample",
+                share,
+            ])
+            .map(|config| config.capture_sample)
+        };
+        assert_eq!(sample("0").unwrap(), 0.0);
+        assert_eq!(sample("0.25").unwrap(), 0.25);
+        assert_eq!(sample("1").unwrap(), 1.0);
+        for bad in ["-0.1", "1.5", "NaN", "inf", "half"] {
+            assert!(sample(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("3m"), Ok(Duration::from_secs(180)));
+        assert!(parse_duration("2").is_err());
+        assert!(parse_duration("2h").is_err());
+        assert!(parse_duration("ms").is_err());
+    }
+
+    #[test]
+    fn durations_that_overflow_are_rejected() {
+        let max_minutes = u64::MAX / 60;
+        assert_eq!(
+            parse_duration(&format!("{max_minutes}m")),
+            Ok(Duration::from_secs(max_minutes * 60))
+        );
+        assert!(parse_duration(&format!("{}m", max_minutes + 1)).is_err());
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_ok());
+        assert!(parse_duration(&format!("{}0s", u64::MAX)).is_err());
+    }
+
+    /// Parse `args` with `config` as the `--config` file.
+    fn with_config_file(
+        name: &str,
+        config: &str,
+        args: &[&str],
+    ) -> Result<HostConfig, Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("host-{name}-{}.toml", std::process::id()));
+        std::fs::write(&path, config).unwrap();
+        let path_arg = path.to_str().unwrap().to_string();
+        let args = ["host", "--config", &path_arg]
+            .into_iter()
+            .chain(args.iter().copied());
+        let loaded = HostConfig::command()
+            .try_get_matches_from(args)
+            .map_err(Into::into)
+            .and_then(|matches| HostConfig::from_matches(&matches));
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn the_config_file_sets_the_runtime() {
+        let config = with_config_file(
+            "runtime",
+            r#"
+                [runtime]
+                flavor = "current-thread"
+                worker-threads = 2
+                worker-thread-name = "guest"
+                provider = "local"
+                provider-thread-name = "rpc"
+                worker-cores = [2, 3]
+                provider-core = 1
+            "#,
+            &[],
+        )
+        .unwrap();
+        let runtime = config.runtime;
+        assert_eq!(runtime.flavor, Flavor::CurrentThread);
+        assert_eq!(runtime.worker_threads, 2);
+        assert_eq!(runtime.worker_thread_name, "guest");
+        assert_eq!(runtime.provider, ProviderPlacement::Local);
+        assert_eq!(runtime.provider_thread_name, "rpc");
+        assert_eq!(runtime.worker_cores, [2, 3]);
+        assert_eq!(runtime.provider_core, Some(1));
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_config_file() {
+        let config = with_config_file(
+            "override",
+            "[runtime]\nworker-threads = 2\nprovider = \"local\"\n",
+            &["--worker-threads", "6", "--provider", "thread"],
+        )
+        .unwrap();
+        assert_eq!(config.runtime.worker_threads, 6);
+        assert_eq!(config.runtime.provider, ProviderPlacement::Thread);
+        // An option given on the command line with its default value still wins.
+        let config = with_config_file(
+            "default",
+            "[runtime]\nworker-threads = 2\n",
+            &["--worker-threads", "4"],
+        )
+        .unwrap();
+        assert_eq!(config.runtime.worker_threads, 4);
+    }
+
+    #[test]
+    fn an_empty_config_file_keeps_the_defaults() {
+        let config = with_config_file("empty", "", &[]).unwrap();
+        let defaults = HostConfig::try_parse_from(["host"]).unwrap();
+        assert_eq!(config.runtime.flavor, defaults.runtime.flavor);
+        assert_eq!(
+            config.runtime.worker_threads,
+            defaults.runtime.worker_threads
+        );
+        assert_eq!(config.runtime.provider, defaults.runtime.provider);
+    }
+
+    #[test]
+    fn bad_config_files_are_rejected() {
+        assert!(with_config_file("unknown", "[runtime]\nworkers = 2\n", &[]).is_err());
+        assert!(with_config_file("table", "[guest]\nwasm = \"a.wasm\"\n", &[]).is_err());
+        assert!(with_config_file("zero", "[runtime]\nworker-threads = 0\n", &[]).is_err());
+        assert!(with_config_file("flavor", "[runtime]\nflavor = \"fast\"\n", &[]).is_err());
+        let missing = std::env::temp_dir().join("no-such-host-config.toml");
+        let matches = HostConfig::command()
+            .try_get_matches_from(["host", "--config", missing.to_str().unwrap()])
+            .unwrap();
+        assert!(HostConfig::from_matches(&matches).is_err());
+    }
+
+    #[test]
+    fn events_need_a_reactor() {
+        assert!(HostConfig::try_parse_from(["host", "--events", "events.txt"]).is_err());
+        let config =
+            HostConfig::try_parse_from(["host", "--reactor", "--events", "events.txt"]).unwrap();
+        assert_eq!(config.events, Some(PathBuf::from("events.txt")));
+    }
+}
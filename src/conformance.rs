@@ -0,0 +1,160 @@
+//! Conformance harness for guests implementing the transport convention.
+//!
+//! In conformance mode the roles are reversed: the guest exports an `EchoerProvider` as its
+//! bootstrap capability and the host runs a fixed battery of RPC scenarios against it. This lets
+//! guests written in any language be validated against this crate's transport.
+
+use std::time::{Duration, Instant};
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use capnp_rpc::{RpcSystem, rpc_twoparty_capnp};
+use futures::future::join_all;
+use tracing::{info, warn};
+
+/// Environment variable set in the guest to request conformance mode.
+pub const CONFORMANCE_ENV: &str = "WETWARE_CONFORMANCE";
+
+const SCENARIO_TIMEOUT: Duration = Duration::from_secs(30);
+const PIPELINED_CALLS: usize = 100;
+const CANCELLED_CALLS: usize = 100;
+const LARGE_PAYLOAD_SIZES: [usize; 3] = [64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+pub struct ScenarioResult {
+    pub name: &'static str,
+    pub outcome: Result<Duration, String>,
+}
+
+/// Drive `rpc_system` while running every scenario against `target`, then drop the RPC system
+/// so the guest observes EOF and exits.
+pub async fn run(
+    rpc_system: RpcSystem<rpc_twoparty_capnp::Side>,
+    target: echoer_provider::Client,
+) -> Vec<ScenarioResult> {
+    let suite = run_suite(target);
+    tokio::pin!(rpc_system);
+    tokio::pin!(suite);
+    tokio::select! {
+        results = &mut suite => results,
+        res = &mut rpc_system => {
+            // Remaining calls fail fast once the connection is gone.
+            warn!(?res, "connection ended during the conformance suite");
+            suite.await
+        }
+    }
+}
+
+async fn run_suite(target: echoer_provider::Client) -> Vec<ScenarioResult> {
+    vec![
+        scenario("echo", echo(&target)).await,
+        scenario("pipelining", pipelining(&target)).await,
+        scenario("cancellation", cancellation(&target)).await,
+        scenario("large-payloads", large_payloads(&target)).await,
+    ]
+}
+
+async fn scenario(
+    name: &'static str,
+    fut: impl Future<Output = Result<(), capnp::Error>>,
+) -> ScenarioResult {
+    info!(scenario = name, "running conformance scenario");
+    let start = Instant::now();
+    let outcome = match tokio::time::timeout(SCENARIO_TIMEOUT, fut).await {
+        Ok(Ok(())) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("timed out after {SCENARIO_TIMEOUT:?}")),
+    };
+    ScenarioResult { name, outcome }
+}
+
+/// Log every result and return the number of failed scenarios.
+pub fn report(results: &[ScenarioResult]) -> usize {
+    let mut failures = 0;
+    for result in results {
+        match &result.outcome {
+            Ok(elapsed) => info!(scenario = result.name, ?elapsed, "conformance: PASS"),
+            Err(e) => {
+                failures += 1;
+                warn!(scenario = result.name, error = %e, "conformance: FAIL");
+            }
+        }
+    }
+    info!(
+        passed = results.len() - failures,
+        failed = failures,
+        "conformance suite finished"
+    );
+    failures
+}
+
+async fn get_echoer(target: &echoer_provider::Client) -> Result<echoer::Client, capnp::Error> {
+    target
+        .echoer_request()
+        .send()
+        .promise
+        .await?
+        .get()?
+        .get_echoer()
+}
+
+async fn check_echo(echoer: &echoer::Client, msg: &str) -> Result<(), capnp::Error> {
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg);
+    let response = request.send().promise.await?;
+    let reply = response.get()?.get_reply()?;
+    if reply != msg.as_bytes() {
+        return Err(capnp::Error::failed(format!(
+            "reply mismatch: sent {} bytes, got {} bytes",
+            msg.len(),
+            reply.len()
+        )));
+    }
+    Ok(())
+}
+
+/// A single call on a freshly obtained capability.
+async fn echo(target: &echoer_provider::Client) -> Result<(), capnp::Error> {
+    let echoer = get_echoer(target).await?;
+    check_echo(&echoer, "conformance: echo").await
+}
+
+/// Calls on promised capabilities, issued before `echoer()` resolves.
+async fn pipelining(target: &echoer_provider::Client) -> Result<(), capnp::Error> {
+    let calls = (0..PIPELINED_CALLS).map(|i| async move {
+        let echoer = target.echoer_request().send().pipeline.get_echoer();
+        check_echo(&echoer, &format!("conformance: pipelined #{i}")).await
+    });
+    join_all(calls).await.into_iter().collect()
+}
+
+/// Drop half of the outstanding calls before they complete, then check the rest and the
+/// connection are unaffected.
+async fn cancellation(target: &echoer_provider::Client) -> Result<(), capnp::Error> {
+    let echoer = get_echoer(target).await?;
+    let mut kept = Vec::new();
+    for i in 0..CANCELLED_CALLS {
+        let msg = format!("conformance: cancellation #{i}");
+        let mut request = echoer.echo_request();
+        request.get().set_msg(msg.as_str());
+        let promise = request.send().promise;
+        if i % 2 == 0 {
+            kept.push((msg, promise));
+        }
+        // Odd promises are dropped here, which cancels the call.
+    }
+    for (msg, promise) in kept {
+        let response = promise.await?;
+        if response.get()?.get_reply()? != msg.as_bytes() {
+            return Err(capnp::Error::failed(format!("reply mismatch for {msg:?}")));
+        }
+    }
+    check_echo(&echoer, "conformance: after cancellation").await
+}
+
+/// Messages well beyond a single transport read or write.
+async fn large_payloads(target: &echoer_provider::Client) -> Result<(), capnp::Error> {
+    let echoer = get_echoer(target).await?;
+    for size in LARGE_PAYLOAD_SIZES {
+        check_echo(&echoer, &"x".repeat(size)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! Per-connection statistics.
+//!
+//! A [`ConnectionMonitor`] follows one Cap'n Proto connection: the messages and bytes that cross
+//! it in each direction, the questions outstanding on it, and when it last carried anything. Its
+//! counters are atomics, so any thread can take a [`ConnectionStats`] snapshot while the
+//! provider thread updates them. Bytes and messages are counted by [`Counted`], wrapped around
+//! each half of the connection's stream, which follows the standard Cap'n Proto stream framing to
+//! tell where each message ends. Questions are mirrored from the connection's
+//! [`QuestionGate`](crate::flow::QuestionGate). Snapshots are logged under the `conn` target
+//! while the connection is up and returned in the run's outcome.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+// `last_activity_ms` before the first byte.
+const NEVER: u64 = u64::MAX;
+
+/// A point-in-time view of one connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub outstanding_questions: usize,
+    pub uptime: Duration,
+    /// When the connection last moved a byte either way; `None` if it never has.
+    pub last_activity: Option<SystemTime>,
+}
+
+pub struct ConnectionMonitor {
+    started: Instant,
+    started_at: SystemTime,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    questions: AtomicUsize,
+    last_activity_ms: AtomicU64,
+}
+
+impl ConnectionMonitor {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            questions: AtomicUsize::new(0),
+            last_activity_ms: AtomicU64::new(NEVER),
+        })
+    }
+
+    pub fn snapshot(&self) -> ConnectionStats {
+        let last_ms = self.last_activity_ms.load(Ordering::Relaxed);
+        ConnectionStats {
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            outstanding_questions: self.questions.load(Ordering::Relaxed),
+            uptime: self.started.elapsed(),
+            last_activity: (last_ms != NEVER)
+                .then(|| self.started_at + Duration::from_millis(last_ms)),
+        }
+    }
+
+    /// Time since the connection last moved a byte, or since it was set up.
+    pub fn idle(&self) -> Duration {
+        match self.last_activity_ms.load(Ordering::Relaxed) {
+            NEVER => self.started.elapsed(),
+            ms => self
+                .started
+                .elapsed()
+                .saturating_sub(Duration::from_millis(ms)),
+        }
+    }
+
+    pub(crate) fn set_questions(&self, outstanding: usize) {
+        self.questions.store(outstanding, Ordering::Relaxed);
+    }
+
+    fn record(&self, direction: Direction, bytes: usize, messages: u64) {
+        let (byte_count, message_count) = match direction {
+            Direction::In => (&self.bytes_in, &self.messages_in),
+            Direction::Out => (&self.bytes_out, &self.messages_out),
+        };
+        byte_count.fetch_add(bytes as u64, Ordering::Relaxed);
+        message_count.fetch_add(messages, Ordering::Relaxed);
+        self.last_activity_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Log a snapshot of `monitor` every `interval` until aborted.
+pub async fn report(monitor: Arc<ConnectionMonitor>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let stats = monitor.snapshot();
+        debug!(
+            target: "conn",
+            messages_in = stats.messages_in,
+            messages_out = stats.messages_out,
+            bytes_in = stats.bytes_in,
+            bytes_out = stats.bytes_out,
+            outstanding_questions = stats.outstanding_questions,
+            idle = ?monitor.idle(),
+            "connection"
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    In,
+    Out,
+}
+
+/// Finds message boundaries in a Cap'n Proto stream: a segment count, the segment sizes in
+/// words, padding to a word, then the segments.
+#[derive(Default)]
+struct Framing {
+    header: Vec<u8>,
+    body_left: usize,
+}
+
+impl Framing {
+    /// Feed the next bytes of the stream; returns how many messages they completed.
+    fn feed(&mut self, mut bytes: &[u8]) -> u64 {
+        let mut completed = 0;
+        while !bytes.is_empty() {
+            if self.body_left > 0 {
+                let n = self.body_left.min(bytes.len());
+                self.body_left -= n;
+                bytes = &bytes[n..];
+                if self.body_left == 0 {
+                    completed += 1;
+                }
+                continue;
+            }
+            self.take_header(&mut bytes, 4);
+            if self.header.len() < 4 {
+                break;
+            }
+            let segments = le_u32(&self.header[..4]) + 1;
+            // Count word, one size word per segment, padded to a whole 8-byte word.
+            let header_len = (4 + 4 * segments).next_multiple_of(8);
+            self.take_header(&mut bytes, header_len);
+            if self.header.len() < header_len {
+                break;
+            }
+            let words: usize = self.header[4..4 + 4 * segments]
+                .chunks_exact(4)
+                .map(le_u32)
+                .sum();
+            self.header.clear();
+            self.body_left = words * 8;
+            if self.body_left == 0 {
+                completed += 1;
+            }
+        }
+        completed
+    }
+
+    fn take_header(&mut self, bytes: &mut &[u8], len: usize) {
+        let n = len.saturating_sub(self.header.len()).min(bytes.len());
+        self.header.extend_from_slice(&bytes[..n]);
+        *bytes = &bytes[n..];
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> usize {
+    u32::from_le_bytes(bytes.try_into().unwrap()) as usize
+}
+
+/// One half of a connection's stream, counted into a [`ConnectionMonitor`].
+pub struct Counted<S> {
+    inner: S,
+    monitor: Arc<ConnectionMonitor>,
+    framing: Framing,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, monitor: Arc<ConnectionMonitor>) -> Self {
+        Self {
+            inner,
+            monitor,
+            framing: Framing::default(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            let messages = this.framing.feed(read);
+            this.monitor.record(Direction::In, read.len(), messages);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if n > 0 {
+            let messages = this.framing.feed(&buf[..n]);
+            this.monitor.record(Direction::Out, n, messages);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,148 @@
+//! CPU time spent on each side of the RPC boundary (`--cpu-report`).
+//!
+//! Wall-clock time can't tell a slow guest from a slow host, and process CPU time lumps the two
+//! together. So the host reads the calling thread's CPU clock before and after every poll of the
+//! guest's run future and of the provider's, and charges the difference to that side, per thread.
+//! The guest's account covers its Wasm and the host calls it makes (WASI streams, the bridge);
+//! the provider's covers Cap'n Proto processing and the capability servers. Work on other tasks,
+//! such as the transport pumps and reporters, is in neither.
+//!
+//! Hosts built with `alloc-stats` count each side's allocations the same way (see
+//! `alloc_count`).
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::alloc_count;
+
+/// CPU time the calling thread has used so far.
+pub fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid, writable `timespec`; clock_gettime only writes to it.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// CPU time charged to one side, by the name of the thread it was spent on, and the
+/// allocations made meanwhile.
+#[derive(Clone, Default)]
+pub struct CpuAccount {
+    threads: Arc<Mutex<BTreeMap<String, Duration>>>,
+    allocations: Arc<AtomicU64>,
+}
+
+impl CpuAccount {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap `fut` so the CPU time of its polls is charged to this account.
+    pub fn meter<F: Future>(&self, fut: F) -> Metered<F> {
+        Metered {
+            inner: Box::pin(fut),
+            account: self.clone(),
+        }
+    }
+
+    fn charge(&self, spent: Duration, allocations: u64) {
+        self.allocations.fetch_add(allocations, Ordering::Relaxed);
+        let thread = std::thread::current();
+        let name = thread.name().unwrap_or("unnamed");
+        let mut threads = self.threads.lock().unwrap();
+        match threads.get_mut(name) {
+            Some(total) => *total += spent,
+            None => {
+                threads.insert(name.to_string(), spent);
+            }
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.threads.lock().unwrap().values().sum()
+    }
+
+    /// Always 0 without `alloc-stats`.
+    pub fn allocations(&self) -> u64 {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    pub fn by_thread(&self) -> BTreeMap<String, Duration> {
+        self.threads.lock().unwrap().clone()
+    }
+}
+
+/// A future whose polls are charged to a [`CpuAccount`].
+pub struct Metered<F> {
+    inner: Pin<Box<F>>,
+    account: CpuAccount,
+}
+
+impl<F: Future> Future for Metered<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let before = thread_cpu_time();
+        let allocations_before = alloc_count::thread_allocations();
+        let poll = self.inner.as_mut().poll(cx);
+        let allocations = alloc_count::thread_allocations() - allocations_before;
+        self.account
+            .charge(thread_cpu_time().saturating_sub(before), allocations);
+        poll
+    }
+}
+
+/// The `--cpu-report` file.
+#[derive(Serialize)]
+struct Report {
+    guest_cpu_ms: f64,
+    provider_cpu_ms: f64,
+    /// Host allocations on each side; 0 without `alloc-stats`.
+    guest_allocations: u64,
+    provider_allocations: u64,
+    /// Allocations the segment pool served, on both sides; 0 without `segment-pool`.
+    pooled_allocations: u64,
+    /// Milliseconds by side, then by thread.
+    threads: BTreeMap<&'static str, BTreeMap<String, f64>>,
+}
+
+/// Write the guest's and provider's CPU time and allocations to `path` as JSON.
+pub fn write_report(
+    path: &Path,
+    guest: &CpuAccount,
+    provider: &CpuAccount,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ms = |d: Duration| d.as_secs_f64() * 1e3;
+    let by_thread = |account: &CpuAccount| {
+        account
+            .by_thread()
+            .into_iter()
+            .map(|(thread, spent)| (thread, ms(spent)))
+            .collect()
+    };
+    let report = Report {
+        guest_cpu_ms: ms(guest.total()),
+        provider_cpu_ms: ms(provider.total()),
+        guest_allocations: guest.allocations(),
+        provider_allocations: provider.allocations(),
+        pooled_allocations: alloc_count::pooled_allocations(),
+        threads: BTreeMap::from([
+            ("guest", by_thread(guest)),
+            ("provider", by_thread(provider)),
+        ]),
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    Ok(())
+}
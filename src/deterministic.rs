@@ -0,0 +1,77 @@
+//! Deterministic execution: seeded randomness and virtual clocks for the guest.
+//!
+//! Everything the guest can observe through `wasi:random` and `wasi:clocks` is derived from a
+//! single seed, so a stress run (including the guest's shuffles, which are seeded from
+//! `wasi:random`) replays exactly. Clocks are virtual: every read advances them by a fixed tick,
+//! independent of real time. Sleeps still take real time; only the values the guest reads are
+//! virtual.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use cap_rand::SeedableRng;
+use cap_rand::rngs::StdRng;
+use wasmtime_wasi::{HostMonotonicClock, HostWallClock, WasiCtxBuilder};
+
+// How far the virtual clocks advance on every read.
+const CLOCK_TICK: Duration = Duration::from_micros(1);
+// Virtual wall-clock time at startup: 2020-09-13T12:26:40Z.
+const WALL_CLOCK_EPOCH: Duration = Duration::from_secs(1_600_000_000);
+
+/// Back the guest's randomness and clocks in `builder` with implementations derived from `seed`.
+pub fn configure(builder: &mut WasiCtxBuilder, seed: u64) {
+    builder
+        .secure_random(StdRng::seed_from_u64(seed))
+        .insecure_random(StdRng::seed_from_u64(seed.rotate_left(32)))
+        .insecure_random_seed(u128::from(seed) << 64 | u128::from(!seed))
+        .wall_clock(VirtualWallClock::new())
+        .monotonic_clock(VirtualMonotonicClock::new());
+}
+
+/// A monotonic clock that starts at zero and advances by `CLOCK_TICK` per read.
+struct VirtualMonotonicClock {
+    now_ns: AtomicU64,
+}
+
+impl VirtualMonotonicClock {
+    fn new() -> Self {
+        Self {
+            now_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+impl HostMonotonicClock for VirtualMonotonicClock {
+    fn resolution(&self) -> u64 {
+        CLOCK_TICK.as_nanos() as u64
+    }
+
+    fn now(&self) -> u64 {
+        self.now_ns
+            .fetch_add(CLOCK_TICK.as_nanos() as u64, Ordering::Relaxed)
+    }
+}
+
+/// A wall clock that starts at `WALL_CLOCK_EPOCH` and advances by `CLOCK_TICK` per read.
+struct VirtualWallClock {
+    reads: AtomicU64,
+}
+
+impl VirtualWallClock {
+    fn new() -> Self {
+        Self {
+            reads: AtomicU64::new(0),
+        }
+    }
+}
+
+impl HostWallClock for VirtualWallClock {
+    fn resolution(&self) -> Duration {
+        CLOCK_TICK
+    }
+
+    fn now(&self) -> Duration {
+        let reads = self.reads.fetch_add(1, Ordering::Relaxed);
+        WALL_CLOCK_EPOCH + CLOCK_TICK * reads as u32
+    }
+}
@@ -0,0 +1,225 @@
+//! Hanging up on the guest at a chosen call (`--test-control`).
+//!
+//! Killing a connection from outside lands wherever the timing puts it, so a guest's handling of
+//! a connection that dies partway through a batch is hard to test that way. With
+//! `--test-control`, `EchoerProvider.control()` hands the guest a `Control` capability instead of
+//! failing as unimplemented. `Control.disconnect(afterCalls)` arms a [`Tripwire`] on the echo calls
+//! that reach the echoers. The next `afterCalls` calls are answered as usual. The one after that
+//! fails with a `disconnected` error, and once the calls before it have been answered, the host
+//! hangs up: it stops reading from the guest, and the connection closes after the replies it has
+//! queued are sent. Calls sent after the tripping one are never read, so the guest sees them fail
+//! as disconnected. The cut lands on the same call on every run.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use cap::echo_capnp::{control, echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::trace;
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::teardown::Sever;
+
+/// Where the connection is to be cut, shared by the connection's echoers.
+pub struct Tripwire {
+    // Ends the provider's reads from the guest.
+    hangup: Sever,
+    // Calls still to answer before hanging up; `None` until armed.
+    remaining: Cell<Option<u32>>,
+    tripped: Cell<bool>,
+    // Calls let through and not yet answered.
+    in_flight: Cell<u32>,
+    answered: Notify,
+}
+
+impl Tripwire {
+    pub fn new(hangup: Sever) -> Rc<Self> {
+        info!(target: "disconnect", "test control enabled");
+        Rc::new(Self {
+            hangup,
+            remaining: Cell::new(None),
+            tripped: Cell::new(false),
+            in_flight: Cell::new(0),
+            answered: Notify::new(),
+        })
+    }
+
+    fn arm(&self, after_calls: u32) {
+        info!(target: "disconnect", after_calls, "hanging up after the next calls");
+        self.remaining.set(Some(after_calls));
+    }
+
+    /// Let a call through, or trip on it.
+    fn admit(self: &Rc<Self>) -> Admission {
+        if self.tripped.get() {
+            return Admission::Cut;
+        }
+        match self.remaining.get() {
+            Some(0) => {
+                self.tripped.set(true);
+                return Admission::Trip;
+            }
+            Some(n) => self.remaining.set(Some(n - 1)),
+            None => {}
+        }
+        self.in_flight.set(self.in_flight.get() + 1);
+        Admission::Pass(InFlight(self.clone()))
+    }
+
+    /// Wait for the calls let through to be answered, then hang up.
+    async fn hang_up(&self) -> capnp::Error {
+        while self.in_flight.get() > 0 {
+            self.answered.notified().await;
+        }
+        // Let the connection queue the last replies before it sees the end of its input.
+        tokio::task::yield_now().await;
+        warn!(target: "disconnect", "hanging up on the guest");
+        self.hangup.sever();
+        hung_up()
+    }
+}
+
+fn hung_up() -> capnp::Error {
+    capnp::Error::disconnected("the host hung up (Control.disconnect)".to_string())
+}
+
+enum Admission {
+    Pass(InFlight),
+    /// This is the call to hang up on.
+    Trip,
+    /// The connection is already being cut.
+    Cut,
+}
+
+/// A call let through by the tripwire, until it is answered.
+struct InFlight(Rc<Tripwire>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.set(self.0.in_flight.get() - 1);
+        self.0.answered.notify_one();
+    }
+}
+
+/// Run `call` unless the tripwire cuts it off.
+async fn guarded<T>(
+    admission: Admission,
+    tripwire: Rc<Tripwire>,
+    call: impl Future<Output = Result<T, capnp::Error>>,
+) -> Result<T, capnp::Error> {
+    match admission {
+        Admission::Pass(_in_flight) => call.await,
+        Admission::Trip => Err(tripwire.hang_up().await),
+        Admission::Cut => Err(hung_up()),
+    }
+}
+
+/// `EchoerProvider` that answers `control()`, and hands out echoers behind its tripwire.
+pub struct DisconnectingEchoerProvider {
+    tripwire: Rc<Tripwire>,
+}
+
+impl DisconnectingEchoerProvider {
+    pub fn client(
+        inner: echoer_provider::Client,
+        tripwire: Rc<Tripwire>,
+    ) -> echoer_provider::Client {
+        let control = ControlProvider {
+            tripwire: tripwire.clone(),
+        };
+        layer::builder(inner, Self { tripwire })
+            .serve::<echoer_provider::Client, _>(&[layer::CONTROL], control)
+            .build()
+    }
+}
+
+impl Layer for DisconnectingEchoerProvider {
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(DisconnectingEchoer {
+            inner,
+            tripwire: self.tripwire.clone(),
+        })
+    }
+}
+
+/// Answers `EchoerProvider.control()` with a `Control` over the tripwire.
+struct ControlProvider {
+    tripwire: Rc<Tripwire>,
+}
+
+impl echoer_provider::Server for ControlProvider {
+    fn control(
+        &mut self,
+        _params: echoer_provider::ControlParams,
+        mut results: echoer_provider::ControlResults,
+    ) -> Promise<(), capnp::Error> {
+        let control: control::Client = capnp_rpc::new_client(ControlServer {
+            tripwire: self.tripwire.clone(),
+        });
+        results.get().set_control(control);
+        Promise::ok(())
+    }
+}
+
+struct ControlServer {
+    tripwire: Rc<Tripwire>,
+}
+
+impl control::Server for ControlServer {
+    fn disconnect(
+        &mut self,
+        params: control::DisconnectParams,
+        _results: control::DisconnectResults,
+    ) -> Promise<(), capnp::Error> {
+        self.tripwire.arm(pry!(params.get()).get_after_calls());
+        Promise::ok(())
+    }
+}
+
+struct DisconnectingEchoer {
+    inner: echoer::Client,
+    tripwire: Rc<Tripwire>,
+}
+
+impl echoer::Server for DisconnectingEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let admission = self.tripwire.admit();
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        let tripwire = self.tripwire.clone();
+        trace::promise(async move {
+            let call = async { request.send().promise.await };
+            let response = guarded(admission, tripwire, call).await?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let admission = self.tripwire.admit();
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        let tripwire = self.tripwire.clone();
+        trace::promise(async move {
+            let call = async { request.send().promise.await };
+            let response = guarded(admission, tripwire, call).await?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
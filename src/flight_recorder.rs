@@ -0,0 +1,82 @@
+//! The host's recent log, written out when a run hits its deadline (`--flight-recorder`).
+//!
+//! The deadline snapshot says where the transport stood at the end; the flight recorder says how
+//! it got there. With `--flight-recorder <PATH>`, a tracing layer keeps the last [`RECORDS`]
+//! events in a [`LogBook`], debug ones included even when the log filter hides them. Nothing is
+//! written while the run goes well. When `--deadline` passes, the runner calls [`dump`], which
+//! writes the book to the path, oldest event first, one line per event.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use cap::logtail::{Entry, LogBook};
+use tracing::Subscriber;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::log_tail::BookLayer;
+
+/// Events the recorder keeps.
+pub const RECORDS: usize = 4096;
+
+static RECORDER: OnceLock<(LogBook, PathBuf)> = OnceLock::new();
+
+/// A layer feeding the recorder; `None` leaves the log untouched.
+pub fn layer<S>(path: Option<&Path>) -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let path = path?;
+    let (book, _) = RECORDER.get_or_init(|| (LogBook::new(RECORDS), path.to_owned()));
+    // Debug from this crate and its capabilities, but not from the compiler.
+    let filter = EnvFilter::new("debug,wasmtime=info,cranelift_codegen=info,regalloc2=info");
+    Some(BookLayer::new(book.clone()).with_filter(filter))
+}
+
+/// Write the recorder to its path, if it is on. Returns the path written.
+pub fn dump() -> io::Result<Option<PathBuf>> {
+    let Some((book, path)) = RECORDER.get() else {
+        return Ok(None);
+    };
+    fs::write(path, render(&book.read(0, RECORDS)))?;
+    Ok(Some(path.clone()))
+}
+
+/// One line per entry: seconds since the book started, level, target and message.
+fn render(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let secs = entry.timestamp_ns as f64 / 1e9;
+        writeln!(
+            out,
+            "{secs:>12.6}s {:<5} {}: {}",
+            entry.level, entry.target, entry.message
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_dump_lists_the_newest_events_oldest_first() {
+        let book = LogBook::new(2);
+        book.append("INFO", "pipe", "first".into());
+        book.append("DEBUG", "flow", "second".into());
+        book.append("WARN", "deadline", "third".into());
+        let out = render(&book.read(0, RECORDS));
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("s DEBUG flow: second"), "{}", lines[0]);
+        assert!(
+            lines[1].ends_with("s WARN  deadline: third"),
+            "{}",
+            lines[1]
+        );
+    }
+}
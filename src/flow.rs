@@ -0,0 +1,181 @@
+//! Connection-level flow control: a cap on questions outstanding from the guest.
+//!
+//! Every call the guest has in flight holds buffered request and response state on both sides.
+//! [`QuestionGate`] counts them per connection and, once the cap is reached, rejects further
+//! calls with an `overloaded` error telling the guest to back off, instead of letting a badly
+//! behaved client grow the transport's memory without bound. Well-behaved guests read the cap
+//! from `WETWARE_MAX_QUESTIONS` and never hit it.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use cap::trace;
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use tracing::debug;
+
+use crate::conn_stats::ConnectionMonitor;
+use crate::log_limit::{LogLimit, LogRate};
+
+/// Environment variable advertising the cap to the guest.
+pub const MAX_QUESTIONS_ENV: &str = "WETWARE_MAX_QUESTIONS";
+
+pub struct QuestionGate {
+    max: Option<usize>,
+    outstanding: Cell<usize>,
+    peak: Cell<usize>,
+    rejected: Cell<u64>,
+    monitor: Option<Arc<ConnectionMonitor>>,
+    // A guest over the cap may have every call rejected; those are limited to `--log-rate`.
+    rejections: LogLimit,
+}
+
+impl QuestionGate {
+    /// A gate admitting at most `max` questions; the outstanding count is mirrored into the
+    /// connection's `monitor`, if any.
+    pub fn new(
+        max: Option<usize>,
+        monitor: Option<Arc<ConnectionMonitor>>,
+        log_rate: Option<LogRate>,
+    ) -> Rc<Self> {
+        Rc::new(Self {
+            max,
+            outstanding: Cell::new(0),
+            peak: Cell::new(0),
+            rejected: Cell::new(0),
+            monitor,
+            rejections: LogLimit::new("flow", log_rate),
+        })
+    }
+
+    fn set_outstanding(&self, outstanding: usize) {
+        self.outstanding.set(outstanding);
+        if let Some(monitor) = &self.monitor {
+            monitor.set_questions(outstanding);
+        }
+    }
+
+    /// Admit one question, or reject it if the cap is reached. The question counts as
+    /// outstanding until the returned guard is dropped.
+    fn enter(self: &Rc<Self>, method: &str) -> Result<Question, capnp::Error> {
+        let outstanding = self.outstanding.get();
+        if let Some(max) = self.max
+            && outstanding >= max
+        {
+            self.rejected.set(self.rejected.get() + 1);
+            if self.rejections.admit() {
+                debug!(method, outstanding, max, "rejecting question over the cap");
+            }
+            return Err(capnp::Error::overloaded(format!(
+                "backoff: {outstanding} questions outstanding, limit {max}"
+            )));
+        }
+        self.set_outstanding(outstanding + 1);
+        self.peak.set(self.peak.get().max(outstanding + 1));
+        Ok(Question(self.clone()))
+    }
+
+    /// The most questions that were ever outstanding at once.
+    pub fn peak(&self) -> usize {
+        self.peak.get()
+    }
+
+    /// Questions rejected for exceeding the cap.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.get()
+    }
+}
+
+struct Question(Rc<QuestionGate>);
+
+impl Drop for Question {
+    fn drop(&mut self) {
+        self.0.set_outstanding(self.0.outstanding.get() - 1);
+    }
+}
+
+/// `EchoerProvider` whose calls, and those on the echoers it hands out, pass through the gate.
+///
+/// Calls on the other capabilities it hands out are not gated: a `LogTail.follow` call lasts as
+/// long as the guest keeps following, and would hold a question all that time.
+pub struct GatedEchoerProvider {
+    gate: Rc<QuestionGate>,
+}
+
+impl GatedEchoerProvider {
+    pub fn client(
+        inner: echoer_provider::Client,
+        gate: Rc<QuestionGate>,
+    ) -> echoer_provider::Client {
+        layer::provider(inner, Self { gate })
+    }
+}
+
+impl Layer for GatedEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let question = pry!(self.gate.enter(layer::short_name(method)));
+        trace::promise(async move {
+            let _question = question;
+            call.send().await
+        })
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(GatedEchoer {
+            inner,
+            gate: self.gate.clone(),
+        })
+    }
+}
+
+struct GatedEchoer {
+    inner: echoer::Client,
+    gate: Rc<QuestionGate>,
+}
+
+impl echoer::Server for GatedEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let question = pry!(self.gate.enter("echo"));
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        trace::promise(async move {
+            let _question = question;
+            let response = request.send().promise.await?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let question = pry!(self.gate.enter("echoBatch"));
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        trace::promise(async move {
+            let _question = question;
+            let response = request.send().promise.await?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
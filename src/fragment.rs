@@ -0,0 +1,112 @@
+//! Randomly fragmented transfers on the host ends of the transport pipes.
+//!
+//! [`Fragmented`] splits every read and write into a piece of 1 to `max` bytes, sized from a
+//! seeded splitmix64 stream, and now and then reports "not ready" for one poll instead. The
+//! guest then sees its input arrive a few bytes at a time and its output drained unevenly, which
+//! is the traffic its stream adapters have to get right: no bytes lost, duplicated or reordered,
+//! and no stall. Cap'n Proto framing and the guest's reply checks catch the first three, the
+//! liveness watchdog the last. The pattern comes from the run seed, so sweeping `--seed` over a
+//! range of values explores different schedules, and a failing one reproduces.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::seed::SplitMix64;
+
+// One poll in this many returns `Pending` (after waking itself) instead of moving bytes.
+const STALL_ONE_IN: u64 = 8;
+
+/// Piece sizes and stalls for one direction.
+struct Schedule {
+    rng: SplitMix64,
+    max: usize,
+}
+
+impl Schedule {
+    fn new(seed: u64, max: usize) -> Self {
+        Self {
+            rng: SplitMix64::new(seed),
+            max: max.max(1),
+        }
+    }
+
+    /// `None` to stall this poll, otherwise the most bytes it may move.
+    fn piece(&mut self, cx: &mut Context<'_>) -> Option<usize> {
+        let z = self.rng.next_u64();
+        if z % STALL_ONE_IN == 0 {
+            cx.waker().wake_by_ref();
+            return None;
+        }
+        Some(1 + (z >> 8) as usize % self.max)
+    }
+}
+
+/// A stream whose reads and writes move randomly sized pieces.
+pub struct Fragmented<S> {
+    inner: S,
+    read: Option<Schedule>,
+    write: Option<Schedule>,
+}
+
+impl<S> Fragmented<S> {
+    /// Wrap `inner` with pieces of at most `max` bytes; `None` leaves it untouched.
+    pub fn new(inner: S, max: Option<usize>, seed: u64) -> Self {
+        Self {
+            inner,
+            read: max.map(|max| Schedule::new(seed, max)),
+            write: max.map(|max| Schedule::new(seed.rotate_left(32), max)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Fragmented<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(schedule) = this.read.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let Some(piece) = schedule.piece(cx) else {
+            return Poll::Pending;
+        };
+        let mut limited = buf.take(piece.min(buf.remaining()));
+        std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        // SAFETY: `limited` is a view of `buf`'s unfilled part and the inner read just filled
+        // its first `n` bytes.
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Fragmented<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(schedule) = this.write.as_mut() else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let Some(piece) = schedule.piece(cx) else {
+            return Poll::Pending;
+        };
+        Pin::new(&mut this.inner).poll_write(cx, &buf[..piece.min(buf.len())])
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
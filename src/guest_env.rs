@@ -0,0 +1,214 @@
+//! Arguments and environment handed to the guest.
+//!
+//! Nothing from the host environment reaches the guest unless allowed: variables must match a
+//! `--pass-env` pattern, can be renamed on the way in, and explicit `--env` values are injected
+//! on top. Guest arguments are the ones given after `--`, never the host's own.
+//!
+//! The variables the host sets itself to tell the guest how it is being run are reserved: values
+//! for them from `--env` or the host environment are dropped, so the guest sees only the host's.
+
+use std::collections::BTreeMap;
+
+use tracing::{debug, warn};
+use wasmtime_wasi::WasiCtxBuilder;
+
+use crate::config::HostConfig;
+use crate::{conformance, flow, log_tail, mux, seed, stats};
+
+/// Variables only the host sets.
+const RESERVED: [&str; 6] = [
+    seed::SEED_ENV,
+    conformance::CONFORMANCE_ENV,
+    flow::MAX_QUESTIONS_ENV,
+    mux::MUX_ENV,
+    log_tail::LOG_TAIL_ENV,
+    stats::POLL_STATS_ENV,
+];
+
+/// Set the guest's arguments and environment in `builder` according to `config`.
+pub fn configure(builder: &mut WasiCtxBuilder, config: &HostConfig) {
+    // argv[0] is the guest's file name, like a program started from a shell.
+    let program = config
+        .wasm
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "guest".to_string());
+    builder.arg(&program);
+    builder.args(&config.guest_args);
+
+    for (key, value) in guest_env(config, std::env::vars()) {
+        debug!(%key, "passing environment variable to guest");
+        builder.env(key, value);
+    }
+}
+
+/// The guest environment: allowed host variables (renamed where configured), then injected
+/// values, which win on conflicts. Reserved names are left out.
+fn guest_env(
+    config: &HostConfig,
+    host_env: impl Iterator<Item = (String, String)>,
+) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::new();
+    for (key, value) in host_env {
+        if !config.pass_env.iter().any(|pattern| matches(pattern, &key)) {
+            continue;
+        }
+        let key = config
+            .rename_env
+            .iter()
+            .find(|(from, _)| *from == key)
+            .map(|(_, to)| to.clone())
+            .unwrap_or(key);
+        if RESERVED.contains(&key.as_str()) {
+            debug!(%key, "not passing reserved variable to guest");
+            continue;
+        }
+        env.insert(key, value);
+    }
+    for (key, value) in &config.env {
+        if RESERVED.contains(&key.as_str()) {
+            warn!(%key, "ignoring --env for a variable the host sets itself");
+            continue;
+        }
+        env.insert(key.clone(), value.clone());
+    }
+    env
+}
+
+/// Match `name` against `pattern`, where `*` matches any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(mut remaining) = name.strip_prefix(prefix) else {
+        return false;
+    };
+    let mut parts = rest.split('*').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part has to sit at the very end.
+            return remaining.ends_with(part);
+        }
+        match remaining.find(part) {
+            Some(at) => remaining = &remaining[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Parse a `KEY=VALUE` command-line value. The value may be empty, and may contain `=`; the key
+/// may not be empty.
+pub fn parse_pair(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {arg:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn patterns_without_a_wildcard_match_exactly() {
+        assert!(matches("HOME", "HOME"));
+        assert!(!matches("HOME", "HOMEDIR"));
+        assert!(!matches("HOME", "MY_HOME"));
+        assert!(!matches("", "HOME"));
+        assert!(matches("", ""));
+    }
+
+    #[test]
+    fn wildcards_match_any_run_of_characters() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "ANYTHING"));
+        assert!(matches("WETWARE_*", "WETWARE_SEED"));
+        assert!(matches("WETWARE_*", "WETWARE_"));
+        assert!(!matches("WETWARE_*", "WETWARE"));
+        assert!(matches("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(!matches("*_TOKEN", "GITHUB_TOKENS"));
+        assert!(matches("A*B*C", "ABC"));
+        assert!(matches("A*B*C", "AxxBxxC"));
+        assert!(!matches("A*B*C", "ACB"));
+        assert!(matches("A**B", "AB"));
+    }
+
+    #[test]
+    fn wildcard_parts_do_not_overlap() {
+        // The prefix and the suffix can't share the one `A`.
+        assert!(!matches("A*A", "A"));
+        assert!(matches("A*A", "AA"));
+        assert!(!matches("*AB*AB", "AB"));
+        assert!(matches("*AB*AB", "ABAB"));
+    }
+
+    #[test]
+    fn pairs_split_at_the_first_equals_sign() {
+        assert_eq!(parse_pair("KEY=VALUE"), Ok(("KEY".into(), "VALUE".into())));
+        assert_eq!(parse_pair("KEY="), Ok(("KEY".into(), String::new())));
+        assert_eq!(parse_pair("KEY=a=b"), Ok(("KEY".into(), "a=b".into())));
+        assert!(parse_pair("KEY").is_err());
+        assert!(parse_pair("").is_err());
+        assert!(parse_pair("=VALUE").is_err());
+    }
+
+    #[test]
+    fn only_allowed_variables_reach_the_guest() {
+        let config = HostConfig::try_parse_from([
+            "host",
+            "--pass-env",
+            "WETWARE_*",
+            "--rename-env",
+            "WETWARE_SEED=SEED",
+            "--env",
+            "WETWARE_MODE=fast",
+            "--env",
+            "EMPTY=",
+        ])
+        .unwrap();
+        let host_env = [
+            ("WETWARE_SEED", "7"),
+            ("WETWARE_MODE", "slow"),
+            ("AWS_SECRET_ACCESS_KEY", "hunter2"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let env = guest_env(&config, host_env.into_iter());
+        assert_eq!(
+            env.into_iter().collect::<Vec<_>>(),
+            [("EMPTY", ""), ("SEED", "7"), ("WETWARE_MODE", "fast")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn reserved_variables_are_left_to_the_host() {
+        let config = HostConfig::try_parse_from([
+            "host",
+            "--pass-env",
+            "*",
+            "--rename-env",
+            "MY_MUX=WETWARE_MUX",
+            "--env",
+            "WETWARE_SEED=1",
+            "--env",
+            "WETWARE_CONFORMANCE=1",
+            "--env",
+            "WETWARE_MODE=fast",
+        ])
+        .unwrap();
+        let host_env = [
+            ("WETWARE_MAX_QUESTIONS", "1"),
+            ("MY_MUX", "1"),
+            ("WETWARE_POLL_STATS", "1"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let env = guest_env(&config, host_env.into_iter());
+        assert_eq!(
+            env.into_iter().collect::<Vec<_>>(),
+            [("WETWARE_MODE".to_string(), "fast".to_string())]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! What the host does with guest stderr (`--guest-stderr`).
+//!
+//! Heartbeat lines always go to the liveness watchdog. Every other line is handled in one of
+//! these modes:
+//!
+//! - `tracing` (the default): each line becomes an `info` event with target `guest`, and
+//!   `guest:trace` lines become host spans and events (see [`crate::guest_trace`]);
+//! - `json`: each line is parsed as a JSON object, and its `level`, `target` and `message` (or
+//!   `msg`) set the event's level, guest target and message. The remaining keys become a `fields`
+//!   string. Lines that aren't JSON objects are logged as in `tracing` mode;
+//! - `raw`: lines are copied to the host's own stderr untouched, trace lines included.
+//!
+//! With `--guest-stderr-tee`, every line, heartbeats included, is also appended to a file as the
+//! guest wrote it, whatever the mode. `--log-rate` limits the lines logged as events; trace lines
+//! and raw copies are never dropped, so guest spans stay whole.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tracing::{Level, Span, info, warn};
+
+use crate::guest_trace::GuestSpans;
+use crate::liveness::{self, Heartbeat};
+use crate::log_limit::{LogLimit, LogRate};
+
+/// How non-heartbeat stderr lines are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StderrMode {
+    Raw,
+    #[default]
+    Tracing,
+    Json,
+}
+
+// Events need a level known at compile time, so dispatch on the parsed one.
+macro_rules! event_at {
+    ($level:expr, $($rest:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::error!($($rest)*),
+            Level::WARN => tracing::warn!($($rest)*),
+            Level::INFO => tracing::info!($($rest)*),
+            Level::DEBUG => tracing::debug!($($rest)*),
+            Level::TRACE => tracing::trace!($($rest)*),
+        }
+    };
+}
+
+/// Read `stderr` line by line until EOF, handling each line according to `mode`. Guest spans
+/// and events are nested under `connection`; plain and JSON lines are limited to `rate`.
+pub async fn forward(
+    stderr: impl AsyncRead + Unpin,
+    mode: StderrMode,
+    tee: Option<PathBuf>,
+    heartbeat: Heartbeat,
+    connection: Span,
+    rate: Option<LogRate>,
+) {
+    let limit = LogLimit::new("guest_stderr", rate);
+    let mut tee = match tee {
+        Some(path) => match open_tee(&path).await {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "can't open guest stderr tee file");
+                None
+            }
+        },
+        None => None,
+    };
+    let mut spans = GuestSpans::new(connection);
+    let mut host_stderr = tokio::io::stderr();
+    let mut reader = BufReader::new(stderr);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) => break, // EOF
+            Ok(_) => {
+                if let Some(file) = &mut tee
+                    && let Err(e) = file.write_all(line.as_bytes()).await
+                {
+                    warn!(error = %e, "error writing guest stderr tee file; no longer teeing");
+                    tee = None;
+                }
+                let msg = line.trim_end_matches(['\n', '\r']);
+                if msg == liveness::HEARTBEAT_LINE {
+                    heartbeat.beat();
+                    continue;
+                }
+                match mode {
+                    StderrMode::Raw => {
+                        let _ = host_stderr.write_all(line.as_bytes()).await;
+                    }
+                    StderrMode::Tracing => {
+                        if !spans.handle(msg) && limit.admit() {
+                            info!(target: "guest", "{}", msg);
+                        }
+                    }
+                    StderrMode::Json => {
+                        if !spans.handle(msg) && limit.admit() && !log_json(msg) {
+                            info!(target: "guest", "{}", msg);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, target = "guest", "error reading guest stderr");
+                break;
+            }
+        }
+    }
+    if let Some(file) = &mut tee {
+        let _ = file.flush().await;
+    }
+}
+
+async fn open_tee(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Log `line` if it is a JSON object; returns false otherwise.
+fn log_json(line: &str) -> bool {
+    let Ok(serde_json::Value::Object(mut object)) = serde_json::from_str(line) else {
+        return false;
+    };
+    let mut take = |key: &str| match object.remove(key) {
+        Some(serde_json::Value::String(s)) => Some(s),
+        Some(other) => Some(other.to_string()),
+        None => None,
+    };
+    let level = take("level")
+        .and_then(|level| Level::from_str(&level).ok())
+        .unwrap_or(Level::INFO);
+    let guest_target = take("target").unwrap_or_default();
+    let message = take("message").or_else(|| take("msg")).unwrap_or_default();
+    let fields = object
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    event_at!(level, target: "guest", guest_target, fields, "{message}");
+    true
+}
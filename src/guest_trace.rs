@@ -0,0 +1,114 @@
+//! Rebuilding the guest's `tracing` spans and events on the host.
+//!
+//! Guests built with the SDK's `tracing` feature write each span and event as a `guest:trace` line
+//! on stderr (see `wetware_guest::trace` for the format). [`GuestSpans`] turns those lines back
+//! into host spans and events with target `guest`, nested under the guest's connection span, so
+//! they show up in logs and traces alongside the host's own.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use tracing::{Level, Span, debug};
+
+/// Prefix of every trace line; must match `TRACE_PREFIX` in the guest SDK.
+pub const TRACE_PREFIX: &str = "guest:trace";
+
+// Spans and events need a level known at compile time, so dispatch on the parsed one.
+macro_rules! span_at {
+    ($level:expr, $($rest:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::error_span!($($rest)*),
+            Level::WARN => tracing::warn_span!($($rest)*),
+            Level::INFO => tracing::info_span!($($rest)*),
+            Level::DEBUG => tracing::debug_span!($($rest)*),
+            Level::TRACE => tracing::trace_span!($($rest)*),
+        }
+    };
+}
+
+macro_rules! event_at {
+    ($level:expr, $($rest:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::error!($($rest)*),
+            Level::WARN => tracing::warn!($($rest)*),
+            Level::INFO => tracing::info!($($rest)*),
+            Level::DEBUG => tracing::debug!($($rest)*),
+            Level::TRACE => tracing::trace!($($rest)*),
+        }
+    };
+}
+
+/// The guest's open spans, keyed by the IDs the guest assigned them.
+pub struct GuestSpans {
+    connection: Span,
+    spans: HashMap<u64, Span>,
+}
+
+impl GuestSpans {
+    /// Root guest spans and events will be nested under `connection`.
+    pub fn new(connection: Span) -> Self {
+        Self {
+            connection,
+            spans: HashMap::new(),
+        }
+    }
+
+    /// Handle `line` if it is a trace line; returns false for any other line.
+    pub fn handle(&mut self, line: &str) -> bool {
+        let Some(body) = line
+            .strip_prefix(TRACE_PREFIX)
+            .and_then(|rest| rest.strip_prefix('\t'))
+        else {
+            return false;
+        };
+        if self.apply(body).is_none() {
+            debug!(line, "malformed guest trace line");
+        }
+        true
+    }
+
+    fn apply(&mut self, body: &str) -> Option<()> {
+        let mut parts = body.split('\t');
+        match parts.next()? {
+            "new" => {
+                let id: u64 = parts.next()?.parse().ok()?;
+                let parent = self.parent(parts.next()?.parse().ok()?);
+                let level = Level::from_str(parts.next()?).ok()?;
+                let (target, name, fields) = (parts.next()?, parts.next()?, parts.next()?);
+                let span = span_at!(
+                    level,
+                    target: "guest",
+                    parent: &parent,
+                    "guest_span",
+                    name,
+                    guest_target = target,
+                    fields
+                );
+                self.spans.insert(id, span);
+            }
+            "event" => {
+                let parent = self.parent(parts.next()?.parse().ok()?);
+                let level = Level::from_str(parts.next()?).ok()?;
+                let (target, message, fields) = (parts.next()?, parts.next()?, parts.next()?);
+                event_at!(
+                    level,
+                    target: "guest",
+                    parent: &parent,
+                    guest_target = target,
+                    fields,
+                    "{message}"
+                );
+            }
+            "close" => {
+                let id: u64 = parts.next()?.parse().ok()?;
+                self.spans.remove(&id);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn parent(&self, id: u64) -> Span {
+        self.spans.get(&id).unwrap_or(&self.connection).clone()
+    }
+}
@@ -0,0 +1,208 @@
+//! Half-closing the guest's side of the RPC transport.
+//!
+//! The guest's RPC output stream shares its pipe with WASI stdout, so dropping the stream
+//! resource alone wouldn't close anything: the pipe stays open until the store goes away. To let
+//! the guest say "no more calls" while it keeps reading replies, the stream handed out through
+//! `wetware:guest/transport` is a [`ClosingOutputStream`]. When the guest drops it, what it wrote
+//! is flushed and the write half of the pipe is shut down, so the provider reads EOF. The other
+//! direction is untouched.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use tokio::io::AsyncWrite;
+use tracing::debug;
+use wasmtime_wasi_io::poll::Pollable;
+use wasmtime_wasi_io::streams::{DynOutputStream, OutputStream, StreamResult};
+
+/// A writer that can be shut down through a [`CloseHandle`] while someone else owns it. Writes
+/// after that fail with `BrokenPipe`.
+pub struct HalfClose<W> {
+    inner: Arc<Mutex<Option<W>>>,
+}
+
+/// Shuts down the writer of a [`HalfClose`].
+#[derive(Clone)]
+pub struct CloseHandle<W> {
+    inner: Arc<Mutex<Option<W>>>,
+}
+
+impl<W> HalfClose<W> {
+    pub fn new(inner: W) -> (Self, CloseHandle<W>) {
+        let inner = Arc::new(Mutex::new(Some(inner)));
+        (
+            Self {
+                inner: inner.clone(),
+            },
+            CloseHandle { inner },
+        )
+    }
+}
+
+impl<W: AsyncWrite + Unpin> CloseHandle<W> {
+    /// Shut the writer down and drop it. Closing twice does nothing.
+    pub async fn close(&self) {
+        let result = std::future::poll_fn(|cx| {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.as_mut() {
+                Some(writer) => Pin::new(writer).poll_shutdown(cx),
+                None => Poll::Ready(Ok(())),
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            debug!(error = %e, "error shutting down guest transport writer");
+        }
+        self.inner.lock().unwrap().take();
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "transport closed")
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HalfClose<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.inner.lock().unwrap().as_mut() {
+            Some(writer) => Pin::new(writer).poll_write(cx, buf),
+            None => Poll::Ready(Err(closed())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.inner.lock().unwrap().as_mut() {
+            Some(writer) => Pin::new(writer).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.inner.lock().unwrap().as_mut() {
+            Some(writer) => Pin::new(writer).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// The guest's RPC output stream: closes the pipe behind it when the guest drops it.
+pub struct ClosingOutputStream<W> {
+    inner: DynOutputStream,
+    close: CloseHandle<W>,
+}
+
+impl<W> ClosingOutputStream<W> {
+    pub fn new(inner: DynOutputStream, close: CloseHandle<W>) -> Self {
+        Self { inner, close }
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: AsyncWrite + Send + Unpin + 'static> OutputStream for ClosingOutputStream<W> {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.inner.write(bytes)
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        self.inner.flush()
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        self.inner.check_write()
+    }
+
+    async fn cancel(&mut self) {
+        // Commit what the guest already wrote before the provider sees EOF.
+        if self.inner.flush().is_ok() {
+            self.inner.ready().await;
+        }
+        self.inner.cancel().await;
+        self.close.close().await;
+        debug!("guest closed its RPC output stream");
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: Send + 'static> Pollable for ClosingOutputStream<W> {
+    async fn ready(&mut self) {
+        self.inner.ready().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use wasmtime_wasi::cli::{AsyncStdoutStream, StdoutStream};
+
+    use super::*;
+
+    // Frames of assorted sizes, each filled with its own byte so a misplaced piece shows.
+    fn frames() -> Vec<Vec<u8>> {
+        [1, 8, 100, 4096, 70_000]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| vec![i as u8 + 1; len])
+            .collect()
+    }
+
+    // The guest's RPC output stream over a pipe whose write budget is `budget` bytes, and the
+    // provider's end of the pipe.
+    fn budgeted(budget: usize) -> (ClosingOutputStream<DuplexStream>, DuplexStream) {
+        let (guest, host) = tokio::io::duplex(budget);
+        let (guest, close) = HalfClose::new(guest);
+        let stream = AsyncStdoutStream::new(budget, guest).p2_stream();
+        (ClosingOutputStream::new(stream, close), host)
+    }
+
+    // Write `frame` the way a non-blocking guest must: never more than `check-write` allows.
+    async fn write_frame(stream: &mut impl OutputStream, frame: &[u8], budget: usize) {
+        let mut rest = frame;
+        while !rest.is_empty() {
+            stream.ready().await;
+            let permit = stream.check_write().unwrap();
+            assert!(
+                permit <= budget,
+                "check-write allowed {permit} of {budget} bytes"
+            );
+            let n = permit.min(rest.len());
+            if n > 0 {
+                stream.write(Bytes::copy_from_slice(&rest[..n])).unwrap();
+                rest = &rest[n..];
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_arrive_whole_under_any_write_budget() {
+        for budget in [1, 2, 7, 64, 4096] {
+            let (mut stream, mut host) = budgeted(budget);
+            let reader = tokio::spawn(async move {
+                let mut received = Vec::new();
+                host.read_to_end(&mut received).await.unwrap();
+                received
+            });
+            for frame in frames() {
+                write_frame(&mut stream, &frame, budget).await;
+            }
+            // Dropping the stream resource: flush, then EOF for the provider.
+            stream.cancel().await;
+            let received = reader.await.unwrap();
+            assert_eq!(received, frames().concat(), "budget of {budget} bytes");
+        }
+    }
+
+    #[tokio::test]
+    async fn writing_past_check_write_fails() {
+        let (mut stream, _host) = budgeted(1);
+        stream.ready().await;
+        assert_eq!(stream.check_write().unwrap(), 1);
+        // A write path that ignores the budget is stopped, not cut short without a word.
+        assert!(stream.write(Bytes::from_static(b"two")).is_err());
+    }
+}
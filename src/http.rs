@@ -0,0 +1,281 @@
+//! Serving `wasi:http/proxy` guests.
+//!
+//! Guests targeting the `http-guest` world export `wasi:http/incoming-handler` instead of
+//! `wasi:cli/run`. The host accepts HTTP connections and injects each request into the guest,
+//! while the guest keeps its Cap'n Proto connection to the provider over the
+//! `wetware:guest/transport` side channel, exactly as a command guest would.
+//!
+//! The listener is bound with `SO_REUSEPORT`, so a restarted host can bind the same address while
+//! the old one is still running. On SIGTERM or ctrl-c the old host stops accepting, tells its
+//! open connections to close after their current request, and keeps serving until they have
+//! drained or the drain timeout passes; new connections meanwhile land on the new host.
+//! Under systemd the host serves on the socket it was activated with, if any, and reports
+//! readiness, shutdown and watchdog pings (see [`crate::systemd`]).
+
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty};
+use hyper::StatusCode;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, info, warn};
+use wasmtime::Store;
+use wasmtime::component::{Component, Linker};
+use wasmtime_wasi_http::WasiHttpView;
+use wasmtime_wasi_http::bindings::Proxy;
+use wasmtime_wasi_http::bindings::http::types::{ErrorCode, Scheme};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use crate::{ComponentRunStates, systemd};
+
+// Requests accepted but not yet handed to the guest; beyond this, connections wait.
+const REQUEST_QUEUE_DEPTH: usize = 64;
+const LISTEN_BACKLOG: i32 = 1024;
+
+type GuestResponse = Result<hyper::Response<HyperOutgoingBody>, ErrorCode>;
+
+struct IncomingRequest {
+    request: hyper::Request<hyper::body::Incoming>,
+    response: oneshot::Sender<GuestResponse>,
+}
+
+/// Instantiate the guest as a `wasi:http/proxy` and serve HTTP on `addr` until SIGTERM or
+/// ctrl-c, then drain open connections for at most `drain_timeout`.
+///
+/// One store backs one instance, so requests are handed to the guest one at a time. The instance
+/// is reused across requests, which keeps the guest's capnp connection (and any capabilities it
+/// holds) alive between them. A request the guest traps on is answered with a 500, and the next
+/// one goes to a new instance in the same store.
+pub async fn serve(
+    addr: SocketAddr,
+    drain_timeout: Duration,
+    store: &mut Store<ComponentRunStates>,
+    linker: &Linker<ComponentRunStates>,
+    component: &Component,
+) -> wasmtime::Result<()> {
+    let mut proxy = Proxy::instantiate_async(&mut *store, component, linker).await?;
+    let listener = match systemd::activated_listener() {
+        Some(listener) => {
+            info!(
+                addr = ?listener.local_addr().ok(),
+                "serving guest over HTTP on the socket passed by systemd"
+            );
+            TcpListener::from_std(listener)?
+        }
+        None => {
+            let listener = bind(addr)?;
+            info!(%addr, "serving guest over HTTP");
+            listener
+        }
+    };
+    systemd::notify("READY=1");
+    let mut watchdog = systemd::Watchdog::from_env();
+
+    let (requests_tx, mut requests_rx) = mpsc::channel(REQUEST_QUEUE_DEPTH);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(accept(listener, requests_tx, shutdown_rx));
+
+    // Installed once: a signal that arrives while a request is being handled still counts.
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        tokio::select! {
+            incoming = requests_rx.recv() => match incoming {
+                Some(incoming) => {
+                    handle_or_recover(store, &mut proxy, linker, component, incoming).await?
+                }
+                None => return Ok(()),
+            },
+            _ = &mut signal => break,
+            _ = watchdog.pet() => {}
+        }
+    }
+
+    // Stop accepting and ask every connection to close once its current request is answered.
+    // The queue closes when the last connection has gone.
+    info!(?drain_timeout, "draining HTTP connections");
+    systemd::notify("STOPPING=1");
+    let _ = shutdown_tx.send(true);
+    let drained = tokio::time::timeout(drain_timeout, async {
+        loop {
+            tokio::select! {
+                incoming = requests_rx.recv() => match incoming {
+                    Some(incoming) => {
+                        handle_or_recover(store, &mut proxy, linker, component, incoming).await?
+                    }
+                    None => return wasmtime::Result::<()>::Ok(()),
+                },
+                _ = watchdog.pet() => {}
+            }
+        }
+    })
+    .await;
+    match drained {
+        Ok(result) => {
+            info!("HTTP connections drained");
+            result
+        }
+        Err(_) => {
+            warn!("drain timeout passed; dropping the remaining HTTP connections");
+            Ok(())
+        }
+    }
+}
+
+/// Hand one request to the guest, and replace the instance if it trapped.
+///
+/// A trapped instance can't be entered again. Its response outparam is dropped with the failed
+/// call, so the request is answered with a 500, and a new instance takes the following ones. The
+/// new instance starts from scratch: the transport's streams went to the first one, so it has no
+/// capnp connection. The run is over instead if it was ended, by the watchdog or the deadline.
+async fn handle_or_recover(
+    store: &mut Store<ComponentRunStates>,
+    proxy: &mut Proxy,
+    linker: &Linker<ComponentRunStates>,
+    component: &Component,
+    incoming: IncomingRequest,
+) -> wasmtime::Result<()> {
+    let Err(e) = handle(store, proxy, incoming).await else {
+        return Ok(());
+    };
+    if store.data().heartbeat.ended() {
+        return Err(e);
+    }
+    warn!(error = %e, "guest failed on an HTTP request; starting a new instance");
+    *proxy = Proxy::instantiate_async(&mut *store, component, linker).await?;
+    Ok(())
+}
+
+/// Hand one request to the guest.
+async fn handle(
+    store: &mut Store<ComponentRunStates>,
+    proxy: &Proxy,
+    incoming: IncomingRequest,
+) -> wasmtime::Result<()> {
+    debug!(method = %incoming.request.method(), uri = %incoming.request.uri(), "handing request to guest");
+
+    // The guest may start streaming the body before `handle` returns, so the response is
+    // forwarded as soon as it is set rather than after the call.
+    let (sender, receiver) = oneshot::channel();
+    let reply = incoming.response;
+    tokio::spawn(async move {
+        let response = receiver.await.unwrap_or(Err(ErrorCode::InternalError(Some(
+            "guest returned without setting a response".to_string(),
+        ))));
+        let _ = reply.send(response);
+    });
+
+    let request = store
+        .data_mut()
+        .new_incoming_request(Scheme::Http, incoming.request)?;
+    let response_out = store.data_mut().new_response_outparam(sender)?;
+    proxy
+        .wasi_http_incoming_handler()
+        .call_handle(&mut *store, request, response_out)
+        .await
+}
+
+/// Bind `addr` so that another host process can bind it too while this one drains.
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Resolve on ctrl-c, or on SIGTERM where there are signals.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => warn!(error = %e, "cannot listen for SIGTERM"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn accept(
+    listener: TcpListener,
+    requests: mpsc::Sender<IncomingRequest>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(error = %e, "failed to accept HTTP connection");
+                    continue;
+                }
+            },
+            // Dropping the listener hands new connections to whichever host still listens.
+            _ = shutdown.wait_for(|draining| *draining) => return,
+        };
+        debug!(%peer, "accepted HTTP connection");
+        let requests = requests.clone();
+        let mut shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| forward(requests.clone(), request));
+            let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown.wait_for(|draining| *draining) => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                debug!(%peer, error = %e, "HTTP connection ended with error");
+            }
+        });
+    }
+}
+
+/// Queue `request` for the guest and wait for its response.
+async fn forward(
+    requests: mpsc::Sender<IncomingRequest>,
+    request: hyper::Request<hyper::body::Incoming>,
+) -> Result<hyper::Response<HyperOutgoingBody>, Infallible> {
+    let (response, receiver) = oneshot::channel();
+    if requests
+        .send(IncomingRequest { request, response })
+        .await
+        .is_err()
+    {
+        return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    Ok(match receiver.await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            warn!(error = ?e, "guest failed to handle HTTP request");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => error_response(StatusCode::SERVICE_UNAVAILABLE),
+    })
+}
+
+fn error_response(status: StatusCode) -> hyper::Response<HyperOutgoingBody> {
+    let mut response = hyper::Response::new(Empty::new().map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,271 @@
+//! Latency and error injection on capability methods.
+//!
+//! A rules file names methods to tamper with, so a guest's timeouts, retries and circuit
+//! breakers can be exercised against a slow or failing dependency without changing the
+//! capability itself:
+//!
+//! ```toml
+//! [[rule]]
+//! method = "Echoer.echo"
+//! delay = "50ms"
+//! probability = 0.1
+//!
+//! [[rule]]
+//! method = "EchoerProvider.echoer"
+//! error = "overloaded"
+//! probability = 0.05
+//! ```
+//!
+//! Each rule fires on its share of calls to its method: a rule with a `delay` holds the call that
+//! long before passing it on, one with an `error` fails it with that kind of error instead. Every
+//! rule matching a call rolls on its own; the delays of those that fire add up, and the first
+//! error wins. The rolls are derived from the run seed. The injecting wrappers sit in front of
+//! the budget ledger: calls are delayed or failed before they are charged, while the flow-control
+//! gate and the profiler see the faults as the dependency's own.
+
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use cap::trace;
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use serde::{Deserialize, Deserializer};
+use tracing::{debug, info};
+
+use crate::config::parse_duration;
+use crate::seed::SplitMix64;
+
+/// Prefix of the messages of injected errors.
+pub const INJECTED: &str = "injected fault:";
+
+const ECHOER_METHODS: &[&str] = &["Echoer.echo"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// `Interface.method`, e.g. `Echoer.echo`.
+    pub method: String,
+    #[serde(default, deserialize_with = "duration")]
+    pub delay: Option<Duration>,
+    #[serde(default)]
+    pub error: Option<FaultKind>,
+    /// Share of calls the rule fires on, from 0 to 1.
+    #[serde(default = "always")]
+    pub probability: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FaultKind {
+    Failed,
+    Overloaded,
+    Disconnected,
+    Unimplemented,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Read and validate the rules file at `path`.
+pub fn load(path: &Path) -> Result<Vec<Rule>, Box<dyn std::error::Error>> {
+    let file: RulesFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+    let methods: Vec<&str> = layer::names()
+        .chain(ECHOER_METHODS.iter().copied())
+        .collect();
+    for rule in &file.rule {
+        if !methods.contains(&rule.method.as_str()) {
+            return Err(format!(
+                "unknown method {:?} in {} (expected one of {})",
+                rule.method,
+                path.display(),
+                methods.join(", ")
+            )
+            .into());
+        }
+        if !(0.0..=1.0).contains(&rule.probability) {
+            return Err(format!("{}: probability must be between 0 and 1", rule.method).into());
+        }
+        if rule.delay.is_none() && rule.error.is_none() {
+            return Err(format!("{}: rule needs a delay or an error", rule.method).into());
+        }
+    }
+    Ok(file.rule)
+}
+
+/// The rules in force on one connection, with their rolls and what they injected.
+pub struct Faults {
+    rules: Vec<Rule>,
+    rolls: RefCell<SplitMix64>,
+    delayed: Cell<u64>,
+    failed: Cell<u64>,
+}
+
+impl Faults {
+    pub fn new(rules: Vec<Rule>, seed: u64) -> Rc<Self> {
+        Rc::new(Self {
+            rules,
+            rolls: RefCell::new(SplitMix64::new(seed)),
+            delayed: Cell::new(0),
+            failed: Cell::new(0),
+        })
+    }
+
+    /// Roll the rules for one call to `method`; the returned future applies what fired.
+    fn roll(&self, method: &'static str) -> Injection {
+        let mut injection = Injection {
+            method,
+            delay: Duration::ZERO,
+            error: None,
+        };
+        let mut rolls = self.rolls.borrow_mut();
+        for rule in self.rules.iter().filter(|rule| rule.method == method) {
+            if rolls.next_f64() >= rule.probability {
+                continue;
+            }
+            injection.delay += rule.delay.unwrap_or_default();
+            if injection.error.is_none() {
+                injection.error = rule.error;
+            }
+        }
+        if !injection.delay.is_zero() {
+            self.delayed.set(self.delayed.get() + 1);
+        }
+        if injection.error.is_some() {
+            self.failed.set(self.failed.get() + 1);
+        }
+        injection
+    }
+
+    /// Log how many calls were tampered with.
+    pub fn log_summary(&self) {
+        info!(
+            target: "inject",
+            rules = self.rules.len(),
+            delayed = self.delayed.get(),
+            failed = self.failed.get(),
+            "injected faults"
+        );
+    }
+}
+
+struct Injection {
+    method: &'static str,
+    delay: Duration,
+    error: Option<FaultKind>,
+}
+
+impl Injection {
+    async fn apply(self) -> Result<(), capnp::Error> {
+        if !self.delay.is_zero() {
+            debug!(target: "inject", method = self.method, delay = ?self.delay, "delaying call");
+            tokio::time::sleep(self.delay).await;
+        }
+        let Some(kind) = self.error else {
+            return Ok(());
+        };
+        debug!(target: "inject", method = self.method, ?kind, "failing call");
+        let message = format!("{INJECTED} {}", self.method);
+        Err(match kind {
+            FaultKind::Failed => capnp::Error::failed(message),
+            FaultKind::Overloaded => capnp::Error::overloaded(message),
+            FaultKind::Disconnected => capnp::Error::disconnected(message),
+            FaultKind::Unimplemented => capnp::Error::unimplemented(message),
+        })
+    }
+}
+
+/// `EchoerProvider` whose calls, and those on the echoers it hands out, are subject to the rules.
+pub struct InjectingEchoerProvider {
+    faults: Rc<Faults>,
+}
+
+impl InjectingEchoerProvider {
+    pub fn client(inner: echoer_provider::Client, faults: Rc<Faults>) -> echoer_provider::Client {
+        layer::provider(inner, Self { faults })
+    }
+}
+
+impl Layer for InjectingEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let injection = self.faults.roll(layer::name(method));
+        trace::promise(async move {
+            injection.apply().await?;
+            call.send().await
+        })
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(InjectingEchoer {
+            inner,
+            faults: self.faults.clone(),
+        })
+    }
+}
+
+struct InjectingEchoer {
+    inner: echoer::Client,
+    faults: Rc<Faults>,
+}
+
+impl echoer::Server for InjectingEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        let injection = self.faults.roll("Echoer.echo");
+        trace::promise(async move {
+            injection.apply().await?;
+            let response = request.send().promise.await?;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        let injection = self.faults.roll("Echoer.echoBatch");
+        trace::promise(async move {
+            injection.apply().await?;
+            let response = request.send().promise.await?;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
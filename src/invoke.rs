@@ -0,0 +1,95 @@
+//! Connection-less calls into reactor guests.
+//!
+//! A reactor guest that targets the `invokable-guest` world exports `wetware:guest/invoke`
+//! besides `wetware:guest/reactor`. Each call names a method and carries its parameters as one
+//! framed Cap'n Proto message; the reply is another. Nothing goes through the guest's RPC
+//! connection: the call is a plain export call, answered before it returns, so it neither waits
+//! behind queued RPC traffic nor needs the guest to poll its `RpcSystem`.
+
+use std::time::Instant;
+
+use cap::echo_capnp::echoer;
+use capnp::message::{self, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+use tracing::info;
+use wasmtime::Store;
+use wasmtime::component::{Instance, TypedFunc};
+
+use crate::ComponentRunStates;
+
+const INVOKE_INTERFACE: &str = "wetware:guest/invoke";
+
+/// The guest's `invoke.call` export.
+pub struct Invoker {
+    call: TypedFunc<(String, Vec<u8>), (Result<Vec<u8>, String>,)>,
+}
+
+impl Invoker {
+    /// The invoker of `instance`, or `None` if the guest doesn't export `wetware:guest/invoke`.
+    pub fn find(
+        store: &mut Store<ComponentRunStates>,
+        instance: &Instance,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Some(interface_idx) = instance.get_export_index(&mut *store, None, INVOKE_INTERFACE)
+        else {
+            return Ok(None);
+        };
+        let call_idx = instance
+            .get_export_index(&mut *store, Some(&interface_idx), "call")
+            .ok_or("invoke interface has no `call` function")?;
+        let call = instance.get_typed_func(&mut *store, call_idx)?;
+        Ok(Some(Self { call }))
+    }
+
+    /// Call `method` with `params` and return the guest's reply message.
+    pub async fn call<A: message::Allocator>(
+        &self,
+        store: &mut Store<ComponentRunStates>,
+        method: &str,
+        params: &message::Builder<A>,
+    ) -> Result<message::Reader<OwnedSegments>, Box<dyn std::error::Error>> {
+        let params = serialize::write_message_to_words(params);
+        let (result,) = self
+            .call
+            .call_async(&mut *store, (method.to_string(), params))
+            .await?;
+        self.call.post_return_async(&mut *store).await?;
+        let reply = result.map_err(|e| format!("guest failed to serve {method}: {e}"))?;
+        Ok(serialize::read_message(
+            reply.as_slice(),
+            ReaderOptions::new(),
+        )?)
+    }
+
+    /// Call `Echoer.echo` with `msg` and return the reply.
+    pub async fn echo(
+        &self,
+        store: &mut Store<ComponentRunStates>,
+        msg: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut params = message::Builder::new_default();
+        params
+            .init_root::<echoer::echo_params::Builder>()
+            .set_msg(msg);
+        let reply = self.call(store, "Echoer.echo", &params).await?;
+        Ok(reply
+            .get_root::<echoer::echo_results::Reader>()?
+            .get_reply()?
+            .to_vec())
+    }
+}
+
+/// Check that the guest's invoke path answers, and log how long one echo took.
+pub async fn probe(
+    store: &mut Store<ComponentRunStates>,
+    invoker: &Invoker,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const PROBE: &str = "invoke probe";
+    let started = Instant::now();
+    let reply = invoker.echo(store, PROBE).await?;
+    if reply != PROBE.as_bytes() {
+        return Err("guest answered the invoke probe with a different message".into());
+    }
+    info!(elapsed = ?started.elapsed(), "guest answered an invoked echo");
+    Ok(())
+}
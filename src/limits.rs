@@ -0,0 +1,91 @@
+//! Per-guest resource limits and capability grants.
+
+use wasmtime::{ResourceLimiter, StoreLimits, StoreLimitsBuilder};
+
+/// What a single guest is allowed to consume and use.
+#[derive(Debug, Clone)]
+pub struct GuestLimits {
+    /// Upper bound on each linear memory, in bytes.
+    pub max_memory: usize,
+    /// Fuel budget for the whole run; `None` disables fuel metering.
+    pub fuel: Option<u64>,
+    /// Capability-call budget, in ledger units; `None` is unlimited.
+    pub budget: Option<u64>,
+    pub grants: Grants,
+}
+
+/// Host capabilities a guest may use. Everything is granted outside multi-tenant mode.
+#[derive(Debug, Clone, Copy)]
+pub struct Grants {
+    /// The `EchoerProvider` bootstrap capability on the Cap'n Proto connection.
+    pub echoer: bool,
+    /// The `wetware:guest/capnp-bridge` WIT import.
+    pub capnp_bridge: bool,
+    /// The `wasi:http` imports.
+    pub http: bool,
+}
+
+impl Grants {
+    pub const ALL: Grants = Grants {
+        echoer: true,
+        capnp_bridge: true,
+        http: true,
+    };
+}
+
+/// A store limiter that enforces `GuestLimits::max_memory` and records the peak memory size, so
+/// per-guest usage can be reported after the run.
+pub struct GuestLimiter {
+    limits: StoreLimits,
+    peak_memory: usize,
+}
+
+impl GuestLimiter {
+    pub fn new(max_memory: usize) -> Self {
+        Self {
+            limits: StoreLimitsBuilder::new().memory_size(max_memory).build(),
+            peak_memory: 0,
+        }
+    }
+
+    /// The largest size any linear memory reached, in bytes.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+}
+
+impl ResourceLimiter for GuestLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        if allowed {
+            self.peak_memory = self.peak_memory.max(desired);
+        }
+        Ok(allowed)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        self.limits.table_growing(current, desired, maximum)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
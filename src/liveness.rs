@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+use wasmtime::Engine;
+
+use crate::pause::Pause;
+
+/// Stderr line the guest emits periodically to signal that its executor is still turning.
+/// Must match `HEARTBEAT_LINE` in the guest.
+pub const HEARTBEAT_LINE: &str = "guest:heartbeat";
+
+/// Tracks the most recent guest heartbeat.
+pub struct Heartbeat {
+    start: Instant,
+    last_beat_ms: AtomicU64,
+    tripped: AtomicBool,
+    expired: AtomicBool,
+    starved: AtomicBool,
+}
+
+impl Heartbeat {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            last_beat_ms: AtomicU64::new(0),
+            tripped: AtomicBool::new(false),
+            expired: AtomicBool::new(false),
+            starved: AtomicBool::new(false),
+        })
+    }
+
+    /// Record a heartbeat received from the guest.
+    pub fn beat(&self) {
+        let now_ms = self.start.elapsed().as_millis() as u64;
+        self.last_beat_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    /// Time elapsed since the last heartbeat (or since creation if none was seen yet).
+    pub fn since_last(&self) -> Duration {
+        let last = Duration::from_millis(self.last_beat_ms.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    /// Whether the watchdog interrupted the guest for missing heartbeats.
+    pub fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the run's overall deadline passed and the guest was interrupted for it.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Whether the question alarm ended the run and the guest was interrupted for it.
+    pub fn starved(&self) -> bool {
+        self.starved.load(Ordering::Relaxed)
+    }
+
+    /// Whether the run was ended for any of the reasons above.
+    pub fn ended(&self) -> bool {
+        self.tripped() || self.expired() || self.starved()
+    }
+
+    /// Interrupt the guest for the question alarm, the way [`deadline`] does.
+    pub fn starve(&self, engine: &Engine) {
+        self.starved.store(true, Ordering::Relaxed);
+        engine.increment_epoch();
+    }
+}
+
+/// Watch guest heartbeats and interrupt the guest through epoch interruption once none has been
+/// seen for `timeout`. The store running the guest must have its epoch callback installed with
+/// [`crate::pause::install`] for the interruption to take effect. A paused guest can't beat, so
+/// time spent paused doesn't count.
+pub async fn watchdog(heartbeat: Arc<Heartbeat>, pause: Pause, engine: Engine, timeout: Duration) {
+    let mut ticker = tokio::time::interval(timeout / 4);
+    let mut warned = false;
+    loop {
+        ticker.tick().await;
+        if pause.is_paused() {
+            heartbeat.beat();
+            continue;
+        }
+        let silence = heartbeat.since_last();
+        if silence >= timeout {
+            warn!(
+                ?silence,
+                ?timeout,
+                "guest missed its heartbeat deadline; interrupting it"
+            );
+            heartbeat.tripped.store(true, Ordering::Relaxed);
+            engine.increment_epoch();
+            return;
+        }
+        if silence >= timeout / 2 {
+            if !warned {
+                warn!(?silence, "guest heartbeat is late");
+                warned = true;
+            }
+        } else {
+            if warned {
+                debug!("guest heartbeat recovered");
+            }
+            warned = false;
+        }
+    }
+}
+
+/// Interrupt the guest through epoch interruption once `deadline` passes, however healthy its
+/// heartbeat. Like [`watchdog`], it needs the store's epoch callback; a paused guest is
+/// interrupted too, since the deadline bounds the whole run.
+pub async fn deadline(heartbeat: Arc<Heartbeat>, engine: Engine, deadline: tokio::time::Instant) {
+    tokio::time::sleep_until(deadline).await;
+    warn!(target: "deadline", "guest run reached its deadline; interrupting it");
+    heartbeat.expired.store(true, Ordering::Relaxed);
+    engine.increment_epoch();
+}
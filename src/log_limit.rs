@@ -0,0 +1,101 @@
+//! Rate limiting for high-volume logs (`--log-rate`, `--log-sample`).
+//!
+//! A stress run makes tens of thousands of calls, and a guest may write a line for each; logging
+//! all of them buries everything else. A [`LogLimit`] lets through at most `per_second` messages
+//! from one source each second. Past that it keeps one message in every `sample`, if sampling is
+//! on, and drops the rest. The next message let through is preceded by a summary of how many were
+//! dropped, under the `log_limit` target, and so is the end of the source.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::config::HostConfig;
+
+/// How much of a source is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRate {
+    pub per_second: u32,
+    /// Past the rate, log one message in this many; 0 logs none.
+    pub sample: u32,
+}
+
+impl LogRate {
+    /// The rate `config` asks for, if any.
+    pub fn from_config(config: &HostConfig) -> Option<Self> {
+        config.log_rate.map(|per_second| Self {
+            per_second,
+            sample: config.log_sample,
+        })
+    }
+}
+
+/// Limits the messages of one source to a [`LogRate`]; with no rate, admits everything.
+pub struct LogLimit {
+    source: &'static str,
+    rate: Option<LogRate>,
+    window_start: Cell<Option<Instant>>,
+    logged: Cell<u32>,
+    over: Cell<u32>,
+    suppressed: Cell<u64>,
+}
+
+impl LogLimit {
+    pub fn new(source: &'static str, rate: Option<LogRate>) -> Self {
+        Self {
+            source,
+            rate,
+            window_start: Cell::new(None),
+            logged: Cell::new(0),
+            over: Cell::new(0),
+            suppressed: Cell::new(0),
+        }
+    }
+
+    /// Whether to log one more message. Summarizes the messages dropped before it if it is.
+    pub fn admit(&self) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        let now = Instant::now();
+        if self
+            .window_start
+            .get()
+            .is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1))
+        {
+            self.window_start.set(Some(now));
+            self.logged.set(0);
+            self.over.set(0);
+        }
+        if self.logged.get() < rate.per_second {
+            self.logged.set(self.logged.get() + 1);
+        } else {
+            self.over.set(self.over.get() + 1);
+            if rate.sample == 0 || self.over.get() % rate.sample != 0 {
+                self.suppressed.set(self.suppressed.get() + 1);
+                return false;
+            }
+        }
+        self.summarize();
+        true
+    }
+
+    fn summarize(&self) {
+        let suppressed = self.suppressed.replace(0);
+        if suppressed > 0 {
+            info!(
+                target: "log_limit",
+                source = self.source,
+                suppressed,
+                "{suppressed} messages suppressed"
+            );
+        }
+    }
+}
+
+impl Drop for LogLimit {
+    fn drop(&mut self) {
+        self.summarize();
+    }
+}
@@ -0,0 +1,67 @@
+//! The host's own log, kept for the guest to follow (`--log-tail`).
+//!
+//! With `--log-tail <RECORDS>`, a tracing layer copies every event that passes the log filter
+//! into a [`LogBook`] of that many records, and each guest's `EchoerProvider.logTail` serves a
+//! `LogTail` capability over it (see `cap::logtail`). The host also sets [`LOG_TAIL_ENV`], which
+//! asks the example guest to follow the log while it runs its workload.
+
+use std::fmt::{self, Write};
+use std::sync::OnceLock;
+
+use cap::logtail::LogBook;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+/// Environment variable asking the guest to follow the host's log.
+pub const LOG_TAIL_ENV: &str = "WETWARE_LOG_TAIL";
+
+static BOOK: OnceLock<LogBook> = OnceLock::new();
+
+/// The book the layer writes to, if `--log-tail` is on.
+pub fn book() -> Option<LogBook> {
+    BOOK.get().cloned()
+}
+
+/// A layer keeping the last `capacity` events; `None` leaves the log untouched.
+pub fn layer<S: Subscriber>(capacity: Option<usize>) -> Option<impl Layer<S>> {
+    let capacity = capacity?;
+    let book = BOOK.get_or_init(|| LogBook::new(capacity)).clone();
+    Some(BookLayer::new(book))
+}
+
+/// A layer appending every event it sees to a book.
+pub struct BookLayer {
+    book: LogBook,
+}
+
+impl BookLayer {
+    pub fn new(book: LogBook) -> Self {
+        Self { book }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for BookLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        self.book
+            .append(metadata.level().as_str(), metadata.target(), message.0);
+    }
+}
+
+/// The event's message followed by its other fields, as `fmt` prints them.
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.0);
+            write!(self.0, "{value:?}{fields}").unwrap();
+        } else {
+            write!(self.0, " {}={value:?}", field.name()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Moving a running guest to a fresh instance (`GuestControl.migrate`).
+//!
+//! The host doesn't copy a guest's memory: a component's linear memories aren't reachable through
+//! wasmtime's component API, and its WASI handles and its half of the Cap'n Proto connection
+//! would be left behind in the old store anyway. The guest moves itself instead. An operator's
+//! `GuestControl.migrate()` marks the run. A guest watching `Migration.requested()` (from
+//! `EchoerProvider.migration()`) stops, hands its state to the host as a `Checkpoint`, with the
+//! capabilities it holds as sturdy refs, and exits. The runner then starts the next generation: a
+//! fresh instance of the `--wasm` module, read and compiled again so that an updated file takes
+//! effect, with its own connection and provider. The successor picks up the checkpoint with
+//! `Migration.resume()` and restores its capabilities from the refs. Sturdy refs name provider
+//! slots, so they resolve on the new provider as on the old one.
+//!
+//! `migrate()` returns once the guest has handed off. A guest that never watches for the request
+//! doesn't, so operators bound the call.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use cap::echo_capnp::{checkpoint, migration};
+use cap::trace;
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use tokio::sync::watch;
+use tracing::info;
+
+/// A guest's own state, as it handed it off.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    pub state: Vec<u8>,
+    pub refs: Vec<Vec<u8>>,
+}
+
+impl Checkpoint {
+    fn read(reader: checkpoint::Reader) -> capnp::Result<Self> {
+        let refs = reader.get_refs()?;
+        Ok(Self {
+            state: reader.get_state()?.to_vec(),
+            refs: refs
+                .iter()
+                .map(|r| r.map(<[u8]>::to_vec))
+                .collect::<capnp::Result<_>>()?,
+        })
+    }
+
+    fn write(&self, mut builder: checkpoint::Builder) {
+        builder.set_state(&self.state[..]);
+        let mut refs = builder.init_refs(self.refs.len() as u32);
+        for (i, r) in self.refs.iter().enumerate() {
+            refs.set(i as u32, &r[..]);
+        }
+    }
+}
+
+/// One run's migrations, shared by the control server, the providers and the runner.
+#[derive(Clone, Default)]
+pub struct Migration {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Whether the current generation has been asked to move.
+    requested: watch::Sender<bool>,
+    // Hand-offs so far, for `migrate()` to wait on.
+    handoffs: watch::Sender<u32>,
+    // What the current generation handed off, until the runner starts its successor.
+    handed_off: Mutex<Option<Checkpoint>>,
+    // What the previous generation handed off, for the current one to resume from.
+    resume: Mutex<Option<Checkpoint>>,
+    generation: AtomicU32,
+}
+
+impl Migration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The instance running now: 0 for the first, one more for each successor.
+    pub fn generation(&self) -> u32 {
+        self.inner.generation.load(Ordering::Relaxed)
+    }
+
+    /// Ask the guest to move, and wait until it has handed off. Returns its successor's
+    /// generation.
+    pub async fn migrate(&self) -> u32 {
+        let mut handoffs = self.inner.handoffs.subscribe();
+        let seen = *handoffs.borrow_and_update();
+        let generation = self.generation();
+        if !self.inner.requested.send_replace(true) {
+            info!(target: "migrate", generation, "asking the guest to move");
+        }
+        // The sender lives as long as `self`.
+        let _ = handoffs.wait_for(|n| *n > seen).await;
+        generation + 1
+    }
+
+    /// A `Migration` capability for the current generation's guest.
+    pub fn client(&self) -> migration::Client {
+        capnp_rpc::new_client(MigrationServer {
+            migration: self.clone(),
+        })
+    }
+
+    fn hand_off(&self, checkpoint: Checkpoint) {
+        info!(
+            target: "migrate",
+            generation = self.generation(),
+            state = checkpoint.state.len(),
+            refs = checkpoint.refs.len(),
+            "guest handed off"
+        );
+        *self.inner.handed_off.lock().unwrap() = Some(checkpoint);
+        self.inner.handoffs.send_modify(|n| *n += 1);
+    }
+
+    /// Once the current generation has exited: if it handed off, make its checkpoint the one the
+    /// next generation resumes from, and return true.
+    pub fn next_generation(&self) -> bool {
+        let Some(checkpoint) = self.inner.handed_off.lock().unwrap().take() else {
+            return false;
+        };
+        *self.inner.resume.lock().unwrap() = Some(checkpoint);
+        self.inner.requested.send_replace(false);
+        let generation = self.inner.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(target: "migrate", generation, "starting the guest's successor");
+        true
+    }
+}
+
+struct MigrationServer {
+    migration: Migration,
+}
+
+impl migration::Server for MigrationServer {
+    fn requested(
+        &mut self,
+        _params: migration::RequestedParams,
+        _results: migration::RequestedResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut requested = self.migration.inner.requested.subscribe();
+        trace::promise(async move {
+            // The sender lives as long as the migration.
+            let _ = requested.wait_for(|requested| *requested).await;
+            Ok(())
+        })
+    }
+
+    fn hand_off(
+        &mut self,
+        params: migration::HandOffParams,
+        _results: migration::HandOffResults,
+    ) -> Promise<(), capnp::Error> {
+        let checkpoint = pry!(Checkpoint::read(pry!(pry!(params.get()).get_checkpoint())));
+        self.migration.hand_off(checkpoint);
+        Promise::ok(())
+    }
+
+    fn resume(
+        &mut self,
+        _params: migration::ResumeParams,
+        mut results: migration::ResumeResults,
+    ) -> Promise<(), capnp::Error> {
+        if let Some(checkpoint) = &*self.migration.inner.resume.lock().unwrap() {
+            checkpoint.write(results.get().init_checkpoint());
+            results.get().set_resumed(true);
+        }
+        Promise::ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            state: vec![7, 0, 0, 0],
+            refs: vec![vec![3, 0, 0, 0]],
+        }
+    }
+
+    #[tokio::test]
+    async fn migrate_returns_once_the_guest_has_handed_off() {
+        let migration = Migration::new();
+        let asked = tokio::spawn({
+            let migration = migration.clone();
+            async move { migration.migrate().await }
+        });
+        // The guest sees the request, then hands off.
+        let mut requested = migration.inner.requested.subscribe();
+        requested.wait_for(|requested| *requested).await.unwrap();
+        assert!(!asked.is_finished());
+        migration.hand_off(checkpoint());
+        let successor = tokio::time::timeout(Duration::from_secs(5), asked)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(successor, 1);
+    }
+
+    #[test]
+    fn the_successor_resumes_from_the_checkpoint() {
+        let migration = Migration::new();
+        assert!(!migration.next_generation());
+        assert_eq!(migration.generation(), 0);
+
+        migration.inner.requested.send_replace(true);
+        migration.hand_off(checkpoint());
+        assert!(migration.next_generation());
+        assert_eq!(migration.generation(), 1);
+        assert_eq!(*migration.inner.resume.lock().unwrap(), Some(checkpoint()));
+        // The successor hasn't been asked to move yet.
+        assert!(!*migration.inner.requested.borrow());
+        // Nor has it handed off.
+        assert!(!migration.next_generation());
+    }
+}
@@ -0,0 +1,485 @@
+//! An in-process message channel in place of the RPC pipes (`--message-channel`).
+//!
+//! By default the guest's transport streams write into one bounded byte pipe and read from
+//! another, and the provider's `VatNetwork` works the other ends. Every byte is copied into a
+//! pipe and out of it again. With the message channel, the guest's streams are adapters over two
+//! bounded channels of whole Cap'n Proto messages instead. The guest's output stream cuts the
+//! `Bytes` it is given into messages by their segment tables and sends them on without copying
+//! them; the guest's input stream hands out the provider's messages the same way. The provider's
+//! side reads and writes the channels as byte streams, so the rest of the transport stack stays as
+//! it is. Each direction saves a copy, and every message is seen whole: its size and the time it
+//! waited in the channel are traced under the `msg_channel` target, and summed up when the
+//! connection ends.
+//!
+//! Only guests that take their transport from `wetware:guest/transport` can use it. Their WASI
+//! stdin and stdout are left unconnected, and the multiplexed transport, whose frames aren't
+//! Cap'n Proto messages, can't run over it.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{self, error::TryRecvError, error::TrySendError};
+use tokio_util::sync::PollSender;
+use tracing::{info, trace};
+use wasmtime_wasi_io::poll::Pollable;
+use wasmtime_wasi_io::streams::{InputStream, OutputStream, StreamError, StreamResult};
+
+// Bytes the guest may write before it has to wait, when the channel has room.
+const WRITE_WINDOW: usize = 64 * 1024;
+
+/// A serialized message on its way through the channel.
+struct Message {
+    bytes: Bytes,
+    sent: Instant,
+}
+
+impl Message {
+    fn new(bytes: Bytes) -> Self {
+        Self {
+            bytes,
+            sent: Instant::now(),
+        }
+    }
+}
+
+/// Counts for one direction of the channel.
+#[derive(Default)]
+pub struct ChannelStats {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    wait_ns: AtomicU64,
+    max_wait_ns: AtomicU64,
+}
+
+impl ChannelStats {
+    fn received(&self, direction: &'static str, message: &Message) {
+        let waited = message.sent.elapsed().as_nanos() as u64;
+        trace!(
+            target: "msg_channel",
+            direction,
+            bytes = message.bytes.len(),
+            waited_us = waited / 1_000,
+            "message"
+        );
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(message.bytes.len() as u64, Ordering::Relaxed);
+        self.wait_ns.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_ns.fetch_max(waited, Ordering::Relaxed);
+    }
+
+    /// Log the totals for `direction`.
+    pub fn log_summary(&self, direction: &'static str) {
+        let messages = self.messages.load(Ordering::Relaxed);
+        let wait_ns = self.wait_ns.load(Ordering::Relaxed);
+        info!(
+            target: "msg_channel",
+            direction,
+            messages,
+            bytes = self.bytes.load(Ordering::Relaxed),
+            mean_wait_us = wait_ns / messages.max(1) / 1_000,
+            max_wait_us = self.max_wait_ns.load(Ordering::Relaxed) / 1_000,
+            "message channel totals"
+        );
+    }
+}
+
+/// Both directions of a connection: the guest's transport streams and the provider's ends.
+pub struct Channel {
+    pub guest_input: GuestInput,
+    pub guest_output: GuestOutput,
+    pub provider_reader: ProviderReader,
+    pub provider_writer: ProviderWriter,
+    /// Guest-to-host and host-to-guest counts.
+    pub upstream: Arc<ChannelStats>,
+    pub downstream: Arc<ChannelStats>,
+}
+
+/// A channel holding up to `capacity` messages each way.
+pub fn channel(capacity: usize) -> Channel {
+    let (up_tx, up_rx) = mpsc::channel(capacity);
+    let (down_tx, down_rx) = mpsc::channel(capacity);
+    let upstream = Arc::new(ChannelStats::default());
+    let downstream = Arc::new(ChannelStats::default());
+    Channel {
+        guest_input: GuestInput {
+            rx: down_rx,
+            current: Bytes::new(),
+            closed: false,
+            stats: downstream.clone(),
+        },
+        guest_output: GuestOutput {
+            tx: Some(up_tx),
+            framer: Framer::default(),
+            pending: VecDeque::new(),
+        },
+        provider_reader: ProviderReader {
+            rx: up_rx,
+            current: Bytes::new(),
+            stats: upstream.clone(),
+        },
+        provider_writer: ProviderWriter {
+            tx: PollSender::new(down_tx),
+            framer: Framer::default(),
+            pending: VecDeque::new(),
+        },
+        upstream,
+        downstream,
+    }
+}
+
+/// Cuts a byte stream into the Cap'n Proto messages it carries.
+#[derive(Default)]
+struct Framer {
+    // The start of a message not yet complete.
+    partial: BytesMut,
+}
+
+impl Framer {
+    /// Add `bytes` to the stream and append every message completed by them to `out`. Whole
+    /// messages are split off `bytes` without copying; only an incomplete tail is copied.
+    fn push(&mut self, mut bytes: Bytes, out: &mut VecDeque<Bytes>) {
+        if !self.partial.is_empty() {
+            return self.copy(&bytes, out);
+        }
+        while let Some(len) = frame_len(&bytes) {
+            out.push_back(bytes.split_to(len));
+        }
+        self.partial.extend_from_slice(&bytes);
+    }
+
+    /// Add borrowed bytes to the stream, copying them once.
+    fn copy(&mut self, bytes: &[u8], out: &mut VecDeque<Bytes>) {
+        self.partial.extend_from_slice(bytes);
+        while let Some(len) = frame_len(&self.partial) {
+            out.push_back(self.partial.split_to(len).freeze());
+        }
+    }
+}
+
+/// The length of the message at the start of `buf`, if all of it is there.
+fn frame_len(buf: &[u8]) -> Option<usize> {
+    let word = |i: usize| -> Option<usize> {
+        let bytes = buf.get(i * 4..i * 4 + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    let segments = word(0)?.checked_add(1)?;
+    // The segment count and sizes, padded to a whole word.
+    let header = (4 + 4 * segments).next_multiple_of(8);
+    let mut len = header;
+    for i in 0..segments {
+        len = len.checked_add(word(i + 1)?.checked_mul(8)?)?;
+    }
+    (buf.len() >= len).then_some(len)
+}
+
+/// The guest's RPC output stream.
+pub struct GuestOutput {
+    // Dropped when the guest closes the stream, so the provider reads EOF.
+    tx: Option<mpsc::Sender<Message>>,
+    framer: Framer,
+    // Complete messages the channel had no room for yet.
+    pending: VecDeque<Bytes>,
+}
+
+impl GuestOutput {
+    /// Send pending messages while the channel has room.
+    fn try_send(&mut self) -> StreamResult<()> {
+        let Some(tx) = &self.tx else {
+            return Err(StreamError::Closed);
+        };
+        while let Some(bytes) = self.pending.pop_front() {
+            match tx.try_send(Message::new(bytes)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(message)) => {
+                    self.pending.push_front(message.bytes);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => return Err(StreamError::Closed),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for GuestOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        if self.tx.is_none() {
+            return Err(StreamError::Closed);
+        }
+        self.framer.push(bytes, &mut self.pending);
+        self.try_send()
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        // Complete messages are already on their way; a partial one goes when it is complete.
+        self.try_send()
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        self.try_send()?;
+        Ok(if self.pending.is_empty() {
+            WRITE_WINDOW
+        } else {
+            0
+        })
+    }
+
+    async fn cancel(&mut self) {
+        self.ready().await;
+        self.tx = None;
+    }
+}
+
+#[async_trait::async_trait]
+impl Pollable for GuestOutput {
+    async fn ready(&mut self) {
+        while let Some(tx) = &self.tx
+            && !self.pending.is_empty()
+        {
+            let Ok(permit) = tx.reserve().await else {
+                return;
+            };
+            if let Some(bytes) = self.pending.pop_front() {
+                permit.send(Message::new(bytes));
+            }
+        }
+    }
+}
+
+/// The guest's RPC input stream.
+pub struct GuestInput {
+    rx: mpsc::Receiver<Message>,
+    // What is left of the message being read.
+    current: Bytes,
+    closed: bool,
+    stats: Arc<ChannelStats>,
+}
+
+impl GuestInput {
+    fn take(&mut self, message: Message) {
+        self.stats.received("host_to_guest", &message);
+        self.current = message.bytes;
+    }
+}
+
+#[async_trait::async_trait]
+impl InputStream for GuestInput {
+    fn read(&mut self, size: usize) -> StreamResult<Bytes> {
+        if self.current.is_empty() {
+            match self.rx.try_recv() {
+                Ok(message) => self.take(message),
+                Err(TryRecvError::Empty) if !self.closed => return Ok(Bytes::new()),
+                Err(_) => return Err(StreamError::Closed),
+            }
+        }
+        let len = size.min(self.current.len());
+        Ok(self.current.split_to(len))
+    }
+}
+
+#[async_trait::async_trait]
+impl Pollable for GuestInput {
+    async fn ready(&mut self) {
+        if !self.current.is_empty() || self.closed {
+            return;
+        }
+        match self.rx.recv().await {
+            Some(message) => self.take(message),
+            None => self.closed = true,
+        }
+    }
+}
+
+/// The provider's end of the guest-to-host direction.
+pub struct ProviderReader {
+    rx: mpsc::Receiver<Message>,
+    current: Bytes,
+    stats: Arc<ChannelStats>,
+}
+
+impl AsyncRead for ProviderReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.current.is_empty() {
+            // A closed channel leaves `buf` untouched: end of stream.
+            let Some(message) = ready!(this.rx.poll_recv(cx)) else {
+                return Poll::Ready(Ok(()));
+            };
+            this.stats.received("guest_to_host", &message);
+            this.current = message.bytes;
+        }
+        let len = buf.remaining().min(this.current.len());
+        buf.put_slice(&this.current[..len]);
+        this.current.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The provider's end of the host-to-guest direction.
+pub struct ProviderWriter {
+    tx: PollSender<Message>,
+    framer: Framer,
+    pending: VecDeque<Bytes>,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "message channel closed")
+}
+
+impl ProviderWriter {
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            ready!(self.tx.poll_reserve(cx)).map_err(|_| closed())?;
+            if let Some(bytes) = self.pending.pop_front() {
+                self.tx
+                    .send_item(Message::new(bytes))
+                    .map_err(|_| closed())?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ProviderWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_send(cx))?;
+        // The one copy on this side: the connection's writes are borrowed.
+        this.framer.copy(buf, &mut this.pending);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_send(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send(cx))?;
+        self.tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    // A single-segment message of `words` words, each byte of its body `fill`.
+    fn message(words: u32, fill: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&words.to_le_bytes());
+        bytes.resize(8 + 8 * words as usize, fill);
+        bytes
+    }
+
+    #[test]
+    fn frames_are_cut_at_message_boundaries() {
+        let first = message(2, 1);
+        assert_eq!(frame_len(&first), Some(24));
+        assert_eq!(frame_len(&first[..23]), None);
+        assert_eq!(frame_len(&first[..3]), None);
+        // Two segments: the header is padded to a whole word.
+        let mut two = Vec::new();
+        for word in [1u32, 1, 2, 0] {
+            two.extend_from_slice(&word.to_le_bytes());
+        }
+        two.resize(16 + 24, 0);
+        assert_eq!(frame_len(&two), Some(40));
+    }
+
+    #[tokio::test]
+    async fn guest_messages_reach_the_provider_whole() {
+        let Channel {
+            guest_output: mut output,
+            provider_reader: mut reader,
+            upstream,
+            ..
+        } = channel(4);
+        let (first, second) = (message(1, 1), message(3, 2));
+        let stream = [first.clone(), second.clone()].concat();
+        // Cut mid-way through the second message.
+        let (head, tail) = stream.split_at(first.len() + 5);
+        output.write(Bytes::copy_from_slice(head)).unwrap();
+        output.write(Bytes::copy_from_slice(tail)).unwrap();
+        output.flush().unwrap();
+        drop(output);
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, stream);
+        assert_eq!(upstream.messages.load(Ordering::Relaxed), 2);
+        assert_eq!(upstream.bytes.load(Ordering::Relaxed), stream.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn provider_messages_reach_the_guest_whole() {
+        let Channel {
+            guest_input: mut input,
+            provider_writer: mut writer,
+            ..
+        } = channel(4);
+        let (first, second) = (message(1, 1), message(3, 2));
+        writer.write_all(&first[..5]).await.unwrap();
+        writer.write_all(&first[5..]).await.unwrap();
+        writer.write_all(&second).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        for expected in [first, second] {
+            input.ready().await;
+            assert_eq!(input.read(usize::MAX).unwrap(), expected);
+        }
+        // The provider has closed its end.
+        input.ready().await;
+        assert!(matches!(input.read(1), Err(StreamError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn closing_the_guest_input_fails_the_provider_writes() {
+        let Channel {
+            guest_input,
+            provider_writer: mut writer,
+            ..
+        } = channel(4);
+        drop(guest_input);
+        let err = writer.write_all(&message(1, 1)).await;
+        let err = match err {
+            Ok(()) => writer.flush().await.unwrap_err(),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn closing_the_provider_reader_fails_the_guest_writes() {
+        let Channel {
+            guest_output: mut output,
+            provider_reader,
+            ..
+        } = channel(4);
+        drop(provider_reader);
+        assert!(matches!(
+            output.write(Bytes::from(message(1, 1))),
+            Err(StreamError::Closed)
+        ));
+    }
+}
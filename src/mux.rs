@@ -0,0 +1,550 @@
+//! Logical channels multiplexed over the RPC pipes (`--mux`).
+//!
+//! Without it the pipes carry one Cap'n Proto byte stream, so a large message holds up every
+//! call queued behind it. With `--mux`, each direction carries frames instead. A frame is a
+//! channel ID, a kind and a length, followed by that many bytes. Each channel is its own byte
+//! stream with its own flow control. A sender may only have [`WINDOW`] unread bytes outstanding
+//! per channel, and the receiver hands credit back as its reader consumes them. A channel that
+//! falls behind stops only its own sender. Frames are at most [`MAX_FRAME`] bytes.
+//!
+//! Each channel is a priority lane. The writer always sends a frame from the highest-priority
+//! channel with data queued, and channels of equal priority take turns. So control traffic waits
+//! behind at most one bulk frame, however much bulk data is queued, plus whatever already sits in
+//! the pipe (`--pipe-buffer`). Each side schedules its own writes, and both use the same priority
+//! for each channel ([`priority`]).
+//!
+//! Channel [`RPC`] carries the main Cap'n Proto connection. [`CONTROL`] carries a second
+//! connection to the same bootstrap capability at high priority, for calls that must not queue
+//! behind the main one. [`BULK`] is the low-priority lane for large transfers. The guest SDK's
+//! `mux` module speaks the same format, and the host tells guests to use it by setting
+//! [`MUX_ENV`]. When the mux shuts down, the host logs each channel's longest queueing delay
+//! under the `mux` target. That is how long a frame waited for the pipe, which bounds what bulk
+//! traffic added to a control call.
+//!
+//! Channels exist only once the host has opened them: the three above from the start, others when
+//! [`Mux::channel`] asks for them. A frame on any other channel, like a data frame past the window,
+//! fails the connection, and credit never adds up past [`WINDOW`]. So the guest can't make the host
+//! keep state it didn't ask for.
+//!
+//! ```text
+//! frame = channel:u16le kind:u8 len:u32le payload[len]
+//! kind  = 0 data | 1 credit (payload: u32le bytes) | 2 close
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{Instrument, debug, info, warn};
+
+/// Environment variable telling the guest that its transport is multiplexed.
+pub const MUX_ENV: &str = "WETWARE_MUX";
+
+/// The Cap'n Proto connection.
+pub const RPC: u16 = 0;
+/// A second connection for control and health calls.
+pub const CONTROL: u16 = 1;
+/// Bulk data.
+pub const BULK: u16 = 2;
+
+/// Scheduling priority of a channel's outgoing frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+/// The priority of channel `id`; keep in sync with the guest SDK.
+pub fn priority(id: u16) -> Priority {
+    match id {
+        CONTROL => Priority::High,
+        BULK => Priority::Low,
+        _ => Priority::Normal,
+    }
+}
+
+/// Unread bytes a sender may have outstanding on one channel.
+pub const WINDOW: usize = 256 * 1024;
+/// Largest payload in one frame.
+pub const MAX_FRAME: usize = 16 * 1024;
+
+const HEADER_LEN: usize = 7;
+const DATA: u8 = 0;
+const CREDIT: u8 = 1;
+const CLOSE: u8 = 2;
+
+fn frame(channel: u16, kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&channel.to_le_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+struct Channel {
+    priority: Priority,
+    inbound: VecDeque<u8>,
+    // Bytes read by the application and not yet credited back to the sender.
+    unacked: usize,
+    // Bytes we may still send.
+    credit: usize,
+    // Frames waiting for the pipe, with when they were queued.
+    outbound: VecDeque<(Instant, Vec<u8>)>,
+    frames_sent: u64,
+    max_delay: Duration,
+    remote_closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Channel {
+    fn new(priority: Priority) -> Self {
+        Self {
+            priority,
+            inbound: VecDeque::new(),
+            unacked: 0,
+            credit: WINDOW,
+            outbound: VecDeque::new(),
+            frames_sent: 0,
+            max_delay: Duration::ZERO,
+            remote_closed: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn queue(&mut self, frame: Vec<u8>) {
+        self.outbound.push_back((Instant::now(), frame));
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    channels: BTreeMap<u16, Channel>,
+    // Credit and close frames; they go out ahead of data.
+    urgent: VecDeque<Vec<u8>>,
+    // The channel whose data went out last, for round-robin within a priority.
+    last_sent: Option<u16>,
+    // Handles (the `Mux` and its streams) still alive; the writer shuts down at zero.
+    handles: usize,
+    // Set once the underlying reader has ended.
+    ended: Option<io::ErrorKind>,
+    writer_waker: Option<Waker>,
+}
+
+impl Shared {
+    /// Open channel `id` on this side, if it isn't open yet.
+    fn open(&mut self, id: u16) {
+        self.channels
+            .entry(id)
+            .or_insert_with(|| Channel::new(priority(id)));
+    }
+
+    /// Channel `id`, which a stream or the writer knows to be open.
+    fn channel(&mut self, id: u16) -> &mut Channel {
+        self.channels.get_mut(&id).expect("mux channel not open")
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// The next frame to write: urgent frames first, then data from the highest-priority
+    /// channels with any, in turn.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.urgent.pop_front() {
+            return Some(frame);
+        }
+        let top = self
+            .channels
+            .values()
+            .filter(|ch| !ch.outbound.is_empty())
+            .map(|ch| ch.priority)
+            .max()?;
+        let ready: Vec<u16> = self
+            .channels
+            .iter()
+            .filter(|(_, ch)| ch.priority == top && !ch.outbound.is_empty())
+            .map(|(id, _)| *id)
+            .collect();
+        let after = self.last_sent;
+        let next = *ready
+            .iter()
+            .find(|id| after.is_none_or(|last| **id > last))
+            .or(ready.first())?;
+        self.last_sent = Some(next);
+        let ch = self.channel(next);
+        let (queued, frame) = ch.outbound.pop_front()?;
+        ch.frames_sent += 1;
+        ch.max_delay = ch.max_delay.max(queued.elapsed());
+        Some(frame)
+    }
+
+    fn report(&self) {
+        for (channel, ch) in &self.channels {
+            info!(
+                target: "mux",
+                channel,
+                priority = ?ch.priority,
+                frames_sent = ch.frames_sent,
+                max_queue_delay = ?ch.max_delay,
+                "mux channel"
+            );
+        }
+    }
+}
+
+/// A multiplexed connection over one reader/writer pair.
+pub struct Mux {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Mux {
+    /// Start multiplexing over `reader` and `writer`. Must be called inside a Tokio runtime: the
+    /// frame reader and writer run as tasks on it.
+    pub fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut state = Shared {
+            handles: 1,
+            ..Shared::default()
+        };
+        for id in [RPC, CONTROL, BULK] {
+            state.open(id);
+        }
+        let shared = Arc::new(Mutex::new(state));
+        tokio::spawn(read_frames(reader, shared.clone()).in_current_span());
+        tokio::spawn(write_frames(writer, shared.clone()).in_current_span());
+        Self { shared }
+    }
+
+    /// The stream for channel `id`.
+    pub fn channel(&self, id: u16) -> MuxStream {
+        let mut shared = self.shared.lock().unwrap();
+        shared.open(id);
+        shared.handles += 1;
+        MuxStream {
+            id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        release(&self.shared);
+    }
+}
+
+fn release(shared: &Mutex<Shared>) {
+    let mut shared = shared.lock().unwrap();
+    shared.handles -= 1;
+    shared.wake_writer();
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(mut reader: R, shared: Arc<Mutex<Shared>>) {
+    let result: io::Result<()> = async {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            match reader.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let id = u16::from_le_bytes([header[0], header[1]]);
+            let kind = header[2];
+            let len = u32::from_le_bytes([header[3], header[4], header[5], header[6]]) as usize;
+            if len > MAX_FRAME {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("mux frame of {len} bytes on channel {id}"),
+                ));
+            }
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload).await?;
+            let mut shared = shared.lock().unwrap();
+            let Some(ch) = shared.channels.get_mut(&id) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("mux frame on unopened channel {id}"),
+                ));
+            };
+            match kind {
+                DATA => {
+                    if ch.inbound.len() + len > WINDOW {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("mux channel {id} overran its window"),
+                        ));
+                    }
+                    ch.inbound.extend(payload);
+                    if let Some(waker) = ch.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+                CREDIT if len == 4 => {
+                    let credit = u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+                    ch.credit = (ch.credit + credit).min(WINDOW);
+                    if let Some(waker) = ch.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+                CLOSE => {
+                    ch.remote_closed = true;
+                    if let Some(waker) = ch.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("bad mux frame kind {kind} on channel {id}"),
+                    ));
+                }
+            }
+        }
+    }
+    .await;
+    if let Err(e) = &result {
+        warn!(error = %e, "mux reader failed");
+    }
+    // Every channel sees the end of the underlying stream, and writers stop waiting for credit.
+    let mut shared = shared.lock().unwrap();
+    shared.ended = Some(
+        result
+            .err()
+            .map_or(io::ErrorKind::UnexpectedEof, |e| e.kind()),
+    );
+    for ch in shared.channels.values_mut() {
+        ch.remote_closed = true;
+        for waker in [ch.read_waker.take(), ch.write_waker.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(writer: W, shared: Arc<Mutex<Shared>>) {
+    send_frames(writer, &shared).await;
+    shared.lock().unwrap().report();
+}
+
+async fn send_frames<W: AsyncWrite + Unpin>(mut writer: W, shared: &Mutex<Shared>) {
+    loop {
+        let frame = std::future::poll_fn(|cx| {
+            let mut shared = shared.lock().unwrap();
+            match shared.next_frame() {
+                Some(frame) => Poll::Ready(Some(frame)),
+                None if shared.handles == 0 => Poll::Ready(None),
+                None => {
+                    shared.writer_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        let Some(frame) = frame else {
+            break;
+        };
+        if let Err(e) = writer.write_all(&frame).await {
+            debug!(error = %e, "mux writer failed");
+            return;
+        }
+        // Frames are handed over one at a time; flushing each keeps latency low.
+        if writer.flush().await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// One channel of a [`Mux`], as a byte stream.
+pub struct MuxStream {
+    id: u16,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        let ch = shared.channel(self.id);
+        if ch.inbound.is_empty() {
+            if ch.remote_closed {
+                return Poll::Ready(Ok(()));
+            }
+            ch.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = ch.inbound.len().min(buf.remaining());
+        let (front, back) = ch.inbound.as_slices();
+        let from_front = n.min(front.len());
+        buf.put_slice(&front[..from_front]);
+        buf.put_slice(&back[..n - from_front]);
+        ch.inbound.drain(..n);
+        // Hand credit back in chunks rather than per read.
+        ch.unacked += n;
+        if ch.unacked >= WINDOW / 4 || ch.inbound.is_empty() {
+            let credit = std::mem::take(&mut ch.unacked) as u32;
+            shared
+                .urgent
+                .push_back(frame(self.id, CREDIT, &credit.to_le_bytes()));
+            shared.wake_writer();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(kind) = shared.ended {
+            return Poll::Ready(Err(io::Error::new(kind, "mux connection ended")));
+        }
+        let ch = shared.channel(self.id);
+        if ch.credit == 0 {
+            ch.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(ch.credit).min(MAX_FRAME);
+        ch.credit -= n;
+        ch.queue(frame(self.id, DATA, &buf[..n]));
+        shared.wake_writer();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are queued for the writer task as soon as they are written.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        // Behind the channel's own data, so the peer reads everything before EOF.
+        shared.channel(self.id).queue(frame(self.id, CLOSE, &[]));
+        shared.wake_writer();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        release(&self.shared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_on_unopened_channel_fails_the_connection() {
+        let (host, mut guest) = tokio::io::duplex(64 * 1024);
+        let (r, w) = tokio::io::split(host);
+        let mux = Mux::new(r, w);
+        let mut rpc = mux.channel(RPC);
+        guest.write_all(&frame(9, DATA, b"hello")).await.unwrap();
+        let mut buf = Vec::new();
+        rpc.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        let shared = mux.shared.lock().unwrap();
+        assert_eq!(shared.ended, Some(io::ErrorKind::InvalidData));
+        assert!(!shared.channels.contains_key(&9));
+    }
+
+    #[tokio::test]
+    async fn credit_is_clamped_at_the_window() {
+        let (host, mut guest) = tokio::io::duplex(64 * 1024);
+        let (r, w) = tokio::io::split(host);
+        let mux = Mux::new(r, w);
+        let _rpc = mux.channel(RPC);
+        let credit = frame(RPC, CREDIT, &u32::MAX.to_le_bytes());
+        guest.write_all(&credit).await.unwrap();
+        guest.write_all(&credit).await.unwrap();
+        // A close frame after the credit tells us when both have been read.
+        guest.write_all(&frame(RPC, CLOSE, &[])).await.unwrap();
+        loop {
+            if mux.shared.lock().unwrap().channel(RPC).remote_closed {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(mux.shared.lock().unwrap().channel(RPC).credit, WINDOW);
+    }
+
+    // One frame off the raw end of the pipe: channel, kind and payload.
+    async fn read_frame(peer: &mut DuplexStream) -> (u16, u8, Vec<u8>) {
+        let mut header = [0u8; HEADER_LEN];
+        peer.read_exact(&mut header).await.unwrap();
+        let len = u32::from_le_bytes(header[3..].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len];
+        peer.read_exact(&mut payload).await.unwrap();
+        let channel = u16::from_le_bytes([header[0], header[1]]);
+        (channel, header[2], payload)
+    }
+
+    #[tokio::test]
+    async fn control_round_trip_is_bounded_under_bulk_load() {
+        const PIPE: usize = 4096;
+        let (host, mut guest) = tokio::io::duplex(PIPE);
+        let (r, w) = tokio::io::split(host);
+        let mux = Mux::new(r, w);
+        let mut bulk = mux.channel(BULK);
+        let mut control = mux.channel(CONTROL);
+        // A whole window of bulk data is queued at once, and the writer fills the pipe with it.
+        bulk.write_all(&vec![0; WINDOW]).await.unwrap();
+        tokio::task::yield_now().await;
+        control.write_all(b"ping").await.unwrap();
+
+        let mut bulk_ahead = 0;
+        loop {
+            match read_frame(&mut guest).await {
+                (CONTROL, DATA, payload) => {
+                    assert_eq!(payload, b"ping");
+                    break;
+                }
+                (BULK, DATA, payload) => bulk_ahead += payload.len(),
+                (channel, kind, _) => panic!("unexpected frame kind {kind} on channel {channel}"),
+            }
+        }
+        // The call waits behind what was in the pipe and the one bulk frame being written, not
+        // behind the rest of the window.
+        assert!(
+            bulk_ahead <= PIPE + MAX_FRAME,
+            "{bulk_ahead} bulk bytes went out ahead of the control call"
+        );
+
+        let pong = frame(CONTROL, DATA, b"pong");
+        guest.write_all(&pong).await.unwrap();
+        let mut reply = [0u8; 4];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"pong");
+        // The round trip is done with most of the bulk data still queued.
+        let mut shared = mux.shared.lock().unwrap();
+        let queued: usize = (shared.channel(BULK).outbound.iter())
+            .map(|(_, frame)| frame.len())
+            .sum();
+        assert!(queued > WINDOW / 2, "only {queued} bulk bytes left queued");
+    }
+}
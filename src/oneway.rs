@@ -0,0 +1,60 @@
+//! One-way records from the guest, outside RPC.
+//!
+//! With `--mux`, the guest can write `oneway.capnp` `Record`s on the bulk channel: plain Cap'n
+//! Proto messages in stream framing, with no questions, answers or capabilities around them. The
+//! host reads them until the guest closes the channel, logs each under the `oneway` target and
+//! counts them by topic. Records are never acknowledged; the mux channel's credit window is the
+//! only backpressure.
+
+use std::collections::BTreeMap;
+
+use cap::oneway_capnp::record;
+use capnp::message::ReaderOptions;
+use futures::AsyncRead;
+use tracing::{debug, info, warn};
+
+/// What one guest sent over the one-way channel.
+#[derive(Debug, Default, Clone)]
+pub struct OnewayReport {
+    pub records: u64,
+    pub payload_bytes: u64,
+    pub by_topic: BTreeMap<String, u64>,
+}
+
+/// Read records from `reader` until it ends, then log a summary.
+pub async fn ingest<R: AsyncRead + Unpin>(mut reader: R) -> OnewayReport {
+    let mut report = OnewayReport::default();
+    loop {
+        let message =
+            match capnp_futures::serialize::try_read_message(&mut reader, ReaderOptions::new())
+                .await
+            {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!(target: "oneway", error = %e, "failed to read one-way record");
+                    break;
+                }
+            };
+        match message.get_root::<record::Reader>().and_then(|r| {
+            let topic = r.get_topic()?.to_string()?;
+            Ok((topic, r.get_timestamp_ns(), r.get_payload()?.len()))
+        }) {
+            Ok((topic, timestamp_ns, len)) => {
+                debug!(target: "oneway", %topic, timestamp_ns, len, "record");
+                report.records += 1;
+                report.payload_bytes += len as u64;
+                *report.by_topic.entry(topic).or_default() += 1;
+            }
+            Err(e) => warn!(target: "oneway", error = %e, "malformed one-way record"),
+        }
+    }
+    info!(
+        target: "oneway",
+        records = report.records,
+        payload_bytes = report.payload_bytes,
+        by_topic = ?report.by_topic,
+        "one-way channel closed"
+    );
+    report
+}
@@ -0,0 +1,205 @@
+//! Pausing and resuming a guest from the host.
+//!
+//! A paused guest stops at its next epoch check, and its store waits there until it is resumed,
+//! so it burns no CPU while holding on to its instance and connection. [`Pause`] is the host API;
+//! `--control <addr>` additionally serves it to operators as a Cap'n Proto `GuestControl`
+//! capability, for stepping through interleavings or scheduling guests by hand. The same
+//! capability moves the guest to a fresh instance (see `migrate`).
+//!
+//! Epoch checks only happen in the guest's own code. A guest blocked inside a host call, such as a
+//! read from its stdin, isn't stopped until the call returns, and the call itself goes on while
+//! the guest is paused.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use cap::control_capnp::guest_control;
+use capnp::capability::Promise;
+use capnp_rpc::{RpcSystem, rpc_twoparty_capnp, twoparty};
+use tokio::sync::Notify;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tracing::{debug, info, warn};
+use wasmtime::{Engine, Store, UpdateDeadline};
+
+use crate::ComponentRunStates;
+use crate::liveness::Heartbeat;
+use crate::migrate::Migration;
+
+// How often a paused guest checks whether its run has been ended meanwhile; the deadline and the
+// question alarm interrupt it through the engine, which a waiting store doesn't see.
+const PAUSED_TICK: Duration = Duration::from_millis(10);
+
+/// Shared pause switch for one guest.
+#[derive(Clone)]
+pub struct Pause {
+    paused: Arc<AtomicBool>,
+    resumed: Arc<Notify>,
+    engine: Engine,
+}
+
+impl Pause {
+    /// A switch for guests running on `engine`; each guest needs its own engine.
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            paused: Arc::new(AtomicBool::new(false)),
+            resumed: Arc::new(Notify::new()),
+            engine,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            info!(target: "pause", "pausing guest");
+            // Reach the guest's epoch check right away rather than on the next tick.
+            self.engine.increment_epoch();
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            info!(target: "pause", "resuming guest");
+            // A permit is kept if the store isn't waiting yet, so the resume can't be missed.
+            self.resumed.notify_one();
+        }
+    }
+
+    /// Wait until the guest is resumed, or its run has been ended while it was paused.
+    async fn parked(self, heartbeat: Arc<Heartbeat>) {
+        while self.is_paused() && !heartbeat.ended() {
+            let _ = tokio::time::timeout(PAUSED_TICK, self.resumed.notified()).await;
+        }
+    }
+}
+
+/// Install the epoch callback on `store`: trap once the liveness watchdog has tripped or the run's
+/// deadline has passed, hold the store while paused, and otherwise carry on until the next tick.
+pub fn install(store: &mut Store<ComponentRunStates>, pause: Pause) {
+    store.epoch_deadline_callback(move |ctx| {
+        if ctx.data().heartbeat.tripped() {
+            return Err(wasmtime::Error::msg("guest missed its heartbeat deadline"));
+        }
+        if ctx.data().heartbeat.expired() {
+            return Err(wasmtime::Error::msg("guest run exceeded its deadline"));
+        }
+        if ctx.data().heartbeat.starved() {
+            return Err(wasmtime::Error::msg("question alarm went off"));
+        }
+        if pause.is_paused() {
+            let parked = pause.clone().parked(ctx.data().heartbeat.clone());
+            // A deadline of 0 more epochs brings the store straight back here once it's released,
+            // to trap if the run was ended meanwhile.
+            Ok(UpdateDeadline::YieldCustom(0, Box::pin(parked)))
+        } else {
+            Ok(UpdateDeadline::Continue(1))
+        }
+    });
+    store.set_epoch_deadline(1);
+}
+
+/// Serve `GuestControl` for `pause` and `migration` on `addr`, on a dedicated thread for the rest
+/// of the run.
+pub fn serve_control(addr: SocketAddr, pause: Pause, migration: Migration) -> std::io::Result<()> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    info!(%addr, "serving guest control");
+    thread::Builder::new()
+        .name("guest-control".to_string())
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build Tokio runtime for guest control");
+            let local = tokio::task::LocalSet::new();
+            local.block_on(&rt, async move {
+                let listener = match tokio::net::TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        warn!(error = %e, "cannot serve guest control");
+                        return;
+                    }
+                };
+                let control: guest_control::Client =
+                    capnp_rpc::new_client(GuestControl { pause, migration });
+                loop {
+                    let (stream, peer) = match listener.accept().await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            warn!(error = %e, "failed to accept control connection");
+                            continue;
+                        }
+                    };
+                    debug!(%peer, "accepted control connection");
+                    let _ = stream.set_nodelay(true);
+                    let (reader, writer) = tokio::io::split(stream);
+                    let network = twoparty::VatNetwork::new(
+                        reader.compat(),
+                        writer.compat_write(),
+                        rpc_twoparty_capnp::Side::Server,
+                        Default::default(),
+                    );
+                    let rpc_system =
+                        RpcSystem::new(Box::new(network), Some(control.clone().client));
+                    tokio::task::spawn_local(async move {
+                        if let Err(e) = rpc_system.await {
+                            debug!(%peer, error = %e, "control connection ended with error");
+                        }
+                    });
+                }
+            });
+        })?;
+    Ok(())
+}
+
+struct GuestControl {
+    pause: Pause,
+    migration: Migration,
+}
+
+impl guest_control::Server for GuestControl {
+    fn pause(
+        &mut self,
+        _params: guest_control::PauseParams,
+        _results: guest_control::PauseResults,
+    ) -> Promise<(), capnp::Error> {
+        self.pause.pause();
+        Promise::ok(())
+    }
+
+    fn resume(
+        &mut self,
+        _params: guest_control::ResumeParams,
+        _results: guest_control::ResumeResults,
+    ) -> Promise<(), capnp::Error> {
+        self.pause.resume();
+        Promise::ok(())
+    }
+
+    fn status(
+        &mut self,
+        _params: guest_control::StatusParams,
+        mut results: guest_control::StatusResults,
+    ) -> Promise<(), capnp::Error> {
+        results.get().set_paused(self.pause.is_paused());
+        results.get().set_generation(self.migration.generation());
+        Promise::ok(())
+    }
+
+    fn migrate(
+        &mut self,
+        _params: guest_control::MigrateParams,
+        mut results: guest_control::MigrateResults,
+    ) -> Promise<(), capnp::Error> {
+        let migration = self.migration.clone();
+        Promise::from_future(async move {
+            results.get().set_generation(migration.migrate().await);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,194 @@
+//! Queue-depth metering for the transport pipes, and the slow-consumer simulation.
+//!
+//! Each direction of the RPC transport is a bounded pipe: the guest's writes land in one that the
+//! provider drains, and the provider's in one the guest drains. [`PipeMeter`] counts bytes on both
+//! ends of a pipe, so the difference is the bytes buffered in it, and counts how often the writer
+//! found the pipe full and had to wait (a stall: nothing is dropped, the writer is pushed back).
+//! In slow-consumer mode the host pauses before every read, so the queue fills up while the guest
+//! keeps issuing calls and the backpressure path is exercised end to end.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use tracing::{info, trace};
+
+#[derive(Default)]
+pub struct PipeMeter {
+    written: AtomicU64,
+    read: AtomicU64,
+    peak_depth: AtomicU64,
+    write_stalls: AtomicU64,
+}
+
+impl PipeMeter {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Bytes written by the guest and not yet read by the host.
+    pub fn depth(&self) -> u64 {
+        self.written
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.read.load(Ordering::Relaxed))
+    }
+
+    pub fn peak_depth(&self) -> u64 {
+        self.peak_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn write_stalls(&self) -> u64 {
+        self.write_stalls.load(Ordering::Relaxed)
+    }
+
+    fn record_write(&self, bytes: usize) {
+        self.written.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.peak_depth.fetch_max(self.depth(), Ordering::Relaxed);
+    }
+}
+
+/// Log the depth of every pipe in `meters`, by direction, every `interval` until aborted.
+pub async fn report(meters: Vec<(&'static str, Arc<PipeMeter>)>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for (direction, meter) in &meters {
+            info!(
+                target: "pipe",
+                direction,
+                depth = meter.depth(),
+                peak_depth = meter.peak_depth(),
+                write_stalls = meter.write_stalls(),
+                "transport queue"
+            );
+        }
+    }
+}
+
+/// The writing end of a pipe.
+pub struct MeteredWriter<W> {
+    inner: W,
+    meter: Arc<PipeMeter>,
+}
+
+impl<W> MeteredWriter<W> {
+    pub fn new(inner: W, meter: Arc<PipeMeter>) -> Self {
+        Self { inner, meter }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for MeteredWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                trace!(target: "pipe", bytes = n, "pipe write");
+                self.meter.record_write(n);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Pending => {
+                self.meter.write_stalls.fetch_add(1, Ordering::Relaxed);
+                Poll::Pending
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// The reading end of a pipe, optionally pausing `delay` before every read.
+pub struct MeteredReader<R> {
+    inner: R,
+    meter: Arc<PipeMeter>,
+    delay: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> MeteredReader<R> {
+    pub fn new(inner: R, meter: Arc<PipeMeter>, delay: Option<Duration>) -> Self {
+        Self {
+            inner,
+            meter,
+            delay,
+            sleep: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MeteredReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(delay) = this.delay {
+            let sleep = this
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            ready!(sleep.as_mut().poll(cx));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        // The next read pauses again.
+        this.sleep = None;
+        let n = buf.filled().len() - before;
+        trace!(target: "pipe", bytes = n, "pipe read");
+        this.meter.read.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn a_slow_consumer_holds_the_queue_at_the_pipe_bound() {
+        const BOUND: usize = 4096;
+        let meter = PipeMeter::new();
+        let (guest, host) = tokio::io::duplex(BOUND);
+        let mut writer = MeteredWriter::new(guest, meter.clone());
+        let mut reader = MeteredReader::new(host, meter.clone(), Some(Duration::from_millis(1)));
+        let sent: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+
+        let write = async {
+            writer.write_all(&sent).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut received = Vec::new();
+            let mut chunk = [0u8; 1024];
+            loop {
+                match reader.read(&mut chunk).await.unwrap() {
+                    0 => return received,
+                    n => received.extend_from_slice(&chunk[..n]),
+                }
+            }
+        };
+        let ((), received) = tokio::join!(write, read);
+
+        assert_eq!(received, sent);
+        let peak = meter.peak_depth();
+        assert!(peak <= BOUND as u64, "peak depth {peak} past the bound");
+        assert!(meter.write_stalls() > 0);
+        assert_eq!(meter.depth(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+//! Pooling instance allocator settings.
+//!
+//! wasmtime's default on-demand allocator maps fresh memory for every instance. The pooling
+//! allocator reserves fixed-size slots up front and recycles them, which makes instantiation
+//! much cheaper and bounds how many guests an engine can host at once.
+
+use wasmtime::{Config, InstanceAllocationStrategy, PoolingAllocationConfig};
+
+use crate::limits::GuestLimits;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+// Core instances, memories and tables a single guest component may bring along (the component's
+// own modules plus WASI adapter shims).
+const CORE_INSTANCES_PER_COMPONENT: u32 = 16;
+const MEMORIES_PER_COMPONENT: u32 = 4;
+const TABLES_PER_COMPONENT: u32 = 4;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PoolingOptions {
+    /// Use wasmtime's pooling instance allocator instead of allocating on demand.
+    #[arg(long)]
+    pub pooling: bool,
+
+    /// Component instances the pool holds per engine.
+    #[arg(long, default_value_t = 16)]
+    pub pool_max_instances: u32,
+
+    /// Size of each memory slot in 64 KiB Wasm pages; guest memory caps must fit in it. Defaults
+    /// to the guest's memory cap, rounded up to whole pages.
+    #[arg(long)]
+    pub pool_memory_pages: Option<u64>,
+
+    /// Elements per table slot.
+    #[arg(long, default_value_t = 20_000)]
+    pub pool_table_elements: usize,
+}
+
+impl PoolingOptions {
+    /// Switch `config` to the pooling allocator, if enabled, with slots for a guest with `limits`.
+    pub fn apply(&self, config: &mut Config, limits: &GuestLimits) {
+        if !self.pooling {
+            return;
+        }
+        let instances = self.pool_max_instances;
+        let mut pool = PoolingAllocationConfig::new();
+        pool.total_component_instances(instances)
+            .total_core_instances(instances * CORE_INSTANCES_PER_COMPONENT)
+            .total_memories(instances * MEMORIES_PER_COMPONENT)
+            .total_tables(instances * TABLES_PER_COMPONENT)
+            .max_memory_size(self.memory_slot_bytes(limits) as usize)
+            .table_elements(self.pool_table_elements);
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
+    }
+
+    /// Check that a guest with `limits` fits in a pool slot, so misconfiguration is reported up
+    /// front instead of as an instantiation failure.
+    pub fn validate(&self, limits: &GuestLimits) -> Result<(), String> {
+        if !self.pooling {
+            return Ok(());
+        }
+        if self.pool_max_instances == 0 {
+            return Err("--pool-max-instances must be at least 1".to_string());
+        }
+        let slot = self.memory_slot_bytes(limits);
+        if limits.max_memory as u64 > slot {
+            return Err(format!(
+                "guest memory cap of {} bytes exceeds the pool's {slot} byte memory slots \
+                 (raise --pool-memory-pages or lower the cap)",
+                limits.max_memory,
+            ));
+        }
+        Ok(())
+    }
+
+    fn memory_slot_bytes(&self, limits: &GuestLimits) -> u64 {
+        let pages = self
+            .pool_memory_pages
+            .unwrap_or((limits.max_memory as u64).div_ceil(WASM_PAGE_SIZE));
+        pages * WASM_PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::config::HostConfig;
+    use crate::limits::Grants;
+
+    fn limits(config: &HostConfig) -> GuestLimits {
+        GuestLimits {
+            max_memory: config.max_memory,
+            fuel: None,
+            budget: None,
+            grants: Grants::ALL,
+        }
+    }
+
+    #[test]
+    fn default_configuration_validates() {
+        let config = HostConfig::try_parse_from(["host", "--pooling"]).unwrap();
+        assert_eq!(config.pooling.validate(&limits(&config)), Ok(()));
+    }
+
+    #[test]
+    fn slots_default_to_the_memory_cap() {
+        let config =
+            HostConfig::try_parse_from(["host", "--pooling", "--max-memory", "100000"]).unwrap();
+        assert_eq!(
+            config.pooling.memory_slot_bytes(&limits(&config)),
+            2 * WASM_PAGE_SIZE
+        );
+        assert_eq!(config.pooling.validate(&limits(&config)), Ok(()));
+    }
+
+    #[test]
+    fn cap_larger_than_explicit_slots_is_rejected() {
+        let config = HostConfig::try_parse_from([
+            "host",
+            "--pooling",
+            "--pool-memory-pages",
+            "1",
+            "--max-memory",
+            "65537",
+        ])
+        .unwrap();
+        assert!(config.pooling.validate(&limits(&config)).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Directories preopened into the guest's WASI filesystem.
+
+use std::path::PathBuf;
+
+use tracing::info;
+use wasmtime_wasi::{DirPerms, FilePerms, WasiCtxBuilder};
+
+/// One `--dir HOST:GUEST[:ro]` mapping.
+#[derive(Debug, Clone)]
+pub struct Preopen {
+    pub host: PathBuf,
+    pub guest: String,
+    pub read_only: bool,
+}
+
+impl Preopen {
+    /// Parse a `HOST:GUEST[:ro]` command-line value.
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let (rest, read_only) = match arg.strip_suffix(":ro") {
+            Some(rest) => (rest, true),
+            None => (arg, false),
+        };
+        let (host, guest) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected HOST:GUEST[:ro], got {arg:?}"))?;
+        if host.is_empty() || guest.is_empty() {
+            return Err(format!("expected HOST:GUEST[:ro], got {arg:?}"));
+        }
+        Ok(Self {
+            host: PathBuf::from(host),
+            guest: guest.to_string(),
+            read_only,
+        })
+    }
+}
+
+/// Preopen every mapping in `builder`. Fails if a host directory can't be opened.
+pub fn configure(
+    builder: &mut WasiCtxBuilder,
+    preopens: &[Preopen],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for preopen in preopens {
+        let (dir_perms, file_perms) = if preopen.read_only {
+            (DirPerms::READ, FilePerms::READ)
+        } else {
+            (DirPerms::all(), FilePerms::all())
+        };
+        builder
+            .preopened_dir(&preopen.host, &preopen.guest, dir_perms, file_perms)
+            .map_err(|e| format!("cannot preopen {}: {e}", preopen.host.display()))?;
+        info!(
+            host = %preopen.host.display(),
+            guest = %preopen.guest,
+            read_only = preopen.read_only,
+            "preopened directory for guest"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_host_and_guest_paths() {
+        let preopen = Preopen::parse("./out:/out").unwrap();
+        assert_eq!(preopen.host, PathBuf::from("./out"));
+        assert_eq!(preopen.guest, "/out");
+        assert!(!preopen.read_only);
+        // Only the last colon splits, so host paths may contain colons.
+        let preopen = Preopen::parse("C:/data:/data").unwrap();
+        assert_eq!(preopen.host, PathBuf::from("C:/data"));
+        assert_eq!(preopen.guest, "/data");
+    }
+
+    #[test]
+    fn parses_read_only_mappings() {
+        let preopen = Preopen::parse("/srv/in:/in:ro").unwrap();
+        assert_eq!(preopen.host, PathBuf::from("/srv/in"));
+        assert_eq!(preopen.guest, "/in");
+        assert!(preopen.read_only);
+        // Any other suffix is taken as the guest path.
+        let preopen = Preopen::parse("/srv/in:/in:rw").unwrap();
+        assert_eq!(preopen.host, PathBuf::from("/srv/in:/in"));
+        assert_eq!(preopen.guest, "rw");
+        assert!(!preopen.read_only);
+    }
+
+    #[test]
+    fn rejects_a_missing_guest_path() {
+        assert!(Preopen::parse("/srv/in").is_err());
+        assert!(Preopen::parse("/srv/in:").is_err());
+        assert!(Preopen::parse("/srv/in:ro").is_err());
+        assert!(Preopen::parse("/srv/in::ro").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(Preopen::parse("").is_err());
+        assert!(Preopen::parse(":").is_err());
+        assert!(Preopen::parse(":/in").is_err());
+        assert!(Preopen::parse(":ro").is_err());
+    }
+
+    #[test]
+    fn configure_fails_on_a_missing_host_directory() {
+        let missing = std::env::temp_dir().join(format!("no-such-dir-{}", std::process::id()));
+        let preopen = Preopen {
+            host: missing,
+            guest: "/in".to_string(),
+            read_only: true,
+        };
+        let mut builder = WasiCtxBuilder::new();
+        let err = configure(&mut builder, &[preopen]).unwrap_err();
+        assert!(err.to_string().starts_with("cannot preopen"), "{err}");
+        configure(&mut builder, &[Preopen::parse(".:/here").unwrap()]).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+//! Per-phase RPC profiling (`--profile`).
+//!
+//! Host-side phases of every call are timed and summed into a [`Profile`], which is written out
+//! as folded stacks (`phase;subphase microseconds` per line) for `flamegraph.pl`, inferno or
+//! speedscope. The phases are:
+//!
+//! - `transport;read` / `transport;write`: copying bytes off and onto the pipes, and
+//!   `transport;write;blocked` for time spent waiting on a full pipe;
+//! - `rpc;<method>;decode`: reading the parameters and building the inner request;
+//! - `rpc;<method>;server`: the server's own work, including the budget and flow layers;
+//! - `rpc;<method>;encode`: copying the result into the reply, for calls on echoers. The proxy
+//!   the provider's layers are built on copies provider results itself, untimed.
+//!
+//! Serialization inside capnp-rpc and everything on the guest side of the pipes are not visible
+//! from here; they show up as the gaps between these phases.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use cap::trace;
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Time spent per phase, shared by the transport and the server wrappers.
+#[derive(Default)]
+pub struct Profile {
+    phases: Mutex<BTreeMap<Cow<'static, str>, Duration>>,
+}
+
+impl Profile {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    pub fn record(&self, phase: impl Into<Cow<'static, str>>, elapsed: Duration) {
+        *self.phases.lock().unwrap().entry(phase.into()).or_default() += elapsed;
+    }
+
+    /// Write the profile as folded stacks, one `phase microseconds` line per phase.
+    pub fn write_folded(&self, path: &Path) -> io::Result<()> {
+        let folded: String = self
+            .phases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(phase, total)| format!("{phase} {}\n", total.as_micros()))
+            .collect();
+        fs::write(path, folded)
+    }
+}
+
+/// A stream that records how long its reads and writes take; `None` records nothing.
+pub struct Profiled<S> {
+    inner: S,
+    profile: Option<Arc<Profile>>,
+    write_blocked_since: Option<Instant>,
+}
+
+impl<S> Profiled<S> {
+    pub fn new(inner: S, profile: Option<Arc<Profile>>) -> Self {
+        Self {
+            inner,
+            profile,
+            write_blocked_since: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Profiled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(profile) = &this.profile else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        // Waiting for the guest to send something is idle time, not part of any call, so only
+        // reads that complete are timed.
+        let started = Instant::now();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            profile.record("transport;read", started.elapsed());
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Profiled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(profile) = &this.profile else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let started = Instant::now();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        match poll {
+            Poll::Pending => {
+                this.write_blocked_since.get_or_insert(started);
+            }
+            Poll::Ready(_) => {
+                profile.record("transport;write", started.elapsed());
+                if let Some(since) = this.write_blocked_since.take() {
+                    profile.record("transport;write;blocked", since.elapsed());
+                }
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// `EchoerProvider` that times the phases of every call, and hands out profiled echoers.
+pub struct ProfiledEchoerProvider {
+    profile: Arc<Profile>,
+}
+
+impl ProfiledEchoerProvider {
+    pub fn client(
+        inner: echoer_provider::Client,
+        profile: Arc<Profile>,
+    ) -> echoer_provider::Client {
+        layer::provider(inner, Self { profile })
+    }
+}
+
+impl Layer for ProfiledEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let profile = self.profile.clone();
+        trace::promise(async move {
+            let started = Instant::now();
+            let response = call.send().await;
+            let phase = format!("rpc;{};server", layer::short_name(method));
+            profile.record(phase, started.elapsed());
+            response
+        })
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(ProfiledEchoer {
+            inner,
+            profile: self.profile.clone(),
+        })
+    }
+}
+
+struct ProfiledEchoer {
+    inner: echoer::Client,
+    profile: Arc<Profile>,
+}
+
+impl echoer::Server for ProfiledEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let started = Instant::now();
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        self.profile.record("rpc;echo;decode", started.elapsed());
+        let profile = self.profile.clone();
+        trace::promise(async move {
+            let started = Instant::now();
+            let response = request.send().promise.await?;
+            profile.record("rpc;echo;server", started.elapsed());
+            let started = Instant::now();
+            results.get().set_reply(response.get()?.get_reply()?);
+            profile.record("rpc;echo;encode", started.elapsed());
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let started = Instant::now();
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        self.profile
+            .record("rpc;echoBatch;decode", started.elapsed());
+        let profile = self.profile.clone();
+        trace::promise(async move {
+            let started = Instant::now();
+            let response = request.send().promise.await?;
+            profile.record("rpc;echoBatch;server", started.elapsed());
+            let started = Instant::now();
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            profile.record("rpc;echoBatch;encode", started.elapsed());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,89 @@
+//! Guest progress, reported through the `Progress` capability.
+//!
+//! Guests fetch `Progress` from their `EchoerProvider` and report each batch as it starts and
+//! finishes, so the host can follow a guest's workload without parsing its stderr. Calls on
+//! `Progress` itself aren't gated or charged; they are bookkeeping, not work.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use cap::echo_capnp::progress;
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use tracing::info;
+
+/// What a guest reported over its run.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgressReport {
+    pub batches_started: u32,
+    pub batches_finished: u32,
+    pub calls_completed: u64,
+    pub calls_failed: u64,
+}
+
+/// Accumulates one guest's progress reports.
+#[derive(Default)]
+pub struct ProgressTracker {
+    report: Cell<ProgressReport>,
+}
+
+impl ProgressTracker {
+    pub fn new() -> Rc<Self> {
+        Rc::default()
+    }
+
+    pub fn report(&self) -> ProgressReport {
+        self.report.get()
+    }
+
+    /// A `Progress` capability feeding this tracker.
+    pub fn client(self: &Rc<Self>) -> progress::Client {
+        capnp_rpc::new_client(ProgressServer {
+            tracker: self.clone(),
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ProgressReport)) {
+        let mut report = self.report.get();
+        f(&mut report);
+        self.report.set(report);
+    }
+}
+
+struct ProgressServer {
+    tracker: Rc<ProgressTracker>,
+}
+
+impl progress::Server for ProgressServer {
+    fn batch_started(
+        &mut self,
+        params: progress::BatchStartedParams,
+        _results: progress::BatchStartedResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let (batch, calls) = (params.get_batch(), params.get_calls());
+        self.tracker.update(|report| report.batches_started += 1);
+        info!(target: "progress", batch, calls, "guest batch started");
+        Promise::ok(())
+    }
+
+    fn batch_finished(
+        &mut self,
+        params: progress::BatchFinishedParams,
+        _results: progress::BatchFinishedResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let (batch, completed, failed) = (
+            params.get_batch(),
+            params.get_completed(),
+            params.get_failed(),
+        );
+        self.tracker.update(|report| {
+            report.batches_finished += 1;
+            report.calls_completed += u64::from(completed);
+            report.calls_failed += u64::from(failed);
+        });
+        info!(target: "progress", batch, completed, failed, "guest batch finished");
+        Promise::ok(())
+    }
+}
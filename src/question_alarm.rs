@@ -0,0 +1,98 @@
+//! An early warning of reply starvation (`--question-alarm`, `--question-growth`).
+//!
+//! A connection on its way to a deadlock rarely stops all at once. Replies stop coming back while
+//! the guest keeps asking, so its outstanding questions pile up well before the transport wedges.
+//! [`watch`] samples the count that the connection's [`ConnectionMonitor`] mirrors from its
+//! question gate. It warns under the `flow` target when the count passes `--question-alarm`, or
+//! when it has grown without once falling for `--question-growth`. Each warning is given once per
+//! episode: the count has to drop back under the threshold, or drop at all, before it is given
+//! again. With `--question-alarm-abort`, the first warning also ends the run.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::conn_stats::ConnectionMonitor;
+
+// How often the question count is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When to raise the alarm.
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionAlarm {
+    /// Warn when more questions than this are outstanding.
+    pub threshold: Option<usize>,
+    /// Warn when the count has grown without falling for this long.
+    pub growth: Option<Duration>,
+    /// End the run at the first warning.
+    pub abort: bool,
+}
+
+/// An unbroken rise of the question count.
+struct Rise {
+    since: Instant,
+    from: usize,
+    warned: bool,
+}
+
+/// Watch the questions outstanding on `monitor`'s connection. Returns only if `alarm` goes off
+/// and is set to abort; the caller then stops the run.
+pub async fn watch(monitor: Arc<ConnectionMonitor>, alarm: QuestionAlarm) {
+    let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+    let mut last = 0;
+    let mut over = false;
+    let mut rise: Option<Rise> = None;
+    loop {
+        ticker.tick().await;
+        let outstanding = monitor.snapshot().outstanding_questions;
+        let now = Instant::now();
+
+        let mut tripped = false;
+        if let Some(threshold) = alarm.threshold {
+            if outstanding > threshold && !over {
+                warn!(
+                    target: "flow",
+                    outstanding,
+                    threshold,
+                    "outstanding questions passed the alarm threshold; replies may be starved"
+                );
+                tripped = true;
+            } else if outstanding <= threshold && over {
+                debug!(target: "flow", outstanding, "outstanding questions back under the alarm");
+            }
+            over = outstanding > threshold;
+        }
+
+        if outstanding < last {
+            rise = None;
+        } else if outstanding > last && rise.is_none() {
+            rise = Some(Rise {
+                since: now,
+                from: last,
+                warned: false,
+            });
+        }
+        if let (Some(window), Some(rise)) = (alarm.growth, &mut rise) {
+            let rising_for = now - rise.since;
+            if rising_for >= window && !rise.warned {
+                warn!(
+                    target: "flow",
+                    from = rise.from,
+                    outstanding,
+                    ?rising_for,
+                    "outstanding questions have grown without falling; replies may be starved"
+                );
+                rise.warned = true;
+                tripped = true;
+            }
+        }
+        last = outstanding;
+
+        if tripped && alarm.abort {
+            warn!(target: "flow", outstanding, "question alarm is set to abort; stopping the run");
+            return;
+        }
+    }
+}
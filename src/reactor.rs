@@ -0,0 +1,76 @@
+//! Driving reactor guests.
+//!
+//! Reactor guests target the `reactor-guest` world and export `wetware:guest/reactor` instead of
+//! `wasi:cli/run`. The host instantiates them once, calls `init`, then calls `handle-event` for
+//! every event, so the guest's Cap'n Proto connection (and whatever state it keeps) lives across
+//! invocations. The guest only runs while one of its exports is executing, so it must drive its
+//! `RpcSystem` from within those calls.
+//!
+//! A reactor guest that also exports `wetware:guest/invoke` gets one invoked echo right after
+//! `init`, checking that path before any event arrives (see `invoke`).
+
+use std::path::Path;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tracing::{debug, info, warn};
+use wasmtime::Store;
+use wasmtime::component::{Component, Linker};
+
+use crate::ComponentRunStates;
+use crate::invoke::{self, Invoker};
+
+const REACTOR_INTERFACE: &str = "wetware:guest/reactor";
+
+/// Instantiate the guest, call its `init`, then feed it one event per line read from `events`, or
+/// from the host's stdin without one, until EOF.
+pub async fn run(
+    store: &mut Store<ComponentRunStates>,
+    linker: &Linker<ComponentRunStates>,
+    component: &Component,
+    events: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let instance = linker.instantiate_async(&mut *store, component).await?;
+    let interface_idx = instance
+        .get_export_index(&mut *store, None, REACTOR_INTERFACE)
+        .ok_or_else(|| format!("guest does not export `{REACTOR_INTERFACE}`"))?;
+    let init_idx = instance
+        .get_export_index(&mut *store, Some(&interface_idx), "init")
+        .ok_or("reactor interface has no `init` function")?;
+    let handle_idx = instance
+        .get_export_index(&mut *store, Some(&interface_idx), "handle-event")
+        .ok_or("reactor interface has no `handle-event` function")?;
+    let init = instance.get_typed_func::<(), (Result<(), String>,)>(&mut *store, init_idx)?;
+    let handle_event = instance
+        .get_typed_func::<(Vec<u8>,), (Result<Vec<u8>, String>,)>(&mut *store, handle_idx)?;
+
+    info!("initializing reactor guest");
+    let (result,) = init.call_async(&mut *store, ()).await?;
+    init.post_return_async(&mut *store).await?;
+    result.map_err(|e| format!("reactor guest failed to initialize: {e}"))?;
+    if let Some(invoker) = Invoker::find(store, &instance)? {
+        invoke::probe(store, &invoker).await?;
+    }
+
+    let events: Box<dyn AsyncRead + Unpin> = match events {
+        Some(path) => Box::new(tokio::fs::File::open(path).await?),
+        None => Box::new(tokio::io::stdin()),
+    };
+    let mut events = BufReader::new(events).lines();
+    let mut handled = 0u64;
+    while let Some(event) = events.next_line().await? {
+        debug!(len = event.len(), "delivering event to reactor guest");
+        let (result,) = handle_event
+            .call_async(&mut *store, (event.into_bytes(),))
+            .await?;
+        handle_event.post_return_async(&mut *store).await?;
+        match result {
+            Ok(reply) => {
+                info!(reply = %String::from_utf8_lossy(&reply), "reactor guest handled event")
+            }
+            Err(e) => warn!(error = %e, "reactor guest failed to handle event"),
+        }
+        handled += 1;
+    }
+    info!(handled, "event source closed; reactor guest done");
+    Ok(())
+}
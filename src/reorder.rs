@@ -0,0 +1,160 @@
+//! Replies deliberately out of order (`--reorder-replies`).
+//!
+//! The in-memory echoers answer each call as soon as it arrives, so a guest gets its replies back
+//! in the order it asked for them. Bookkeeping on the guest's side that quietly depends on that
+//! order would go unnoticed. A [`DelayedEchoer`] withholds each reply once the echoer behind it
+//! has answered. When `--reorder-replies` of them are held, or the oldest has waited
+//! `--reorder-hold`, the whole group is released in a shuffled order derived from the run seed.
+//! The replies themselves are untouched, so a guest that checks each reply against its request,
+//! as the stress guest does, shows whether it copes. The wrappers sit right around the echoers,
+//! so the rest of the host sees the reordering as the service's own.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::trace;
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+
+use crate::seed::SplitMix64;
+
+/// The replies withheld on one connection, shared by all of its echoers.
+pub struct Reorder {
+    window: usize,
+    hold: Duration,
+    held: RefCell<Vec<oneshot::Sender<()>>>,
+    rng: RefCell<SplitMix64>,
+    groups: Cell<u64>,
+    replies: Cell<u64>,
+}
+
+impl Reorder {
+    /// Withhold up to `window` replies at a time, none for longer than `hold`, and shuffle them
+    /// with `seed`.
+    pub fn new(window: usize, hold: Duration, seed: u64) -> Rc<Self> {
+        info!(target: "reorder", window, ?hold, "returning replies out of order");
+        Rc::new(Self {
+            window,
+            hold,
+            held: RefCell::new(Vec::new()),
+            rng: RefCell::new(SplitMix64::new(seed)),
+            groups: Cell::new(0),
+            replies: Cell::new(0),
+        })
+    }
+
+    /// Wait until this call's reply is released.
+    async fn hold(&self) {
+        let (tx, rx) = oneshot::channel();
+        let full = {
+            let mut held = self.held.borrow_mut();
+            held.push(tx);
+            held.len() >= self.window
+        };
+        if full {
+            self.release();
+        }
+        // Released or not, a reply that has waited its time takes the rest of its group along.
+        if tokio::time::timeout(self.hold, rx).await.is_err() {
+            self.release();
+        }
+    }
+
+    /// Release every withheld reply, in a shuffled order.
+    fn release(&self) {
+        let mut group = std::mem::take(&mut *self.held.borrow_mut());
+        // Fisher-Yates, with the rolls taken from the seeded stream.
+        let mut rng = self.rng.borrow_mut();
+        for i in (1..group.len()).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            group.swap(i, j);
+        }
+        drop(rng);
+        debug!(target: "reorder", replies = group.len(), "releasing withheld replies");
+        self.groups.set(self.groups.get() + 1);
+        self.replies.set(self.replies.get() + group.len() as u64);
+        for tx in group {
+            // The call may have been canceled, or stopped waiting on its own.
+            let _ = tx.send(());
+        }
+    }
+
+    /// Log how many replies were withheld, and in how many groups.
+    pub fn log_summary(&self) {
+        info!(
+            target: "reorder",
+            replies = self.replies.get(),
+            groups = self.groups.get(),
+            "replies returned out of order"
+        );
+    }
+}
+
+/// `EchoerProvider` whose echoers return their replies out of order.
+pub struct DelayedEchoerProvider {
+    reorder: Rc<Reorder>,
+}
+
+impl DelayedEchoerProvider {
+    pub fn client(inner: echoer_provider::Client, reorder: Rc<Reorder>) -> echoer_provider::Client {
+        layer::provider(inner, Self { reorder })
+    }
+}
+
+impl Layer for DelayedEchoerProvider {
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(DelayedEchoer {
+            inner,
+            reorder: self.reorder.clone(),
+        })
+    }
+}
+
+/// An echoer whose replies are withheld and released with others, in a shuffled order.
+pub struct DelayedEchoer {
+    inner: echoer::Client,
+    reorder: Rc<Reorder>,
+}
+
+impl echoer::Server for DelayedEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_request();
+        let params = pry!(params.get());
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(params.get_call_id());
+        let reorder = self.reorder.clone();
+        trace::promise(async move {
+            let response = request.send().promise.await?;
+            reorder.hold().await;
+            results.get().set_reply(response.get()?.get_reply()?);
+            Ok(())
+        })
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.echo_batch_request();
+        let params = pry!(params.get());
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(params.get_call_id());
+        let reorder = self.reorder.clone();
+        trace::promise(async move {
+            let response = request.send().promise.await?;
+            reorder.hold().await;
+            results.get().set_replies(response.get()?.get_replies()?)?;
+            Ok(())
+        })
+    }
+}
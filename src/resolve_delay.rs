@@ -0,0 +1,75 @@
+//! Echoers that resolve late (`--resolve-delay`).
+//!
+//! The in-memory provider answers `echoer()` with an echoer that is ready at once, so a guest's
+//! calls pipelined on the answer never wait on anything. A `--inject` delay on
+//! `EchoerProvider.echoer` holds back the whole answer. With `--resolve-delay`, `echoer()` is
+//! answered right away, but with a promise for the echoer that resolves only once the delay has
+//! passed since the call. The RPC system exports it as a promise and sends the guest a `Resolve`
+//! message later, and calls that arrive in between wait in the promise's queue. The wrapper sits
+//! outermost, so the promise is what goes out on the wire. `restore()` is answered the same way.
+//! The answer goes out before the echoer is known, so `echoer()` answers carry no sturdy ref.
+
+use std::time::Duration;
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{ECHOER, RESTORE};
+use cap::proxy::ProxyBuilder;
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// `EchoerProvider` whose echoers resolve `delay` after they were asked for.
+pub struct SlowResolvingEchoerProvider {
+    inner: echoer_provider::Client,
+    delay: Duration,
+}
+
+impl SlowResolvingEchoerProvider {
+    pub fn client(inner: echoer_provider::Client, delay: Duration) -> echoer_provider::Client {
+        info!(target: "resolve", ?delay, "delaying the resolution of echoers");
+        // The other methods pass through.
+        ProxyBuilder::new(inner.clone())
+            .serve::<echoer_provider::Client, _>(&[ECHOER, RESTORE], Self { inner, delay })
+            .build()
+    }
+}
+
+impl echoer_provider::Server for SlowResolvingEchoerProvider {
+    fn echoer(
+        &mut self,
+        _params: echoer_provider::EchoerParams,
+        mut results: echoer_provider::EchoerResults,
+    ) -> Promise<(), capnp::Error> {
+        // Asked for now, so the layers below see the call when the guest made it.
+        let response = self.inner.echoer_request().send().promise;
+        let deadline = Instant::now() + self.delay;
+        let echoer: echoer::Client = capnp_rpc::new_promise_client(Box::pin(async move {
+            let response = response.await?;
+            tokio::time::sleep_until(deadline).await;
+            debug!(target: "resolve", "resolving an echoer");
+            Ok(response.get()?.get_echoer()?.client)
+        }));
+        results.get().set_echoer(echoer);
+        Promise::ok(())
+    }
+
+    fn restore(
+        &mut self,
+        params: echoer_provider::RestoreParams,
+        mut results: echoer_provider::RestoreResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut request = self.inner.restore_request();
+        request.get().set_ref(pry!(pry!(params.get()).get_ref()));
+        let response = request.send().promise;
+        let deadline = Instant::now() + self.delay;
+        let echoer: echoer::Client = capnp_rpc::new_promise_client(Box::pin(async move {
+            let response = response.await?;
+            tokio::time::sleep_until(deadline).await;
+            debug!(target: "resolve", "resolving a restored echoer");
+            Ok(response.get()?.get_echoer()?.client)
+        }));
+        results.get().set_echoer(echoer);
+        Promise::ok(())
+    }
+}
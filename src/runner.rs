@@ -0,0 +1,1244 @@
+//! Running one guest: pipes, the Cap'n Proto provider thread, the wasmtime engine and store, and
+//! the guest's entry point.
+
+use capnp_rpc::{RpcSystem, rpc_twoparty_capnp, twoparty};
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tokio_util::either::Either;
+use wasmtime::component::{Component, Linker, ResourceTable};
+use wasmtime::*;
+use wasmtime_wasi::WasiCtx;
+use wasmtime_wasi::cli::{AsyncStdinStream, AsyncStdoutStream, StdinStream, StdoutStream};
+use wasmtime_wasi_http::WasiHttpCtx;
+use wasmtime_wasi_io::streams::{DynInputStream, DynOutputStream};
+
+use cap::{self, echo_capnp::echoer_provider, guest_capnp::guest_stats};
+use tracing::{Instrument, Span, debug, info, warn};
+
+use crate::alloc_count;
+use crate::config::HostConfig;
+use crate::conn_stats::{self, ConnectionMonitor, ConnectionStats, Counted};
+use crate::cpu_time::{self, CpuAccount};
+use crate::fragment::Fragmented;
+use crate::half_close::{ClosingOutputStream, HalfClose};
+use crate::limits::{GuestLimiter, GuestLimits};
+use crate::log_limit::LogRate;
+use crate::migrate::Migration;
+use crate::mux::{self, Mux};
+use crate::pause::{self, Pause};
+use crate::pipe_meter::{self, MeteredReader, MeteredWriter, PipeMeter};
+use crate::profile::{Profile, Profiled, ProfiledEchoerProvider};
+use crate::progress::{ProgressReport, ProgressTracker};
+use crate::teardown::{Sever, Severable};
+use crate::throttle::{Throttle, Throttled};
+use crate::wan::{self, Wan};
+use crate::{
+    ComponentRunStates, blocking, bridge, budget, capture, conformance, deterministic, disconnect,
+    flight_recorder, flow, guest_env, guest_stderr, http, inject, liveness, log_tail, msg_channel,
+    oneway, preopens, question_alarm, reactor, reorder, resolve_delay, seed, snapshot, stats,
+    topology, traced, verify, world, write_batch,
+};
+
+const GUEST_STATS_INTERVAL: Duration = Duration::from_secs(1);
+const PIPE_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// How often the RPC connection's statistics are logged.
+const CONN_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// How long the guest may go without a heartbeat before it is considered wedged and interrupted.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(30);
+// How long to wait for the rest of the guest's one-way records once its RPC connection ends.
+const ONEWAY_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// After `--deadline`, how long epoch interruption gets to stop the guest before its run is
+// dropped, and how long the provider thread then gets to wind down.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(1);
+const PROVIDER_GRACE: Duration = Duration::from_secs(5);
+// Exit status of a run stopped by `--deadline`, as for timeout(1).
+pub const DEADLINE_EXIT_CODE: i32 = 124;
+// Fuel units between cooperative yields when fuel metering is on.
+const FUEL_YIELD_INTERVAL: u64 = 10_000_000;
+
+/// The run's `--deadline` passed before the guest and its connection finished.
+#[derive(Debug)]
+pub struct DeadlineExceeded(pub Duration);
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "guest run exceeded its {:?} deadline", self.0)
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// What a finished guest run produced.
+pub struct RunOutcome {
+    /// Results of the conformance suite, when run in conformance mode.
+    pub conformance: Option<Vec<conformance::ScenarioResult>>,
+    pub usage: Usage,
+    /// What the guest reported through its `Progress` capability.
+    pub progress: ProgressReport,
+    /// The RPC connection's statistics when it closed.
+    pub connection: ConnectionStats,
+}
+
+/// Resources a guest consumed during its run.
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub elapsed: Duration,
+    /// `None` when fuel metering was off.
+    pub fuel_consumed: Option<u64>,
+    pub peak_memory: usize,
+    /// Most bytes buffered at once in each direction of the RPC transport.
+    pub peak_guest_to_host: u64,
+    pub peak_host_to_guest: u64,
+    /// Capability-call budget units charged to the guest.
+    pub budget_spent: u64,
+    /// CPU time spent running the guest, and serving its RPC calls (see `cpu_time`).
+    pub guest_cpu: Duration,
+    pub provider_cpu: Duration,
+}
+
+/// What the provider thread hands back once the connection is closed.
+#[derive(Default)]
+struct ProviderOutcome {
+    conformance: Option<Vec<conformance::ScenarioResult>>,
+    budget_spent: u64,
+    peak_questions: usize,
+    rejected_questions: u64,
+    progress: ProgressReport,
+}
+
+/// Run the guest described by `host_config` to completion:
+/// 1. Set up async pipes, map them to the guest stdin/stdout
+/// 2. Map the guest stderr to host tracing
+/// 3. Spawn the Cap'n Proto provider where the runtime topology places it
+/// 4. Bootstrap the capability over the async pipes
+/// 5. Spawn the guest process
+/// 6. Wait for the guest to exit
+///
+/// Everything about the run is traced under one `connection` span; the guest instance gets a
+/// `guest` span under it, and each of its capability calls an `rpc` span under that (see
+/// `traced`).
+///
+/// A guest that hands off on `GuestControl.migrate` is followed by a fresh instance, on a
+/// connection of its own (see `migrate`), until one exits without handing off. The outcome is
+/// that last generation's.
+pub async fn run_guest(
+    host_config: &HostConfig,
+    limits: &GuestLimits,
+    run_seed: u64,
+) -> Result<RunOutcome, Box<dyn std::error::Error>> {
+    // The deadline covers everything from here on: compiling, running and tearing down, for
+    // every generation.
+    let deadline_at = host_config
+        .deadline
+        .map(|d| tokio::time::Instant::now() + d);
+    let engine = engine(host_config, limits)?;
+    // Epoch ticks either trap (once the liveness watchdog trips) or park a paused guest.
+    let pause = Pause::new(engine.clone());
+    let migration = Migration::new();
+    if let Some(addr) = host_config.control {
+        pause::serve_control(addr, pause.clone(), migration.clone())?;
+    }
+    loop {
+        let span = tracing::info_span!(
+            "connection",
+            wasm = %host_config.wasm.display(),
+            transport = "pipe",
+            generation = migration.generation()
+        );
+        let generation = Generation {
+            engine: &engine,
+            pause: &pause,
+            migration: &migration,
+            deadline_at,
+        };
+        let outcome = run_connection(host_config, limits, run_seed, generation)
+            .instrument(span)
+            .await?;
+        if !migration.next_generation() {
+            return Ok(outcome);
+        }
+    }
+}
+
+/// What the generations of one guest run share.
+struct Generation<'a> {
+    engine: &'a Engine,
+    pause: &'a Pause,
+    migration: &'a Migration,
+    deadline_at: Option<tokio::time::Instant>,
+}
+
+/// The engine every generation of a guest runs on.
+fn engine(host_config: &HostConfig, limits: &GuestLimits) -> wasmtime::Result<Engine> {
+    info!("setting up WASM engine");
+    let mut config = Config::new();
+    config.async_support(true);
+    // Epoch interruption lets the liveness watchdog stop a wedged guest.
+    config.epoch_interruption(true);
+    // Accept guests with 64-bit linear memories; growth is bounded by the store limiter below.
+    config.wasm_memory64(true);
+    // WASI 0.3 guests (built with the guest's `wasip3` feature) use component-model async streams.
+    #[cfg(feature = "wasip3")]
+    config.wasm_component_model_async(true);
+    if limits.fuel.is_some() {
+        config.consume_fuel(true);
+    }
+    host_config.pooling.apply(&mut config, limits);
+    Engine::new(&config)
+}
+
+async fn run_connection(
+    host_config: &HostConfig,
+    limits: &GuestLimits,
+    run_seed: u64,
+    generation: Generation<'_>,
+) -> Result<RunOutcome, Box<dyn std::error::Error>> {
+    let Generation {
+        engine,
+        pause,
+        migration,
+        deadline_at,
+    } = generation;
+    let wasm_path = host_config.wasm.display();
+    let guest_span = tracing::info_span!("guest", wasm = %wasm_path);
+    let grants = limits.grants;
+
+    // Create pipes for WASI stdio and host/provider RPC network.
+    // Use larger pipe buffers to reduce backpressure interactions between read/write sides.
+    // Each pipe's capacity is configurable, so the slow-consumer mode can fill them and small
+    // buffers can be tested one stream at a time.
+    let (stdin_buffer, stdout_buffer) = (host_config.stdin_buffer(), host_config.stdout_buffer());
+    let (host_w, guest_r): (DuplexStream, DuplexStream) = tokio::io::duplex(stdin_buffer);
+    let (host_r, guest_w): (DuplexStream, DuplexStream) = tokio::io::duplex(stdout_buffer);
+    // Meter what is buffered in each direction; in slow-consumer mode the host also delays its
+    // reads from the guest.
+    let upstream = PipeMeter::new();
+    let guest_w = MeteredWriter::new(guest_w, upstream.clone());
+    let host_r = MeteredReader::new(host_r, upstream.clone(), host_config.slow_consumer);
+    let downstream = PipeMeter::new();
+    let host_w = MeteredWriter::new(host_w, downstream.clone());
+    let guest_r = MeteredReader::new(guest_r, downstream.clone(), None);
+    // With `--message-channel`, the provider's ends belong to a channel of whole messages
+    // instead, and the guest's transport streams are its adapters (see `msg_channel`).
+    let message_channel = host_config.message_channel.map(|capacity| {
+        info!(capacity, "guest transport runs through a message channel");
+        msg_channel::channel(capacity)
+    });
+    let (host_r, host_w, channel_streams, channel_stats) = match message_channel {
+        Some(channel) => (
+            Either::Right(channel.provider_reader),
+            Either::Right(channel.provider_writer),
+            Some((channel.guest_input, channel.guest_output)),
+            Some((channel.upstream, channel.downstream)),
+        ),
+        None => (Either::Left(host_r), Either::Left(host_w), None, None),
+    };
+    // Optionally splice an emulated wide-area link into both directions.
+    let wan = host_config.wan_rtt.map(|rtt| Wan {
+        rtt,
+        jitter: host_config.wan_jitter.unwrap_or_default(),
+        bandwidth: host_config.wan_bandwidth,
+    });
+    let (host_r, host_w) = match wan {
+        Some(wan) => {
+            info!(?wan, "emulating a wide-area link on the RPC transport");
+            let up = seed::derive(run_seed, "wan:up");
+            let down = seed::derive(run_seed, "wan:down");
+            (
+                Either::Right(wan::reader(host_r, wan, stdout_buffer, up)),
+                Either::Right(wan::writer(host_w, wan, stdin_buffer, down)),
+            )
+        }
+        None => (Either::Left(host_r), Either::Left(host_w)),
+    };
+    // `--test-control` hangs up by ending the provider's reads alone, so the replies it has
+    // already queued still reach the guest.
+    let hangup = Sever::new();
+    let host_r = Severable::new(host_r, &hangup);
+    // The provider's ends can be closed from here if the guest's ends outlive the guest.
+    let sever = Sever::new();
+    let (host_r, host_w) = (
+        Severable::new(host_r, &sever),
+        Severable::new(host_w, &sever),
+    );
+    // Tasks spawned for this run carry its span along (see `cap::trace`).
+    let pipe_reporter = tokio::spawn(
+        pipe_meter::report(
+            vec![
+                ("guest_to_host", upstream.clone()),
+                ("host_to_guest", downstream.clone()),
+            ],
+            PIPE_REPORT_INTERVAL,
+        )
+        .in_current_span(),
+    );
+
+    // Wrap guest-side ends in WASI-compatible async stdio streams. The guest's write half can be
+    // shut down on its own when the guest drops its RPC output stream.
+    let (guest_w, guest_w_close) = HalfClose::new(guest_w);
+    let guest_r_async = AsyncStdinStream::new(guest_r);
+    let guest_w_async = AsyncStdoutStream::new(stdout_buffer, guest_w);
+
+    // Separate stderr so we can capture and map it to host tracing.
+    let (guest_stderr_host_r, guest_stderr_guest_w): (DuplexStream, DuplexStream) =
+        tokio::io::duplex(host_config.stderr_buffer);
+    let guest_e_async = AsyncStdoutStream::new(host_config.stderr_buffer, guest_stderr_guest_w);
+
+    // Spawn a task to handle guest stderr lines as `--guest-stderr` says. Heartbeat lines are
+    // consumed there and fed to the liveness watchdog instead of being logged.
+    let heartbeat = liveness::Heartbeat::new();
+    let stderr_task = tokio::spawn(guest_stderr::forward(
+        guest_stderr_host_r,
+        host_config.guest_stderr,
+        host_config.guest_stderr_tee.clone(),
+        heartbeat.clone(),
+        guest_span.clone(),
+        LogRate::from_config(host_config),
+    ));
+
+    // Create a readiness channel so the main thread waits until the provider is listening.
+    let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+
+    // Channel carrying bridged capability calls from WIT imports to the provider thread.
+    let (bridge_handle, bridge_server) = bridge::channel();
+
+    // Spawn the Cap'n Proto provider, by default on a dedicated background thread with its own
+    // single-threaded Tokio runtime (see `topology`). This keeps the RPC system on one thread,
+    // while the Wasm module runs on the main runtime.
+    // In conformance mode the provider returns the results of the suite it ran against the guest.
+    let conformance_mode = host_config.conformance;
+    let budget_limit = limits.budget;
+    let log_rate = LogRate::from_config(host_config);
+    let max_questions = host_config.max_questions;
+    let use_mux = host_config.mux;
+    let throttle = host_config.throttle.map(|rate| Throttle {
+        rate,
+        burst: host_config.throttle_burst,
+    });
+    if let Some(throttle) = throttle {
+        info!(?throttle, "throttling the RPC transport");
+    }
+    let inject_rules = host_config
+        .inject
+        .as_deref()
+        .map(inject::load)
+        .transpose()?;
+    if let Some(rules) = &inject_rules {
+        info!(
+            rules = rules.len(),
+            "injecting faults into capability calls"
+        );
+    }
+    let inject_seed = seed::derive(run_seed, "inject");
+    let reorder_replies = host_config.reorder_replies;
+    let reorder_hold = host_config.reorder_hold;
+    let reorder_seed = seed::derive(run_seed, "reorder");
+    let resolve_delay = host_config.resolve_delay;
+    let blocking_threshold = host_config.blocking_threshold;
+    let test_control = host_config.test_control;
+    // Only an operator on `--control` can ask the guest to move.
+    let provider_migration = host_config.control.map(|_| migration.clone());
+    let capture_file = match &host_config.capture {
+        Some(path) => {
+            info!(
+                target: "capture",
+                path = %path.display(),
+                sample = host_config.capture_sample,
+                max_bytes = host_config.capture_max_bytes,
+                "capturing call payloads"
+            );
+            Some(fs::File::create(path)?)
+        }
+        None => None,
+    };
+    let (capture_sample, capture_max_bytes) =
+        (host_config.capture_sample, host_config.capture_max_bytes);
+    let capture_seed = seed::derive(run_seed, "capture");
+    let write_batch = host_config.write_batch;
+    if let Some(capacity) = write_batch {
+        info!(
+            capacity,
+            "batching the provider's writes on the RPC transport"
+        );
+    }
+    let fragment = host_config.fragment;
+    let fragment_seed = seed::derive(run_seed, "fragment");
+    if let Some(max) = fragment {
+        info!(max, "fragmenting transfers on the RPC transport");
+    }
+    let profile = host_config.profile.as_ref().map(|_| Profile::new());
+    let provider_profile = profile.clone();
+    let connection = ConnectionMonitor::new();
+    let provider_connection = connection.clone();
+    let conn_reporter = tokio::spawn(
+        conn_stats::report(connection.clone(), CONN_REPORT_INTERVAL).in_current_span(),
+    );
+    let mut alarm_task = host_config.question_alarm().map(|alarm| {
+        tokio::spawn(question_alarm::watch(connection.clone(), alarm).in_current_span())
+    });
+    // The provider's own work is traced under the connection, its calls under the guest.
+    let provider_span = Span::current();
+    let provider_guest_span = guest_span.clone();
+    let guest_cpu = CpuAccount::new();
+    let provider_cpu = CpuAccount::new();
+    let provider_cpu_account = provider_cpu.clone();
+    let provider_task = topology::spawn_provider(&host_config.runtime, provider_span, move || {
+        provider_cpu_account.meter(async move {
+            // Set up the RPC provider inside the provider's own task so we don't have to
+            // move non-Send types across threads.
+            info!("initializing echoer_provider client");
+            // Guest calls on host capabilities, over RPC or the bridge, go through the ledger.
+            let ledger = budget::Ledger::new(budget_limit, log_rate);
+            let progress = ProgressTracker::new();
+            let provider = cap::EchoerProvider::with_services(
+                progress.client(),
+                log_tail::book().map(cap::logtail::LogTailServer::client),
+                provider_migration.as_ref().map(Migration::client),
+            );
+            let handouts = provider.handouts();
+            let mut service: echoer_provider::Client = capnp_rpc::new_client(provider);
+            // Right around the services, so only their own time on the thread is watched.
+            let blocking = blocking_threshold
+                .map(|threshold| blocking::BlockingWatch::new(threshold, log_rate));
+            if let Some(blocking) = &blocking {
+                service = blocking::WatchedEchoerProvider::client(service, blocking.clone());
+            }
+            // Replies are reordered at the echoers, so everything above sees it as the service's.
+            let reorder = reorder_replies
+                .map(|window| reorder::Reorder::new(window, reorder_hold, reorder_seed));
+            if let Some(reorder) = &reorder {
+                service = reorder::DelayedEchoerProvider::client(service, reorder.clone());
+            }
+            // Outside the reordering, so the host hangs up only once withheld replies are out.
+            if test_control {
+                let tripwire = disconnect::Tripwire::new(hangup);
+                service = disconnect::DisconnectingEchoerProvider::client(service, tripwire);
+            }
+            let metered = budget::MeteredEchoerProvider::client(service, ledger.clone());
+            // Injected faults land before the ledger, so a failed call costs nothing.
+            let faults = inject_rules.map(|rules| inject::Faults::new(rules, inject_seed));
+            let metered = match &faults {
+                Some(faults) => inject::InjectingEchoerProvider::client(metered, faults.clone()),
+                None => metered,
+            };
+            // Questions beyond the per-connection cap are rejected before they are charged.
+            let gate =
+                flow::QuestionGate::new(max_questions, Some(provider_connection.clone()), log_rate);
+            let mut echoer_provider: echoer_provider::Client =
+                flow::GatedEchoerProvider::client(metered, gate.clone());
+            if let Some(profile) = &provider_profile {
+                echoer_provider = ProfiledEchoerProvider::client(echoer_provider, profile.clone());
+            }
+            // Captured outermost, as the guest sees its calls.
+            let capture = capture_file.map(|file| {
+                capture::Capture::new(file, capture_sample, capture_max_bytes, capture_seed)
+            });
+            if let Some(capture) = &capture {
+                echoer_provider =
+                    capture::CapturingEchoerProvider::client(echoer_provider, capture.clone());
+            }
+            // Call spans wrap everything, capture included.
+            let mut echoer_provider =
+                traced::TracedEchoerProvider::client(echoer_provider, provider_guest_span);
+            // Outside everything, so the guest is handed the promise itself.
+            if let Some(delay) = resolve_delay {
+                echoer_provider =
+                    resolve_delay::SlowResolvingEchoerProvider::client(echoer_provider, delay);
+            }
+
+            let transport_r = Profiled::new(
+                Throttled::new(Fragmented::new(host_r, fragment, fragment_seed), throttle),
+                provider_profile.clone(),
+            );
+            let transport_w = Profiled::new(
+                Throttled::new(Fragmented::new(host_w, fragment, fragment_seed), throttle),
+                provider_profile.clone(),
+            );
+            // With `--mux`, the connection is one channel of the multiplexed transport, and a
+            // second connection to the same bootstrap runs on the high-priority control lane.
+            // One-way records on the bulk channel are read by a task of their own.
+            let (rpc_r, rpc_w, control_system, oneway_task) = if use_mux {
+                info!("multiplexing the RPC transport");
+                let mux = Mux::new(transport_r, transport_w);
+                let (r, w) = tokio::io::split(mux.channel(mux::RPC));
+                let (control_r, control_w) = tokio::io::split(mux.channel(mux::CONTROL));
+                let control = twoparty::VatNetwork::new(
+                    control_r.compat(),
+                    control_w.compat_write(),
+                    rpc_twoparty_capnp::Side::Server,
+                    Default::default(),
+                );
+                let control = RpcSystem::new(
+                    Box::new(control),
+                    grants.echoer.then(|| echoer_provider.clone().client),
+                );
+                let oneway_task =
+                    tokio::spawn(oneway::ingest(mux.channel(mux::BULK).compat()).in_current_span());
+                (
+                    Either::Right(r),
+                    Either::Right(w),
+                    Some(control),
+                    Some(oneway_task),
+                )
+            } else {
+                (
+                    Either::Left(transport_r),
+                    Either::Left(transport_w),
+                    None,
+                    None,
+                )
+            };
+
+            // With batching, the RpcSystem's writes only queue; a task of their own does the
+            // writing.
+            let rpc_w = match write_batch {
+                Some(capacity) => Either::Right(write_batch::spawn(rpc_w, capacity)),
+                None => Either::Left(rpc_w),
+            };
+
+            // Counted at the connection's own stream, so the frames are its messages whether
+            // or not it shares the transport.
+            let rpc_r = Counted::new(rpc_r, provider_connection.clone());
+            let rpc_w = Counted::new(rpc_w, provider_connection);
+
+            info!("constructing twoparty VatNetwork (server side)");
+            let network = twoparty::VatNetwork::new(
+                rpc_r.compat(),
+                rpc_w.compat_write(),
+                rpc_twoparty_capnp::Side::Server,
+                Default::default(),
+            );
+            debug!("VatNetwork constructed");
+
+            info!("starting RpcSystem");
+            let mut rpc_system = RpcSystem::new(
+                Box::new(network),
+                grants.echoer.then(|| echoer_provider.clone().client),
+            );
+
+            if conformance_mode {
+                // The guest's bootstrap is the `EchoerProvider` under test.
+                let target: echoer_provider::Client =
+                    rpc_system.bootstrap(rpc_twoparty_capnp::Side::Client);
+                let _ = ready_tx.send(());
+                debug!("provider readiness signal sent");
+
+                info!("running conformance suite against the guest bootstrap");
+                let (results, ()) = tokio::join!(
+                    conformance::run(rpc_system, target),
+                    bridge::serve(bridge_server, echoer_provider),
+                );
+                if let Some(faults) = &faults {
+                    faults.log_summary();
+                }
+                if let Some(capture) = &capture {
+                    capture.finish();
+                }
+                return ProviderOutcome {
+                    conformance: Some(results),
+                    budget_spent: ledger.spent(),
+                    peak_questions: gate.peak(),
+                    rejected_questions: gate.rejected(),
+                    progress: progress.report(),
+                };
+            }
+
+            // The guest exports `GuestStats` as its own bootstrap capability.
+            let guest_stats: guest_stats::Client =
+                rpc_system.bootstrap(rpc_twoparty_capnp::Side::Client);
+
+            // Signal to the main thread that the provider is ready to accept connections.
+            let _ = ready_tx.send(());
+            debug!("provider readiness signal sent");
+
+            // Drive the RPC system until the connection closes (e.g., when the Wasm exits).
+            // Guest stats polling, bridged capability calls and the control lane are served
+            // alongside; all are dropped once the RPC system ends.
+            info!("RpcSystem running; awaiting shutdown");
+            let control = async {
+                if let Some(control) = control_system
+                    && let Err(e) = control.await
+                {
+                    debug!(error = %e, "control lane RpcSystem ended with error");
+                }
+            };
+            let side_tasks = async {
+                tokio::join!(
+                    stats::poll_guest_stats(guest_stats, GUEST_STATS_INTERVAL),
+                    bridge::serve(bridge_server, echoer_provider),
+                    control,
+                )
+            };
+            tokio::pin!(rpc_system);
+            tokio::pin!(side_tasks);
+            let rpc_result = tokio::select! {
+                res = &mut rpc_system => res,
+                _ = &mut side_tasks => {
+                    debug!("guest stats poller and capnp bridge finished");
+                    rpc_system.await
+                }
+            };
+            match rpc_result {
+                Ok(()) => info!("RpcSystem completed"),
+                Err(e) => warn!(error = %e, "RpcSystem terminated with error"),
+            }
+            // Records can still be in flight behind the RPC traffic.
+            if let Some(task) = oneway_task
+                && tokio::time::timeout(ONEWAY_DRAIN_TIMEOUT, task)
+                    .await
+                    .is_err()
+            {
+                debug!("one-way channel still open after the RPC connection ended");
+            }
+            handouts.log_summary();
+            if let Some(blocking) = &blocking {
+                blocking.log_summary();
+            }
+            if let Some(reorder) = &reorder {
+                reorder.log_summary();
+            }
+            if let Some(faults) = &faults {
+                faults.log_summary();
+            }
+            if let Some(capture) = &capture {
+                capture.finish();
+            }
+            ProviderOutcome {
+                conformance: None,
+                budget_spent: ledger.spent(),
+                peak_questions: gate.peak(),
+                rejected_questions: gate.rejected(),
+                progress: progress.report(),
+            }
+        })
+    });
+
+    // Wait for the provider thread to be ready before running the Wasm guest.
+    info!("waiting for RPC provider readiness");
+    let _ = ready_rx.await;
+    info!("RPC provider is ready");
+
+    // Load and run the Wasm guest in the main thread.
+    info!(path = %wasm_path, "loading Wasm bytes");
+    let wasm_bytes = fs::read(&host_config.wasm)?;
+    debug!(len = wasm_bytes.len(), "loaded Wasm bytes");
+    verify::verify(&host_config.verify, &host_config.wasm, &wasm_bytes)?;
+    check_component(&wasm_bytes).map_err(|e| format!("{wasm_path}: {e}"))?;
+
+    // Create a Store.
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+    #[cfg(feature = "wasip3")]
+    wasmtime_wasi::p3::add_to_linker(&mut linker)?;
+    if grants.http {
+        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+    }
+    world::add_to_linker(&mut linker)?;
+
+    // The `wetware:guest/transport` streams share the pipes backing the guest's stdin/stdout,
+    // unless they are the message channel's.
+    let stdio_transport = channel_streams.is_none();
+    let rpc_streams: (DynInputStream, DynOutputStream) = match channel_streams {
+        Some((input, output)) => (Box::new(input), Box::new(output)),
+        None => {
+            let rpc_out = ClosingOutputStream::new(guest_w_async.p2_stream(), guest_w_close);
+            (guest_r_async.p2_stream(), Box::new(rpc_out))
+        }
+    };
+
+    // Wire the async stdio streams into WASI; args and environment are allowlisted.
+    let mut wasi_builder = WasiCtx::builder();
+    wasi_builder.stderr(guest_e_async);
+    if stdio_transport {
+        wasi_builder.stdin(guest_r_async).stdout(guest_w_async);
+    }
+    guest_env::configure(&mut wasi_builder, host_config);
+    preopens::configure(&mut wasi_builder, &host_config.dir)?;
+    if conformance_mode {
+        wasi_builder.env(conformance::CONFORMANCE_ENV, "1");
+    }
+    wasi_builder.env(
+        seed::SEED_ENV,
+        seed::derive(run_seed, "guest-shuffle").to_string(),
+    );
+    if let Some(max) = host_config.max_questions {
+        wasi_builder.env(flow::MAX_QUESTIONS_ENV, max.to_string());
+    }
+    if host_config.mux {
+        wasi_builder.env(mux::MUX_ENV, "1");
+    }
+    if host_config.log_tail.is_some() {
+        wasi_builder.env(log_tail::LOG_TAIL_ENV, "1");
+    }
+    if host_config.poll_stats {
+        wasi_builder.env(stats::POLL_STATS_ENV, "1");
+    }
+    if host_config.deterministic {
+        info!("guest randomness and clocks are deterministic");
+        deterministic::configure(&mut wasi_builder, seed::derive(run_seed, "wasi"));
+    }
+    let wasi = wasi_builder.build();
+    let state = ComponentRunStates {
+        wasi_ctx: wasi,
+        resource_table: ResourceTable::new(),
+        http_ctx: WasiHttpCtx::new(),
+        bridge: bridge_handle,
+        rpc_streams: Some(rpc_streams),
+        heartbeat: heartbeat.clone(),
+        limiter: GuestLimiter::new(limits.max_memory),
+        grants,
+    };
+    let mut store = Store::new(engine, state);
+    store.limiter(|state| &mut state.limiter);
+    if let Some(fuel) = limits.fuel {
+        store.set_fuel(fuel)?;
+        // Yield periodically so a fuel-hungry guest can't monopolize a worker thread.
+        store.fuel_async_yield_interval(Some(FUEL_YIELD_INTERVAL))?;
+    }
+    pause::install(&mut store, pause.clone());
+    let deadline_task = deadline_at.map(|at| {
+        tokio::spawn(liveness::deadline(heartbeat.clone(), engine.clone(), at).in_current_span())
+    });
+
+    let component = match &host_config.snapshot {
+        Some(path) => snapshot::load_or_compile(engine, &wasm_bytes, path)?,
+        None => {
+            info!("compiling WASM module");
+            Component::new(engine, &wasm_bytes)?
+        }
+    };
+    let started = Instant::now();
+    // The guest's whole lifetime is its span, so traces show it against the provider's activity.
+    let run = guest_cpu.meter(
+        async {
+            if let Some(addr) = host_config.http {
+                http::serve(
+                    addr,
+                    host_config.drain_timeout,
+                    &mut store,
+                    &linker,
+                    &component,
+                )
+                .await
+                .map_err(Into::into)
+            } else if host_config.reactor {
+                reactor::run(
+                    &mut store,
+                    &linker,
+                    &component,
+                    host_config.events.as_deref(),
+                )
+                .await
+            } else {
+                run_command(&mut store, &linker, &component, &heartbeat, pause).await
+            }
+        }
+        .instrument(guest_span),
+    );
+    // A question alarm set to abort ends the run the way the deadline does.
+    let run = async {
+        tokio::pin!(run);
+        let Some(alarm) = &mut alarm_task else {
+            return run.await;
+        };
+        tokio::select! {
+            result = &mut run => return result,
+            _ = alarm => heartbeat.starve(engine),
+        }
+        match tokio::time::timeout(INTERRUPT_GRACE, run).await {
+            Ok(result) => result,
+            Err(_) => Err("guest run dropped by the question alarm".into()),
+        }
+    };
+    // Epoch interruption only lands while the guest runs Wasm; one parked in a host call is
+    // dropped instead once the grace period is over.
+    let mut expired = false;
+    let run_result = match deadline_at {
+        Some(at) => match tokio::time::timeout_at(at + INTERRUPT_GRACE, run).await {
+            Ok(result) => result,
+            Err(_) => {
+                expired = true;
+                Err("guest run dropped at its deadline".into())
+            }
+        },
+        None => run.await,
+    };
+    if let Some(task) = deadline_task {
+        task.abort();
+    }
+    if let Some(task) = &alarm_task {
+        task.abort();
+    }
+    expired |= heartbeat.expired();
+    let usage = Usage {
+        elapsed: started.elapsed(),
+        fuel_consumed: limits.fuel.map(|fuel| fuel - store.get_fuel().unwrap_or(0)),
+        peak_memory: store.data().limiter.peak_memory(),
+        peak_guest_to_host: upstream.peak_depth(),
+        peak_host_to_guest: downstream.peak_depth(),
+        guest_cpu: guest_cpu.total(),
+        // Filled in from the provider thread once it has finished.
+        budget_spent: 0,
+        provider_cpu: Duration::ZERO,
+    };
+
+    // Proactively drop the Wasm instance and store to close WASI stdio resources
+    // (guest_r_async/guest_w_async). This signals EOF to the provider's transport
+    // so its RpcSystem can shut down cleanly.
+    info!("Shutting down WASM store and closing guest stdio");
+    // Dropping the store will close WASI resources (guest stdio), allowing the
+    // provider's transport to observe EOF and exit.
+    drop(store);
+
+    // Ensure the provider thread terminates cleanly after the guest exits and
+    // its stdio has been closed.
+    info!("Wasm guest finished; joining provider thread");
+    let teardown_grace = host_config.teardown_grace;
+    let force_close = tokio::spawn({
+        let sever = sever.clone();
+        async move {
+            tokio::time::sleep(teardown_grace).await;
+            warn!(
+                grace = ?teardown_grace,
+                "guest transport still open after the guest exited; closing it"
+            );
+            sever.sever();
+        }
+        .in_current_span()
+    });
+    let join = provider_task.join();
+    let provider = match deadline_at {
+        Some(at) => {
+            let grace = at.max(tokio::time::Instant::now()) + PROVIDER_GRACE;
+            match tokio::time::timeout_at(grace, join).await {
+                Ok(joined) => joined.unwrap_or_default(),
+                Err(_) => {
+                    warn!(target: "deadline", "provider thread still running; abandoning it");
+                    expired = true;
+                    ProviderOutcome::default()
+                }
+            }
+        }
+        None => join.await.unwrap_or_default(),
+    };
+    force_close.abort();
+    conn_reporter.abort();
+    let connection = connection.snapshot();
+    if expired {
+        // What the transport looked like at the end, for the post-mortem.
+        warn!(
+            target: "deadline",
+            since_heartbeat = ?heartbeat.since_last(),
+            guest_to_host_depth = upstream.depth(),
+            guest_to_host_stalls = upstream.write_stalls(),
+            host_to_guest_depth = downstream.depth(),
+            host_to_guest_stalls = downstream.write_stalls(),
+            peak_questions = provider.peak_questions,
+            ?connection,
+            progress = ?provider.progress,
+            "guest run exceeded its deadline"
+        );
+        match flight_recorder::dump() {
+            Ok(Some(path)) => {
+                warn!(target: "deadline", path = %path.display(), "wrote the flight recorder")
+            }
+            Ok(None) => {}
+            Err(e) => warn!(target: "deadline", "couldn't write the flight recorder: {e}"),
+        }
+        pipe_reporter.abort();
+        stderr_task.abort();
+        return Err(DeadlineExceeded(host_config.deadline.unwrap_or_default()).into());
+    }
+    pipe_reporter.abort();
+    info!(
+        target: "pipe",
+        guest_to_host_peak = upstream.peak_depth(),
+        guest_to_host_stalls = upstream.write_stalls(),
+        host_to_guest_peak = downstream.peak_depth(),
+        host_to_guest_stalls = downstream.write_stalls(),
+        "transport queue summary"
+    );
+    if let Some((upstream, downstream)) = &channel_stats {
+        upstream.log_summary("guest_to_host");
+        downstream.log_summary("host_to_guest");
+    }
+    info!(
+        target: "budget",
+        spent = provider.budget_spent,
+        limit = ?limits.budget,
+        "capability budget consumption"
+    );
+    info!(
+        target: "flow",
+        peak = provider.peak_questions,
+        rejected = provider.rejected_questions,
+        limit = ?max_questions,
+        "outstanding questions"
+    );
+    let usage = Usage {
+        budget_spent: provider.budget_spent,
+        provider_cpu: provider_cpu.total(),
+        ..usage
+    };
+    info!(
+        target: "cpu",
+        guest = ?usage.guest_cpu,
+        provider = ?usage.provider_cpu,
+        guest_allocations = guest_cpu.allocations(),
+        provider_allocations = provider_cpu.allocations(),
+        pooled_allocations = alloc_count::pooled_allocations(),
+        "CPU time by side of the RPC boundary"
+    );
+    if let Some(path) = &host_config.cpu_report {
+        cpu_time::write_report(path, &guest_cpu, &provider_cpu)?;
+        info!(path = %path.display(), "wrote CPU time report");
+    }
+    if let (Some(profile), Some(path)) = (&profile, &host_config.profile) {
+        profile.write_folded(path)?;
+        info!(path = %path.display(), "wrote RPC phase profile");
+    }
+
+    // Ensure the stderr mapping task has finished.
+    let _ = stderr_task.await;
+
+    if heartbeat.starved() {
+        return Err("guest run stopped by the question alarm".into());
+    }
+    run_result?;
+    Ok(RunOutcome {
+        conformance: provider.conformance,
+        usage,
+        progress: provider.progress,
+        connection,
+    })
+}
+
+/// Fail early, and say why, when the guest is a core module rather than a component: the
+/// WASIp1 and wasi-threads builds of the guest are core modules, which this host doesn't run.
+fn check_component(wasm_bytes: &[u8]) -> Result<(), String> {
+    // Both start with the `\0asm` magic; the version that follows tells them apart.
+    const CORE_MODULE_VERSION: [u8; 4] = [1, 0, 0, 0];
+    match wasm_bytes.get(4..8) {
+        Some(version) if version == CORE_MODULE_VERSION && wasm_bytes.starts_with(b"\0asm") => Err(
+            "this is a core Wasm module (e.g. a WASIp1 or wasi-threads build), \
+                 but the host only runs components"
+                .to_string(),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Run a `wasi:cli/command` guest to completion under the liveness watchdog.
+async fn run_command(
+    store: &mut Store<ComponentRunStates>,
+    linker: &Linker<ComponentRunStates>,
+    component: &Component,
+    heartbeat: &Arc<liveness::Heartbeat>,
+    pause: &Pause,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let instance = linker.instantiate_async(&mut *store, component).await?;
+    // Get the index for the exported interface
+    let interface_idx = instance
+        .get_export_index(&mut *store, None, "wasi:cli/run@0.2.0")
+        .expect("Cannot get `wasi:cli/run@0.2.0` interface");
+    // Get the index for the exported function in the exported interface
+    let parent_export_idx = Some(&interface_idx);
+    let func_idx = instance
+        .get_export_index(&mut *store, parent_export_idx, "run")
+        .expect("Cannot get `run` function in `wasi:cli/run@0.2.0` interface");
+    let func = instance
+        .get_func(&mut *store, func_idx)
+        .expect("Unreachable since we've got func_idx");
+    let typed = func.typed::<(), (Result<(), ()>,)>(&*store)?;
+
+    // Start watching guest heartbeats only once the guest is actually about to run.
+    heartbeat.beat();
+    let watchdog = tokio::spawn(
+        liveness::watchdog(
+            heartbeat.clone(),
+            pause.clone(),
+            store.engine().clone(),
+            LIVENESS_TIMEOUT,
+        )
+        .in_current_span(),
+    );
+    let call_result = typed.call_async(&mut *store, ()).await;
+    watchdog.abort();
+    if call_result.is_err() && heartbeat.tripped() {
+        warn!(timeout = ?LIVENESS_TIMEOUT, "Wasm guest was interrupted by the liveness watchdog");
+    }
+    let (result,) = call_result?;
+    // Required, see documentation of TypedFunc::call
+    typed.post_return_async(&mut *store).await?;
+    if result.is_err() {
+        warn!(?result, "Wasm guest exited with error");
+    } else {
+        info!("Wasm guest exited cleanly");
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::limits::Grants;
+
+    const WASM_PAGE_SIZE: usize = 65536;
+
+    const INVOKE_GUEST: &str = "examples/invoke-echo/target/wasm32-wasip2/release/invoke_echo.wasm";
+
+    /// Run a guest the way `main` does, with the host flags in `args`.
+    async fn run(args: &[&str]) -> Result<RunOutcome, Box<dyn std::error::Error>> {
+        let config =
+            HostConfig::try_parse_from(std::iter::once("host").chain(args.iter().copied()))
+                .unwrap();
+        let limits = GuestLimits {
+            max_memory: config.max_memory,
+            fuel: config.fuel,
+            budget: config.budget,
+            grants: Grants::ALL,
+        };
+        tokio::task::LocalSet::new()
+            .run_until(run_guest(&config, &limits, 1))
+            .await
+    }
+
+    // One 64-bit memory, with exports to grow it and to store a word at a 64-bit address.
+    const MEMORY64_GUEST: &str = r#"
+        (module
+          (memory (export "memory") i64 1)
+          (func (export "grow") (param i64) (result i64)
+            local.get 0
+            memory.grow)
+          (func (export "store") (param i64 i32)
+            local.get 0
+            local.get 1
+            i32.store))
+    "#;
+
+    #[tokio::test]
+    #[ignore = "needs `make build-invoke-guest`"]
+    async fn the_invoker_calls_an_invokable_guest() {
+        let events = std::env::temp_dir().join(format!("invoke-echo-{}.txt", std::process::id()));
+        std::fs::write(&events, "first\nsecond\n").unwrap();
+        // The host invokes `Echoer.echo` right after `init` and fails the run if the reply doesn't
+        // match; the events then go through `handle-event` as usual.
+        let result = run(&[
+            "--reactor",
+            "--wasm",
+            INVOKE_GUEST,
+            "--events",
+            events.to_str().unwrap(),
+        ])
+        .await;
+        std::fs::remove_file(&events).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs `make build-guest`"]
+    async fn a_guest_reads_a_preopened_file_while_rpc_is_up() {
+        let dir = std::env::temp_dir().join(format!("preopen-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(input.join("message.txt"), "read through a preopen").unwrap();
+        let in_dir = format!("{}:/in:ro", input.display());
+        let out_dir = format!("{}:/out", output.display());
+        let run_with = |file: &'static str| {
+            let (in_dir, out_dir) = (in_dir.clone(), out_dir.clone());
+            async move {
+                run(&[
+                    "--dir",
+                    &in_dir,
+                    "--dir",
+                    &out_dir,
+                    "--env",
+                    file,
+                    "--env",
+                    "WETWARE_ARTIFACTS=/out",
+                    "--env",
+                    "WETWARE_BATCHES=2",
+                    "--env",
+                    "WETWARE_CALLS=10",
+                    "--env",
+                    "WETWARE_WARMUP=0",
+                    "--env",
+                    "WETWARE_ECHOER_TASKS=10",
+                    "--env",
+                    "WETWARE_CHAINS=10",
+                    "--env",
+                    "WETWARE_MIXED_MS=0",
+                ])
+                .await
+            }
+        };
+
+        // A guest that can't read its file stops before its workload, so it writes no summary.
+        let _ = run_with("WETWARE_ECHO_FILE=/in/missing.txt").await;
+        let no_summary = !output.join("summary.txt").exists();
+        // The guest echoes the file over RPC, runs its workload and writes its summary through
+        // the other preopen, all on the same connection.
+        let outcome = run_with("WETWARE_ECHO_FILE=/in/message.txt").await;
+        let summary = std::fs::read_to_string(output.join("summary.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(no_summary);
+        outcome.unwrap();
+        assert!(summary.unwrap().contains("status: ok"));
+    }
+
+    // A command that returns from `run` straight away, without touching the RPC connection; its
+    // end of the transport is still open when it exits.
+    const EXITING_GUEST: &str = r#"
+        (component
+          (core module $m
+            (func (export "run") (result i32)
+              i32.const 0))
+          (core instance $i (instantiate $m))
+          (func $run (result (result))
+            (canon lift (core func $i "run")))
+          (instance $cli-run
+            (export "run" (func $run)))
+          (export "wasi:cli/run@0.2.0" (instance $cli-run)))
+    "#;
+
+    #[tokio::test]
+    async fn a_guest_that_exits_with_rpc_open_does_not_hang_the_host() {
+        let wasm = std::env::temp_dir().join(format!("exiting-guest-{}.wat", std::process::id()));
+        std::fs::write(&wasm, EXITING_GUEST).unwrap();
+        let run = run(&[
+            "--wasm",
+            wasm.to_str().unwrap(),
+            "--teardown-grace",
+            "100ms",
+        ]);
+        let result = tokio::time::timeout(Duration::from_secs(30), run).await;
+        std::fs::remove_file(&wasm).unwrap();
+        let outcome = result
+            .expect("the host hung after the guest exited")
+            .unwrap();
+        // The provider saw the connection end without a single message from the guest.
+        assert_eq!(outcome.connection.messages_in, 0);
+    }
+
+    #[test]
+    fn core_modules_are_refused_up_front() {
+        // The preambles of an empty core module and an empty component.
+        let err = check_component(b"\0asm\x01\0\0\0").unwrap_err();
+        assert!(err.contains("core Wasm module"), "{err}");
+        assert_eq!(check_component(b"\0asm\x0d\0\x01\0"), Ok(()));
+        // Anything else is left for wasmtime to reject with its own error.
+        assert_eq!(check_component(b"not wasm"), Ok(()));
+    }
+
+    // A component whose core module has a 64-bit memory, exporting a function that grows it. The
+    // canonical ABI memory of a component is 32-bit, so this is as far as a component guest's
+    // memories can go 64-bit; a scalar-only export needs no canonical memory at all.
+    const MEMORY64_COMPONENT: &str = r#"
+        (component
+          (core module $m
+            (memory i64 1)
+            (func (export "grow") (param i64) (result i64)
+              local.get 0
+              memory.grow))
+          (core instance $i (instantiate $m))
+          (func (export "grow") (param "pages" u64) (result s64)
+            (canon lift (core func $i "grow"))))
+    "#;
+
+    /// Grow the memory of a fresh `MEMORY64_COMPONENT` instance by each of `steps` pages under
+    /// `--max-memory max_memory`, returning what each `memory.grow` returned and the peak.
+    async fn grow_memory64_component(max_memory: &str, steps: &[u64]) -> (Vec<i64>, usize) {
+        let config = HostConfig::try_parse_from(["host", "--max-memory", max_memory]).unwrap();
+        let limits = GuestLimits {
+            max_memory: config.max_memory,
+            fuel: None,
+            budget: None,
+            grants: Grants::ALL,
+        };
+        let engine = engine(&config, &limits).unwrap();
+        let component = Component::new(&engine, MEMORY64_COMPONENT).unwrap();
+        let mut store = Store::new(&engine, GuestLimiter::new(limits.max_memory));
+        store.limiter(|limiter| limiter);
+        store.set_epoch_deadline(1);
+        let instance = Linker::new(&engine)
+            .instantiate_async(&mut store, &component)
+            .await
+            .unwrap();
+        let grow = instance
+            .get_typed_func::<(u64,), (i64,)>(&mut store, "grow")
+            .unwrap();
+        let mut grown = Vec::new();
+        for &pages in steps {
+            let (previous,) = grow.call_async(&mut store, (pages,)).await.unwrap();
+            grow.post_return_async(&mut store).await.unwrap();
+            grown.push(previous);
+        }
+        (grown, store.data().peak_memory())
+    }
+
+    #[tokio::test]
+    async fn memory64_components_stay_under_the_memory_cap() {
+        // 64 MiB in steps, well under the default cap.
+        let (grown, peak) = grow_memory64_component("4294967296", &[255, 256, 512]).await;
+        assert_eq!(grown, [1, 256, 512]);
+        assert_eq!(peak, 1024 * WASM_PAGE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn memory64_components_hit_the_memory_cap() {
+        // Four pages allowed: growing to exactly the cap works, one page past it fails, and the
+        // memory stays usable at its size.
+        let (grown, peak) = grow_memory64_component("262144", &[3, 1, 0]).await;
+        assert_eq!(grown, [1, -1, 4]);
+        assert_eq!(peak, 4 * WASM_PAGE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn memory64_guests_run_under_the_memory_cap() {
+        let config = HostConfig::try_parse_from(["host", "--max-memory", "196608"]).unwrap();
+        let limits = GuestLimits {
+            max_memory: config.max_memory,
+            fuel: None,
+            budget: None,
+            grants: Grants::ALL,
+        };
+        let engine = engine(&config, &limits).unwrap();
+        let module = Module::new(&engine, MEMORY64_GUEST).unwrap();
+        let mut store = Store::new(&engine, GuestLimiter::new(limits.max_memory));
+        store.limiter(|limiter| limiter);
+        store.set_epoch_deadline(1);
+        let instance = Instance::new_async(&mut store, &module, &[]).await.unwrap();
+
+        let grow = instance
+            .get_typed_func::<i64, i64>(&mut store, "grow")
+            .unwrap();
+        assert_eq!(grow.call_async(&mut store, 2).await.unwrap(), 1);
+        // A fourth page would go past `--max-memory`.
+        assert_eq!(grow.call_async(&mut store, 1).await.unwrap(), -1);
+        assert_eq!(store.data().peak_memory(), 3 * WASM_PAGE_SIZE);
+
+        let last_word = 3 * WASM_PAGE_SIZE - 4;
+        let store_word = instance
+            .get_typed_func::<(i64, i32), ()>(&mut store, "store")
+            .unwrap();
+        store_word
+            .call_async(&mut store, (last_word as i64, 7))
+            .await
+            .unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        assert_eq!(memory.data(&store)[last_word..], 7i32.to_le_bytes());
+        // Past the end of the memory, a store traps rather than wrapping around.
+        assert!(
+            store_word
+                .call_async(&mut store, (3 * WASM_PAGE_SIZE as i64, 7))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Run seed plumbing.
+//!
+//! Every run has one root seed, given with `--seed` or drawn at startup, and logged either way.
+//! All randomness the host hands out (the guest's shuffle seed, deterministic WASI backends, and
+//! any randomized schedule) is derived from it with [`derive`], so passing the logged seed back
+//! reproduces the run.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Environment variable carrying the guest's derived seed.
+pub const SEED_ENV: &str = "WETWARE_SEED";
+
+/// The root seed for this run: `configured` if set, otherwise a fresh random value.
+pub fn root(configured: Option<u64>) -> u64 {
+    configured.unwrap_or_else(|| RandomState::new().build_hasher().finish())
+}
+
+/// Derive the seed for one consumer of randomness, identified by `label`, from `root`.
+/// Distinct labels give independent streams, so adding a consumer doesn't shift the others.
+pub fn derive(root: u64, label: &str) -> u64 {
+    // FNV-1a over the label, mixed into the root as splitmix64's first output.
+    let mut label_hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in label.bytes() {
+        label_hash ^= u64::from(byte);
+        label_hash = label_hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    SplitMix64::new(root ^ label_hash).next_u64()
+}
+
+/// A splitmix64 stream, for the host's seeded schedules and rolls.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitmix64_matches_the_reference_stream() {
+        let mut rng = SplitMix64::new(0);
+        assert_eq!(rng.next_u64(), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(rng.next_u64(), 0x6e78_9e6a_a1b9_65f4);
+        assert_eq!(rng.next_u64(), 0x06c4_5d18_8009_454f);
+    }
+
+    #[test]
+    fn rolls_stay_below_one() {
+        let mut rng = SplitMix64::new(7);
+        assert!(
+            (0..1000)
+                .map(|_| rng.next_f64())
+                .all(|roll| (0.0..1.0).contains(&roll))
+        );
+    }
+}
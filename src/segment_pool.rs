@@ -0,0 +1,103 @@
+//! Pooled message segments on the host (the `segment-pool` feature).
+//!
+//! `capnp-rpc` builds every outgoing message, replies included, with its own `HeapAllocator`, and
+//! its `OutgoingMessage` trait names that allocator, so an RPC connection can't be given another.
+//! The segments still come from the global allocator, though, so that is where they are pooled.
+//! Cap'n Proto segments are 8-byte aligned and a few hundred bytes to tens of KiB long. Every
+//! block of that shape is rounded up to a power-of-two size class, and a freed block is kept on
+//! its thread, up to [`DEPTH`] per class, for the next allocation of the same class. A
+//! connection's replies are built and freed on the thread that serves it, so each reply's
+//! segments reuse the previous one's.
+//!
+//! Other 8-byte aligned blocks in the same range are pooled too; the pool can't tell them apart.
+//! Blocks kept by a thread when it exits are not given back to the system.
+
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Size classes: powers of two from 256 bytes to 64 KiB.
+const MIN_SHIFT: u32 = 8;
+const MAX_SHIFT: u32 = 16;
+const CLASSES: usize = (MAX_SHIFT - MIN_SHIFT + 1) as usize;
+
+/// Freed blocks a thread keeps per size class.
+pub const DEPTH: usize = 16;
+
+// No destructor, and nothing here allocates: the pool is used from inside the global allocator.
+thread_local! {
+    static FREE: [[Cell<*mut u8>; DEPTH]; CLASSES] =
+        const { [const { [const { Cell::new(ptr::null_mut()) }; DEPTH] }; CLASSES] };
+    static LEN: [Cell<usize>; CLASSES] = const { [const { Cell::new(0) }; CLASSES] };
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Allocations served from the pool so far, on every thread.
+pub fn hits() -> u64 {
+    HITS.load(Ordering::Relaxed)
+}
+
+/// The size class of `layout`; `None` if it isn't poolable.
+pub fn class(layout: Layout) -> Option<usize> {
+    if layout.align() != 8 || layout.size() > 1 << MAX_SHIFT {
+        return None;
+    }
+    let shift = layout.size().next_power_of_two().trailing_zeros();
+    Some((shift.max(MIN_SHIFT) - MIN_SHIFT) as usize)
+}
+
+/// The layout to get `layout` from the system with: its size class's, if it is poolable. Blocks
+/// are always allocated at their class size, so any freed block can serve its whole class.
+pub fn system_layout(layout: Layout) -> Layout {
+    let Some(class) = class(layout) else {
+        return layout;
+    };
+    let size = 1 << (class as u32 + MIN_SHIFT);
+    // SAFETY: the size is a power of two no larger than 64 KiB, and 8 is a valid alignment.
+    unsafe { Layout::from_size_align_unchecked(size, 8) }
+}
+
+/// A kept block for `layout`, zeroed first if `zeroed`; `None` if the pool has none.
+pub fn take(layout: Layout, zeroed: bool) -> Option<*mut u8> {
+    let class = class(layout)?;
+    let block = LEN
+        .try_with(|len| {
+            let n = len[class].get();
+            if n == 0 {
+                return None;
+            }
+            len[class].set(n - 1);
+            let block = FREE.try_with(|free| free[class][n - 1].replace(ptr::null_mut()));
+            block.ok()
+        })
+        .ok()
+        .flatten()?;
+    if zeroed {
+        // SAFETY: the block is at least `layout.size()` bytes long and no longer in use.
+        unsafe { block.write_bytes(0, layout.size()) };
+    }
+    HITS.fetch_add(1, Ordering::Relaxed);
+    Some(block)
+}
+
+/// Keep the block at `ptr` for reuse. Returns false if it isn't poolable or its class is full,
+/// and then the caller frees it.
+pub fn give(ptr: *mut u8, layout: Layout) -> bool {
+    let Some(class) = class(layout) else {
+        return false;
+    };
+    LEN.try_with(|len| {
+        let n = len[class].get();
+        if n == DEPTH {
+            return false;
+        }
+        let kept = FREE.try_with(|free| free[class][n].set(ptr)).is_ok();
+        if kept {
+            len[class].set(n + 1);
+        }
+        kept
+    })
+    .unwrap_or(false)
+}
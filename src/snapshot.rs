@@ -0,0 +1,103 @@
+//! Compiled-guest snapshots.
+//!
+//! Compiling the guest dominates startup for short runs. A snapshot is the guest as compiled by
+//! this engine (`Component::serialize`), written next to a key identifying the guest bytes and
+//! the engine configuration, so later runs can map it in directly. The key is the SHA-256 of the
+//! guest bytes and of the engine's `precompile_compatibility_hash`, which covers the wasmtime
+//! version, the target and every setting that changes the compiled code.
+//!
+//! This is a compile cache only, not a pre-initialized instance: the guest's RPC bootstrap is a
+//! conversation with a live provider, and replaying a captured post-bootstrap heap against a
+//! fresh provider would reference questions and capabilities it never issued. wasmtime can't
+//! capture a component instance either, so restoring a guest after its bootstrap is out of scope
+//! here; pre-initializing connection-independent state would be a build-time pass instead.
+
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+use wasmtime::Engine;
+use wasmtime::component::Component;
+
+/// Load the snapshot at `path` if it matches `wasm_bytes` and `engine`; otherwise compile the
+/// guest and (re)write the snapshot.
+pub fn load_or_compile(
+    engine: &Engine,
+    wasm_bytes: &[u8],
+    path: &Path,
+) -> Result<Component, Box<dyn std::error::Error>> {
+    let key = snapshot_key(engine, wasm_bytes);
+    let key_path = key_path(path);
+
+    if fs::read_to_string(&key_path).is_ok_and(|stored| stored.trim() == key) {
+        // SAFETY: the snapshot was produced by `Component::serialize` for this guest and engine
+        // configuration (checked through the key); wasmtime also rejects incompatible artifacts.
+        match unsafe { Component::deserialize_file(engine, path) } {
+            Ok(component) => {
+                info!(path = %path.display(), "loaded guest snapshot");
+                return Ok(component);
+            }
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "unusable guest snapshot; recompiling")
+            }
+        }
+    } else {
+        debug!(path = %path.display(), "no matching guest snapshot");
+    }
+
+    info!("compiling WASM module");
+    let component = Component::new(engine, wasm_bytes)?;
+    fs::write(path, component.serialize()?)?;
+    fs::write(&key_path, &key)?;
+    info!(path = %path.display(), "wrote guest snapshot");
+    Ok(component)
+}
+
+fn snapshot_key(engine: &Engine, wasm_bytes: &[u8]) -> String {
+    let mut digest = DigestHasher(Sha256::new());
+    digest.0.update(wasm_bytes);
+    engine.precompile_compatibility_hash().hash(&mut digest);
+    hex::encode(digest.0.finalize())
+}
+
+/// Feeds what a `Hash` impl writes into a digest, for hashes that are only `impl Hash`.
+struct DigestHasher(Sha256);
+
+impl Hasher for DigestHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("the digest is read with `finalize`")
+    }
+}
+
+fn key_path(path: &Path) -> PathBuf {
+    let mut key_path = path.as_os_str().to_owned();
+    key_path.push(".key");
+    PathBuf::from(key_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::Config;
+
+    use super::*;
+
+    #[test]
+    fn the_key_follows_the_guest_bytes_and_the_engine_config() {
+        let engine = Engine::default();
+        let key = snapshot_key(&engine, b"guest");
+        assert_eq!(key.len(), 64);
+        assert_eq!(snapshot_key(&engine, b"guest"), key);
+        assert_ne!(snapshot_key(&engine, b"another guest"), key);
+
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let fueled = Engine::new(&config).unwrap();
+        assert_ne!(snapshot_key(&fueled, b"guest"), key);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use cap::guest_capnp::{alloc_stats, guest_stats, poll_stats};
+use tracing::{debug, info, warn};
+
+/// Environment variable asking the guest to count its polls (`--poll-stats`).
+pub const POLL_STATS_ENV: &str = "WETWARE_POLL_STATS";
+
+// A single guest poll taking longer than this holds up the whole guest.
+const LONG_POLL: Duration = Duration::from_millis(10);
+// Self-wakes per future per interval above which a future counts as busy-polling.
+const BUSY_SELF_WAKES: u64 = 1000;
+
+/// Periodically poll the guest's `GuestStats` capability and report it through host tracing.
+///
+/// Stops at the first failed call: either the guest doesn't export `GuestStats` as its bootstrap
+/// or the connection is gone, and in both cases there is nothing left to poll.
+pub async fn poll_guest_stats(stats: guest_stats::Client, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut polls = PollHistory::default();
+    loop {
+        ticker.tick().await;
+        let response = match stats.stats_request().send().promise.await {
+            Ok(response) => response,
+            Err(e) => {
+                debug!(error = %e, "guest stats unavailable; stopping poller");
+                return;
+            }
+        };
+        let snapshot = match response.get().and_then(|r| r.get_stats()) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                debug!(error = %e, "malformed guest stats reply; stopping poller");
+                return;
+            }
+        };
+        info!(
+            target: "guest_stats",
+            memory_bytes = snapshot.get_memory_bytes(),
+            allocations = snapshot.get_allocations(),
+            deallocations = snapshot.get_deallocations(),
+            bytes_allocated = snapshot.get_bytes_allocated(),
+            in_flight = snapshot.get_in_flight(),
+            transport_buffered = snapshot.get_transport_buffered(),
+            transport_buffered_peak = snapshot.get_transport_buffered_peak(),
+            "guest resource usage"
+        );
+        if let Err(e) = snapshot.get_polls().map(|list| polls.report(list.iter())) {
+            debug!(error = %e, "malformed guest poll stats");
+        }
+        if let Err(e) = snapshot.get_alloc_scopes().map(report_alloc_scopes) {
+            debug!(error = %e, "malformed guest allocation stats");
+        }
+    }
+}
+
+/// Log the guest's allocation totals by scope (guests built with `alloc-stats`).
+fn report_alloc_scopes(scopes: capnp::struct_list::Reader<alloc_stats::Owned>) {
+    for scope in scopes.iter() {
+        let Ok(Ok(name)) = scope.get_scope().map(|s| s.to_string()) else {
+            continue;
+        };
+        debug!(
+            target: "guest_alloc",
+            scope = %name,
+            allocations = scope.get_allocations(),
+            bytes = scope.get_bytes(),
+            "guest allocations"
+        );
+    }
+}
+
+/// Guest poll totals from the previous snapshot, to report each interval's share.
+#[derive(Default)]
+struct PollHistory {
+    // Future type name to (polls, self-wakes, longest poll).
+    last: HashMap<String, (u64, u64, u64)>,
+}
+
+impl PollHistory {
+    fn report<'a>(&mut self, entries: impl Iterator<Item = poll_stats::Reader<'a>>) {
+        for entry in entries {
+            let Ok(Ok(future)) = entry.get_future().map(|f| f.to_string()) else {
+                continue;
+            };
+            let (polls, self_wakes, longest) = (
+                entry.get_polls(),
+                entry.get_self_wakes(),
+                entry.get_longest_poll_nanos(),
+            );
+            let (last_polls, last_self_wakes, last_longest) =
+                self.last.get(&future).copied().unwrap_or_default();
+            let futures = entry.get_futures().max(1);
+            debug!(
+                target: "guest_polls",
+                future = %future,
+                futures = entry.get_futures(),
+                polls = polls - last_polls,
+                self_wakes = self_wakes - last_self_wakes,
+                poll_ms = entry.get_poll_nanos() / 1_000_000,
+                longest_poll_us = longest / 1000,
+                "guest poll totals"
+            );
+            if (self_wakes - last_self_wakes) / futures > BUSY_SELF_WAKES {
+                warn!(
+                    target: "guest_polls",
+                    future = %future,
+                    self_wakes = self_wakes - last_self_wakes,
+                    futures,
+                    "guest future is busy-polling: it keeps waking itself"
+                );
+            }
+            if longest > last_longest && longest > LONG_POLL.as_nanos() as u64 {
+                warn!(
+                    target: "guest_polls",
+                    future = %future,
+                    longest_poll_ms = longest / 1_000_000,
+                    "guest future blocked the guest in a single poll"
+                );
+            }
+            self.last.insert(future, (polls, self_wakes, longest));
+        }
+    }
+}
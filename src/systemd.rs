@@ -0,0 +1,117 @@
+//! systemd supervision: socket activation and `sd_notify` readiness and watchdog messages.
+//!
+//! Everything here is a no-op unless the host was started by systemd with the matching settings
+//! (`LISTEN_FDS` from a `.socket` unit, `NOTIFY_SOCKET` from `Type=notify`, `WATCHDOG_USEC` from
+//! `WatchdogSec=`).
+
+use std::env;
+use std::future;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use tokio::time::Interval;
+use tracing::{debug, warn};
+
+// systemd passes activated sockets starting at this fd.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The listening socket systemd passed to this process, if it was socket-activated.
+#[cfg(unix)]
+pub fn activated_listener() -> Option<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!(fds, "systemd passed several sockets; serving on the first");
+    }
+    // SAFETY: with LISTEN_PID naming this process, systemd hands it ownership of the fds
+    // starting at LISTEN_FDS_START, and nothing else in the host has claimed them.
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    if let Err(e) = listener.set_nonblocking(true) {
+        warn!(error = %e, "cannot use the socket passed by systemd");
+        return None;
+    }
+    Some(listener)
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, if there is one.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        if let Err(e) = send(&path, state) {
+            warn!(error = %e, state, "sd_notify failed");
+        } else {
+            debug!(state, "sd_notify");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// Keep-alive pings for systemd's watchdog, at half the interval it asked for.
+pub struct Watchdog {
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    /// A watchdog following `WATCHDOG_USEC`; never fires when the watchdog is off.
+    pub fn from_env() -> Self {
+        let pid_matches = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let usec = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && pid_matches);
+        Self {
+            interval: usec.map(|usec| tokio::time::interval(Duration::from_micros(usec) / 2)),
+        }
+    }
+
+    /// Wait for the next ping and send it. Selected alongside a serving loop, so pings stop
+    /// when the loop is stuck in the guest.
+    pub async fn pet(&mut self) {
+        match self.interval.as_mut() {
+            Some(interval) => {
+                interval.tick().await;
+                notify("WATCHDOG=1");
+            }
+            None => future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Forced teardown of the provider's transport.
+//!
+//! The provider thread's `RpcSystem` ends when it reads EOF from the guest, which normally
+//! happens when the guest's store is dropped and its stream ends close. If anything keeps a guest
+//! end alive past that, the provider would wait forever and the host with it, joining the
+//! thread. So the host ends of the pipes are wrapped in [`Severable`]: once the guest is gone and
+//! a grace period has passed, [`Sever::sever`] makes their reads return EOF and their writes fail,
+//! whatever the guest side is doing.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Default)]
+struct State {
+    severed: AtomicBool,
+    // Tasks waiting on a `Severable`; woken when it is severed so they see the EOF.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// Cuts every [`Severable`] made from it.
+#[derive(Clone, Default)]
+pub struct Sever {
+    state: Arc<State>,
+}
+
+impl Sever {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sever(&self) {
+        if !self.state.severed.swap(true, Ordering::AcqRel) {
+            for waker in self.state.wakers.lock().unwrap().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn is_severed(&self) -> bool {
+        self.state.severed.load(Ordering::Acquire)
+    }
+
+    fn register(&self, cx: &Context<'_>) {
+        let mut wakers = self.state.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+    }
+}
+
+/// A stream that ends when its [`Sever`] is cut.
+pub struct Severable<S> {
+    inner: S,
+    sever: Sever,
+}
+
+impl<S> Severable<S> {
+    pub fn new(inner: S, sever: &Sever) -> Self {
+        Self {
+            inner,
+            sever: sever.clone(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Severable<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.sever.is_severed() {
+            return Poll::Ready(Ok(()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_pending() {
+            self.sever.register(cx);
+            // Severed between the check and the registration.
+            if self.sever.is_severed() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        poll
+    }
+}
+
+fn severed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "transport torn down")
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Severable<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.sever.is_severed() {
+            return Poll::Ready(Err(severed()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if poll.is_pending() {
+            self.sever.register(cx);
+            if self.sever.is_severed() {
+                return Poll::Ready(Err(severed()));
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.sever.is_severed() {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.sever.is_severed() {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn severing_ends_a_read_the_guest_never_closes() {
+        let (host, mut guest) = tokio::io::duplex(64);
+        let sever = Sever::new();
+        let mut host = Severable::new(host, &sever);
+        guest.write_all(b"before").await.unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            host.read_to_end(&mut received).await.map(|_| received)
+        });
+        // The guest's writer stays open: without the sever the read would never end.
+        tokio::task::yield_now().await;
+        assert!(!reader.is_finished());
+        sever.sever();
+        let received = reader.await.unwrap().unwrap();
+        assert_eq!(received, b"before");
+        drop(guest);
+    }
+
+    #[tokio::test]
+    async fn severing_fails_a_write_blocked_on_a_full_pipe() {
+        let (host, guest) = tokio::io::duplex(16);
+        let sever = Sever::new();
+        let mut host = Severable::new(host, &sever);
+        let writer = tokio::spawn(async move { host.write_all(&[0; 64]).await });
+        tokio::task::yield_now().await;
+        assert!(!writer.is_finished());
+        sever.sever();
+        let error = writer.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        drop(guest);
+    }
+}
@@ -0,0 +1,197 @@
+//! Multi-tenant mode.
+//!
+//! A tenants file lists guests to run side by side. Each tenant gets its own engine and store
+//! (nothing compiled or allocated is shared), its own memory cap and fuel budget, and only the
+//! host capabilities it is granted, so one tenant can't exhaust what the others rely on. Usage is
+//! reported per tenant once all of them have finished.
+//!
+//! ```toml
+//! [[tenant]]
+//! name = "alice"
+//! wasm = "wasm/target/wasm32-wasip2/release/wasm.wasm"
+//! max_memory = 268435456
+//! fuel = 50000000000
+//! capabilities = ["echoer", "capnp-bridge"]
+//! stderr = "json"
+//! stderr_tee = "alice.stderr.log"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use futures::future::join_all;
+use serde::Deserialize;
+use tracing::{Instrument, info, warn};
+
+use crate::config::{DEFAULT_MAX_MEMORY, HostConfig};
+use crate::guest_stderr::StderrMode;
+use crate::limits::{Grants, GuestLimits};
+use crate::{runner, seed};
+
+#[derive(Debug, Deserialize)]
+struct TenantsFile {
+    tenant: Vec<TenantConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    pub wasm: PathBuf,
+    #[serde(default = "default_max_memory")]
+    pub max_memory: usize,
+    #[serde(default)]
+    pub fuel: Option<u64>,
+    #[serde(default)]
+    pub budget: Option<u64>,
+    #[serde(default = "all_capabilities")]
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub stderr: Option<StderrMode>,
+    #[serde(default)]
+    pub stderr_tee: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    Echoer,
+    CapnpBridge,
+    Http,
+}
+
+fn default_max_memory() -> usize {
+    DEFAULT_MAX_MEMORY
+}
+
+fn all_capabilities() -> Vec<Capability> {
+    vec![
+        Capability::Echoer,
+        Capability::CapnpBridge,
+        Capability::Http,
+    ]
+}
+
+impl TenantConfig {
+    fn limits(&self) -> GuestLimits {
+        GuestLimits {
+            max_memory: self.max_memory,
+            fuel: self.fuel,
+            budget: self.budget,
+            grants: Grants {
+                echoer: self.capabilities.contains(&Capability::Echoer),
+                capnp_bridge: self.capabilities.contains(&Capability::CapnpBridge),
+                http: self.capabilities.contains(&Capability::Http),
+            },
+        }
+    }
+
+    /// The host configuration for this tenant: the shared one with the tenant's guest swapped in.
+    fn host_config(&self, shared: &HostConfig) -> HostConfig {
+        let mut config = shared.clone();
+        config.wasm = self.wasm.clone();
+        config.guest_args = self.args.clone();
+        config
+            .env
+            .extend(self.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(mode) = self.stderr {
+            config.guest_stderr = mode;
+        }
+        if let Some(tee) = &self.stderr_tee {
+            config.guest_stderr_tee = Some(tee.clone());
+        }
+        // Snapshots are keyed per guest, so a shared path would just thrash.
+        config.snapshot = None;
+        config
+    }
+}
+
+/// Read and validate the tenants file at `path`.
+pub fn load(path: &Path) -> Result<Vec<TenantConfig>, Box<dyn std::error::Error>> {
+    let file: TenantsFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+    let mut names = std::collections::HashSet::new();
+    for tenant in &file.tenant {
+        if !names.insert(tenant.name.as_str()) {
+            return Err(format!("duplicate tenant name {:?}", tenant.name).into());
+        }
+    }
+    if file.tenant.is_empty() {
+        return Err(format!("no tenants defined in {}", path.display()).into());
+    }
+    Ok(file.tenant)
+}
+
+/// Check every tenant against the shared pooling settings before starting any of them.
+fn validate_pooling(
+    shared: &HostConfig,
+    tenants: &[TenantConfig],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !shared.pooling.pooling {
+        return Ok(());
+    }
+    for tenant in tenants {
+        shared
+            .pooling
+            .validate(&tenant.limits())
+            .map_err(|e| format!("tenant {:?}: {e}", tenant.name))?;
+    }
+    // Each tenant has its own engine and therefore its own pool: the reservation is per tenant.
+    info!(
+        tenants = tenants.len(),
+        slots_per_tenant = shared.pooling.pool_max_instances,
+        "pooling allocator enabled for every tenant engine"
+    );
+    Ok(())
+}
+
+/// Run every tenant concurrently and report their usage. Fails if any tenant failed.
+pub async fn run_all(
+    shared: &HostConfig,
+    tenants: Vec<TenantConfig>,
+    run_seed: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    validate_pooling(shared, &tenants)?;
+    info!(count = tenants.len(), "running tenants");
+    let runs = tenants.iter().map(|tenant| {
+        let config = tenant.host_config(shared);
+        let limits = tenant.limits();
+        let tenant_seed = seed::derive(run_seed, &format!("tenant:{}", tenant.name));
+        async move {
+            let outcome = runner::run_guest(&config, &limits, tenant_seed).await;
+            (tenant, outcome)
+        }
+        .instrument(tracing::info_span!("tenant", name = %tenant.name))
+    });
+
+    let mut failed = 0;
+    for (tenant, outcome) in join_all(runs).await {
+        match outcome {
+            Ok(outcome) => info!(
+                tenant = %tenant.name,
+                elapsed = ?outcome.usage.elapsed,
+                fuel_consumed = ?outcome.usage.fuel_consumed,
+                peak_memory = outcome.usage.peak_memory,
+                budget_spent = outcome.usage.budget_spent,
+                guest_cpu = ?outcome.usage.guest_cpu,
+                provider_cpu = ?outcome.usage.provider_cpu,
+                peak_guest_to_host = outcome.usage.peak_guest_to_host,
+                peak_host_to_guest = outcome.usage.peak_host_to_guest,
+                batches_finished = outcome.progress.batches_finished,
+                calls_failed = outcome.progress.calls_failed,
+                "tenant finished"
+            ),
+            Err(e) => {
+                failed += 1;
+                warn!(tenant = %tenant.name, error = %e, "tenant failed");
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{failed} tenant(s) failed").into());
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! Bandwidth throttling for the host ends of the transport pipes.
+//!
+//! [`Throttled`] wraps a stream with a token bucket per direction: tokens are bytes, refilled at
+//! `rate` per second up to `burst`, and reads and writes only move as many bytes as there are
+//! tokens. Used to simulate slow links and consumers and check that both sides apply
+//! backpressure (the pipes themselves are bounded) instead of buffering without limit or
+//! deadlocking.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Token-bucket parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    /// Sustained rate, in bytes per second.
+    pub rate: u64,
+    /// Bucket size: the most bytes that can move at once after a pause.
+    pub burst: u64,
+}
+
+struct TokenBucket {
+    throttle: Throttle,
+    tokens: f64,
+    last_refill: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl TokenBucket {
+    fn new(throttle: Throttle) -> Self {
+        Self {
+            throttle,
+            tokens: throttle.burst as f64,
+            last_refill: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Wait until at least one byte may move, then return how many may.
+    fn poll_available(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill(Instant::now());
+            if self.tokens >= 1.0 {
+                return Poll::Ready(self.tokens as usize);
+            }
+            self.sleep = Some(Box::pin(tokio::time::sleep(self.wait())));
+        }
+    }
+
+    /// Add the tokens earned since the last refill, up to the bucket size.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens =
+            (self.tokens + elapsed * self.throttle.rate as f64).min(self.throttle.burst as f64);
+    }
+
+    /// How long until there are tokens for one byte.
+    fn wait(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.throttle.rate as f64)
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// A stream whose reads and writes are each limited by their own token bucket.
+pub struct Throttled<S> {
+    inner: S,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+}
+
+impl<S> Throttled<S> {
+    /// Wrap `inner`; `None` leaves it unthrottled.
+    pub fn new(inner: S, throttle: Option<Throttle>) -> Self {
+        Self {
+            inner,
+            read: throttle.map(TokenBucket::new),
+            write: throttle.map(TokenBucket::new),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(bucket) = this.read.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let allowed = ready!(bucket.poll_available(cx)).min(buf.remaining());
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        // SAFETY: `limited` is a view of `buf`'s unfilled part and the inner read just filled
+        // its first `n` bytes.
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        bucket.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(bucket) = this.write.as_mut() else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let allowed = ready!(bucket.poll_available(cx)).min(buf.len());
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_bucket(rate: u64, burst: u64) -> TokenBucket {
+        let mut bucket = TokenBucket::new(Throttle { rate, burst });
+        bucket.consume(burst as usize);
+        bucket
+    }
+
+    #[test]
+    fn bucket_starts_full() {
+        let bucket = TokenBucket::new(Throttle {
+            rate: 1000,
+            burst: 64,
+        });
+        assert_eq!(bucket.tokens, 64.0);
+        assert_eq!(bucket.wait(), Duration::ZERO);
+    }
+
+    #[test]
+    fn refill_adds_rate_times_elapsed() {
+        let mut bucket = empty_bucket(1000, 4096);
+        let start = bucket.last_refill;
+        bucket.refill(start + Duration::from_millis(500));
+        assert!((bucket.tokens - 500.0).abs() < 1e-6);
+        bucket.refill(start + Duration::from_millis(750));
+        assert!((bucket.tokens - 750.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn refill_stops_at_the_burst() {
+        let mut bucket = empty_bucket(1000, 100);
+        let start = bucket.last_refill;
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 100.0);
+    }
+
+    #[test]
+    fn wait_is_the_time_to_earn_one_byte() {
+        let mut bucket = empty_bucket(1000, 100);
+        assert_eq!(bucket.wait(), Duration::from_millis(1));
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.wait(), Duration::from_micros(500));
+    }
+
+    #[test]
+    fn waiting_out_the_wait_leaves_a_byte() {
+        let mut bucket = empty_bucket(3, 100);
+        let wait = bucket.wait();
+        let start = bucket.last_refill;
+        bucket.refill(start + wait);
+        assert!(bucket.tokens >= 1.0 - 1e-9);
+    }
+}
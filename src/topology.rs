@@ -0,0 +1,282 @@
+//! Shape of the host's Tokio runtimes.
+//!
+//! The guest runs on the host's main runtime: multi-threaded with four workers by default, or
+//! current-threaded for density. The Cap'n Proto provider is not `Send`, so it needs a thread it
+//! can stay on. By default it gets a thread of its own with a current-thread runtime, which keeps
+//! RPC processing off the guest's workers. With `--provider local`, it runs on a `LocalSet` driven
+//! by the host's main thread instead. That saves a thread and a runtime per guest, but the
+//! provider then shares the main thread with the guest.
+//!
+//! Either set of threads can be pinned to CPU cores, to keep benchmark runs from being moved
+//! around by the scheduler or to keep RPC processing and guest execution on separate cores.
+//! Runtime threads take the worker cores in turn; the provider's thread takes its own core.
+//!
+//! The same settings can come from the `[runtime]` table of a `--config` file (see
+//! [`RuntimeFile`]); options given on the command line take precedence over it.
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use core_affinity::CoreId;
+use serde::Deserialize;
+use tokio::runtime::Runtime;
+use tracing::{Instrument, Span, info, warn};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flavor {
+    #[default]
+    MultiThread,
+    CurrentThread,
+}
+
+/// Where the Cap'n Proto provider runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProviderPlacement {
+    /// A dedicated thread with its own current-thread runtime.
+    #[default]
+    Thread,
+    /// The host's `LocalSet`, on the main thread.
+    Local,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct RuntimeOptions {
+    /// Flavor of the host's main runtime.
+    #[arg(long = "runtime", value_enum, default_value_t = Flavor::MultiThread)]
+    pub flavor: Flavor,
+
+    /// Worker threads of a multi-threaded main runtime; at least one.
+    #[arg(
+        long,
+        default_value_t = 4,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub worker_threads: usize,
+
+    /// Name of the main runtime's worker threads.
+    #[arg(long, default_value = "tokio-runtime-worker")]
+    pub worker_thread_name: String,
+
+    /// Where the Cap'n Proto provider runs.
+    #[arg(long, value_enum, default_value_t = ProviderPlacement::Thread)]
+    pub provider: ProviderPlacement,
+
+    /// Name of the provider's thread.
+    #[arg(long, default_value = "rpc-provider")]
+    pub provider_thread_name: String,
+
+    /// Pin the main runtime's threads to these CPU cores (comma-separated), one core per thread
+    /// in turn. Blocking-pool threads are pinned too.
+    #[arg(long, value_name = "CORES", value_delimiter = ',')]
+    pub worker_cores: Vec<usize>,
+
+    /// Pin the provider's thread to this CPU core. Ignored with `--provider local`, where the
+    /// provider runs on the main thread.
+    #[arg(long, value_name = "CORE")]
+    pub provider_core: Option<usize>,
+}
+
+/// The `[runtime]` table of a `--config` file. Keys are named after the command-line options,
+/// with `flavor` for `--runtime`:
+///
+/// ```toml
+/// [runtime]
+/// flavor = "multi-thread"
+/// worker-threads = 8
+/// provider = "thread"
+/// worker-cores = [2, 3]
+/// provider-core = 1
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RuntimeFile {
+    flavor: Option<Flavor>,
+    worker_threads: Option<usize>,
+    worker_thread_name: Option<String>,
+    provider: Option<ProviderPlacement>,
+    provider_thread_name: Option<String>,
+    worker_cores: Option<Vec<usize>>,
+    provider_core: Option<usize>,
+}
+
+impl RuntimeOptions {
+    /// Take the settings in `file` for every option `matches` didn't get from the command line.
+    pub fn merge(&mut self, file: RuntimeFile, matches: &ArgMatches) -> Result<(), String> {
+        // Only options typed on the command line win; defaults give way to the file.
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        if let Some(flavor) = file.flavor.filter(|_| unset("flavor")) {
+            self.flavor = flavor;
+        }
+        if let Some(threads) = file.worker_threads.filter(|_| unset("worker_threads")) {
+            if threads == 0 {
+                return Err("runtime.worker-threads must be at least 1".to_string());
+            }
+            self.worker_threads = threads;
+        }
+        if let Some(name) = file
+            .worker_thread_name
+            .filter(|_| unset("worker_thread_name"))
+        {
+            self.worker_thread_name = name;
+        }
+        if let Some(provider) = file.provider.filter(|_| unset("provider")) {
+            self.provider = provider;
+        }
+        if let Some(name) = file
+            .provider_thread_name
+            .filter(|_| unset("provider_thread_name"))
+        {
+            self.provider_thread_name = name;
+        }
+        if let Some(cores) = file.worker_cores.filter(|_| unset("worker_cores")) {
+            self.worker_cores = cores;
+        }
+        if let Some(core) = file.provider_core.filter(|_| unset("provider_core")) {
+            self.provider_core = Some(core);
+        }
+        Ok(())
+    }
+
+    /// Build the host's main runtime, pinning its threads if asked to. A current-thread runtime
+    /// runs on the calling thread, which is pinned to the first worker core.
+    pub fn build(&self) -> io::Result<Runtime> {
+        self.check_cores()?;
+        let mut builder = match self.flavor {
+            Flavor::MultiThread => {
+                let mut builder = tokio::runtime::Builder::new_multi_thread();
+                builder.worker_threads(self.worker_threads);
+                builder
+            }
+            Flavor::CurrentThread => {
+                if let Some(&core) = self.worker_cores.first() {
+                    pin(core);
+                }
+                tokio::runtime::Builder::new_current_thread()
+            }
+        };
+        if !self.worker_cores.is_empty() {
+            let cores = Arc::new(self.worker_cores.clone());
+            let next = AtomicUsize::new(0);
+            builder.on_thread_start(move || {
+                pin(cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()]);
+            });
+        }
+        builder
+            .thread_name(self.worker_thread_name.clone())
+            .enable_all()
+            .build()
+    }
+
+    /// Fail up front on cores this process can't run on, rather than on every thread.
+    fn check_cores(&self) -> io::Result<()> {
+        let requested: Vec<usize> = self
+            .worker_cores
+            .iter()
+            .copied()
+            .chain(self.provider_core)
+            .collect();
+        if requested.is_empty() {
+            return Ok(());
+        }
+        let available = core_affinity::get_core_ids().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't list this host's CPU cores",
+            )
+        })?;
+        match requested
+            .into_iter()
+            .find(|core| !available.contains(&CoreId { id: *core }))
+        {
+            Some(core) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CPU core {core} is not available to this process"),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Pin the calling thread to `core`.
+fn pin(core: usize) {
+    if !core_affinity::set_for_current(CoreId { id: core }) {
+        // Runtime workers start before logging is set up; the up-front check makes this rare.
+        warn!(core, "failed to pin thread to CPU core");
+    }
+}
+
+/// A running Cap'n Proto provider.
+pub enum Provider<T> {
+    Thread(thread::JoinHandle<T>),
+    Local(tokio::task::JoinHandle<T>),
+}
+
+/// Start the provider `serve` builds, where `options` place it. `Local` placement must be called
+/// from within the host's `LocalSet`.
+pub fn spawn_provider<F, Fut>(
+    options: &RuntimeOptions,
+    span: Span,
+    serve: F,
+) -> Provider<Fut::Output>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future + 'static,
+    Fut::Output: Send + 'static,
+{
+    match options.provider {
+        ProviderPlacement::Thread => {
+            info!(name = %options.provider_thread_name, "Spawning RPC provider thread");
+            let core = options.provider_core;
+            let handle = thread::Builder::new()
+                .name(options.provider_thread_name.clone())
+                .spawn(move || {
+                    let rt = span.in_scope(|| {
+                        if let Some(core) = core {
+                            info!(core, "pinning provider thread");
+                            pin(core);
+                        }
+                        info!("building single-threaded Tokio runtime for provider");
+                        let rt = tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()
+                            .expect("failed to build Tokio runtime for provider");
+                        info!("provider runtime built; entering event loop");
+                        rt
+                    });
+                    // The span goes with the future rather than staying entered on the thread,
+                    // so tasks the provider spawns don't inherit it by accident.
+                    rt.block_on(serve().instrument(span))
+                })
+                .expect("failed to spawn provider thread");
+            Provider::Thread(handle)
+        }
+        ProviderPlacement::Local => {
+            info!("running the RPC provider on the host's LocalSet");
+            if options.provider_core.is_some() {
+                warn!("--provider-core has no effect with --provider local");
+            }
+            Provider::Local(tokio::task::spawn_local(serve().instrument(span)))
+        }
+    }
+}
+
+impl<T: Send + 'static> Provider<T> {
+    /// Wait for the provider to finish; `None` if it panicked.
+    pub async fn join(self) -> Option<T> {
+        match self {
+            // Joined off the runtime's workers, so timers keep running meanwhile.
+            Provider::Thread(handle) => tokio::task::spawn_blocking(move || handle.join())
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Provider::Local(handle) => handle.await.ok(),
+        }
+    }
+}
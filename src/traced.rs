@@ -0,0 +1,96 @@
+//! Per-call tracing spans.
+//!
+//! Host tracing nests by what it belongs to: each guest connection has a long-lived
+//! `connection` span, the guest instance on it a `guest` span, and each capability call the
+//! guest makes an `rpc` span under that. The wrappers here open the `rpc` spans, outermost on the
+//! provider thread. The provider may run on a thread of its own, where the guest's span is never
+//! entered, so the call spans name it as their parent explicitly.
+
+use cap::echo_capnp::{echoer, echoer_provider};
+use cap::layer::{self, Layer};
+use cap::proxy::{Forward, Method};
+use capnp::any_pointer;
+use capnp::capability::{Promise, Response};
+use capnp_rpc::pry;
+use tracing::{Instrument, Span};
+
+/// `EchoerProvider` that runs every call in a span under `guest`, and hands out traced echoers.
+pub struct TracedEchoerProvider {
+    guest: Span,
+}
+
+impl TracedEchoerProvider {
+    pub fn client(inner: echoer_provider::Client, guest: Span) -> echoer_provider::Client {
+        layer::provider(inner, Self { guest })
+    }
+}
+
+impl Layer for TracedEchoerProvider {
+    fn around(
+        &self,
+        method: Method,
+        call: Forward,
+    ) -> Promise<Response<any_pointer::Owned>, capnp::Error> {
+        let span = tracing::debug_span!(parent: &self.guest, "rpc", method = layer::name(method));
+        // Sent from inside the span, so the layers below run in it too.
+        Promise::from_future(async move { call.send().await }.instrument(span))
+    }
+
+    fn echoer(&self, inner: echoer::Client) -> echoer::Client {
+        capnp_rpc::new_client(TracedEchoer {
+            inner,
+            guest: self.guest.clone(),
+        })
+    }
+}
+
+struct TracedEchoer {
+    inner: echoer::Client,
+    guest: Span,
+}
+
+impl echoer::Server for TracedEchoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let call_id = params.get_call_id();
+        let span =
+            tracing::debug_span!(parent: &self.guest, "rpc", method = "Echoer.echo", call_id);
+        let mut request = self.inner.echo_request();
+        request.get().set_msg(pry!(params.get_msg()));
+        request.get().set_call_id(call_id);
+        Promise::from_future(
+            async move {
+                let response = request.send().promise.await?;
+                results.get().set_reply(response.get()?.get_reply()?);
+                Ok(())
+            }
+            .instrument(span),
+        )
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let call_id = params.get_call_id();
+        let span =
+            tracing::debug_span!(parent: &self.guest, "rpc", method = "Echoer.echoBatch", call_id);
+        let mut request = self.inner.echo_batch_request();
+        pry!(request.get().set_msgs(pry!(params.get_msgs())));
+        request.get().set_call_id(call_id);
+        Promise::from_future(
+            async move {
+                let response = request.send().promise.await?;
+                results.get().set_replies(response.get()?.get_replies()?)?;
+                Ok(())
+            }
+            .instrument(span),
+        )
+    }
+}
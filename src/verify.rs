@@ -0,0 +1,159 @@
+//! Guest binary verification.
+//!
+//! Before a guest is compiled, its SHA-256 can be checked against an allowlist and a detached
+//! ed25519 signature can be required from one of a set of trusted keys. Either check failing
+//! refuses the guest, so unknown or tampered binaries never run. Signatures are checked strictly:
+//! weak (small-order) keys and non-canonical signatures are refused, since they let a signature
+//! pass for more than one message.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct VerifyOptions {
+    /// Only run guests whose SHA-256 is listed in this file: one lowercase hex digest per line,
+    /// optionally followed by a comment; `#` starts a comment line.
+    #[arg(long, value_name = "FILE")]
+    pub allowlist: Option<PathBuf>,
+
+    /// Require a detached ed25519 signature of the guest from this key (hex-encoded, 32 bytes).
+    /// Repeatable; any one trusted key is enough. The signature is read from `<wasm>.sig`, as
+    /// hex or raw 64 bytes.
+    #[arg(long, value_name = "HEX")]
+    pub trusted_key: Vec<String>,
+}
+
+/// Check `wasm_bytes`, loaded from `wasm_path`, against the configured policy.
+pub fn verify(
+    options: &VerifyOptions,
+    wasm_path: &Path,
+    wasm_bytes: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let digest = hex::encode(Sha256::digest(wasm_bytes));
+
+    if let Some(allowlist) = &options.allowlist {
+        let allowed = read_allowlist(allowlist)?;
+        if !allowed.contains(&digest) {
+            return Err(format!(
+                "guest {} (sha256 {digest}) is not in the allowlist {}",
+                wasm_path.display(),
+                allowlist.display()
+            )
+            .into());
+        }
+        info!(sha256 = %digest, "guest hash is allowlisted");
+    }
+
+    if !options.trusted_key.is_empty() {
+        let keys = options
+            .trusted_key
+            .iter()
+            .map(|key| parse_key(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let signature = read_signature(&signature_path(wasm_path))?;
+        if !keys
+            .iter()
+            .any(|key| key.verify_strict(wasm_bytes, &signature).is_ok())
+        {
+            return Err(format!(
+                "guest {} has no valid signature from a trusted key",
+                wasm_path.display()
+            )
+            .into());
+        }
+        info!(sha256 = %digest, "guest signature verified");
+    }
+    Ok(())
+}
+
+fn read_allowlist(path: &Path) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read allowlist {}: {e}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
+
+fn parse_key(key: &str) -> Result<VerifyingKey, Box<dyn std::error::Error>> {
+    let bytes: [u8; 32] = hex::decode(key.trim())?
+        .try_into()
+        .map_err(|_| format!("trusted key {key:?} is not 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+fn signature_path(wasm_path: &Path) -> PathBuf {
+    let mut path = wasm_path.as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+fn read_signature(path: &Path) -> Result<Signature, Box<dyn std::error::Error>> {
+    let raw =
+        fs::read(path).map_err(|e| format!("cannot read signature {}: {e}", path.display()))?;
+    // Accept either the raw 64 bytes or their hex encoding.
+    let bytes = match std::str::from_utf8(&raw) {
+        Ok(text) if raw.len() != 64 => hex::decode(text.trim())?,
+        _ => raw,
+    };
+    let bytes: [u8; 64] = bytes
+        .try_into()
+        .map_err(|_| format!("signature {} is not 64 bytes", path.display()))?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    const GUEST: &[u8] = b"\0asm guest bytes";
+
+    // Write the guest and its signature to a fresh directory, and check them against `key`.
+    fn check(name: &str, key: [u8; 32], signature: [u8; 64]) -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("verify-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wasm_path = dir.join("guest.wasm");
+        fs::write(&wasm_path, GUEST).unwrap();
+        fs::write(signature_path(&wasm_path), hex::encode(signature)).unwrap();
+        let options = VerifyOptions {
+            allowlist: None,
+            trusted_key: vec![hex::encode(key)],
+        };
+        let result = verify(&options, &wasm_path, GUEST).map_err(|e| e.to_string());
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn a_signature_from_a_trusted_key_passes() {
+        let signer = SigningKey::from_bytes(&[7; 32]);
+        let signature = signer.sign(GUEST).to_bytes();
+        check("good", signer.verifying_key().to_bytes(), signature).unwrap();
+
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let err = check("other", other.verifying_key().to_bytes(), signature).unwrap_err();
+        assert!(err.contains("no valid signature"), "{err}");
+    }
+
+    #[test]
+    fn a_weak_key_is_refused() {
+        // The identity point as the key, and as the signature's R with s = 0: this "signs" every
+        // message, and only strict verification turns it down.
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&identity);
+        let err = check("weak", identity, signature).unwrap_err();
+        assert!(err.contains("no valid signature"), "{err}");
+    }
+}
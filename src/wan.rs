@@ -0,0 +1,269 @@
+//! Wide-area network emulation for the RPC transport.
+//!
+//! In-memory pipes deliver every byte instantly, which hides how pipelining and batching behave
+//! over a real link. [`reader`] and [`writer`] splice a simulated link into one direction of a
+//! pipe: each chunk is held for half the round-trip time, plus or minus a random jitter, plus
+//! its serialization time at the configured bandwidth. Chunks are never reordered, so a jittery
+//! chunk also holds up the ones behind it, as on a single TCP stream.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{Instrument, warn};
+
+use crate::seed::SplitMix64;
+
+// Largest chunk the link moves at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+// Chunks in flight per direction before the sender is pushed back.
+const IN_FLIGHT_CHUNKS: usize = 1024;
+
+/// Link parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct Wan {
+    /// Round-trip time; each direction adds half of it.
+    pub rtt: Duration,
+    /// Largest random deviation from the one-way delay, either way.
+    pub jitter: Duration,
+    /// Link bandwidth in bytes per second; `None` is unlimited.
+    pub bandwidth: Option<u64>,
+}
+
+/// Return a stream that yields what `src` yields, delayed by the link.
+pub fn reader<R>(src: R, wan: Wan, buffer: usize, seed: u64) -> DuplexStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (near, far) = tokio::io::duplex(buffer);
+    tokio::spawn(forward(src, far, wan, seed).in_current_span());
+    near
+}
+
+/// Return a stream whose writes reach `dst` delayed by the link.
+pub fn writer<W>(dst: W, wan: Wan, buffer: usize, seed: u64) -> DuplexStream
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (near, far) = tokio::io::duplex(buffer);
+    tokio::spawn(forward(far, dst, wan, seed).in_current_span());
+    near
+}
+
+/// Copy `from` into `to`, holding each chunk until the link would have delivered it.
+async fn forward<R, W>(mut from: R, mut to: W, wan: Wan, seed: u64)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(IN_FLIGHT_CHUNKS);
+
+    let send = async move {
+        let mut jitter = Jitter::new(seed, wan.jitter);
+        let mut link_free = Instant::now();
+        let mut last_delivery = Instant::now();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let n = match from.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    warn!(target: "wan", error = %e, "emulated link read failed");
+                    break;
+                }
+            };
+            let now = Instant::now();
+            link_free = link_free.max(now) + serialization(n, wan.bandwidth);
+            let one_way =
+                Duration::from_secs_f64(((wan.rtt / 2).as_secs_f64() + jitter.next()).max(0.0));
+            last_delivery = last_delivery.max(link_free + one_way);
+            if tx.send((last_delivery, chunk[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let deliver = async move {
+        while let Some((at, bytes)) = rx.recv().await {
+            tokio::time::sleep_until(at).await;
+            if let Err(e) = to.write_all(&bytes).await {
+                warn!(target: "wan", error = %e, "emulated link write failed");
+                return;
+            }
+        }
+        // The sender hung up: pass the EOF along once everything has been delivered.
+        let _ = to.shutdown().await;
+    };
+
+    tokio::join!(send, deliver);
+}
+
+fn serialization(bytes: usize, bandwidth: Option<u64>) -> Duration {
+    match bandwidth {
+        Some(rate) if rate > 0 => Duration::from_secs_f64(bytes as f64 / rate as f64),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Uniform jitter in `[-max, +max]`, from a splitmix64 stream.
+struct Jitter {
+    rng: SplitMix64,
+    max: Duration,
+}
+
+impl Jitter {
+    fn new(seed: u64, max: Duration) -> Self {
+        Self {
+            rng: SplitMix64::new(seed),
+            max,
+        }
+    }
+
+    /// The next deviation, in seconds.
+    fn next(&mut self) -> f64 {
+        if self.max.is_zero() {
+            return 0.0;
+        }
+        // Map to [-1, 1) and scale.
+        let unit = 2.0 * self.rng.next_f64() - 1.0;
+        unit * self.max.as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn link(rtt_ms: u64, jitter_ms: u64, bandwidth: Option<u64>) -> Wan {
+        Wan {
+            rtt: MS * rtt_ms as u32,
+            jitter: MS * jitter_ms as u32,
+            bandwidth,
+        }
+    }
+
+    /// Check that `expected` has passed since `start`, to the timer's resolution of 1 ms.
+    fn assert_elapsed(start: Instant, expected: Duration) {
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= expected && elapsed <= expected + MS,
+            "{elapsed:?} elapsed, expected {expected:?}"
+        );
+    }
+
+    /// Send one byte through a link every 5 ms, and return when each arrived.
+    async fn arrivals(wan: Wan, seed: u64, count: u8) -> Vec<Duration> {
+        let (mut input, src) = duplex(1024);
+        let mut output = reader(src, wan, 1024, seed);
+        let start = Instant::now();
+        let send = async {
+            for i in 0..count {
+                input.write_all(&[i]).await.unwrap();
+                tokio::time::sleep(5 * MS).await;
+            }
+        };
+        let receive = async {
+            let mut arrived = Vec::new();
+            for i in 0..count {
+                assert_eq!(output.read_u8().await.unwrap(), i, "chunks were reordered");
+                arrived.push(start.elapsed());
+            }
+            arrived
+        };
+        tokio::join!(send, receive).1
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_direction_takes_half_the_round_trip() {
+        let (mut input, src) = duplex(1024);
+        let mut output = reader(src, link(100, 0, None), 1024, 1);
+        let start = Instant::now();
+        input.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        output.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        assert_elapsed(start, 50 * MS);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_bounds() {
+        let max = 20 * MS;
+        let mut jitter = Jitter::new(7, max);
+        let deviations: Vec<f64> = (0..10_000).map(|_| jitter.next()).collect();
+        assert!(deviations.iter().all(|d| d.abs() <= max.as_secs_f64()));
+        // Both ways, and spread over most of the range.
+        let (min, max_seen) = deviations
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(lo, hi), &d| (lo.min(d), hi.max(d)));
+        assert!(min < -0.019 && max_seen > 0.019, "{min} {max_seen}");
+        assert_eq!(Jitter::new(7, Duration::ZERO).next(), 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jittered_deliveries_stay_in_bounds_and_repeat_per_seed() {
+        let wan = link(100, 40, None);
+        let arrived = arrivals(wan, 1, 50).await;
+        for (i, at) in arrived.iter().enumerate() {
+            // Sent at 5 ms * i, then held for 50 ms +- 40 ms; a chunk held up behind an earlier
+            // one still arrives within the bound of its own send time, since sends are ordered.
+            let sent = 5 * MS * i as u32;
+            assert!(
+                *at >= sent + 10 * MS && *at <= sent + 91 * MS,
+                "chunk {i} sent at {sent:?} arrived at {at:?}"
+            );
+        }
+        assert_eq!(arrivals(wan, 1, 50).await, arrived);
+        assert_ne!(arrivals(wan, 2, 50).await, arrived);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_adds_serialization_time() {
+        // 1000 bytes/s: a byte takes a millisecond to put on the link, on top of the 10 ms delay.
+        let (mut input, src) = duplex(1024);
+        let mut output = reader(src, link(20, 0, Some(1000)), 1024, 1);
+        let start = Instant::now();
+        input.write_all(&[1; 100]).await.unwrap();
+        output.read_exact(&mut [0; 100]).await.unwrap();
+        assert_elapsed(start, 110 * MS);
+        input.write_all(&[2; 500]).await.unwrap();
+        output.read_exact(&mut [0; 500]).await.unwrap();
+        assert_elapsed(start, 620 * MS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chunks_are_never_reordered() {
+        // The jitter is larger than the delay and the gaps between chunks, so without ordering
+        // later chunks would routinely overtake earlier ones; `arrivals` checks the order.
+        let arrived = arrivals(link(10, 50, None), 3, 100).await;
+        assert!(arrived.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn eof_follows_the_last_delivery() {
+        let (mut input, src) = duplex(1024);
+        let mut output = reader(src, link(100, 0, None), 1024, 1);
+        let start = Instant::now();
+        input.write_all(b"last words").await.unwrap();
+        drop(input);
+        let mut received = Vec::new();
+        output.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"last words");
+        assert_elapsed(start, 50 * MS);
+
+        // The same through a writer: shutting it down reaches the far end after the data.
+        let (dst, mut far) = duplex(1024);
+        let mut near = writer(dst, link(100, 0, None), 1024, 1);
+        let start = Instant::now();
+        near.write_all(b"bye").await.unwrap();
+        near.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        far.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+        assert_elapsed(start, 50 * MS);
+    }
+}
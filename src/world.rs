@@ -0,0 +1,70 @@
+//! Host side of the `wetware:guest` WIT world (see `wit/guest.wit`).
+
+use tracing::{debug, error, info, trace, warn};
+use wasmtime::component::{HasSelf, Linker, Resource};
+use wasmtime_wasi_io::streams::{DynInputStream, DynOutputStream};
+
+use crate::ComponentRunStates;
+
+wasmtime::component::bindgen!({
+    path: "wit",
+    world: "guest",
+    imports: { default: async },
+    with: {
+        "wasi": wasmtime_wasi::p2::bindings,
+        "wetware:guest/capnp-bridge/echoer-provider": crate::bridge::BridgedEchoerProvider,
+        "wetware:guest/capnp-bridge/echoer": crate::bridge::BridgedEchoer,
+        "wetware:guest/capnp-bridge/log-tail": crate::bridge::BridgedLogTail,
+        "wetware:guest/capnp-bridge/log-sink": crate::bridge::BridgedLogSink,
+    },
+});
+
+pub use wetware::guest::{capnp_bridge, lifecycle, logging, transport};
+
+/// Register every `wetware:guest` host import in the linker. WASI itself is added separately.
+pub fn add_to_linker(linker: &mut Linker<ComponentRunStates>) -> wasmtime::Result<()> {
+    transport::add_to_linker::<_, HasSelf<_>>(linker, |state| state)?;
+    lifecycle::add_to_linker::<_, HasSelf<_>>(linker, |state| state)?;
+    logging::add_to_linker::<_, HasSelf<_>>(linker, |state| state)?;
+    capnp_bridge::add_to_linker::<_, HasSelf<_>>(linker, |state| state)?;
+    Ok(())
+}
+
+impl transport::Host for ComponentRunStates {
+    async fn rpc_streams(
+        &mut self,
+    ) -> Result<(Resource<DynInputStream>, Resource<DynOutputStream>), String> {
+        let (input, output) = self
+            .rpc_streams
+            .take()
+            .ok_or_else(|| "RPC streams were already taken".to_string())?;
+        let input = self.resource_table.push(input).map_err(|e| e.to_string())?;
+        let output = self
+            .resource_table
+            .push(output)
+            .map_err(|e| e.to_string())?;
+        Ok((input, output))
+    }
+}
+
+impl lifecycle::Host for ComponentRunStates {
+    async fn ready(&mut self) {
+        info!("guest reported ready");
+    }
+
+    async fn heartbeat(&mut self) {
+        self.heartbeat.beat();
+    }
+}
+
+impl logging::Host for ComponentRunStates {
+    async fn log(&mut self, level: logging::Level, target: String, message: String) {
+        match level {
+            logging::Level::Trace => trace!(target: "guest", guest_target = %target, "{message}"),
+            logging::Level::Debug => debug!(target: "guest", guest_target = %target, "{message}"),
+            logging::Level::Info => info!(target: "guest", guest_target = %target, "{message}"),
+            logging::Level::Warn => warn!(target: "guest", guest_target = %target, "{message}"),
+            logging::Level::Error => error!(target: "guest", guest_target = %target, "{message}"),
+        }
+    }
+}
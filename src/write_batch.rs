@@ -0,0 +1,187 @@
+//! Batched writes on the provider's side of a connection.
+//!
+//! Without batching, the `RpcSystem` writes each outgoing message to the transport itself and
+//! waits for the pipe whenever it is slow. [`spawn`] puts a bounded channel and a writer task in
+//! between. The connection's writes only copy the message into the channel, and the task writes
+//! whatever has queued up since its last write as one batch, then flushes once. A burst of small
+//! replies then costs one pipe write instead of one each. When the channel is full, writes wait
+//! for the task, so the pipe still applies back-pressure.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
+use tracing::{Instrument, debug};
+
+// Largest batch the writer task builds before writing.
+const MAX_BATCH: usize = 256 * 1024;
+
+/// Write half of a connection, fed to a writer task through a channel of `capacity` writes.
+pub fn spawn<W>(inner: W, capacity: usize) -> Batched
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(capacity);
+    let (done_tx, done_rx) = oneshot::channel();
+    tokio::spawn(
+        async move {
+            if let Err(e) = drain(rx, inner).await {
+                debug!(target: "write_batch", error = %e, "batched writer failed");
+            }
+            let _ = done_tx.send(());
+        }
+        .in_current_span(),
+    );
+    Batched {
+        sender: PollSender::new(tx),
+        done: done_rx,
+    }
+}
+
+async fn drain<W: AsyncWrite + Unpin>(
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    mut inner: W,
+) -> io::Result<()> {
+    let mut batch = Vec::new();
+    let (mut batches, mut writes, mut largest) = (0u64, 0u64, 0usize);
+    while let Some(first) = rx.recv().await {
+        batch.extend_from_slice(&first);
+        writes += 1;
+        while batch.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(next) => {
+                    batch.extend_from_slice(&next);
+                    writes += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        inner.write_all(&batch).await?;
+        inner.flush().await?;
+        batches += 1;
+        largest = largest.max(batch.len());
+        batch.clear();
+    }
+    debug!(target: "write_batch", batches, writes, largest, "batched writer finished");
+    inner.shutdown().await
+}
+
+pub struct Batched {
+    sender: PollSender<Vec<u8>>,
+    done: oneshot::Receiver<()>,
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "batched writer task has stopped")
+}
+
+impl AsyncWrite for Batched {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.sender.poll_reserve(cx)).map_err(|_| writer_gone())?;
+        self.sender
+            .send_item(buf.to_vec())
+            .map_err(|_| writer_gone())?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Queued writes are flushed by the writer task after each batch; waiting for them here
+    /// would put the pipe back on the connection's critical path.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Closes the channel, then waits for the task to write out what was queued and shut down
+    /// the transport.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sender.close();
+        let _ = ready!(Pin::new(&mut self.done).poll(cx));
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    // Records each write it is given, and whether it was shut down.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+        shut_down: Arc<Mutex<bool>>,
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            *self.shut_down.lock().unwrap() = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn a_burst_is_written_as_one_batch() {
+        let recorder = Recorder::default();
+        let mut batched = spawn(recorder.clone(), 8);
+        // The writer task doesn't run until this task yields, so all three queue up.
+        for message in [&b"one"[..], b"two", b"three"] {
+            batched.write_all(message).await.unwrap();
+        }
+        batched.shutdown().await.unwrap();
+        assert_eq!(*recorder.writes.lock().unwrap(), [b"onetwothree".to_vec()]);
+        assert!(*recorder.shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_writer_ends_the_stream_after_what_was_queued() {
+        let (inner, mut peer) = tokio::io::duplex(64);
+        let mut batched = spawn(inner, 1);
+        batched.write_all(b"queued").await.unwrap();
+        drop(batched);
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"queued");
+    }
+
+    #[tokio::test]
+    async fn a_full_channel_holds_writes_back() {
+        let (inner, mut peer) = tokio::io::duplex(4);
+        let mut batched = spawn(inner, 1);
+        // The pipe takes 4 bytes and the channel one more write; the rest waits for the peer.
+        let writes = tokio::spawn(async move {
+            for _ in 0..4 {
+                batched.write_all(b"12345678").await.unwrap();
+            }
+            batched.shutdown().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        assert!(!writes.is_finished());
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"12345678".repeat(4));
+        writes.await.unwrap();
+    }
+}
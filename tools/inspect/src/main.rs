@@ -0,0 +1,99 @@
+//! Debugging utilities for the host's output files.
+//!
+//! `inspect capture <FILE>` prints a `--capture` file one record at a time, with each payload
+//! decoded by its method's schema (see `cap::capture`). `--raw` prints the payloads as hex
+//! instead, and `--method` and `--call` narrow the output down.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use cap::capture_capnp::{payload, record};
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(about = "Inspect the files the host writes")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the records of a payload capture.
+    Capture {
+        /// Capture file written with the host's `--capture`.
+        path: PathBuf,
+
+        /// Only print calls to this method, e.g. `Echoer.echo`.
+        #[arg(long)]
+        method: Option<String>,
+
+        /// Only print the call with this id.
+        #[arg(long)]
+        call: Option<u64>,
+
+        /// Print payloads as hex instead of decoding them.
+        #[arg(long)]
+        raw: bool,
+    },
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    match Args::parse().command {
+        Command::Capture {
+            path,
+            method,
+            call,
+            raw,
+        } => {
+            let mut printed = 0;
+            for message in cap::capture::read(BufReader::new(File::open(&path)?)) {
+                let message = message?;
+                let record: record::Reader = message.get_root()?;
+                let record_method = record.get_method()?.to_str()?;
+                if method.as_deref().is_some_and(|m| m != record_method)
+                    || call.is_some_and(|c| c != record.get_call_id())
+                {
+                    continue;
+                }
+                if raw {
+                    print_raw(record)?;
+                } else {
+                    print!("{}", cap::capture::render(record)?);
+                }
+                printed += 1;
+            }
+            eprintln!("{printed} record(s)");
+        }
+    }
+    Ok(())
+}
+
+fn print_raw(record: record::Reader) -> capnp::Result<()> {
+    let method = record.get_method()?.to_str()?;
+    let (side, payload) = match record.which()? {
+        record::Params(payload) => ("params", payload?),
+        record::Results(payload) => ("results", payload?),
+        record::Error(error) => {
+            println!(
+                "#{} {method} error: {}",
+                record.get_call_id(),
+                error?.to_str()?
+            );
+            return Ok(());
+        }
+    };
+    let bytes = match payload.which()? {
+        payload::Full(value) => {
+            let mut message = capnp::message::Builder::new_default();
+            message.set_root(value)?;
+            capnp::serialize::write_message_to_words(&message)
+        }
+        payload::Truncated(prefix) => prefix?.to_vec(),
+        payload::Omitted(()) => Vec::new(),
+    };
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    println!("#{} {method} {side}: {hex}", record.get_call_id());
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! The smallest pipe buffer a scenario completes with (`--bisect-buffer`).
+//!
+//! Transport deadlocks show up below some pipe size: both directions fill up, and each writer
+//! waits on a reader that is itself waiting. `--bisect-buffer` takes one scenario, the first value
+//! of each matrix option, and searches for the smallest `--pipe-buffer` it still completes with,
+//! between `--bisect-min` and the first `--pipe-buffers` value. Every probe is an ordinary
+//! scenario run with the same seed and time budget, so a hang shows up as a timeout. The search
+//! assumes a scenario that completes at one size completes at every larger one. Each probe is
+//! printed, so a run where that doesn't hold can be spotted.
+
+use crate::{Args, Scenario, Status, run_scenario};
+
+/// Where the search ended.
+#[derive(Debug, PartialEq, Eq)]
+enum Threshold {
+    /// Even the smallest size tried completes.
+    Minimum(usize),
+    /// `completes` is the smallest size that completes; one byte less fails.
+    Found { completes: usize, fails: usize },
+}
+
+/// Bisect the pipe buffer of `scenario`, from `args.bisect_min` up to its own, and print the
+/// threshold.
+pub fn run(args: &Args, scenario: Scenario, seed: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let mut probes = 0;
+    eprintln!("bisecting the pipe buffer of {scenario}");
+    let threshold = search(args.bisect_min, scenario.pipe_buffer, |pipe_buffer| {
+        let probe = Scenario {
+            pipe_buffer,
+            ..scenario
+        };
+        let result = run_scenario(args, probe, probes, seed)?;
+        probes += 1;
+        eprintln!(
+            "  {pipe_buffer:>10} bytes: {:?} in {} ms",
+            result.status, result.wall_ms
+        );
+        Ok(result.status == Status::Ok)
+    })
+    .map_err(|e| format!("{scenario}: {e}"))?;
+    match threshold {
+        Threshold::Minimum(low) => {
+            println!("{scenario}: completes with the minimum of {low} bytes")
+        }
+        Threshold::Found { completes, fails } => println!(
+            "{scenario}: smallest pipe buffer that completes is {completes} bytes ({fails} fails)"
+        ),
+    }
+    Ok(())
+}
+
+/// Search `low..=high` for the smallest size `completes` holds for. A probe that can't be run at
+/// all ends the search with its error.
+fn search(
+    mut low: usize,
+    mut high: usize,
+    mut completes: impl FnMut(usize) -> Result<bool, Box<dyn std::error::Error>>,
+) -> Result<Threshold, Box<dyn std::error::Error>> {
+    if low >= high {
+        return Err(format!("--bisect-min {low} must be below the pipe buffer {high}").into());
+    }
+    if !completes(high)? {
+        return Err(format!("doesn't complete even with {high} bytes").into());
+    }
+    if completes(low)? {
+        return Ok(Threshold::Minimum(low));
+    }
+    // From here on, `low` fails and `high` completes.
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if completes(mid)? {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+    Ok(Threshold::Found {
+        completes: high,
+        fails: low,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scenario that completes from `threshold` bytes up, recording every size it runs with.
+    fn fake(
+        threshold: usize,
+        probes: &mut Vec<usize>,
+    ) -> impl FnMut(usize) -> Result<bool, Box<dyn std::error::Error>> {
+        move |size| {
+            probes.push(size);
+            Ok(size >= threshold)
+        }
+    }
+
+    #[test]
+    fn the_smallest_completing_size_is_found() {
+        for threshold in [2, 3, 100, 4095, 4096] {
+            let mut probes = Vec::new();
+            let found = search(1, 4096, fake(threshold, &mut probes)).unwrap();
+            assert_eq!(
+                found,
+                Threshold::Found {
+                    completes: threshold,
+                    fails: threshold - 1
+                }
+            );
+            // Both ends, then a binary search of the range between them.
+            assert_eq!(probes[..2], [4096, 1]);
+            assert!(probes.len() <= 2 + 12, "{probes:?}");
+        }
+    }
+
+    #[test]
+    fn a_scenario_that_always_completes_stops_at_the_minimum() {
+        let mut probes = Vec::new();
+        assert_eq!(
+            search(64, 4096, fake(0, &mut probes)).unwrap(),
+            Threshold::Minimum(64)
+        );
+        assert_eq!(probes, [4096, 64]);
+    }
+
+    #[test]
+    fn a_scenario_that_never_completes_is_an_error() {
+        let mut probes = Vec::new();
+        assert!(search(64, 4096, fake(usize::MAX, &mut probes)).is_err());
+        assert_eq!(probes, [4096]);
+    }
+
+    #[test]
+    fn a_probe_that_cannot_run_ends_the_search() {
+        let mut probes = 0;
+        let result = search(1, 4096, |size| {
+            probes += 1;
+            if size == 4096 {
+                Ok(true)
+            } else if size == 1 {
+                Ok(false)
+            } else {
+                Err("host binary missing".into())
+            }
+        });
+        assert_eq!(result.unwrap_err().to_string(), "host binary missing");
+        assert_eq!(probes, 3);
+    }
+
+    #[test]
+    fn an_empty_range_is_refused() {
+        assert!(search(4096, 4096, |_| Ok(true)).is_err());
+        assert!(search(8192, 4096, |_| Ok(true)).is_err());
+    }
+}
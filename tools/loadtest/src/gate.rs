@@ -0,0 +1,151 @@
+//! Regression gate: compare a run against a stored baseline.
+//!
+//! A baseline is the `--json` output of an earlier run, checked in. Each scenario present in both
+//! is compared on throughput and on p99 latency. It regresses when throughput drops, or p99
+//! latency grows, by more than the allowed percentage. Scenarios that passed in the baseline but
+//! now fail or time out regress too. Scenarios missing from either side are only reported.
+
+use std::path::Path;
+
+use crate::{ScenarioResult, Status};
+
+pub fn load(path: &Path) -> Result<Vec<ScenarioResult>, Box<dyn std::error::Error>> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read baseline {}: {e}", path.display()))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// Print how each scenario moved against `baseline` and return how many regressed.
+pub fn compare(results: &[ScenarioResult], baseline: &[ScenarioResult], max_percent: f64) -> usize {
+    let mut regressions = 0;
+    for result in results {
+        let scenario = result.scenario;
+        let Some(base) = baseline.iter().find(|b| b.scenario == scenario) else {
+            println!("new       {scenario}: no baseline");
+            continue;
+        };
+        if base.status != Status::Ok {
+            println!("skipped   {scenario}: baseline was {:?}", base.status);
+            continue;
+        }
+        if result.status != Status::Ok {
+            regressions += 1;
+            println!("REGRESSED {scenario}: {:?}", result.status);
+            continue;
+        }
+        let throughput = percent_change(base.throughput, result.throughput);
+        let p99 = percent_change(base.p99_us as f64, result.p99_us as f64);
+        let regressed = -throughput > max_percent || p99 > max_percent;
+        if regressed {
+            regressions += 1;
+        }
+        println!(
+            "{:<9} {scenario}: throughput {:.0} -> {:.0} ({throughput:+.1}%), \
+             p99 {} -> {} us ({p99:+.1}%)",
+            if regressed { "REGRESSED" } else { "ok" },
+            base.throughput,
+            result.throughput,
+            base.p99_us,
+            result.p99_us,
+        );
+    }
+    for base in baseline {
+        if !results.iter().any(|r| r.scenario == base.scenario) {
+            println!("missing   {}: not run", base.scenario);
+        }
+    }
+    regressions
+}
+
+fn percent_change(before: f64, after: f64) -> f64 {
+    if before == 0.0 {
+        return 0.0;
+    }
+    (after - before) / before * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Scenario, Transport};
+
+    fn scenario(payload: usize) -> Scenario {
+        Scenario {
+            payload,
+            concurrency: 10,
+            batches: 10,
+            pipe_buffer: 32 * 1024 * 1024,
+            transport: Transport::Pipe,
+            reuse: true,
+        }
+    }
+
+    fn result(payload: usize, throughput: f64, p99_us: u64) -> ScenarioResult {
+        let json = serde_json::json!({
+            "scenario": scenario(payload),
+            "status": Status::Ok,
+            "wall_ms": 1000,
+            "cpu_ms": 500,
+            "throughput": throughput,
+            "p50_us": p99_us / 2,
+            "p90_us": p99_us * 9 / 10,
+            "p99_us": p99_us,
+            "max_us": p99_us * 2,
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn a_throughput_drop_or_latency_rise_past_the_limit_regresses() {
+        let baseline = [result(16, 1000.0, 100), result(1024, 1000.0, 100)];
+        let slower = [result(16, 850.0, 100), result(1024, 1000.0, 120)];
+        assert_eq!(compare(&slower, &baseline, 10.0), 2);
+        // Within the allowed percentage, the same numbers pass.
+        assert_eq!(compare(&slower, &baseline, 25.0), 0);
+    }
+
+    #[test]
+    fn an_improvement_passes() {
+        let baseline = [result(16, 1000.0, 100)];
+        let faster = [result(16, 2000.0, 50)];
+        assert_eq!(compare(&faster, &baseline, 10.0), 0);
+    }
+
+    #[test]
+    fn a_scenario_that_now_fails_regresses() {
+        let baseline = [result(16, 1000.0, 100)];
+        let mut failed = result(16, 0.0, 0);
+        failed.status = Status::TimedOut;
+        assert_eq!(compare(&[failed], &baseline, 10.0), 1);
+    }
+
+    #[test]
+    fn scenarios_missing_from_either_side_are_only_reported() {
+        let baseline = [result(16, 1000.0, 100), result(1024, 1000.0, 100)];
+        let results = [result(16, 1000.0, 100), result(16384, 10.0, 100_000)];
+        assert_eq!(compare(&results, &baseline, 10.0), 0);
+        assert_eq!(compare(&results, &[], 10.0), 0);
+        assert_eq!(compare(&[], &baseline, 10.0), 0);
+    }
+
+    #[test]
+    fn a_metric_missing_from_either_side() {
+        // `result` leaves out the metrics added after the first baselines, which load as zero;
+        // the gated metrics are required.
+        let mut baseline = serde_json::to_value([result(16, 1000.0, 100)]).unwrap();
+        baseline[0].as_object_mut().unwrap().remove("p99_us");
+        assert!(serde_json::from_value::<Vec<ScenarioResult>>(baseline).is_err());
+        // A baseline without a measurement gives nothing to compare against.
+        let baseline = [result(16, 0.0, 0)];
+        assert_eq!(compare(&[result(16, 10.0, 100)], &baseline, 10.0), 0);
+        // A run that measured no throughput at all has dropped by 100%.
+        let baseline = [result(16, 1000.0, 100)];
+        assert_eq!(compare(&[result(16, 0.0, 100)], &baseline, 10.0), 1);
+    }
+
+    #[test]
+    fn the_checked_in_baseline_loads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("baseline.json");
+        load(&path).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+//! Run history: every scenario result, kept in a SQLite database across runs.
+//!
+//! With `--history`, each scenario's result is stored as a row keyed by the git commit the run
+//! was made at, the run's seed and the scenario, along with its JSON report. `--trends` reads the
+//! rows back instead of running anything, and prints each scenario's recent runs with its failure
+//! rate and throughput range, so performance and flakiness can be followed from commit to commit.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, params};
+
+use crate::{ScenarioResult, Status};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        recorded_at INTEGER NOT NULL,
+        git_commit TEXT NOT NULL,
+        seed INTEGER NOT NULL,
+        scenario TEXT NOT NULL,
+        status TEXT NOT NULL,
+        throughput REAL NOT NULL,
+        p99_us INTEGER NOT NULL,
+        report TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS runs_key ON runs (git_commit, seed, scenario);
+";
+
+pub fn open(path: &Path) -> Result<Connection, Box<dyn std::error::Error>> {
+    let db = Connection::open(path)
+        .map_err(|e| format!("cannot open history {}: {e}", path.display()))?;
+    db.execute_batch(SCHEMA)?;
+    Ok(db)
+}
+
+/// One recorded run of a scenario.
+struct Run {
+    commit: String,
+    seed: u64,
+    result: ScenarioResult,
+}
+
+/// The commit the working tree is at, marked `-dirty` if it has uncommitted changes.
+pub fn git_commit() -> String {
+    Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Store `results`, all made at `commit` with `seed`.
+pub fn record(
+    db: &mut Connection,
+    commit: &str,
+    seed: u64,
+    results: &[ScenarioResult],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let recorded_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let tx = db.transaction()?;
+    for result in results {
+        tx.execute(
+            "INSERT INTO runs
+                (recorded_at, git_commit, seed, scenario, status, throughput, p99_us, report)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                recorded_at,
+                commit,
+                // SQLite integers are signed; the bits round-trip.
+                seed as i64,
+                result.scenario.to_string(),
+                format!("{:?}", result.status),
+                result.throughput,
+                result.p99_us as i64,
+                serde_json::to_string(result)?,
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Print the last `last` runs of every scenario whose description contains `filter`, oldest
+/// first, each scenario followed by its failure rate and throughput range over those runs.
+pub fn print_trends(
+    db: &Connection,
+    filter: &str,
+    last: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let scenarios = scenarios(db, filter)?;
+    if scenarios.is_empty() {
+        println!("no recorded runs match {filter:?}");
+        return Ok(());
+    }
+    for scenario in scenarios {
+        let runs = recent_runs(db, &scenario, last)?;
+        println!("{scenario}");
+        let mut failed = 0;
+        let mut throughputs = Vec::new();
+        for Run {
+            commit,
+            seed,
+            result,
+        } in &runs
+        {
+            println!(
+                "  {commit:<18} seed {seed:<20} {:>9} {:>10.0} calls/s  p99 {:>7} us",
+                format!("{:?}", result.status),
+                result.throughput,
+                result.p99_us
+            );
+            if result.status == Status::Ok {
+                throughputs.push(result.throughput);
+            } else {
+                failed += 1;
+            }
+        }
+        let min = throughputs.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = throughputs.iter().copied().fold(0.0, f64::max);
+        if throughputs.is_empty() {
+            println!("  {failed}/{} failed", runs.len());
+        } else {
+            println!(
+                "  {failed}/{} failed, throughput {min:.0} to {max:.0} calls/s",
+                runs.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The recorded scenarios whose description contains `filter`, sorted.
+fn scenarios(db: &Connection, filter: &str) -> rusqlite::Result<Vec<String>> {
+    db.prepare(
+        "SELECT DISTINCT scenario FROM runs WHERE instr(scenario, ?1) > 0 ORDER BY scenario",
+    )?
+    .query_map([filter], |row| row.get(0))?
+    .collect()
+}
+
+/// The last `last` runs of `scenario`, oldest first.
+fn recent_runs(
+    db: &Connection,
+    scenario: &str,
+    last: usize,
+) -> Result<Vec<Run>, Box<dyn std::error::Error>> {
+    let mut rows = db
+        .prepare(
+            "SELECT git_commit, seed, report FROM runs WHERE scenario = ?1
+             ORDER BY id DESC LIMIT ?2",
+        )?
+        .query_map(params![scenario, last as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)? as u64,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    rows.reverse();
+    rows.into_iter()
+        .map(|(commit, seed, report)| {
+            Ok(Run {
+                commit,
+                seed,
+                result: serde_json::from_str(&report)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Scenario, Transport};
+
+    fn result(payload: usize, status: Status, throughput: f64) -> ScenarioResult {
+        let scenario = Scenario {
+            payload,
+            concurrency: 10,
+            batches: 10,
+            pipe_buffer: 32 * 1024 * 1024,
+            transport: Transport::Pipe,
+            reuse: true,
+        };
+        serde_json::from_value(serde_json::json!({
+            "scenario": scenario,
+            "status": status,
+            "wall_ms": 1000,
+            "cpu_ms": 500,
+            "throughput": throughput,
+            "p50_us": 50,
+            "p90_us": 90,
+            "p99_us": 99,
+            "max_us": 200,
+        }))
+        .unwrap()
+    }
+
+    fn memory_db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+        db
+    }
+
+    #[test]
+    fn recorded_runs_read_back() {
+        let mut db = memory_db();
+        let results = [
+            result(16, Status::Ok, 1000.0),
+            result(1024, Status::Failed, 0.0),
+        ];
+        record(&mut db, "abc123", u64::MAX, &results).unwrap();
+
+        let small = results[0].scenario.to_string();
+        let large = results[1].scenario.to_string();
+        let mut expected = vec![small.clone(), large.clone()];
+        expected.sort();
+        assert_eq!(scenarios(&db, "").unwrap(), expected);
+        assert_eq!(
+            scenarios(&db, "payload=1024 ").unwrap(),
+            std::slice::from_ref(&large)
+        );
+        assert!(scenarios(&db, "transport=mux").unwrap().is_empty());
+
+        let runs = recent_runs(&db, &small, 10).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].commit, "abc123");
+        assert_eq!(runs[0].seed, u64::MAX);
+        assert_eq!(runs[0].result.status, Status::Ok);
+        assert_eq!(runs[0].result.throughput, 1000.0);
+        let runs = recent_runs(&db, &large, 10).unwrap();
+        assert_eq!(runs[0].result.status, Status::Failed);
+    }
+
+    #[test]
+    fn recent_runs_are_the_last_ones_oldest_first() {
+        let mut db = memory_db();
+        for (i, commit) in ["first", "second", "third"].into_iter().enumerate() {
+            record(
+                &mut db,
+                commit,
+                i as u64,
+                &[result(16, Status::Ok, i as f64)],
+            )
+            .unwrap();
+        }
+        let scenario = result(16, Status::Ok, 0.0).scenario.to_string();
+        let commits = |last| {
+            recent_runs(&db, &scenario, last)
+                .unwrap()
+                .into_iter()
+                .map(|run| run.commit)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(commits(10), ["first", "second", "third"]);
+        assert_eq!(commits(2), ["second", "third"]);
+    }
+
+    #[test]
+    fn reopening_an_existing_history_keeps_its_runs() {
+        let path = std::env::temp_dir().join(format!("loadtest-history-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db = open(&path).unwrap();
+        record(&mut db, "abc123", 1, &[result(16, Status::Ok, 1000.0)]).unwrap();
+        drop(db);
+
+        let mut db = open(&path).unwrap();
+        record(&mut db, "def456", 2, &[result(16, Status::Ok, 1000.0)]).unwrap();
+        let scenario = result(16, Status::Ok, 0.0).scenario.to_string();
+        assert_eq!(recent_runs(&db, &scenario, 10).unwrap().len(), 2);
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
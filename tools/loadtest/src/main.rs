@@ -0,0 +1,586 @@
+//! Load-test driver: runs the stress guest under the host for every combination of payload size,
+//! concurrency, batch count, pipe buffer size and transport, and reports throughput, latency
+//! percentiles and CPU time side by side. Every scenario runs within a time budget, and the
+//! ones that hang or fail are listed at the end with the tail of their host log, since the
+//! deadlocks this crate hunts tend to show up only in some configurations.
+//!
+//! Each scenario is one run of the host binary. The guest takes its workload from
+//! `WETWARE_BATCHES`, `WETWARE_CALLS`, `WETWARE_PAYLOAD`, `WETWARE_WARMUP` and
+//! `WETWARE_ECHO_BATCH`, and writes per-call latency percentiles and the length of its batch stage
+//! to `summary.txt` in a preopened artifacts directory. Latencies of the warm-up calls are kept
+//! apart from the steady-state ones. CPU time is the host process's user plus system time, taken
+//! from `getrusage(RUSAGE_CHILDREN)` around the run, so scenarios run one at a time. The host's
+//! `--cpu-report` splits it further, into the guest's execution and the host's RPC processing,
+//! per thread. With `--baseline`, the run is also checked against stored results (see [`gate`]),
+//! and with `--history` the results are kept in a database for trends across commits (see
+//! [`history`]).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+mod bisect;
+mod gate;
+mod history;
+
+// Messages each way in the `channel` transport's message channel.
+const MESSAGE_CHANNEL_CAPACITY: &str = "1024";
+
+#[derive(Parser, Debug)]
+#[command(about = "Run the stress guest over a matrix of scenarios and compare the results")]
+struct Args {
+    /// Host binary to run each scenario with.
+    #[arg(long, default_value = "target/release/wasm-capnp-async")]
+    host: PathBuf,
+
+    /// Stress guest component.
+    #[arg(long, default_value = "wasm/target/wasm32-wasip2/release/wasm.wasm")]
+    wasm: PathBuf,
+
+    /// Echo message sizes, in bytes.
+    #[arg(long, value_delimiter = ',', default_values_t = [16, 1024, 16 * 1024])]
+    payloads: Vec<usize>,
+
+    /// Concurrent calls per batch.
+    #[arg(long, value_delimiter = ',', default_values_t = [10, 100, 1000])]
+    concurrency: Vec<usize>,
+
+    /// Transports to run every payload and concurrency over.
+    #[arg(long, value_delimiter = ',', default_values_t = [Transport::Pipe, Transport::Mux])]
+    transports: Vec<Transport>,
+
+    /// Batches per run; all of them run at once.
+    #[arg(long, value_delimiter = ',', default_values_t = [10])]
+    batches: Vec<usize>,
+
+    /// Capacity of each RPC pipe, in bytes (the host's `--pipe-buffer`).
+    #[arg(long, value_delimiter = ',', default_values_t = [32 * 1024 * 1024])]
+    pipe_buffers: Vec<usize>,
+
+    /// Whether the guest reuses one text buffer across its requests (`WETWARE_REUSE`); give
+    /// `true,false` to measure what reuse saves.
+    #[arg(long, value_delimiter = ',', default_values_t = [true])]
+    reuse: Vec<bool>,
+
+    /// Untimed echo calls the guest makes before the measured batches.
+    #[arg(long, value_name = "CALLS", default_value_t = 100)]
+    warmup: usize,
+
+    /// Messages per `echoBatch` call in the guest's batched stage (`WETWARE_ECHO_BATCH`), which
+    /// echoes the batch stage's messages again for comparison; 0 skips it.
+    #[arg(long, value_name = "MESSAGES", default_value_t = 0)]
+    echo_batch: usize,
+
+    /// Seconds a scenario may run before it is killed and reported as hung.
+    #[arg(long, default_value_t = 120)]
+    timeout_secs: u64,
+
+    /// Directory for each scenario's artifacts and host log.
+    #[arg(long, default_value = "target/loadtest")]
+    work_dir: PathBuf,
+
+    /// Also write the results to this file as JSON.
+    #[arg(long, value_name = "PATH")]
+    json: Option<PathBuf>,
+
+    /// Compare the results against this baseline (an earlier `--json` output) and fail if any
+    /// scenario regressed.
+    #[arg(long, value_name = "PATH")]
+    baseline: Option<PathBuf>,
+
+    /// Largest throughput drop or p99 latency increase, in percent, that doesn't count as a
+    /// regression.
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 10.0,
+        requires = "baseline"
+    )]
+    max_regression: f64,
+
+    /// Root seed for every scenario (the host's `--seed`). One is drawn from the clock when not
+    /// given.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Record the results in this SQLite database, keyed by git commit, seed and scenario.
+    #[arg(long, value_name = "DB")]
+    history: Option<PathBuf>,
+
+    /// Instead of the matrix, find the smallest pipe buffer the first scenario completes with,
+    /// bisecting down from its pipe buffer.
+    #[arg(long)]
+    bisect_buffer: bool,
+
+    /// Smallest pipe buffer `--bisect-buffer` tries, in bytes.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 512,
+        requires = "bisect_buffer"
+    )]
+    bisect_min: usize,
+
+    /// Print per-scenario trends from the `--history` database instead of running.
+    #[arg(long, requires = "history")]
+    trends: bool,
+
+    /// Only show scenarios whose description contains this text, e.g. `transport=mux`.
+    #[arg(long, value_name = "TEXT", default_value = "", requires = "trends")]
+    scenario: String,
+
+    /// Runs per scenario to show.
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "trends")]
+    last: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Transport {
+    /// One Cap'n Proto connection straight over the pipes.
+    Pipe,
+    /// The multiplexed transport (`--mux`).
+    Mux,
+    /// A channel of whole messages instead of the pipes (`--message-channel`).
+    Channel,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Transport::Pipe => "pipe",
+            Transport::Mux => "mux",
+            Transport::Channel => "channel",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Scenario {
+    payload: usize,
+    concurrency: usize,
+    batches: usize,
+    pipe_buffer: usize,
+    transport: Transport,
+    #[serde(default = "reuse_default")]
+    reuse: bool,
+}
+
+/// Results recorded before `reuse` was a dimension ran with reuse on.
+fn reuse_default() -> bool {
+    true
+}
+
+impl Scenario {
+    /// Whether `other` differs from this scenario in transport at most.
+    fn same_workload(&self, other: &Scenario) -> bool {
+        Scenario {
+            transport: self.transport,
+            ..*other
+        } == *self
+    }
+}
+
+impl std::fmt::Display for Scenario {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "payload={} concurrency={} batches={} pipe-buffer={} transport={} reuse={}",
+            self.payload,
+            self.concurrency,
+            self.batches,
+            self.pipe_buffer,
+            self.transport,
+            self.reuse
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Ok,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScenarioResult {
+    scenario: Scenario,
+    status: Status,
+    wall_ms: u64,
+    cpu_ms: u64,
+    /// Echo calls per second over the guest's batch stage.
+    throughput: f64,
+    p50_us: u64,
+    p90_us: u64,
+    p99_us: u64,
+    max_us: u64,
+    /// The part of `cpu_ms` spent running the guest, and serving its RPC calls.
+    #[serde(default)]
+    guest_cpu_ms: u64,
+    #[serde(default)]
+    provider_cpu_ms: u64,
+    /// Milliseconds by side (`guest`, `provider`), then by host thread.
+    #[serde(default)]
+    cpu_threads: BTreeMap<String, BTreeMap<String, f64>>,
+    /// Guest allocations per call over the timed batches; 0 for guests without `alloc-stats`.
+    #[serde(default)]
+    allocs_per_call: f64,
+    /// Host allocations serving the guest's calls, per batch call over the whole run; 0 for hosts
+    /// without `alloc-stats`.
+    #[serde(default)]
+    rpc_allocs_per_call: f64,
+    /// Messages per second echoed through `echoBatch`, for the same messages as `throughput`;
+    /// 0 if the batched stage didn't run.
+    #[serde(default)]
+    batched_throughput: f64,
+    /// Latencies of the warm-up calls, which the percentiles above leave out.
+    #[serde(default)]
+    warmup_p50_us: u64,
+    #[serde(default)]
+    warmup_p99_us: u64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let mut history = args.history.as_deref().map(history::open).transpose()?;
+    if args.trends {
+        let history = history.as_ref().expect("--trends requires --history");
+        return history::print_trends(history, &args.scenario, args.last);
+    }
+    let seed = args.seed.unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        now.unwrap_or_default().as_nanos() as u64
+    });
+    eprintln!("seed {seed}");
+    if args.bisect_buffer {
+        let scenario = Scenario {
+            payload: args.payloads[0],
+            concurrency: args.concurrency[0],
+            batches: args.batches[0],
+            pipe_buffer: args.pipe_buffers[0],
+            transport: args.transports[0],
+            reuse: args.reuse[0],
+        };
+        return bisect::run(&args, scenario, seed);
+    }
+    // Read the baseline up front, so a bad path fails before the runs rather than after.
+    let baseline = args.baseline.as_deref().map(gate::load).transpose()?;
+    let mut scenarios = Vec::new();
+    for &payload in &args.payloads {
+        for &concurrency in &args.concurrency {
+            for &batches in &args.batches {
+                for &pipe_buffer in &args.pipe_buffers {
+                    for &transport in &args.transports {
+                        for &reuse in &args.reuse {
+                            scenarios.push(Scenario {
+                                payload,
+                                concurrency,
+                                batches,
+                                pipe_buffer,
+                                transport,
+                                reuse,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let mut results = Vec::new();
+    for (index, scenario) in scenarios.into_iter().enumerate() {
+        eprintln!("running {scenario}");
+        let result = run_scenario(&args, scenario, index, seed)?;
+        eprintln!("  {:?} in {} ms", result.status, result.wall_ms);
+        results.push(result);
+    }
+    report(&results);
+    report_faults(&results, &args.work_dir);
+    if let Some(path) = &args.json {
+        fs::write(path, serde_json::to_string_pretty(&results)?)?;
+        eprintln!("wrote {}", path.display());
+    }
+    if let Some(history) = &mut history {
+        let commit = history::git_commit();
+        history::record(history, &commit, seed, &results)?;
+        eprintln!("recorded {} results for {commit}", results.len());
+    }
+    if let Some(baseline) = &baseline {
+        let regressions = gate::compare(&results, baseline, args.max_regression);
+        if regressions > 0 {
+            return Err(format!(
+                "{regressions} scenario(s) regressed by more than {}%",
+                args.max_regression
+            )
+            .into());
+        }
+    }
+    if results.iter().any(|r| r.status != Status::Ok) {
+        return Err("some scenarios failed or timed out".into());
+    }
+    Ok(())
+}
+
+fn run_scenario(
+    args: &Args,
+    scenario: Scenario,
+    index: usize,
+    seed: u64,
+) -> Result<ScenarioResult, Box<dyn std::error::Error>> {
+    let dir = scenario_dir(&args.work_dir, index);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    let dir = dir.canonicalize()?;
+    let log = fs::File::create(dir.join("host.log"))?;
+
+    let mut command = Command::new(&args.host);
+    command
+        .arg("--wasm")
+        .arg(&args.wasm)
+        .arg("--dir")
+        .arg(format!("{}:/artifacts", dir.display()))
+        .args(["--env", "WETWARE_ARTIFACTS=/artifacts"])
+        .arg("--env")
+        .arg(format!("WETWARE_BATCHES={}", scenario.batches))
+        .arg("--env")
+        .arg(format!("WETWARE_CALLS={}", scenario.concurrency))
+        .arg("--env")
+        .arg(format!("WETWARE_PAYLOAD={}", scenario.payload))
+        .arg("--env")
+        .arg(format!("WETWARE_WARMUP={}", args.warmup))
+        .arg("--env")
+        .arg(format!("WETWARE_REUSE={}", u8::from(scenario.reuse)))
+        .arg("--env")
+        .arg(format!("WETWARE_ECHO_BATCH={}", args.echo_batch))
+        .arg("--pipe-buffer")
+        .arg(scenario.pipe_buffer.to_string())
+        .arg("--seed")
+        .arg(seed.to_string())
+        .arg("--cpu-report")
+        .arg(dir.join("cpu.json"))
+        .env("RUST_LOG", "warn")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    match scenario.transport {
+        Transport::Pipe => {}
+        Transport::Mux => {
+            command.arg("--mux");
+        }
+        Transport::Channel => {
+            command.args(["--message-channel", MESSAGE_CHANNEL_CAPACITY]);
+        }
+    }
+
+    let cpu_before = children_cpu_time();
+    let started = Instant::now();
+    let mut child = command.spawn()?;
+    let status = wait_with_timeout(&mut child, Duration::from_secs(args.timeout_secs))?;
+    let wall = started.elapsed();
+    let cpu = children_cpu_time().saturating_sub(cpu_before);
+
+    let summary = fs::read_to_string(dir.join("summary.txt")).unwrap_or_default();
+    let field = |key: &str| -> u64 {
+        summary
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    let status = match status {
+        None => Status::TimedOut,
+        Some(status) if status.success() && !summary.is_empty() => Status::Ok,
+        Some(_) => Status::Failed,
+    };
+    let cpu_report = CpuReport::read(&dir.join("cpu.json"));
+    let batch_us = field("batch stage us");
+    let batched_us = field("echo batch stage us");
+    let calls = (scenario.batches * scenario.concurrency) as f64;
+    Ok(ScenarioResult {
+        scenario,
+        status,
+        wall_ms: wall.as_millis() as u64,
+        cpu_ms: cpu.as_millis() as u64,
+        guest_cpu_ms: cpu_report.guest_cpu_ms as u64,
+        provider_cpu_ms: cpu_report.provider_cpu_ms as u64,
+        cpu_threads: cpu_report.threads,
+        throughput: if batch_us > 0 {
+            calls / (batch_us as f64 / 1e6)
+        } else {
+            0.0
+        },
+        batched_throughput: if batched_us > 0 {
+            calls / (batched_us as f64 / 1e6)
+        } else {
+            0.0
+        },
+        p50_us: field("latency p50 us"),
+        p90_us: field("latency p90 us"),
+        p99_us: field("latency p99 us"),
+        max_us: field("latency max us"),
+        allocs_per_call: field("batch allocations") as f64 / calls,
+        rpc_allocs_per_call: cpu_report.provider_allocations as f64 / calls,
+        warmup_p50_us: field("warm-up p50 us"),
+        warmup_p99_us: field("warm-up p99 us"),
+    })
+}
+
+/// The host's `--cpu-report`; empty if the run didn't get far enough to write one.
+#[derive(Default, Deserialize)]
+struct CpuReport {
+    guest_cpu_ms: f64,
+    provider_cpu_ms: f64,
+    provider_allocations: u64,
+    threads: BTreeMap<String, BTreeMap<String, f64>>,
+}
+
+impl CpuReport {
+    fn read(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|report| serde_json::from_str(&report).ok())
+            .unwrap_or_default()
+    }
+}
+
+fn scenario_dir(work_dir: &Path, index: usize) -> PathBuf {
+    work_dir.join(format!("scenario-{index}"))
+}
+
+/// Wait for `child`, killing it once `timeout` has passed. `None` means it was killed.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Duration,
+) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// User plus system time of every waited-for child so far.
+fn children_cpu_time() -> Duration {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: `usage` is a valid, writable `rusage`; getrusage only writes to it.
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) != 0 {
+            return Duration::ZERO;
+        }
+        usage.assume_init()
+    };
+    let timeval = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    timeval(usage.ru_utime) + timeval(usage.ru_stime)
+}
+
+/// Print one row per scenario. Throughput is also shown relative to the first transport run with
+/// the same workload.
+fn report(results: &[ScenarioResult]) {
+    println!(
+        "{:>8} {:>6} {:>7} {:>9} {:>6} {:>5} {:>9} {:>10} {:>7} {:>10} {:>9} {:>9} {:>9} {:>9} {:>9} {:>8} {:>8} {:>8} {:>8} {:>11} {:>15}",
+        "payload",
+        "conc",
+        "batches",
+        "buffer",
+        "trans",
+        "reuse",
+        "status",
+        "calls/s",
+        "rel",
+        "batched/s",
+        "p50 us",
+        "p90 us",
+        "p99 us",
+        "max us",
+        "warm p99",
+        "wall ms",
+        "cpu ms",
+        "guest ms",
+        "rpc ms",
+        "allocs/call",
+        "rpc allocs/call"
+    );
+    for result in results {
+        let s = result.scenario;
+        let reference = results
+            .iter()
+            .find(|r| r.scenario.same_workload(&s))
+            .map_or(0.0, |r| r.throughput);
+        let relative = if reference > 0.0 {
+            format!("{:.2}x", result.throughput / reference)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "{:>8} {:>6} {:>7} {:>9} {:>6} {:>5} {:>9} {:>10.0} {:>7} {:>10.0} {:>9} {:>9} {:>9} {:>9} {:>9} {:>8} {:>8} {:>8} {:>8} {:>11.1} {:>15.1}",
+            s.payload,
+            s.concurrency,
+            s.batches,
+            s.pipe_buffer,
+            s.transport,
+            if s.reuse { "on" } else { "off" },
+            format!("{:?}", result.status),
+            result.throughput,
+            relative,
+            result.batched_throughput,
+            result.p50_us,
+            result.p90_us,
+            result.p99_us,
+            result.max_us,
+            result.warmup_p99_us,
+            result.wall_ms,
+            result.cpu_ms,
+            result.guest_cpu_ms,
+            result.provider_cpu_ms,
+            result.allocs_per_call,
+            result.rpc_allocs_per_call,
+        );
+    }
+}
+
+/// List the scenarios that hung or failed, with the last lines of each one's host log.
+fn report_faults(results: &[ScenarioResult], work_dir: &Path) {
+    const LOG_TAIL: usize = 5;
+    let faults: Vec<_> = results
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.status != Status::Ok)
+        .collect();
+    if faults.is_empty() {
+        return;
+    }
+    println!(
+        "\n{} of {} scenarios hung or failed:",
+        faults.len(),
+        results.len()
+    );
+    for (index, result) in faults {
+        let log = scenario_dir(work_dir, index).join("host.log");
+        let what = match result.status {
+            Status::TimedOut => "hung",
+            _ => "failed",
+        };
+        println!("  {what}: {} ({})", result.scenario, log.display());
+        let contents = fs::read_to_string(&log).unwrap_or_default();
+        let lines: Vec<&str> = contents.lines().collect();
+        for line in &lines[lines.len().saturating_sub(LOG_TAIL)..] {
+            println!("    | {line}");
+        }
+    }
+}
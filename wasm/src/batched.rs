@@ -0,0 +1,102 @@
+//! The batched-echo stage of the stress workload.
+//!
+//! Sends the same messages as the timed batch stage, batches × calls of them, but
+//! `WETWARE_ECHO_BATCH` at a time in `Echoer.echoBatch` calls instead of one `Echoer.echo` call
+//! each (0, the default, skips the stage). All the calls are sent at once, within the question
+//! limit, and every reply is checked. The stage's time against the batch stage's, for the same
+//! messages, is what a call's RPC overhead costs: the question, the message framing, the dispatch
+//! through the host's wrappers and the return.
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use wetware_guest::call_id;
+use wetware_guest::flow::QuestionLimit;
+use wetware_guest::reuse::TextScratch;
+
+use crate::echo_capnp::echoer;
+use crate::stress::{echo_message, env_or};
+use crate::timer;
+
+// Messages per `echoBatch` call.
+const ECHO_BATCH_ENV: &str = "WETWARE_ECHO_BATCH";
+
+/// What the stage did, for `summary.txt`.
+pub struct Batched {
+    pub size: usize,
+    pub elapsed_ns: u64,
+}
+
+/// Echo `count` messages of `payload` bytes through `echoer` in `echoBatch` calls, within the
+/// `questions` bound. `None` if the stage is off.
+pub async fn run(
+    echoer: &echoer::Client,
+    questions: &QuestionLimit,
+    count: usize,
+    payload: usize,
+) -> Result<Option<Batched>, Box<dyn std::error::Error>> {
+    let size = env_or(ECHO_BATCH_ENV, 0);
+    if size == 0 || count == 0 {
+        return Ok(None);
+    }
+    let calls = count.div_ceil(size);
+    log!("guest: echoing {} messages in {} calls", count, calls);
+    let started = timer::monotonic_now_ns();
+    let mut pending: FuturesUnordered<_> = (0..calls)
+        .map(|c| {
+            let first = c * size;
+            let last = count.min(first + size);
+            async move {
+                let _permit = questions.acquire().await;
+                let call_id = call_id::next();
+                echo_batch(echoer, call_id, first..last, payload)
+                    .await
+                    .map_err(|e| format!("batched call {c} (call ID {call_id}): {e}"))
+            }
+        })
+        .collect();
+    while let Some(result) = pending.next().await {
+        result?;
+    }
+    let elapsed_ns = timer::monotonic_now_ns().saturating_sub(started);
+    log!(
+        "guest: {} batched calls completed in {} ms",
+        calls,
+        elapsed_ns / 1_000_000
+    );
+    Ok(Some(Batched { size, elapsed_ns }))
+}
+
+/// Echo messages `indices` in one call, as `call_id`, and check each reply.
+async fn echo_batch(
+    echoer: &echoer::Client,
+    call_id: u64,
+    indices: std::ops::Range<usize>,
+    payload: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut text = TextScratch::with_capacity(payload);
+    let mut request = echoer.echo_batch_request();
+    request.get().set_call_id(call_id);
+    {
+        let mut msgs = request.get().init_msgs(indices.len() as u32);
+        for (slot, i) in indices.clone().enumerate() {
+            let msg = text.fill(|buf| echo_message(buf, i, payload));
+            msgs.set(slot as u32, msg.as_bytes());
+        }
+    }
+    let response = request.send().promise.await?;
+    let replies = response.get()?.get_replies()?;
+    if replies.len() as usize != indices.len() {
+        return Err(format!("{} replies to {} messages", replies.len(), indices.len()).into());
+    }
+    for (slot, i) in indices.enumerate() {
+        let expected = text.fill(|buf| echo_message(buf, i, payload));
+        let reply = replies.get(slot as u32)?;
+        if reply != expected.as_bytes() {
+            return Err(format!(
+                "reply mismatch: expected {expected:?}, got {:?}",
+                String::from_utf8_lossy(reply)
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,14 @@
+// Guest side of the `wetware:guest/capnp-bridge` interface: host capabilities reached through
+// plain WIT resources instead of the Cap'n Proto connection.
+
+use crate::host::capnp_bridge;
+
+/// Perform one echo through the bridged `Echoer` resource and check the reply.
+pub fn bridged_echo(msg: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let echoer = capnp_bridge::bootstrap()?.echoer()?;
+    let reply = echoer.echo(msg)?;
+    if reply != msg.as_bytes() {
+        return Err(format!("bridged echo mismatch: sent {msg:?}, got {reply:?}").into());
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! Bytes held in the guest's transport buffers.
+//!
+//! The host meters its own ends of the transport pipes; this is the guest's side. Whatever keeps
+//! transport bytes in guest memory between a read and its consumer, or between a writer and the
+//! stream, calls [`hold`] when it takes them and [`release`] when they are gone. That is the
+//! [`mux`](crate::mux) channels' inbound and outbound queues, and the WASI 0.3 adapters' read
+//! leftovers and in-flight writes. The WASIp1 and WASIp2 adapters read into the caller's buffer
+//! and write before returning, so they hold nothing. `GuestStats` reports the current total and
+//! its peak.
+//!
+//! The counts are per thread, like the executor that moves the bytes.
+
+use std::cell::Cell;
+
+thread_local! {
+    static BUFFERED: Cell<u64> = const { Cell::new(0) };
+    static PEAK: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Count `bytes` as held in a transport buffer.
+pub fn hold(bytes: usize) {
+    let buffered = BUFFERED.get() + bytes as u64;
+    BUFFERED.set(buffered);
+    PEAK.set(PEAK.get().max(buffered));
+}
+
+/// Count `bytes` held with [`hold`] as gone.
+pub fn release(bytes: usize) {
+    BUFFERED.set(BUFFERED.get().saturating_sub(bytes as u64));
+}
+
+/// Bytes held right now.
+pub fn buffered() -> u64 {
+    BUFFERED.get()
+}
+
+/// The most bytes held at once so far.
+pub fn peak() -> u64 {
+    PEAK.get()
+}
@@ -0,0 +1,15 @@
+//! Call IDs, to follow one call through the guest's and the host's logs.
+//!
+//! Each `Echoer` request carries a `callId`. The guest numbers its calls from 1 and logs the ID
+//! with the call; the host's `rpc` spans record it as `call_id`, and the capture file keeps it with
+//! the params. A call that failed can then be found on both sides by its ID. 0 means the caller
+//! set none.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT: AtomicU64 = AtomicU64::new(1);
+
+/// A call ID not yet handed out in this guest's run.
+pub fn next() -> u64 {
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
@@ -0,0 +1,103 @@
+//! The pipelined-chain stage of the stress workload.
+//!
+//! A chain calls `EchoerProvider.echoer()` and then `Echoer.echo()` on the echoer it promises,
+//! sending both calls at once through promise pipelining instead of waiting for the echoer first.
+//! Each chain then takes one round trip instead of two. The stage runs `WETWARE_CHAINS` chains
+//! concurrently (1000 by default, 0 skips the stage) and checks every reply, then times a few
+//! chains one after another both ways to show what pipelining saves.
+//!
+//! The echo call is queued on the host until the echoer it targets resolves, so a host delaying
+//! `EchoerProvider.echoer` with `--inject` tests that the queued calls are delivered, in order,
+//! to the right echoer.
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use wetware_guest::call_id;
+use wetware_guest::flow::QuestionLimit;
+
+use crate::echo_capnp::{echoer, echoer_provider};
+use crate::stress::env_or;
+use crate::timer;
+
+// Chains in the concurrent run.
+const CHAINS_ENV: &str = "WETWARE_CHAINS";
+
+// Chains timed one after another, each way.
+const SAMPLE: usize = 32;
+
+/// Run the chain stage against `provider`, within the `questions` bound.
+pub async fn run(
+    provider: &echoer_provider::Client,
+    questions: &QuestionLimit,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let count = env_or(CHAINS_ENV, 1000);
+    if count == 0 {
+        return Ok(());
+    }
+    log!("guest: starting {} pipelined chains", count);
+    let started = timer::monotonic_now_ns();
+    let mut chains: FuturesUnordered<_> = (0..count)
+        .map(|i| async move {
+            // Both calls of a chain are outstanding at once.
+            let _permit = questions.acquire_many(2).await;
+            chain(provider, &format!("chain #{i}"), true)
+                .await
+                .map_err(|e| format!("chain {i}: {e}"))
+        })
+        .collect();
+    while let Some(result) = chains.next().await {
+        result?;
+    }
+    let elapsed_ns = timer::monotonic_now_ns().saturating_sub(started);
+    log!(
+        "guest: {} pipelined chains completed in {} ms, {} round trips saved",
+        count,
+        elapsed_ns / 1_000_000,
+        count
+    );
+
+    let awaited_ns = time_sample(provider, questions, false).await?;
+    let pipelined_ns = time_sample(provider, questions, true).await?;
+    log!(
+        "guest: one chain takes {} us awaiting the echoer, {} us pipelined",
+        awaited_ns / 1_000,
+        pipelined_ns / 1_000
+    );
+    Ok(())
+}
+
+/// Mean time of one chain over `SAMPLE` chains run one after another.
+async fn time_sample(
+    provider: &echoer_provider::Client,
+    questions: &QuestionLimit,
+    pipelined: bool,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let started = timer::monotonic_now_ns();
+    for i in 0..SAMPLE {
+        let _permit = questions.acquire_many(2).await;
+        chain(provider, &format!("timed chain #{i}"), pipelined).await?;
+    }
+    Ok(timer::monotonic_now_ns().saturating_sub(started) / SAMPLE as u64)
+}
+
+/// Get an echoer and echo `msg` on it, either pipelined on the promised echoer or after waiting
+/// for it; checks the reply.
+async fn chain(
+    provider: &echoer_provider::Client,
+    msg: &str,
+    pipelined: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let echoer: echoer::Client = if pipelined {
+        provider.echoer_request().send().pipeline.get_echoer()
+    } else {
+        let response = provider.echoer_request().send().promise.await?;
+        response.get()?.get_echoer()?
+    };
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg);
+    request.get().set_call_id(call_id::next());
+    let response = request.send().promise.await?;
+    if response.get()?.get_reply()? != msg.as_bytes() {
+        return Err(format!("reply mismatch for {msg:?}").into());
+    }
+    Ok(())
+}
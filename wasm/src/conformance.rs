@@ -0,0 +1,79 @@
+// Conformance mode: instead of running the stress scenario, export an `EchoerProvider` as our
+// bootstrap capability and serve the host's conformance suite until it closes the connection.
+
+use capnp::capability::Promise;
+use capnp_rpc::{RpcSystem, pry, rpc_twoparty_capnp, twoparty};
+use futures::io::{AsyncRead, AsyncWrite};
+
+use crate::echo_capnp::{echoer, echoer_provider};
+use crate::{executor, heartbeat};
+
+/// Environment variable the host sets to request conformance mode.
+pub const CONFORMANCE_ENV: &str = "WETWARE_CONFORMANCE";
+
+struct Echoer;
+
+impl echoer::Server for Echoer {
+    fn echo(
+        &mut self,
+        params: echoer::EchoParams,
+        mut results: echoer::EchoResults,
+    ) -> Promise<(), capnp::Error> {
+        let msg = pry!(pry!(params.get()).get_msg());
+        results.get().set_reply(msg.as_bytes());
+        Promise::ok(())
+    }
+
+    fn echo_batch(
+        &mut self,
+        params: echoer::EchoBatchParams,
+        mut results: echoer::EchoBatchResults,
+    ) -> Promise<(), capnp::Error> {
+        let msgs = pry!(pry!(params.get()).get_msgs());
+        pry!(results.get().set_replies(msgs));
+        Promise::ok(())
+    }
+}
+
+struct EchoerProvider;
+
+impl echoer_provider::Server for EchoerProvider {
+    fn echoer(
+        &mut self,
+        _params: echoer_provider::EchoerParams,
+        mut results: echoer_provider::EchoerResults,
+    ) -> Promise<(), capnp::Error> {
+        results.get().set_echoer(capnp_rpc::new_client(Echoer));
+        Promise::ok(())
+    }
+}
+
+pub fn enabled() -> bool {
+    std::env::var_os(CONFORMANCE_ENV).is_some()
+}
+
+/// Serve `EchoerProvider` over the given streams until the host closes the connection.
+pub fn serve(
+    input: impl AsyncRead + Unpin + 'static,
+    output: impl AsyncWrite + Unpin + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log!("guest: conformance mode; serving EchoerProvider");
+    let network = twoparty::VatNetwork::new(
+        input,
+        output,
+        rpc_twoparty_capnp::Side::Client,
+        Default::default(),
+    );
+    let provider: echoer_provider::Client = capnp_rpc::new_client(EchoerProvider);
+    let rpc_system = RpcSystem::new(Box::new(network), Some(provider.client));
+
+    let result = executor::block_on(async move {
+        executor::spawn(heartbeat());
+        rpc_system.await
+    });
+    match result {
+        Ok(()) => log!("guest: conformance connection closed"),
+        Err(e) => log!("guest: conformance connection error: {e:?}"),
+    }
+    Ok(())
+}
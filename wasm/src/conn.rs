@@ -0,0 +1,97 @@
+//! Several Cap'n Proto connections on one executor.
+//!
+//! A guest's first connection is its stdio pipe to the embedding host, but it can also talk to
+//! other vats, e.g. a remote peer over a WASI TCP socket. [`Connections`] holds one two-party
+//! `RpcSystem` per stream pair, and [`Connections::run`] drives all of them together. Each
+//! connection gets a [`Connection`] handle, so the handle a capability is fetched from decides
+//! which connection its calls use. Capabilities keep using the connection they came from, so
+//! calls don't cross connections unless the guest passes them along.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use capnp::capability::FromClientHook;
+use capnp_rpc::{RpcSystem, rpc_twoparty_capnp, twoparty};
+use futures::stream::{FuturesUnordered, Stream};
+
+use crate::polls;
+
+/// One connection: its name and the peer's bootstrap capability.
+#[derive(Clone)]
+pub struct Connection {
+    name: Rc<str>,
+    remote: capnp::capability::Client,
+}
+
+impl Connection {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The peer's bootstrap capability, as `C`. Calls on it go over this connection.
+    pub fn bootstrap<C: FromClientHook>(&self) -> C {
+        C::new(self.remote.hook.add_ref())
+    }
+}
+
+/// The guest's connections, by name.
+#[derive(Default)]
+pub struct Connections {
+    handles: BTreeMap<Rc<str>, Connection>,
+    systems: Vec<(Rc<str>, RpcSystem<rpc_twoparty_capnp::Side>)>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to the vat at the other end of `reader`/`writer`, offering it `bootstrap`. The
+    /// guest is the client side; the peer is expected to serve a bootstrap capability. Panics if
+    /// `name` is already taken.
+    pub fn connect<R, W>(
+        &mut self,
+        name: &str,
+        reader: R,
+        writer: W,
+        bootstrap: Option<capnp::capability::Client>,
+    ) -> Connection
+    where
+        R: futures_io::AsyncRead + Unpin + 'static,
+        W: futures_io::AsyncWrite + Unpin + 'static,
+    {
+        let name: Rc<str> = name.into();
+        assert!(
+            !self.handles.contains_key(&name),
+            "connection {name:?} already exists"
+        );
+        let network = twoparty::VatNetwork::new(
+            reader,
+            writer,
+            rpc_twoparty_capnp::Side::Client,
+            Default::default(),
+        );
+        let mut system = RpcSystem::new(Box::new(network), bootstrap);
+        let connection = Connection {
+            name: name.clone(),
+            remote: system.bootstrap(rpc_twoparty_capnp::Side::Server),
+        };
+        self.handles.insert(name.clone(), connection.clone());
+        self.systems.push((name, system));
+        connection
+    }
+
+    /// The connection called `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<Connection> {
+        self.handles.get(name).cloned()
+    }
+
+    /// Drive every connection. The stream yields each connection's name and result as it ends,
+    /// and is done once all of them have. Dropping it drops every connection still open.
+    pub fn run(self) -> impl Stream<Item = (Rc<str>, Result<(), capnp::Error>)> {
+        self.systems
+            .into_iter()
+            .map(|(name, system)| async move { (name, polls::instrument(system).await) })
+            .collect::<FuturesUnordered<_>>()
+    }
+}
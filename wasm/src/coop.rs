@@ -0,0 +1,67 @@
+//! Cooperative yielding in long loops.
+//!
+//! The guest runs everything on one thread, and a future only gives the others a turn when it
+//! returns `Pending`. A loop whose awaits are all ready at once, like submitting calls while
+//! question slots are free, never does: until it ends, the RPC driver isn't polled, replies
+//! pile up unread and the host sees a guest that stopped reading. [`Budget::tick`] at the top of
+//! each iteration returns `Pending` once every so many iterations, so the executor gets a turn.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Iterations between yields when the loop doesn't choose.
+pub const DEFAULT_EVERY: u32 = 64;
+
+/// Counts a loop's iterations and yields to the executor every `every` of them.
+pub struct Budget {
+    every: u32,
+    left: u32,
+}
+
+impl Budget {
+    /// Yield every `every` iterations; 0 never yields.
+    pub fn new(every: u32) -> Self {
+        Self { every, left: every }
+    }
+
+    /// Count one iteration, and yield if the budget ran out.
+    pub async fn tick(&mut self) {
+        if self.every == 0 {
+            return;
+        }
+        self.left -= 1;
+        if self.left == 0 {
+            self.left = self.every;
+            yield_now().await;
+        }
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVERY)
+    }
+}
+
+/// Return `Pending` once, waking right away, so everything else runnable gets polled first.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by [`yield_now`].
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,84 @@
+//! The disconnect stage of the stress workload.
+//!
+//! Tests how the guest copes with its connection closing in the middle of a batch. When
+//! `WETWARE_DISCONNECT_AFTER` is set, and the host runs with `--test-control`, the stress workload
+//! runs this stage alone. It asks the host through `Control.disconnect` to hang up after that many
+//! calls, then sends `DISCONNECT_EXTRA` more echo calls than that, all at once. The first ones must
+//! be answered and every later one must fail as disconnected; anything else fails the stage. The
+//! connection is gone afterwards, so the guest has nothing left to do but exit.
+
+use std::cell::Cell;
+
+use futures::future;
+use wetware_guest::call_id;
+
+use crate::echo_capnp::{echoer, echoer_provider};
+
+const DISCONNECT_AFTER_ENV: &str = "WETWARE_DISCONNECT_AFTER";
+
+// Calls sent past the point where the host hangs up.
+const DISCONNECT_EXTRA: u32 = 16;
+
+thread_local! {
+    static EXPECTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The calls after which the host is to hang up, if the stage is on.
+pub fn after_calls() -> Option<u32> {
+    std::env::var(DISCONNECT_AFTER_ENV).ok()?.parse().ok()
+}
+
+/// Whether the guest has asked the host to hang up, so the connection ending isn't an error.
+pub fn expected() -> bool {
+    EXPECTED.with(Cell::get)
+}
+
+/// Have the host hang up after `after` calls to `echoer`, and check what the guest sees.
+pub async fn run(
+    provider: &echoer_provider::Client,
+    echoer: &echoer::Client,
+    after: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let control = provider.control_request().send().pipeline.get_control();
+    let mut request = control.disconnect_request();
+    request.get().set_after_calls(after);
+    request.send().promise.await?;
+    EXPECTED.with(|expected| expected.set(true));
+
+    let total = after + DISCONNECT_EXTRA;
+    log!(
+        "guest: sending {} calls, the host hangs up after {}",
+        total,
+        after
+    );
+    // Sent here, in order, so the host sees them in the order of their indices.
+    let replies: Vec<_> = (0..total)
+        .map(|i| {
+            let mut request = echoer.echo_request();
+            request.get().set_msg(format!("disconnect {i}").as_str());
+            request.get().set_call_id(call_id::next());
+            request.send().promise
+        })
+        .collect();
+    let outcomes = future::join_all(replies).await;
+
+    for (i, outcome) in outcomes.into_iter().enumerate() {
+        let i = i as u32;
+        match outcome {
+            Ok(response) if i < after => {
+                let expected = format!("disconnect {i}");
+                if response.get()?.get_reply()? != expected.as_bytes() {
+                    return Err(format!("reply mismatch for {expected:?}").into());
+                }
+            }
+            Err(e) if i >= after && e.kind == capnp::ErrorKind::Disconnected => {}
+            Ok(_) => return Err(format!("call {i} was answered after the hang-up").into()),
+            Err(e) => return Err(format!("call {i} failed: {e}").into()),
+        }
+    }
+    log!(
+        "guest: {} calls answered, then the connection closed",
+        after
+    );
+    Ok(())
+}
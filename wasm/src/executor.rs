@@ -0,0 +1,172 @@
+// Single-threaded executor for the guest. With WASIp1/p2 this is a `futures` LocalPool (the
+// default `local-pool` feature), or a small built-in one without it or with `minimal`. With the
+// `wasip3` feature, transport futures await component-model async operations, which only make
+// progress under wit-bindgen's own executor, so that one is used instead. On WASIp2, both of the
+// others batch their WASI waits into one `poll` call per turn once nothing is runnable (see
+// `reactor`).
+//
+// Whichever executor runs, the main future and every spawned task are wrapped with
+// `wetware_guest::polls::instrument`, so `WETWARE_POLL_STATS` can count their polls.
+
+#[cfg(not(feature = "wasip3"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Waker recording that something was woken since it was last checked.
+#[cfg(not(feature = "wasip3"))]
+#[derive(Default)]
+struct Woken(AtomicBool);
+
+#[cfg(not(feature = "wasip3"))]
+impl Woken {
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+
+    fn peek(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(feature = "wasip3"))]
+impl std::task::Wake for Woken {
+    fn wake(self: std::sync::Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Block until a registered WASI wait is ready. WASIp1 has no pollables to batch, so its
+/// futures self-wake and there is never anything to wait for.
+#[cfg(not(feature = "wasip3"))]
+fn wait_for_wasi() {
+    #[cfg(not(feature = "wasip1"))]
+    crate::reactor::wait();
+}
+
+#[cfg(all(
+    feature = "local-pool",
+    not(any(feature = "wasip3", feature = "minimal"))
+))]
+mod imp {
+    use futures::executor::{LocalPool, LocalSpawner};
+    use futures::task::LocalSpawnExt;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Waker};
+
+    use super::{Woken, wait_for_wasi};
+
+    thread_local! {
+        static SPAWNER: RefCell<Option<LocalSpawner>> = const { RefCell::new(None) };
+    }
+
+    pub fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut pool = LocalPool::new();
+        let previous = SPAWNER.replace(Some(pool.spawner()));
+        #[cfg(not(feature = "wasip1"))]
+        let _reactor = crate::reactor::enter();
+        // Poll `fut` when it was woken, run spawned tasks until they stall, and if nothing woke
+        // meanwhile, wait in the host for a registered pollable.
+        let woken = Arc::new(Woken(true.into()));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = std::pin::pin!(wetware_guest::polls::instrument(fut));
+        let output = loop {
+            if woken.take()
+                && let Poll::Ready(output) = fut.as_mut().poll(&mut cx)
+            {
+                break output;
+            }
+            pool.run_until_stalled();
+            if !woken.peek() {
+                wait_for_wasi();
+            }
+        };
+        SPAWNER.set(previous);
+        output
+    }
+
+    pub fn spawn(fut: impl Future<Output = ()> + 'static) {
+        SPAWNER.with_borrow(|spawner| {
+            spawner
+                .as_ref()
+                .expect("executor::spawn called outside executor::block_on")
+                .spawn_local(wetware_guest::polls::instrument(fut))
+                .expect("executor is shut down");
+        });
+    }
+}
+
+// Every round polls the main future and every task, whoever was woken. If nothing woke during a
+// round, the next one waits in the host for a registered pollable first.
+#[cfg(all(
+    any(feature = "minimal", not(feature = "local-pool")),
+    not(feature = "wasip3")
+))]
+mod imp {
+    use std::cell::RefCell;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Waker};
+
+    use super::{Woken, wait_for_wasi};
+
+    type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+    thread_local! {
+        // Tasks spawned since the last round; `None` outside `block_on`.
+        static SPAWNED: RefCell<Option<Vec<Task>>> = const { RefCell::new(None) };
+    }
+
+    pub fn block_on<F: Future>(fut: F) -> F::Output {
+        let previous = SPAWNED.replace(Some(Vec::new()));
+        #[cfg(not(feature = "wasip1"))]
+        let _reactor = crate::reactor::enter();
+        let woken = Arc::new(Woken::default());
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = std::pin::pin!(wetware_guest::polls::instrument(fut));
+        let mut tasks: Vec<Task> = Vec::new();
+        let output = loop {
+            if let std::task::Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                break output;
+            }
+            SPAWNED.with_borrow_mut(|spawned| tasks.append(spawned.as_mut().unwrap()));
+            tasks.retain_mut(|task| task.as_mut().poll(&mut cx).is_pending());
+            if !woken.take() {
+                wait_for_wasi();
+            }
+        };
+        SPAWNED.set(previous);
+        output
+    }
+
+    pub fn spawn(fut: impl Future<Output = ()> + 'static) {
+        SPAWNED.with_borrow_mut(|spawned| {
+            spawned
+                .as_mut()
+                .expect("executor::spawn called outside executor::block_on")
+                .push(Box::pin(wetware_guest::polls::instrument(fut)));
+        });
+    }
+}
+
+#[cfg(feature = "wasip3")]
+mod imp {
+    pub fn block_on<F: Future>(fut: F) -> F::Output
+    where
+        F::Output: 'static,
+    {
+        wit_bindgen_p3::block_on(wetware_guest::polls::instrument(fut))
+    }
+
+    pub fn spawn(fut: impl Future<Output = ()> + 'static) {
+        wit_bindgen_p3::spawn(wetware_guest::polls::instrument(fut))
+    }
+}
+
+/// Run `fut` to completion, driving any task spawned with [`spawn`] alongside it.
+/// Tasks still pending when `fut` completes are dropped.
+pub use imp::block_on;
+
+/// Spawn a background task; must be called from within [`block_on`].
+pub use imp::spawn;
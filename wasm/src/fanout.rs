@@ -0,0 +1,106 @@
+//! The fan-out stage of the stress workload, run before any echo calls.
+//!
+//! `WETWARE_ECHOER_TASKS` tasks (500 by default, 0 skips the stage) call
+//! `EchoerProvider.echoer()` at once and hold on to the echoers they get. Only once every task
+//! has its echoer does each one echo through it, so all the answers are alive together. The
+//! stage counts the distinct capabilities among them: the host exports one for each answer it
+//! wraps, so the count shows the export churn one burst of `echoer()` calls causes. The host logs
+//! how its round robin spread the calls over its echoers when the connection ends.
+//!
+//! Before the burst, the stage checks capability identity across the connection: two
+//! `progress()` answers carry the host's one `Progress` capability and must arrive as the same
+//! capability, while two `echoer()` answers are separate capabilities and must not.
+
+use std::collections::HashSet;
+
+use futures::future;
+use wetware_guest::call_id;
+use wetware_guest::flow::QuestionLimit;
+use wetware_guest::identity;
+
+use crate::echo_capnp::{echoer, echoer_provider};
+use crate::stress::env_or;
+use crate::timer;
+
+// Concurrent `echoer()` calls.
+const TASKS_ENV: &str = "WETWARE_ECHOER_TASKS";
+
+/// Run the fan-out stage against `provider`, within the `questions` bound.
+pub async fn run(
+    provider: &echoer_provider::Client,
+    questions: &QuestionLimit,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_identity(provider).await?;
+    let tasks = env_or(TASKS_ENV, 500);
+    if tasks == 0 {
+        return Ok(());
+    }
+    log!("guest: {} tasks calling echoer() at once", tasks);
+    let started = timer::monotonic_now_ns();
+    let echoers = future::try_join_all((0..tasks).map(|_| async {
+        let _permit = questions.acquire().await;
+        let response = provider.echoer_request().send().promise.await?;
+        response.get()?.get_echoer()
+    }))
+    .await?;
+    let elapsed_ns = timer::monotonic_now_ns().saturating_sub(started);
+    let distinct: HashSet<usize> = echoers.iter().map(|e| e.client.hook.get_ptr()).collect();
+    log!(
+        "guest: {} echoer() calls answered in {} ms with {} distinct capabilities",
+        tasks,
+        elapsed_ns / 1_000_000,
+        distinct.len()
+    );
+
+    future::try_join_all(echoers.iter().enumerate().map(|(i, echoer)| async move {
+        let _permit = questions.acquire().await;
+        echo(echoer, &format!("fan-out #{i}")).await
+    }))
+    .await?;
+    log!("guest: every fanned-out echoer answered");
+    Ok(())
+}
+
+async fn check_identity(
+    provider: &echoer_provider::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = future::try_join(
+        provider.progress_request().send().promise,
+        provider.progress_request().send().promise,
+    )
+    .await;
+    match progress {
+        Ok((first, second)) => {
+            if !identity::same(
+                &first.get()?.get_progress()?,
+                &second.get()?.get_progress()?,
+            ) {
+                return Err("the host's Progress arrived as two different capabilities".into());
+            }
+        }
+        // A host without `Progress` has nothing to send twice.
+        Err(e) if e.kind == capnp::ErrorKind::Unimplemented => {}
+        Err(e) => return Err(e.into()),
+    }
+    let (first, second) = future::try_join(
+        provider.echoer_request().send().promise,
+        provider.echoer_request().send().promise,
+    )
+    .await?;
+    if identity::same(&first.get()?.get_echoer()?, &second.get()?.get_echoer()?) {
+        return Err("two echoer() answers arrived as the same capability".into());
+    }
+    log!("guest: capability identity holds across the connection");
+    Ok(())
+}
+
+async fn echo(echoer: &echoer::Client, msg: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg);
+    request.get().set_call_id(call_id::next());
+    let response = request.send().promise.await?;
+    if response.get()?.get_reply()? != msg.as_bytes() {
+        return Err(format!("reply mismatch for {msg:?}").into());
+    }
+    Ok(())
+}
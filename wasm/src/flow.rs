@@ -0,0 +1,112 @@
+//! Client-side flow control for Cap'n Proto calls.
+//!
+//! Every outstanding call is a question in the connection's question table, and its request and
+//! eventual response are buffered until it resolves. [`QuestionLimit`] bounds how many questions
+//! a guest keeps in flight: take a [`QuestionPermit`] before sending and hold it until the
+//! response has arrived. Hosts enforce their own cap as well and reject calls beyond it with an
+//! `overloaded` error, which [`is_backoff`] recognizes.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+/// Environment variable through which the host advertises its per-connection question cap.
+pub const MAX_QUESTIONS_ENV: &str = "WETWARE_MAX_QUESTIONS";
+
+struct State {
+    available: usize,
+    waiters: VecDeque<Waker>,
+}
+
+/// A bound on outstanding questions, shared by every task on one connection.
+#[derive(Clone)]
+pub struct QuestionLimit {
+    state: Rc<RefCell<State>>,
+}
+
+impl QuestionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                available: max,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// No bound at all.
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    /// The bound advertised by the host in `WETWARE_MAX_QUESTIONS`, or no bound.
+    pub fn from_env() -> Self {
+        std::env::var(MAX_QUESTIONS_ENV)
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .map_or_else(Self::unlimited, Self::new)
+    }
+
+    /// Wait until a question may be sent.
+    pub fn acquire(&self) -> Acquire {
+        self.acquire_many(1)
+    }
+
+    /// Wait until `count` questions may be sent together, as for a pipelined chain of calls. The
+    /// slots are taken all at once, so two chains can't each hold part of what they need.
+    pub fn acquire_many(&self, count: usize) -> Acquire {
+        Acquire {
+            state: self.state.clone(),
+            count,
+        }
+    }
+}
+
+/// Future returned by [`QuestionLimit::acquire`].
+pub struct Acquire {
+    state: Rc<RefCell<State>>,
+    count: usize,
+}
+
+impl Future for Acquire {
+    type Output = QuestionPermit;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<QuestionPermit> {
+        let mut state = self.state.borrow_mut();
+        if state.available >= self.count {
+            state.available -= self.count;
+            return Poll::Ready(QuestionPermit {
+                state: self.state.clone(),
+                count: self.count,
+            });
+        }
+        state.waiters.push_back(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Outstanding questions; dropping it frees their slots.
+pub struct QuestionPermit {
+    state: Rc<RefCell<State>>,
+    count: usize,
+}
+
+impl Drop for QuestionPermit {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.state.borrow_mut();
+            state.available += self.count;
+            // Waiters re-check on wake, so waking all of them can't over-admit.
+            std::mem::take(&mut state.waiters)
+        };
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Whether `error` is the host telling us to back off because too many questions are in flight.
+pub fn is_backoff(error: &capnp::Error) -> bool {
+    error.kind == capnp::ErrorKind::Overloaded
+}
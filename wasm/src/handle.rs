@@ -0,0 +1,121 @@
+use futures::StreamExt;
+use futures::channel::{mpsc, oneshot};
+use wetware_guest::call_id;
+
+use crate::echo_capnp::echoer;
+use crate::executor;
+
+// capnp-rpc clients are `!Send`: they hold `Rc`s into the connection state, so they can only be
+// used from the thread driving the `RpcSystem`. Code running on other threads (wasi-threads, or a
+// future shared-everything-threads component) talks to a dedicated RPC thread instead, through a
+// `Send` handle that forwards each call over a channel and receives the reply on a oneshot.
+
+type Reply = Result<Vec<u8>, String>;
+
+struct EchoCall {
+    msg: String,
+    reply: oneshot::Sender<Reply>,
+}
+
+/// `Send + Clone` stand-in for an `echoer::Client`, usable from any thread.
+#[derive(Clone)]
+pub struct EchoHandle {
+    calls: mpsc::UnboundedSender<EchoCall>,
+}
+
+impl EchoHandle {
+    pub async fn echo(&self, msg: impl Into<String>) -> Reply {
+        let (reply, response) = oneshot::channel();
+        self.calls
+            .unbounded_send(EchoCall {
+                msg: msg.into(),
+                reply,
+            })
+            .map_err(|_| "RPC thread has shut down".to_string())?;
+        response
+            .await
+            .map_err(|_| "RPC thread dropped the call".to_string())?
+    }
+}
+
+/// Create a handle for `echoer` and spawn the task serving it on the current executor, which must
+/// be the one driving the `RpcSystem`. The task ends once every handle has been dropped.
+pub fn spawn(echoer: echoer::Client) -> EchoHandle {
+    let (calls, mut incoming) = mpsc::unbounded::<EchoCall>();
+    executor::spawn(async move {
+        while let Some(call) = incoming.next().await {
+            // Each call gets its own task so a slow reply doesn't hold up the queue.
+            let echoer = echoer.clone();
+            executor::spawn(async move {
+                let _ = call.reply.send(echo(&echoer, &call.msg).await);
+            });
+        }
+    });
+    EchoHandle { calls }
+}
+
+async fn echo(echoer: &echoer::Client, msg: &str) -> Reply {
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg);
+    request.get().set_call_id(call_id::next());
+    let response = request.send().promise.await.map_err(|e| e.to_string())?;
+    let reply = response
+        .get()
+        .and_then(|r| r.get_reply())
+        .map_err(|e| e.to_string())?;
+    Ok(reply.to_vec())
+}
+
+/// Hammer `handle` from `workers` concurrent workers, `calls` echoes each, checking every reply.
+///
+/// With the `threads` feature each worker is a wasi-threads thread blocking on its own calls, so
+/// the only thing crossing threads is the handle; otherwise workers are tasks on this executor,
+/// which exercises the same channel path without real parallelism.
+pub async fn stress(handle: EchoHandle, workers: usize, calls: usize) -> Result<(), String> {
+    #[cfg(feature = "threads")]
+    {
+        let threads: Vec<_> = (0..workers)
+            .map(|w| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    futures::executor::block_on(run_worker(handle, w, calls))
+                })
+            })
+            .collect();
+        drop(handle);
+        // Joining would block the RPC thread that serves the workers, so poll for completion
+        // and keep yielding to the executor meanwhile.
+        let mut pending = threads;
+        while !pending.is_empty() {
+            let (finished, running): (Vec<_>, Vec<_>) =
+                pending.into_iter().partition(|t| t.is_finished());
+            for thread in finished {
+                thread
+                    .join()
+                    .map_err(|_| "worker thread panicked".to_string())??;
+            }
+            pending = running;
+            crate::timer::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "threads"))]
+    {
+        let results =
+            futures::future::join_all((0..workers).map(|w| run_worker(handle.clone(), w, calls)))
+                .await;
+        results.into_iter().collect()
+    }
+}
+
+async fn run_worker(handle: EchoHandle, worker: usize, calls: usize) -> Result<(), String> {
+    for i in 0..calls {
+        let msg = format!("worker {worker} call {i}");
+        let reply = handle.echo(msg.as_str()).await?;
+        if reply != msg.as_bytes() {
+            return Err(format!("reply mismatch for {msg:?}"));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,15 @@
+// Bindings for the `wetware:guest/host-imports` world (see `wit/guest.wit`): the RPC transport,
+// lifecycle, logging and bridged capabilities provided by the wetware host. WASI types are shared
+// with the `wasip2` crate so the transport streams can be used with the stream adapters directly.
+
+wit_bindgen::generate!({
+    path: "../wit",
+    world: "wetware:guest/host-imports",
+    with: {
+        "wasi:io/error@0.2.4": wasip2::io::error,
+        "wasi:io/poll@0.2.4": wasip2::io::poll,
+        "wasi:io/streams@0.2.4": wasip2::io::streams,
+    },
+});
+
+pub use wetware::guest::{capnp_bridge, lifecycle, logging, transport};
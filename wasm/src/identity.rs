@@ -0,0 +1,25 @@
+//! Capability identity across a connection.
+//!
+//! When a peer sends the same capability twice, the RPC system exports it once and the receiver
+//! imports it once, so both references share one import. [`same`] tells whether two references
+//! are the same capability in that sense. Layers that key state by capability, such as a broker
+//! handing out one grant per capability or a membrane wrapping each capability once, depend on it.
+//!
+//! Identity holds only while the capability stays imported: once every reference to it has been
+//! dropped, the import is released and a later copy gets a new one. A promise isn't the same as
+//! what it resolves to until it has resolved, so await `when_resolved` on both first.
+
+use capnp::capability::FromClientHook;
+use capnp::private::capability::ClientHook;
+
+/// Whether `a` and `b` refer to the same capability, through whatever they resolved to.
+pub fn same<A: FromClientHook, B: FromClientHook>(a: &A, b: &B) -> bool {
+    resolved_ptr(a.as_client_hook()) == resolved_ptr(b.as_client_hook())
+}
+
+fn resolved_ptr(hook: &dyn ClientHook) -> usize {
+    match hook.get_resolved() {
+        Some(resolved) => resolved_ptr(resolved.as_ref()),
+        None => hook.get_ptr(),
+    }
+}
@@ -0,0 +1,18 @@
+//! Guest SDK: utilities for writing wetware guests and test scenarios, shared by the example
+//! guest in `main.rs`.
+
+pub mod buffers;
+pub mod call_id;
+pub mod conn;
+pub mod coop;
+pub mod flow;
+pub mod identity;
+pub mod mux;
+pub mod oneway;
+pub mod polls;
+pub mod reconnect;
+pub mod reuse;
+pub mod rng;
+pub mod task;
+#[cfg(feature = "tracing")]
+pub mod trace;
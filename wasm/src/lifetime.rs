@@ -0,0 +1,83 @@
+//! The capability-lifetime stage of the stress workload.
+//!
+//! Each of `WETWARE_LIFETIME_ROUNDS` rounds (20 by default, 0 skips the stage) gets
+//! `LIFETIME_ECHOERS` echoers at once, echoes once through each, and drops them all. Dropping an
+//! imported capability sends the host a release, and the host's provider stops counting an echoer
+//! as live once every reference to it is gone. After the last round the stage asks the host,
+//! through `EchoerProvider.introspect`, how many echoers are still live, and fails if more are
+//! than before the first round: those would be exports the connection leaked.
+
+use std::time::Duration;
+
+use futures::future;
+use wetware_guest::call_id;
+use wetware_guest::flow::QuestionLimit;
+
+use crate::echo_capnp::echoer_provider;
+use crate::stress::env_or;
+use crate::timer;
+
+const ROUNDS_ENV: &str = "WETWARE_LIFETIME_ROUNDS";
+
+// Echoers held at once in each round.
+const LIFETIME_ECHOERS: usize = 100;
+
+// Releases travel behind our other traffic; give them this long to land.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+const SETTLE_POLL: Duration = Duration::from_millis(20);
+
+/// Run the lifetime stage against `provider`, within the `questions` bound.
+pub async fn run(
+    provider: &echoer_provider::Client,
+    questions: &QuestionLimit,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rounds = env_or(ROUNDS_ENV, 20);
+    if rounds == 0 {
+        return Ok(());
+    }
+    let before = live_echoers(provider).await?;
+    log!(
+        "guest: {} rounds of {} short-lived echoers ({} live before)",
+        rounds,
+        LIFETIME_ECHOERS,
+        before
+    );
+    for round in 0..rounds {
+        future::try_join_all((0..LIFETIME_ECHOERS).map(|i| async move {
+            let _permit = questions.acquire_many(2).await;
+            let echoer = provider.echoer_request().send().pipeline.get_echoer();
+            let msg = format!("lifetime {round}.{i}");
+            let mut request = echoer.echo_request();
+            request.get().set_msg(msg.as_str());
+            request.get().set_call_id(call_id::next());
+            let response = request.send().promise.await?;
+            if response.get()?.get_reply()? != msg.as_bytes() {
+                return Err(format!("reply mismatch for {msg:?}").into());
+            }
+            Ok::<_, Box<dyn std::error::Error>>(())
+        }))
+        .await?;
+    }
+
+    let deadline = timer::monotonic_now_ns() + SETTLE_TIMEOUT.as_nanos() as u64;
+    loop {
+        let live = live_echoers(provider).await?;
+        if live <= before {
+            log!("guest: every short-lived echoer was released");
+            return Ok(());
+        }
+        if timer::monotonic_now_ns() >= deadline {
+            return Err(format!(
+                "{} echoers still live on the host after {:?} ({} before the stage)",
+                live, SETTLE_TIMEOUT, before
+            )
+            .into());
+        }
+        timer::sleep(SETTLE_POLL).await;
+    }
+}
+
+async fn live_echoers(provider: &echoer_provider::Client) -> capnp::Result<u32> {
+    let response = provider.introspect_request().send().promise.await?;
+    Ok(response.get()?.get_stats()?.get_live_echoers())
+}
@@ -0,0 +1,151 @@
+// Following the host's log while the workload runs. A host started with `--log-tail` sets
+// `WETWARE_LOG_TAIL`; we then call `LogTail.follow` with a `LogSink` of our own and the host
+// pushes its log records to us until we cancel the call, which we do when the workload is done.
+// The reader runs as a task in the caller's scope, so it has stopped before the scope returns.
+//
+// The sink hands each batch to a bounded channel and answers the push only once the batch is
+// queued, so a slow reader holds up the host's pushes rather than piling them up here.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use futures::channel::mpsc;
+use futures::{FutureExt, SinkExt, StreamExt};
+use wetware_guest::task::{Scope, Task};
+
+use crate::echo_capnp::{echoer_provider, log_sink};
+
+/// Environment variable the host sets to ask us to follow its log.
+pub const LOG_TAIL_ENV: &str = "WETWARE_LOG_TAIL";
+
+// Unanswered pushes the host may have in flight.
+const WINDOW: u32 = 4;
+// Batches queued between the sink and the reader.
+const QUEUED: usize = 2;
+
+struct Record {
+    seq: u64,
+    level: String,
+    target: String,
+    message: String,
+}
+
+struct Sink {
+    queue: mpsc::Sender<Vec<Record>>,
+}
+
+impl log_sink::Server for Sink {
+    fn push(
+        &mut self,
+        params: log_sink::PushParams,
+        _results: log_sink::PushResults,
+    ) -> Promise<(), capnp::Error> {
+        let records = pry!(pry!(params.get()).get_records());
+        let mut batch = Vec::with_capacity(records.len() as usize);
+        for record in records.iter() {
+            batch.push(Record {
+                seq: record.get_seq(),
+                level: pry!(pry!(record.get_level()).to_string()),
+                target: pry!(pry!(record.get_target()).to_string()),
+                message: pry!(pry!(record.get_message()).to_string()),
+            });
+        }
+        let mut queue = self.queue.clone();
+        Promise::from_future(async move {
+            queue
+                .send(batch)
+                .await
+                .map_err(|_| capnp::Error::disconnected("log reader is gone".into()))
+        })
+    }
+}
+
+#[derive(Default)]
+struct Seen {
+    records: Cell<u64>,
+    gaps: Cell<u64>,
+    out_of_order: Cell<u64>,
+}
+
+pub fn enabled() -> bool {
+    std::env::var_os(LOG_TAIL_ENV).is_some()
+}
+
+/// A follow call in progress.
+pub struct Follower {
+    call: Promise<(), capnp::Error>,
+    reader: Task<()>,
+    seen: Rc<Seen>,
+}
+
+impl Follower {
+    /// Start following the host's log from the oldest record it still keeps, reading it in a
+    /// task of `scope`.
+    pub fn start(scope: &Scope<'_>, provider: &echoer_provider::Client) -> Self {
+        let (queue, mut batches) = mpsc::channel(QUEUED);
+        let sink: log_sink::Client = capnp_rpc::new_client(Sink { queue });
+        let mut request = provider
+            .log_tail_request()
+            .send()
+            .pipeline
+            .get_log_tail()
+            .follow_request();
+        request.get().set_sink(sink);
+        request.get().set_from_seq(0);
+        request.get().set_window(WINDOW);
+        let call = request.send().promise;
+        let call = Promise::from_future(async move {
+            call.await?;
+            Ok(())
+        });
+
+        let seen = Rc::new(Seen::default());
+        let reader = seen.clone();
+        let reader = scope.spawn_local(async move {
+            let mut next = None;
+            while let Some(batch) = batches.next().await {
+                for record in batch {
+                    match next {
+                        Some(next) if record.seq < next => {
+                            reader.out_of_order.set(reader.out_of_order.get() + 1)
+                        }
+                        Some(next) if record.seq > next => reader.gaps.set(reader.gaps.get() + 1),
+                        _ => {}
+                    }
+                    next = Some(record.seq + 1);
+                    reader.records.set(reader.records.get() + 1);
+                    if record.level == "ERROR" {
+                        log!("guest: host error: {}: {}", record.target, record.message);
+                    }
+                }
+            }
+        });
+        Self { call, reader, seen }
+    }
+
+    /// Cancel the follow call and the reader, and report what came through. A follow call only
+    /// ends on its own if it failed, so finding it ended is an error.
+    pub async fn stop(self) -> Result<(), Box<dyn std::error::Error>> {
+        let Self { call, reader, seen } = self;
+        // Dropping the unfinished call cancels it.
+        let ended = call.now_or_never();
+        reader.cancel();
+        // Only ends early if the host released the sink; either way the reader is done.
+        let _ = reader.await;
+        log!(
+            "guest: followed host log: {} records, {} gaps, {} out of order",
+            seen.records.get(),
+            seen.gaps.get(),
+            seen.out_of_order.get()
+        );
+        if seen.out_of_order.get() > 0 {
+            return Err("host log records arrived out of order".into());
+        }
+        match ended {
+            Some(Err(e)) => Err(format!("following the host log failed: {e}").into()),
+            _ => Ok(()),
+        }
+    }
+}
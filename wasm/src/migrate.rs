@@ -0,0 +1,98 @@
+//! The migrate stage of the stress workload.
+//!
+//! Moves the guest's work to a fresh instance when an operator asks. When `WETWARE_MIGRATE_CALLS`
+//! is set, and the host runs with `--control`, the stress workload runs this stage alone: that
+//! many echo calls, one at a time, on an echoer it holds by its sturdy ref. Once
+//! `GuestControl.migrate()` has been called, the guest lets the call in flight finish, hands off
+//! the number of calls done and the echoer's ref, and exits. Its successor resumes from the
+//! checkpoint: it restores the echoer from the ref and makes the rest of the calls. Each call's
+//! message carries its number, so none is skipped or made twice across the move.
+
+use futures::future::{self, Either};
+use futures::pin_mut;
+use wetware_guest::call_id;
+
+use crate::echo_capnp::{echoer, echoer_provider, migration};
+
+const MIGRATE_CALLS_ENV: &str = "WETWARE_MIGRATE_CALLS";
+
+/// The calls to make across every instance, if the stage is on.
+pub fn calls() -> Option<u64> {
+    std::env::var(MIGRATE_CALLS_ENV).ok()?.parse().ok()
+}
+
+/// Make echo calls until `calls` have been made in all, handing off if the host asks first.
+pub async fn run(
+    provider: &echoer_provider::Client,
+    calls: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let migration = provider.migration_request().send().pipeline.get_migration();
+    let resumed = migration.resume_request().send().promise.await?;
+    let resumed = resumed.get()?;
+    let (mut done, sturdy, echoer) = if resumed.get_resumed() {
+        let checkpoint = resumed.get_checkpoint()?;
+        let done = u64::from_le_bytes(checkpoint.get_state()?.try_into()?);
+        let sturdy = checkpoint
+            .get_refs()?
+            .try_get(0)
+            .ok_or("the checkpoint holds no sturdy ref")??
+            .to_vec();
+        let mut request = provider.restore_request();
+        request.get().set_ref(&sturdy[..]);
+        let echoer = request.send().pipeline.get_echoer();
+        log!("guest: resumed after {} calls", done);
+        (done, sturdy, echoer)
+    } else {
+        let response = provider.echoer_request().send().promise.await?;
+        let response = response.get()?;
+        (0, response.get_ref()?.to_vec(), response.get_echoer()?)
+    };
+
+    let requested = migration.requested_request().send().promise;
+    pin_mut!(requested);
+    while done < calls {
+        let reply = echo(&echoer, done);
+        pin_mut!(reply);
+        match future::select(requested.as_mut(), reply).await {
+            Either::Left((asked, reply)) => {
+                asked?;
+                reply.await?;
+                done += 1;
+                return hand_off(&migration, done, &sturdy).await;
+            }
+            Either::Right((reply, _)) => {
+                reply?;
+                done += 1;
+            }
+        }
+    }
+    log!("guest: all {} calls made", calls);
+    Ok(())
+}
+
+/// Echo the call's number on `echoer`, and check the reply.
+async fn echo(echoer: &echoer::Client, n: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let msg = format!("migrate {n}");
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg.as_str());
+    request.get().set_call_id(call_id::next());
+    let response = request.send().promise.await?;
+    if response.get()?.get_reply()? != msg.as_bytes() {
+        return Err(format!("reply mismatch for {msg:?}").into());
+    }
+    Ok(())
+}
+
+async fn hand_off(
+    migration: &migration::Client,
+    done: u64,
+    sturdy: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut request = migration.hand_off_request();
+    let mut checkpoint = request.get().init_checkpoint();
+    checkpoint.set_state(&done.to_le_bytes()[..]);
+    checkpoint.init_refs(1).set(0, sturdy);
+    request.send().promise.await?;
+    log!("guest: handed off after {} calls", done);
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! The mixed-workload stage of the stress workload.
+//!
+//! For `WETWARE_MIXED_MS` milliseconds (2000 by default, 0 skips the stage), three lanes share
+//! the connection: small unary echoes one after another, large echoes that fill the pipes the way
+//! a bulk transfer would, and `introspect` calls standing in for control traffic. Each lane
+//! records its slowest call. A lane whose slowest call took over `STARVED` while the others kept
+//! going was starved behind them, head-of-line blocking that single-lane stages don't show.
+//!
+//! The host offers no key-value or streaming service to guests yet; when it does, they belong
+//! here as lanes of their own.
+
+use std::time::Duration;
+
+use futures::future;
+use wetware_guest::call_id;
+
+use crate::echo_capnp::{echoer, echoer_provider};
+use crate::stress::env_or;
+use crate::timer;
+
+const MIXED_MS_ENV: &str = "WETWARE_MIXED_MS";
+
+// Message sizes of the unary and bulk lanes.
+const UNARY_BYTES: usize = 32;
+const BULK_BYTES: usize = 256 * 1024;
+
+// Slowest call a lane may see before it counts as starved.
+const STARVED: Duration = Duration::from_secs(2);
+
+/// What one lane did.
+struct Lane {
+    name: &'static str,
+    calls: u64,
+    slowest_ns: u64,
+}
+
+/// Run the mixed stage against `provider` and `echoer`.
+pub async fn run(
+    provider: &echoer_provider::Client,
+    echoer: &echoer::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let duration_ms = env_or(MIXED_MS_ENV, 2000);
+    if duration_ms == 0 {
+        return Ok(());
+    }
+    log!("guest: running mixed lanes for {} ms", duration_ms);
+    let until = timer::monotonic_now_ns() + duration_ms as u64 * 1_000_000;
+    let (unary, bulk, control) = future::try_join3(
+        lane("unary", until, |i| echo(echoer, UNARY_BYTES, i)),
+        lane("bulk", until, |i| echo(echoer, BULK_BYTES, i)),
+        lane("control", until, |_| introspect(provider)),
+    )
+    .await?;
+
+    let mut starved = Vec::new();
+    for lane in [&unary, &bulk, &control] {
+        log!(
+            "guest: mixed lane {}: {} calls, slowest {} ms",
+            lane.name,
+            lane.calls,
+            lane.slowest_ns / 1_000_000
+        );
+        if lane.calls == 0 || lane.slowest_ns > STARVED.as_nanos() as u64 {
+            starved.push(lane.name);
+        }
+    }
+    if !starved.is_empty() {
+        return Err(format!("mixed lanes starved: {}", starved.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Make calls one after another until `until`, timing each.
+async fn lane<F, Fut>(
+    name: &'static str,
+    until: u64,
+    mut call: F,
+) -> Result<Lane, Box<dyn std::error::Error>>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+{
+    let mut lane = Lane {
+        name,
+        calls: 0,
+        slowest_ns: 0,
+    };
+    while timer::monotonic_now_ns() < until {
+        let started = timer::monotonic_now_ns();
+        call(lane.calls)
+            .await
+            .map_err(|e| format!("mixed lane {name}: {e}"))?;
+        let elapsed = timer::monotonic_now_ns().saturating_sub(started);
+        lane.slowest_ns = lane.slowest_ns.max(elapsed);
+        lane.calls += 1;
+    }
+    Ok(lane)
+}
+
+async fn echo(
+    echoer: &echoer::Client,
+    size: usize,
+    i: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut msg = format!("mixed #{i}");
+    msg.extend(std::iter::repeat_n('.', size.saturating_sub(msg.len())));
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg.as_str());
+    request.get().set_call_id(call_id::next());
+    let response = request.send().promise.await?;
+    if response.get()?.get_reply()? != msg.as_bytes() {
+        return Err(format!("reply mismatch for a {size}-byte echo").into());
+    }
+    Ok(())
+}
+
+async fn introspect(provider: &echoer_provider::Client) -> Result<(), Box<dyn std::error::Error>> {
+    provider.introspect_request().send().promise.await?;
+    Ok(())
+}
@@ -0,0 +1,562 @@
+//! Logical channels over the guest's single RPC stream pair.
+//!
+//! The guest side of the host's `--mux` transport: each direction carries frames tagged with a
+//! channel ID instead of one raw byte stream. Each channel has its own credit-based flow control,
+//! so a busy channel can't hold up the others. Channels are also priority lanes: frames from the
+//! highest-priority channel with data go out first, so calls on the [`CONTROL`] lane don't queue
+//! behind [`BULK`] data. The host sets [`MUX_ENV`] when it multiplexes.
+//! The frame format must match the host's `mux` module:
+//!
+//! ```text
+//! frame = channel:u16le kind:u8 len:u32le payload[len]
+//! kind  = 0 data | 1 credit (payload: u32le bytes) | 2 close
+//! ```
+//!
+//! [`Mux::new`] returns the mux and a driver future that moves frames between the channels and
+//! the underlying streams. The driver has to run on the guest's executor next to the RPC system.
+//! Bytes queued in either direction count in [`buffers`](crate::buffers): a channel holds at
+//! most [`WINDOW`] unread bytes, and at most a window of data waiting to go out, plus framing.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::buffers;
+
+/// Environment variable through which the host tells the guest its transport is multiplexed.
+pub const MUX_ENV: &str = "WETWARE_MUX";
+
+/// The Cap'n Proto connection.
+pub const RPC: u16 = 0;
+/// A second connection to the host's bootstrap, for control and health calls.
+pub const CONTROL: u16 = 1;
+/// Bulk data.
+pub const BULK: u16 = 2;
+
+/// Scheduling priority of a channel's outgoing frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+/// The priority of channel `id`; keep in sync with the host.
+pub fn priority(id: u16) -> Priority {
+    match id {
+        CONTROL => Priority::High,
+        BULK => Priority::Low,
+        _ => Priority::Normal,
+    }
+}
+
+/// Unread bytes a sender may have outstanding on one channel.
+pub const WINDOW: usize = 256 * 1024;
+/// Largest payload in one frame.
+pub const MAX_FRAME: usize = 16 * 1024;
+
+const HEADER_LEN: usize = 7;
+const DATA: u8 = 0;
+const CREDIT: u8 = 1;
+const CLOSE: u8 = 2;
+
+/// Whether the host multiplexes this guest's transport.
+pub fn enabled() -> bool {
+    std::env::var_os(MUX_ENV).is_some()
+}
+
+fn frame(channel: u16, kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&channel.to_le_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Channel {
+    priority: Priority,
+    inbound: VecDeque<u8>,
+    unacked: usize,
+    credit: usize,
+    outbound: VecDeque<Vec<u8>>,
+    remote_closed: bool,
+    close_sent: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    close_waker: Option<Waker>,
+}
+
+impl Channel {
+    fn new(priority: Priority) -> Self {
+        Self {
+            priority,
+            inbound: VecDeque::new(),
+            unacked: 0,
+            credit: WINDOW,
+            outbound: VecDeque::new(),
+            remote_closed: false,
+            close_sent: false,
+            read_waker: None,
+            write_waker: None,
+            close_waker: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    channels: BTreeMap<u16, Channel>,
+    // Credit and close frames, sent ahead of data.
+    urgent: VecDeque<Vec<u8>>,
+    last_sent: Option<u16>,
+    // Channel of the data frame the driver is writing.
+    in_flight: Option<u16>,
+    handles: usize,
+    ended: bool,
+    writer_waker: Option<Waker>,
+}
+
+impl Shared {
+    fn channel(&mut self, id: u16) -> &mut Channel {
+        self.channels
+            .entry(id)
+            .or_insert_with(|| Channel::new(priority(id)))
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Urgent frames first, then one frame from each of the highest-priority channels with data,
+    /// in turn.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.urgent.pop_front() {
+            return Some(frame);
+        }
+        let top = self
+            .channels
+            .values()
+            .filter(|ch| !ch.outbound.is_empty())
+            .map(|ch| ch.priority)
+            .max()?;
+        let ready: Vec<u16> = self
+            .channels
+            .iter()
+            .filter(|(_, ch)| ch.priority == top && !ch.outbound.is_empty())
+            .map(|(id, _)| *id)
+            .collect();
+        let after = self.last_sent;
+        let next = *ready
+            .iter()
+            .find(|id| after.is_none_or(|last| **id > last))
+            .or(ready.first())?;
+        self.last_sent = Some(next);
+        self.in_flight = Some(next);
+        self.channel(next).outbound.pop_front()
+    }
+}
+
+impl Drop for Shared {
+    // Whatever is still queued when the last handle and the driver are gone goes with them.
+    fn drop(&mut self) {
+        let inbound: usize = self.channels.values().map(|ch| ch.inbound.len()).sum();
+        let outbound: usize = (self.channels.values())
+            .flat_map(|ch| &ch.outbound)
+            .chain(&self.urgent)
+            .map(Vec::len)
+            .sum();
+        buffers::release(inbound + outbound);
+    }
+}
+
+/// A multiplexed transport; hands out one stream per channel.
+pub struct Mux {
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Mux {
+    /// Multiplex over `reader` and `writer`. The returned driver must be polled for any channel to
+    /// make progress; it finishes once the underlying stream has ended and every handle is gone.
+    pub fn new<R, W>(reader: R, writer: W) -> (Self, impl Future<Output = ()>)
+    where
+        R: futures_io::AsyncRead + Unpin + 'static,
+        W: futures_io::AsyncWrite + Unpin + 'static,
+    {
+        let mut shared = Shared::default();
+        shared.handles = 1;
+        let shared = Rc::new(RefCell::new(shared));
+        let driver = futures::future::join(
+            read_frames(reader, shared.clone()),
+            write_frames(writer, shared.clone()),
+        );
+        (Self { shared }, async move {
+            driver.await;
+        })
+    }
+
+    /// The stream for channel `id`. Clone it to read and write from separate places.
+    pub fn channel(&self, id: u16) -> MuxStream {
+        let mut shared = self.shared.borrow_mut();
+        shared.channel(id);
+        shared.handles += 1;
+        MuxStream {
+            id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        release(&self.shared);
+    }
+}
+
+fn release(shared: &RefCell<Shared>) {
+    let mut shared = shared.borrow_mut();
+    shared.handles -= 1;
+    shared.wake_writer();
+}
+
+async fn read_frames<R: futures_io::AsyncRead + Unpin>(mut reader: R, shared: Rc<RefCell<Shared>>) {
+    let result: io::Result<()> = async {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            match reader.read_exact(&mut header).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let id = u16::from_le_bytes([header[0], header[1]]);
+            let kind = header[2];
+            let len = u32::from_le_bytes([header[3], header[4], header[5], header[6]]) as usize;
+            if len > MAX_FRAME {
+                return Err(invalid(format!("mux frame of {len} bytes on channel {id}")));
+            }
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload).await?;
+            let mut shared = shared.borrow_mut();
+            let ch = shared.channel(id);
+            let waker = match kind {
+                DATA if ch.inbound.len() + len <= WINDOW => {
+                    ch.inbound.extend(payload);
+                    buffers::hold(len);
+                    ch.read_waker.take()
+                }
+                DATA => return Err(invalid(format!("mux channel {id} overran its window"))),
+                CREDIT if len == 4 => {
+                    ch.credit += u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+                    ch.write_waker.take()
+                }
+                CLOSE => {
+                    ch.remote_closed = true;
+                    ch.read_waker.take()
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "bad mux frame kind {kind} on channel {id}"
+                    )));
+                }
+            };
+            drop(shared);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+    .await;
+    if let Err(e) = &result {
+        log_error(e);
+    }
+    let wakers: Vec<Waker> = {
+        let mut shared = shared.borrow_mut();
+        shared.ended = true;
+        shared
+            .channels
+            .values_mut()
+            .flat_map(|ch| {
+                ch.remote_closed = true;
+                [
+                    ch.read_waker.take(),
+                    ch.write_waker.take(),
+                    ch.close_waker.take(),
+                ]
+            })
+            .flatten()
+            .collect()
+    };
+    wakers.into_iter().for_each(Waker::wake);
+}
+
+#[cfg(feature = "tracing")]
+fn log_error(e: &io::Error) {
+    tracing::warn!(error = %e, "mux reader failed");
+}
+
+#[cfg(not(feature = "tracing"))]
+fn log_error(_e: &io::Error) {}
+
+async fn write_frames<W: futures_io::AsyncWrite + Unpin>(
+    mut writer: W,
+    shared: Rc<RefCell<Shared>>,
+) {
+    loop {
+        let frame = std::future::poll_fn(|cx| {
+            let mut shared = shared.borrow_mut();
+            match shared.next_frame() {
+                Some(frame) => Poll::Ready(Some(frame)),
+                None if shared.handles == 0 => Poll::Ready(None),
+                None => {
+                    shared.writer_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        let Some(frame) = frame else {
+            break;
+        };
+        let frame = InFlight(frame);
+        if writer.write_all(&frame.0).await.is_err() || writer.flush().await.is_err() {
+            return;
+        }
+        drop(frame);
+        // A channel waiting to close is done once its last frame is out.
+        let mut state = shared.borrow_mut();
+        if let Some(id) = state.in_flight.take() {
+            let ch = state.channel(id);
+            if ch.outbound.is_empty()
+                && let Some(waker) = ch.close_waker.take()
+            {
+                waker.wake();
+            }
+        }
+    }
+    let _ = writer.close().await;
+}
+
+// The frame the driver is writing, counted in `buffers` until it is out or the driver is gone.
+struct InFlight(Vec<u8>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        buffers::release(self.0.len());
+    }
+}
+
+/// One channel of a [`Mux`], as a byte stream.
+pub struct MuxStream {
+    id: u16,
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Clone for MuxStream {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().handles += 1;
+        Self {
+            id: self.id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl futures_io::AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.borrow_mut();
+        let ch = shared.channel(self.id);
+        if ch.inbound.is_empty() {
+            if ch.remote_closed {
+                return Poll::Ready(Ok(0));
+            }
+            ch.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = ch.inbound.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(ch.inbound.drain(..n)) {
+            *dst = src;
+        }
+        buffers::release(n);
+        // Hand credit back in chunks rather than per read.
+        ch.unacked += n;
+        if ch.unacked >= WINDOW / 4 || ch.inbound.is_empty() {
+            let credit = std::mem::take(&mut ch.unacked) as u32;
+            let credit = frame(self.id, CREDIT, &credit.to_le_bytes());
+            buffers::hold(credit.len());
+            shared.urgent.push_back(credit);
+            shared.wake_writer();
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl futures_io::AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.ended {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "mux connection ended",
+            )));
+        }
+        let ch = shared.channel(self.id);
+        if ch.credit == 0 {
+            ch.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(ch.credit).min(MAX_FRAME);
+        ch.credit -= n;
+        let data = frame(self.id, DATA, &buf[..n]);
+        buffers::hold(data.len());
+        ch.outbound.push_back(data);
+        shared.wake_writer();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are queued for the driver as soon as they are written.
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queues a close frame behind the channel's data and finishes once the driver has written
+    /// all of it, so a guest can close its channels and exit without losing what it sent.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.ended {
+            return Poll::Ready(Ok(()));
+        }
+        let ch = shared.channel(self.id);
+        if !ch.close_sent {
+            // Behind the channel's own data, so the host reads everything before EOF.
+            ch.close_sent = true;
+            let close = frame(self.id, CLOSE, &[]);
+            buffers::hold(close.len());
+            ch.outbound.push_back(close);
+            shared.wake_writer();
+        }
+        let writing = shared.in_flight == Some(self.id);
+        let ch = shared.channel(self.id);
+        if ch.outbound.is_empty() && !writing {
+            return Poll::Ready(Ok(()));
+        }
+        ch.close_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        release(&self.shared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker;
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use super::*;
+
+    // A peer that never reads what the guest sends and never sends anything.
+    struct Stalled;
+
+    impl AsyncRead for Stalled {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Stalled {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn a_stalled_peer_holds_at_most_a_window_of_writes() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let (mux, driver) = Mux::new(Stalled, Stalled);
+        let mut driver = Box::pin(driver);
+        let mut bulk = mux.channel(BULK);
+        let data = vec![7u8; 4 * WINDOW];
+        let mut sent = 0;
+        loop {
+            assert!(driver.as_mut().poll(&mut cx).is_pending());
+            match Pin::new(&mut bulk).poll_write(&mut cx, &data[sent..]) {
+                Poll::Ready(Ok(n)) => sent += n,
+                Poll::Ready(Err(e)) => panic!("write failed: {e}"),
+                Poll::Pending => break,
+            }
+        }
+        assert_eq!(sent, WINDOW);
+        // The window's data, in frames, one of them the driver's stuck write.
+        let held = (WINDOW + WINDOW.div_ceil(MAX_FRAME) * HEADER_LEN) as u64;
+        assert_eq!(buffers::buffered(), held);
+        assert_eq!(buffers::peak(), held);
+
+        drop((mux, bulk, driver));
+        assert_eq!(buffers::buffered(), 0);
+    }
+
+    #[test]
+    fn read_bytes_are_released() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let payload = vec![7u8; MAX_FRAME];
+        let frames: Vec<u8> = (0..WINDOW / MAX_FRAME)
+            .flat_map(|_| frame(RPC, DATA, &payload))
+            .collect();
+        let (mux, driver) = Mux::new(futures::io::Cursor::new(frames), futures::io::sink());
+        let mut driver = Box::pin(driver);
+        let mut rpc = mux.channel(RPC);
+        assert!(driver.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(buffers::buffered(), WINDOW as u64);
+
+        let mut buf = vec![0u8; WINDOW];
+        let mut read = 0;
+        while let Poll::Ready(Ok(n @ 1..)) = Pin::new(&mut rpc).poll_read(&mut cx, &mut buf) {
+            read += n;
+            assert!(driver.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(read, WINDOW);
+        assert_eq!(buffers::buffered(), 0);
+        assert_eq!(buffers::peak(), WINDOW as u64);
+    }
+}
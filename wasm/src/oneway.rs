@@ -0,0 +1,67 @@
+//! One-way messages: Cap'n Proto messages written back to back on a stream, without RPC.
+//!
+//! Full RPC pays for a question, an answer and a finish per call, plus capability and pipeline
+//! bookkeeping on both sides. Telemetry and bulk ingestion don't need any of that: the sender
+//! only wants its records delivered in order. [`Sender`] frames each message with the standard
+//! Cap'n Proto stream framing (`capnp_futures::serialize`), and [`Receiver`] reads them back.
+//! Nothing is acknowledged; flow control is whatever the stream underneath provides, e.g. the
+//! credit windows of a [`crate::mux`] channel. With `--mux`, the host reads
+//! `oneway.capnp`'s `Record`s on the [`crate::mux::BULK`] channel.
+
+use capnp::message::{self, ReaderOptions};
+use capnp::serialize::OwnedSegments;
+use futures::io::AsyncWriteExt;
+
+/// Writes one-way messages to a stream.
+pub struct Sender<W> {
+    writer: W,
+    sent: u64,
+}
+
+impl<W: futures_io::AsyncWrite + Unpin> Sender<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, sent: 0 }
+    }
+
+    /// Write `message` and flush it. Returns once the stream has taken it, not once the peer
+    /// has read it.
+    pub async fn send<A: message::Allocator>(
+        &mut self,
+        message: &message::Builder<A>,
+    ) -> capnp::Result<()> {
+        capnp_futures::serialize::write_message(&mut self.writer, message).await?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// Messages written so far.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Close the stream, so the receiver sees the end of the messages.
+    pub async fn close(mut self) -> std::io::Result<()> {
+        self.writer.close().await
+    }
+}
+
+/// Reads one-way messages from a stream.
+pub struct Receiver<R> {
+    reader: R,
+    options: ReaderOptions,
+}
+
+impl<R: futures_io::AsyncRead + Unpin> Receiver<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_options(reader, ReaderOptions::new())
+    }
+
+    pub fn with_options(reader: R, options: ReaderOptions) -> Self {
+        Self { reader, options }
+    }
+
+    /// The next message, or `None` once the stream ends between messages.
+    pub async fn next(&mut self) -> capnp::Result<Option<message::Reader<OwnedSegments>>> {
+        capnp_futures::serialize::try_read_message(&mut self.reader, self.options).await
+    }
+}
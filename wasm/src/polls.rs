@@ -0,0 +1,135 @@
+//! Per-future poll accounting.
+//!
+//! [`instrument`] wraps a future so that, once [`enable`] has been called, every poll of it is
+//! counted and timed under the future's type name. Two shapes stand out in the totals: a future
+//! polled far more often than it has work to do, typically because it wakes itself from its own
+//! poll instead of registering for a readiness event (counted separately as self-wakes), and a
+//! single poll that runs long, which holds up everything else on the single-threaded executor.
+//!
+//! Instrumented futures nest: a future's poll time includes the time spent polling the
+//! instrumented futures inside it. While disabled, an instrumented future costs one flag check
+//! per poll.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Instant;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static COUNTS: RefCell<BTreeMap<&'static str, PollCounts>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Totals for every instrumented future of one type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PollCounts {
+    /// Futures of this type that were polled at least once.
+    pub futures: u64,
+    pub polls: u64,
+    /// Polls during which the future woke itself.
+    pub self_wakes: u64,
+    pub poll_ns: u64,
+    pub longest_poll_ns: u64,
+}
+
+/// Start counting polls of instrumented futures.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The totals so far, by future type name with its generic arguments left out.
+pub fn snapshot() -> Vec<(&'static str, PollCounts)> {
+    COUNTS.with_borrow(|counts| counts.iter().map(|(name, c)| (*name, *c)).collect())
+}
+
+/// Count and time the polls of `fut` under its type name.
+pub fn instrument<F: Future>(fut: F) -> Instrumented<F> {
+    let name = std::any::type_name::<F>();
+    Instrumented {
+        fut,
+        name: name.split('<').next().unwrap_or(name),
+        seen: false,
+        flag: None,
+    }
+}
+
+/// A future counted by [`instrument`].
+pub struct Instrumented<F> {
+    fut: F,
+    name: &'static str,
+    seen: bool,
+    // Reused across polls whenever the previous poll's waker wasn't kept.
+    flag: Option<Arc<Flagged>>,
+}
+
+/// Waker that notes being woken before passing the wake on.
+struct Flagged {
+    inner: Waker,
+    woken: AtomicBool,
+}
+
+impl Wake for Flagged {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Relaxed);
+        self.inner.wake_by_ref();
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: `fut` is structurally pinned and never moved; the other fields are not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        if !enabled() {
+            return fut.poll(cx);
+        }
+
+        let flag = match this.flag.as_mut().and_then(Arc::get_mut) {
+            Some(flag) => {
+                flag.inner.clone_from(cx.waker());
+                *flag.woken.get_mut() = false;
+                this.flag.clone().unwrap()
+            }
+            None => {
+                let flag = Arc::new(Flagged {
+                    inner: cx.waker().clone(),
+                    woken: AtomicBool::new(false),
+                });
+                this.flag = Some(flag.clone());
+                flag
+            }
+        };
+        let waker = Waker::from(flag.clone());
+        let started = Instant::now();
+        let poll = fut.poll(&mut Context::from_waker(&waker));
+        let elapsed_ns = started.elapsed().as_nanos() as u64;
+        drop(waker);
+
+        let first = !std::mem::replace(&mut this.seen, true);
+        let self_woke = poll.is_pending() && flag.woken.load(Ordering::Relaxed);
+        COUNTS.with_borrow_mut(|counts| {
+            let counts = counts.entry(this.name).or_default();
+            counts.futures += first as u64;
+            counts.polls += 1;
+            counts.self_wakes += self_woke as u64;
+            counts.poll_ns += elapsed_ns;
+            counts.longest_poll_ns = counts.longest_poll_ns.max(elapsed_ns);
+        });
+        poll
+    }
+}
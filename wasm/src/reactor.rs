@@ -0,0 +1,84 @@
+// Batched WASI waits. Inside `executor::block_on`, futures waiting on a WASI pollable register it
+// here instead of self-waking; once the executor has nothing left to run, it hands every
+// registered pollable to a single `wasi:io/poll.poll` call and wakes the ones that are ready.
+// That is one host call per executor turn, however many streams and timers are waiting, and the
+// guest sleeps in the host instead of spinning. Outside `block_on`, and under wit-bindgen's
+// executor in WASIp3 builds, futures fall back to self-waking. WASIp1 has no pollables, so its
+// builds leave this out.
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::task::Waker;
+
+use wasip2::io::poll::{self, Pollable};
+
+thread_local! {
+    // Registered waits; `None` outside `block_on`.
+    static WAITS: RefCell<Option<Vec<(Weak<Pollable>, Waker)>>> = const { RefCell::new(None) };
+}
+
+/// Marks the current thread's executor as batching waits until dropped.
+pub struct Enter {
+    previous: Option<Vec<(Weak<Pollable>, Waker)>>,
+}
+
+pub fn enter() -> Enter {
+    Enter {
+        previous: WAITS.replace(Some(Vec::new())),
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        WAITS.set(self.previous.take());
+    }
+}
+
+/// Wake `waker` once `pollable` is ready. Returns false if waits aren't being batched, in which
+/// case the caller has to self-wake. Registering the same pollable again replaces its waker.
+pub fn register(pollable: &Rc<Pollable>, waker: &Waker) -> bool {
+    WAITS.with_borrow_mut(|waits| {
+        let Some(waits) = waits else {
+            return false;
+        };
+        let weak = Rc::downgrade(pollable);
+        match waits.iter_mut().find(|(p, _)| p.ptr_eq(&weak)) {
+            Some((_, w)) => w.clone_from(waker),
+            None => waits.push((weak, waker.clone())),
+        }
+        true
+    })
+}
+
+/// Block in one host call until at least one registered pollable is ready, and wake the futures
+/// waiting on those. Waits whose pollable has been dropped are forgotten. Returns immediately if
+/// nothing is registered.
+pub fn wait() {
+    let (pollables, wakers): (Vec<Rc<Pollable>>, Vec<Waker>) = WAITS.with_borrow_mut(|waits| {
+        let Some(waits) = waits else {
+            return Default::default();
+        };
+        waits.retain(|(p, _)| p.strong_count() > 0);
+        waits
+            .iter()
+            .filter_map(|(p, w)| Some((p.upgrade()?, w.clone())))
+            .unzip()
+    });
+    if pollables.is_empty() {
+        return;
+    }
+    let refs: Vec<&Pollable> = pollables.iter().map(|p| &**p).collect();
+    let ready = poll::poll(&refs);
+    WAITS.with_borrow_mut(|waits| {
+        if let Some(waits) = waits {
+            waits.retain(|(p, _)| {
+                !ready
+                    .iter()
+                    .any(|&i| std::ptr::eq(p.as_ptr(), Rc::as_ptr(&pollables[i as usize])))
+            });
+        }
+    });
+    for i in ready {
+        wakers[i as usize].wake_by_ref();
+    }
+}
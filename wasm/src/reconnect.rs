@@ -0,0 +1,394 @@
+//! Reconnecting after a transport reset.
+//!
+//! When a connection drops, every capability imported over it breaks: the calls in flight and
+//! every later call on it fail as `disconnected`. A [`Session`] holds a way to connect, a closure
+//! that sets up a fresh connection and returns the peer's bootstrap capability, and the bootstrap
+//! of the current connection. A call that fails as disconnected marks that connection broken, and
+//! the next use connects again. Calls that reset at the same time share one reconnect.
+//!
+//! A call in flight when the connection broke may or may not have run at the peer.
+//! [`Session::call`] returns that failure to the caller. [`Session::call_idempotent`] is for calls
+//! that are safe to run twice: it sends them again on a new connection, up to the session's
+//! attempts.
+//!
+//! Other capabilities come back through sturdy refs, names for them that outlive the connection,
+//! such as the `ref` that `EchoerProvider.echoer()` answers with. A [`Restorable`] holds the
+//! closure that restores one from the bootstrap, e.g. with `EchoerProvider.restore`. It restores
+//! the capability once on each new connection, when it is first used there.
+//!
+//! Nothing here waits between attempts. A `connect` closure that should back off sleeps before it
+//! connects. This is the host's `cap::reconnect` for guests; keep the two in sync. A guest's
+//! `connect` reopens whatever transport it has, e.g. a new socket, or a new mux channel.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::rc::Rc;
+
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+
+type Connecting<T> = Shared<LocalBoxFuture<'static, capnp::Result<T>>>;
+type Restore<B, C> = Box<dyn Fn(&B) -> LocalBoxFuture<'static, capnp::Result<C>>>;
+
+/// Whether `error` means the connection it was sent on is gone.
+pub fn is_disconnected(error: &capnp::Error) -> bool {
+    error.kind == capnp::ErrorKind::Disconnected
+}
+
+enum State<B> {
+    /// Connection `epoch` is broken, or none has been made yet (epoch 0).
+    Broken(u64),
+    /// Connection `epoch` is being made; everyone who needs it waits on the same attempt.
+    Connecting(u64, Connecting<B>),
+    /// The bootstrap of connection `epoch`.
+    Connected(u64, B),
+}
+
+struct Inner<B> {
+    connect: Box<dyn Fn() -> LocalBoxFuture<'static, capnp::Result<B>>>,
+    attempts: u32,
+    state: RefCell<State<B>>,
+    connections: Cell<u64>,
+}
+
+/// A connection to a peer that is made again when it breaks. Clones share the connection.
+pub struct Session<B> {
+    inner: Rc<Inner<B>>,
+}
+
+impl<B> Clone for Session<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: Clone + 'static> Session<B> {
+    /// A session that connects with `connect` when it is first used. Idempotent calls are sent
+    /// at most `attempts` times.
+    pub fn new<F, Fut>(attempts: u32, connect: F) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = capnp::Result<B>> + 'static,
+    {
+        Self {
+            inner: Rc::new(Inner {
+                connect: Box::new(move || connect().boxed_local()),
+                attempts: attempts.max(1),
+                state: RefCell::new(State::Broken(0)),
+                connections: Cell::new(0),
+            }),
+        }
+    }
+
+    /// Connections made so far.
+    pub fn connections(&self) -> u64 {
+        self.inner.connections.get()
+    }
+
+    /// The bootstrap of the current connection, and the connection's epoch. Connects first if
+    /// there is none.
+    async fn current(&self) -> capnp::Result<(u64, B)> {
+        let (epoch, connecting) = {
+            let mut state = self.inner.state.borrow_mut();
+            match &*state {
+                State::Connected(epoch, bootstrap) => return Ok((*epoch, bootstrap.clone())),
+                State::Connecting(epoch, connecting) => (*epoch, connecting.clone()),
+                State::Broken(broken) => {
+                    let epoch = broken + 1;
+                    let connecting = (self.inner.connect)().shared();
+                    *state = State::Connecting(epoch, connecting.clone());
+                    (epoch, connecting)
+                }
+            }
+        };
+        let result = connecting.await;
+        // The first caller back records the outcome for the others.
+        let mut state = self.inner.state.borrow_mut();
+        if matches!(&*state, State::Connecting(e, _) if *e == epoch) {
+            *state = match &result {
+                Ok(bootstrap) => {
+                    self.inner.connections.set(self.connections() + 1);
+                    if epoch > 1 {
+                        log_reconnected(epoch);
+                    }
+                    State::Connected(epoch, bootstrap.clone())
+                }
+                Err(e) => {
+                    log_connect_failed(epoch, e);
+                    State::Broken(epoch)
+                }
+            };
+        }
+        result.map(|bootstrap| (epoch, bootstrap))
+    }
+
+    /// Mark connection `epoch` broken, unless it has been replaced already.
+    fn broke(&self, epoch: u64) {
+        let mut state = self.inner.state.borrow_mut();
+        if matches!(&*state, State::Connected(e, _) if *e == epoch) {
+            log_reset(epoch);
+            *state = State::Broken(epoch);
+        }
+    }
+
+    /// Run `call` on the bootstrap once. If it fails as disconnected, the next call reconnects.
+    pub async fn call<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: FnOnce(&B) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let (epoch, bootstrap) = self.current().await?;
+        let result = call(&bootstrap).await;
+        if let Err(e) = &result
+            && is_disconnected(e)
+        {
+            self.broke(epoch);
+        }
+        result
+    }
+
+    /// Run `call` on the bootstrap, and again on a new connection each time it fails as
+    /// disconnected, up to the session's attempts. A `connect` that fails as disconnected counts
+    /// as a failed attempt too.
+    pub async fn call_idempotent<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: Fn(&B) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.call(&call).await {
+                Err(e) if is_disconnected(&e) && attempt < self.inner.attempts => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// A capability restored from the bootstrap by `restore` on every connection it is used on.
+    pub fn restorable<C, F, Fut>(&self, restore: F) -> Restorable<B, C>
+    where
+        F: Fn(&B) -> Fut + 'static,
+        Fut: Future<Output = capnp::Result<C>> + 'static,
+    {
+        Restorable {
+            session: self.clone(),
+            restore: Box::new(move |bootstrap| restore(bootstrap).boxed_local()),
+            restored: RefCell::new(Cap::None),
+        }
+    }
+}
+
+enum Cap<C> {
+    None,
+    /// Being restored on connection `epoch`.
+    Restoring(u64, Connecting<C>),
+    /// Restored on connection `epoch`.
+    Restored(u64, C),
+}
+
+/// A capability that is restored from its sturdy ref on each new connection of a [`Session`].
+pub struct Restorable<B, C> {
+    session: Session<B>,
+    restore: Restore<B, C>,
+    restored: RefCell<Cap<C>>,
+}
+
+impl<B: Clone + 'static, C: Clone + 'static> Restorable<B, C> {
+    /// The capability on the current connection, and the connection's epoch. Restores it there
+    /// first if it hasn't been yet.
+    async fn current(&self) -> capnp::Result<(u64, C)> {
+        let (epoch, bootstrap) = self.session.current().await?;
+        let restoring = {
+            let mut restored = self.restored.borrow_mut();
+            match &*restored {
+                Cap::Restored(e, cap) if *e == epoch => return Ok((epoch, cap.clone())),
+                Cap::Restoring(e, restoring) if *e == epoch => restoring.clone(),
+                _ => {
+                    let restoring = (self.restore)(&bootstrap).shared();
+                    *restored = Cap::Restoring(epoch, restoring.clone());
+                    restoring
+                }
+            }
+        };
+        let result = restoring.await;
+        let mut restored = self.restored.borrow_mut();
+        if matches!(&*restored, Cap::Restoring(e, _) if *e == epoch) {
+            *restored = match &result {
+                Ok(cap) => Cap::Restored(epoch, cap.clone()),
+                Err(_) => Cap::None,
+            };
+        }
+        drop(restored);
+        match result {
+            Ok(cap) => Ok((epoch, cap)),
+            Err(e) => {
+                if is_disconnected(&e) {
+                    self.session.broke(epoch);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Run `call` on the capability once. If it fails as disconnected, the next call reconnects.
+    pub async fn call<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: FnOnce(&C) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let (epoch, cap) = self.current().await?;
+        let result = call(&cap).await;
+        if let Err(e) = &result
+            && is_disconnected(e)
+        {
+            self.session.broke(epoch);
+        }
+        result
+    }
+
+    /// Run `call` on the capability, and again on a new connection each time it, or restoring
+    /// the capability, fails as disconnected, up to the session's attempts.
+    pub async fn call_idempotent<T, F, Fut>(&self, call: F) -> capnp::Result<T>
+    where
+        F: Fn(&C) -> Fut,
+        Fut: Future<Output = capnp::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.call(&call).await {
+                Err(e) if is_disconnected(&e) && attempt < self.session.inner.attempts => {
+                    attempt += 1
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn log_reconnected(epoch: u64) {
+    tracing::info!(epoch, "reconnected");
+}
+
+#[cfg(feature = "tracing")]
+fn log_connect_failed(epoch: u64, e: &capnp::Error) {
+    tracing::warn!(epoch, error = %e, "connecting failed");
+}
+
+#[cfg(feature = "tracing")]
+fn log_reset(epoch: u64) {
+    tracing::warn!(epoch, "connection reset");
+}
+
+#[cfg(not(feature = "tracing"))]
+fn log_reconnected(_epoch: u64) {}
+
+#[cfg(not(feature = "tracing"))]
+fn log_connect_failed(_epoch: u64, _e: &capnp::Error) {}
+
+#[cfg(not(feature = "tracing"))]
+fn log_reset(_epoch: u64) {}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+    use std::task::{Context, Poll};
+
+    use futures::future::join_all;
+    use futures::task::noop_waker;
+
+    use super::*;
+
+    // A connection to a peer that echoes numbers, and that resets after answering `left` calls.
+    #[derive(Clone)]
+    struct Conn {
+        alive: Rc<Cell<bool>>,
+        left: Rc<Cell<u32>>,
+    }
+
+    impl Conn {
+        fn new(left: u32) -> Self {
+            Self {
+                alive: Rc::new(Cell::new(true)),
+                left: Rc::new(Cell::new(left)),
+            }
+        }
+
+        fn echo(&self, n: u32) -> impl Future<Output = capnp::Result<u32>> + use<> {
+            let conn = self.clone();
+            async move {
+                if conn.left.get() == 0 {
+                    conn.alive.set(false);
+                }
+                if !conn.alive.get() {
+                    return Err(capnp::Error::disconnected("connection reset".to_string()));
+                }
+                conn.left.set(conn.left.get() - 1);
+                Ok(n)
+            }
+        }
+    }
+
+    // Nothing here waits on anything, so one poll runs `future` to the end.
+    fn run<F: Future>(future: F) -> F::Output {
+        let waker = noop_waker();
+        match pin!(future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future didn't finish"),
+        }
+    }
+
+    // A session whose first connection resets after `left` calls.
+    fn session(left: u32) -> Session<Conn> {
+        let made = Cell::new(0);
+        Session::new(3, move || {
+            let first = made.replace(made.get() + 1) == 0;
+            let conn = Conn::new(if first { left } else { u32::MAX });
+            async move { Ok(conn) }
+        })
+    }
+
+    #[test]
+    fn idempotent_calls_survive_a_reset_mid_batch() {
+        let session = session(20);
+        let restores = Rc::new(Cell::new(0));
+        let counted = restores.clone();
+        let echoer = session.restorable(move |conn: &Conn| {
+            counted.set(counted.get() + 1);
+            let conn = conn.clone();
+            async move { Ok(conn) }
+        });
+        let calls = (0..100).map(|n| echoer.call_idempotent(move |conn: &Conn| conn.echo(n)));
+        for (n, reply) in run(join_all(calls)).into_iter().enumerate() {
+            assert_eq!(reply.unwrap(), n as u32);
+        }
+        // One reset, one reconnect, and the capability restored on each connection once.
+        assert_eq!(session.connections(), 2);
+        assert_eq!(restores.get(), 2);
+    }
+
+    #[test]
+    fn a_call_cut_off_fails_and_the_next_one_reconnects() {
+        let session = session(0);
+        let error = run(session.call(|conn: &Conn| conn.echo(1))).unwrap_err();
+        assert!(is_disconnected(&error));
+        assert_eq!(run(session.call(|conn: &Conn| conn.echo(2))).unwrap(), 2);
+        assert_eq!(session.connections(), 2);
+    }
+
+    #[test]
+    fn idempotent_calls_give_up_after_the_sessions_attempts() {
+        // Every connection resets before answering.
+        let made = Rc::new(Cell::new(0));
+        let counted = made.clone();
+        let session = Session::new(3, move || {
+            counted.set(counted.get() + 1);
+            let conn = Conn::new(0);
+            async move { Ok(conn) }
+        });
+        let error = run(session.call_idempotent(|conn: &Conn| conn.echo(1))).unwrap_err();
+        assert!(is_disconnected(&error));
+        assert_eq!(made.get(), 3);
+    }
+}
@@ -0,0 +1,63 @@
+//! Buffers reused across many near-identical messages.
+//!
+//! A stress batch builds thousands of requests that differ only in a few bytes. Allocating the
+//! text and the message for each one shows up in the guest's allocation counts as much as the
+//! transport does. `capnp-rpc` allocates every request's message itself, so an RPC request builder
+//! can't be recycled, but everything around it can: [`TextScratch`] holds the payload text while a
+//! request is filled in, and [`MessageScratch`] gives messages the guest builds itself, like
+//! one-way records, a first segment that is allocated once.
+
+use capnp::Word;
+use capnp::message::{self, ScratchSpaceHeapAllocator};
+
+/// Words of scratch space when the caller doesn't choose; messages up to 8 KiB fit.
+pub const DEFAULT_WORDS: usize = 1024;
+
+/// A text buffer refilled for each message.
+#[derive(Default)]
+pub struct TextScratch {
+    buf: String,
+}
+
+impl TextScratch {
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            buf: String::with_capacity(bytes),
+        }
+    }
+
+    /// Clear the buffer, let `fill` write the next text into it, and return that text. The
+    /// buffer keeps its capacity, so once it has grown to the largest text it stops allocating.
+    pub fn fill(&mut self, fill: impl FnOnce(&mut String)) -> &str {
+        self.buf.clear();
+        fill(&mut self.buf);
+        &self.buf
+    }
+}
+
+/// A first segment shared by the messages built from it, one at a time.
+pub struct MessageScratch {
+    words: Vec<Word>,
+}
+
+impl MessageScratch {
+    pub fn new(words: usize) -> Self {
+        Self {
+            words: Word::allocate_zeroed_vec(words),
+        }
+    }
+
+    /// A builder whose first segment is the scratch space. A message that fits allocates
+    /// nothing; a larger one gets further segments from the heap. Dropping the builder zeroes
+    /// the space again for the next message.
+    pub fn builder(&mut self) -> message::Builder<ScratchSpaceHeapAllocator<'_>> {
+        let space = Word::words_to_bytes_mut(&mut self.words);
+        message::Builder::new(ScratchSpaceHeapAllocator::new(space))
+    }
+}
+
+impl Default for MessageScratch {
+    fn default() -> Self {
+        Self::new(DEFAULT_WORDS)
+    }
+}
@@ -0,0 +1,193 @@
+use capnp::capability::Promise;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use wetware_guest::{buffers, polls};
+
+use crate::guest_capnp::guest_stats;
+
+/// Environment variable the host sets to have polls counted and reported in `GuestStats`.
+pub const POLL_STATS_ENV: &str = "WETWARE_POLL_STATS";
+
+// Counters backing the `GuestStats` capability. The guest is single-threaded, so relaxed
+// atomics are only used to get safe global mutability, not for synchronization.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// Allocations are also charged to the scope that was running when they were made (see
+// [`attribute`]). The allocator can't allocate, so the scopes are a fixed table: `other`, the
+// transport, then one slot per stress batch. Batches past the table count as `other`.
+const BATCH_SLOTS: usize = 64;
+const OTHER_SLOT: usize = 0;
+const TRANSPORT_SLOT: usize = 1;
+const FIRST_BATCH_SLOT: usize = 2;
+
+struct ScopeCounts {
+    allocations: AtomicU64,
+    bytes: AtomicU64,
+}
+
+static SCOPES: [ScopeCounts; FIRST_BATCH_SLOT + BATCH_SLOTS] = [const {
+    ScopeCounts {
+        allocations: AtomicU64::new(0),
+        bytes: AtomicU64::new(0),
+    }
+}; FIRST_BATCH_SLOT + BATCH_SLOTS];
+static CURRENT_SCOPE: AtomicUsize = AtomicUsize::new(OTHER_SLOT);
+
+/// What an allocation is charged to.
+#[derive(Debug, Clone, Copy)]
+pub enum AllocScope {
+    /// The RPC connections and the transport under them.
+    Transport,
+    /// One stress batch: building and sending its calls and reading their replies.
+    #[cfg_attr(not(feature = "stress"), allow(dead_code))]
+    Batch(usize),
+}
+
+impl AllocScope {
+    fn slot(self) -> usize {
+        match self {
+            AllocScope::Transport => TRANSPORT_SLOT,
+            AllocScope::Batch(b) if b < BATCH_SLOTS => FIRST_BATCH_SLOT + b,
+            AllocScope::Batch(_) => OTHER_SLOT,
+        }
+    }
+}
+
+/// Run `fut`, charging the allocations made while it is polled to `scope`.
+pub async fn attribute<F: Future>(scope: AllocScope, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    std::future::poll_fn(|cx| {
+        let outer = CURRENT_SCOPE.swap(scope.slot(), Ordering::Relaxed);
+        let poll = fut.as_mut().poll(cx);
+        CURRENT_SCOPE.store(outer, Ordering::Relaxed);
+        poll
+    })
+    .await
+}
+
+/// Allocations and bytes charged to batches `0..batches` so far.
+#[cfg_attr(not(feature = "stress"), allow(dead_code))]
+pub fn batch_allocations(batches: usize) -> (u64, u64) {
+    let end = FIRST_BATCH_SLOT + batches.min(BATCH_SLOTS);
+    SCOPES[FIRST_BATCH_SLOT..end]
+        .iter()
+        .fold((0, 0), |(allocations, bytes), scope| {
+            (
+                allocations + scope.allocations.load(Ordering::Relaxed),
+                bytes + scope.bytes.load(Ordering::Relaxed),
+            )
+        })
+}
+
+fn count_allocation(bytes: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_ALLOCATED.fetch_add(bytes as u64, Ordering::Relaxed);
+    let scope = &SCOPES[CURRENT_SCOPE.load(Ordering::Relaxed)];
+    scope.allocations.fetch_add(1, Ordering::Relaxed);
+    scope.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Global allocator wrapper that counts allocations before forwarding to the system allocator.
+/// Installed with the `alloc-stats` feature.
+#[cfg_attr(not(feature = "alloc-stats"), allow(dead_code))]
+pub struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Count a reallocation as a fresh allocation of the new size.
+        count_allocation(new_size);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// Mark a request as submitted; pair with [`request_finished`] once its reply is consumed.
+pub fn request_started() {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn request_finished() {
+    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+// Memory64 guests count pages with a 64-bit index.
+#[cfg(target_arch = "wasm64")]
+fn memory_bytes() -> u64 {
+    core::arch::wasm64::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+/// Capability exported to the host as the guest bootstrap, reporting resource usage on demand.
+pub struct GuestStats;
+
+impl guest_stats::Server for GuestStats {
+    fn stats(
+        &mut self,
+        _params: guest_stats::StatsParams,
+        mut results: guest_stats::StatsResults,
+    ) -> Promise<(), capnp::Error> {
+        let mut stats = results.get().init_stats();
+        stats.set_memory_bytes(memory_bytes());
+        stats.set_allocations(ALLOCATIONS.load(Ordering::Relaxed));
+        stats.set_deallocations(DEALLOCATIONS.load(Ordering::Relaxed));
+        stats.set_bytes_allocated(BYTES_ALLOCATED.load(Ordering::Relaxed));
+        stats.set_in_flight(IN_FLIGHT.load(Ordering::Relaxed));
+        stats.set_transport_buffered(buffers::buffered());
+        stats.set_transport_buffered_peak(buffers::peak());
+        // Empty unless poll counting is enabled.
+        let counts = polls::snapshot();
+        let mut list = stats.init_polls(counts.len() as u32);
+        for (i, (name, c)) in counts.into_iter().enumerate() {
+            let mut entry = list.reborrow().get(i as u32);
+            entry.set_future(name);
+            entry.set_futures(c.futures);
+            entry.set_polls(c.polls);
+            entry.set_self_wakes(c.self_wakes);
+            entry.set_poll_nanos(c.poll_ns);
+            entry.set_longest_poll_nanos(c.longest_poll_ns);
+        }
+        // Only the scopes that allocated anything.
+        let scopes: Vec<_> = SCOPES
+            .iter()
+            .enumerate()
+            .map(|(slot, counts)| {
+                let allocations = counts.allocations.load(Ordering::Relaxed);
+                (slot, allocations, counts.bytes.load(Ordering::Relaxed))
+            })
+            .filter(|&(_, allocations, _)| allocations > 0)
+            .collect();
+        let mut list = stats.init_alloc_scopes(scopes.len() as u32);
+        for (i, (slot, allocations, bytes)) in scopes.into_iter().enumerate() {
+            let mut entry = list.reborrow().get(i as u32);
+            match slot {
+                OTHER_SLOT => entry.set_scope("other"),
+                TRANSPORT_SLOT => entry.set_scope("transport"),
+                batch => entry.set_scope(format!("batch {}", batch - FIRST_BATCH_SLOT).as_str()),
+            }
+            entry.set_allocations(allocations);
+            entry.set_bytes(bytes);
+        }
+        Promise::ok(())
+    }
+}
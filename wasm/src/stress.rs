@@ -0,0 +1,812 @@
+//! The stress workload the example guest runs by default: a burst of concurrent `echoer()` calls
+//! (see `fanout`), batches of concurrent echo calls whose replies are consumed in shuffled order,
+//! the same traffic through `Send` handles, pipelined provider-to-echo chains (see `chain`),
+//! several kinds of traffic at once (see `mixed`), then rounds of short-lived echoers checked for
+//! leaks (see `lifetime`). Left out of size-optimized builds (without the `stress` feature),
+//! which make a single call instead. `WETWARE_READ_ORDER` picks another order to consume the
+//! replies in (see [`ReadOrder`]).
+//!
+//! The first batch to fail shuts the others down: each stops sending at its next call and stops
+//! waiting at its next reply, and its outstanding calls are cancelled. Every batch then reports how
+//! many of its calls completed, failed or were cancelled before the workload returns the error.
+//! `WETWARE_ON_ERROR=continue` keeps going instead, as soak tests need: a batch records a failed
+//! call and goes on with the rest, a failed stage is recorded and the next one runs, and the
+//! failures are reported together at the end (see [`OnError`]).
+//!
+//! Before the timed batch stage, `WETWARE_WARMUP` echo calls run as one untimed batch, so the
+//! first calls' compilation, allocation and buffer growth don't land in the measured latencies.
+//! Their latencies are reported apart from the steady-state ones.
+//!
+//! Each batch formats its request text into one reused buffer (`wetware_guest::reuse`) and
+//! rebuilds the expected reply when checking it, rather than allocating both per call.
+//! `WETWARE_REUSE=0` goes back to a fresh string per call, to measure the difference; the summary
+//! reports the batches' allocations either way.
+//!
+//! `WETWARE_ECHO_FILE` names a file in a preopened directory whose contents the guest echoes
+//! before anything else, so filesystem access is exercised while the connection is up.
+//!
+//! `WETWARE_ECHO_BATCH` then sends the batch stage's messages again, that many to an
+//! `echoBatch` call (see `batched`), to compare against one call per message.
+//!
+//! `WETWARE_DISCONNECT_AFTER` runs the disconnect stage (see `disconnect`) instead of all of
+//! these: it has the host hang up partway through a batch. `WETWARE_MIGRATE_CALLS` runs the
+//! migrate stage (see `migrate`) instead: its calls carry on in a fresh instance when the host
+//! asks the guest to move.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures::pin_mut;
+use futures::stream::{FuturesUnordered, StreamExt};
+use wetware_guest::call_id;
+use wetware_guest::coop::{self, Budget};
+use wetware_guest::flow::QuestionLimit;
+use wetware_guest::reuse::TextScratch;
+use wetware_guest::rng;
+
+use crate::echo_capnp;
+use crate::watchdog::BatchWatch;
+use crate::{
+    batched, chain, disconnect, executor, fanout, handle, lifetime, migrate, mixed, stats, timer,
+};
+
+// Workers and calls per worker for the cross-thread `EchoHandle` stress stage.
+const HANDLE_WORKERS: usize = 8;
+const HANDLE_CALLS: usize = 100;
+
+/// Run every stage of the stress workload against `echoer` and `provider`, reporting each batch
+/// to `progress`.
+pub async fn run(
+    provider: echo_capnp::echoer_provider::Client,
+    echoer: echo_capnp::echoer::Client,
+    progress: echo_capnp::progress::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Number of tasks per batch, number of batches and message size, overridable from the
+    // environment (as the host's load-test driver does) to sweep the workload.
+    let call_count: usize = env_or(CALLS_ENV, 1000);
+    let batch_count: usize = env_or(BATCHES_ENV, 10);
+    let payload: usize = env_or(PAYLOAD_ENV, 0);
+    let reuse = env_or(REUSE_ENV, 1) != 0;
+    let warmup = Warmup {
+        count: env_or(WARMUP_ENV, 100),
+        index: batch_count,
+        reuse,
+    };
+    let read_order = ReadOrder::from_env()?;
+    let mut failures = Failures::new(OnError::from_env()?);
+    // Seed for the shuffles, derived by the host from its run seed; WASI random if absent.
+    let fixed_seed: Option<u64> = if read_order == ReadOrder::Shuffle {
+        seed_from_env()
+    } else {
+        None
+    };
+    // Stay within the host's per-connection question cap, if it advertises one.
+    let questions = QuestionLimit::from_env();
+
+    // The guest may move to a fresh instance partway through the migrate stage, so it runs alone.
+    if let Some(calls) = migrate::calls() {
+        return migrate::run(&provider, calls).await;
+    }
+
+    // The connection doesn't survive the disconnect stage, so it runs alone.
+    if let Some(after) = disconnect::after_calls() {
+        return disconnect::run(&provider, &echoer, after).await;
+    }
+
+    failures.stage("echo file", echo_file(&echoer).await)?;
+
+    let warmup_ns = warm_up(&echoer, warmup, payload, &questions).await;
+    let warmup_ns = failures.stage("warm-up", warmup_ns)?.unwrap_or_default();
+    let started = timer::monotonic_now_ns();
+
+    failures.stage("fanout", fanout::run(&provider, &questions).await)?;
+
+    // Launch all batches at once and await them asynchronously as they finish.
+    let shutdown = Shutdown::default();
+    let on_error = failures.policy;
+    let mut futs: FuturesUnordered<_> = (0..batch_count)
+        .map(|b| {
+            let e = echoer.clone();
+            let questions = questions.clone();
+            let progress = progress.clone();
+            let shutdown = shutdown.clone();
+            // Derive a per-batch seed if a fixed seed was provided; otherwise None -> WASI seed.
+            let batch_seed = fixed_seed.map(|s| s ^ (b as u64).wrapping_mul(0x9E3779B97F4A7C15));
+            let read = Reads {
+                order: read_order,
+                seed: batch_seed,
+                reuse,
+                on_error,
+            };
+            async move {
+                let batch = async {
+                    log!("guest: starting batch {} ({} tasks)", b, call_count);
+                    report_started(&progress, b, call_count).await;
+                    let outcome =
+                        run_echo_batch(e, b, call_count, payload, read, questions, &shutdown).await;
+                    report_finished(&progress, b, &outcome).await;
+                    outcome
+                };
+                #[cfg(feature = "tracing")]
+                let batch =
+                    tracing::Instrument::instrument(batch, tracing::info_span!("batch", index = b));
+                let batch = stats::attribute(stats::AllocScope::Batch(b), batch);
+                (b, batch.await)
+            }
+        })
+        .collect();
+
+    // Wait for every batch, even after one fails, so none is left running.
+    let mut latencies_ns = Vec::with_capacity(batch_count * call_count);
+    let mut outcomes = Vec::with_capacity(batch_count);
+    let mut first_error = None;
+    while let Some((i, mut outcome)) = futs.next().await {
+        match outcome.error.take() {
+            None if outcome.cancelled == 0 => log!("guest: batch {} completed", i),
+            None => log!("guest: batch {} cancelled", i),
+            Some(e) if on_error == OnError::Continue => {
+                log!("guest: batch {} had {} failed calls", i, outcome.failed);
+                failures.batch(i, outcome.failed, e);
+            }
+            Some(e) => {
+                log!("guest: batch {} failed: {e}", i);
+                first_error.get_or_insert(e);
+            }
+        }
+        latencies_ns.extend(std::mem::take(&mut outcome.latencies));
+        outcomes.push((i, outcome));
+    }
+    if let Some(e) = first_error {
+        outcomes.sort_by_key(|(i, _)| *i);
+        for (i, outcome) in &outcomes {
+            log!(
+                "guest: batch {}: {} completed, {} failed, {} cancelled",
+                i,
+                outcome.completed,
+                outcome.failed,
+                outcome.cancelled
+            );
+        }
+        return Err(e);
+    }
+
+    let batches_ns = timer::monotonic_now_ns().saturating_sub(started);
+    let (batch_allocations, batch_alloc_bytes) = stats::batch_allocations(batch_count);
+    if failures.calls == 0 {
+        log!("guest: all batches completed successfully");
+    }
+
+    // The same messages again, several to a call.
+    let echo_batch = batched::run(&echoer, &questions, batch_count * call_count, payload).await;
+    let echo_batch = failures.stage("batched", echo_batch)?.flatten();
+
+    // Same traffic through `Send` handles, as code on other threads would issue it.
+    let echo_handle = handle::spawn(echoer.clone());
+    let handles = handle::stress(echo_handle, HANDLE_WORKERS, HANDLE_CALLS).await;
+    if failures
+        .stage("handle", handles.map_err(Into::into))?
+        .is_some()
+    {
+        log!("guest: handle stress completed successfully");
+    }
+
+    failures.stage("chain", chain::run(&provider, &questions).await)?;
+    failures.stage("mixed", mixed::run(&provider, &echoer).await)?;
+    failures.stage("lifetime", lifetime::run(&provider, &questions).await)?;
+
+    // Write a run summary while the connection is still live, if the host gave us a place.
+    write_summary(&Summary {
+        batch_count,
+        call_count,
+        payload,
+        batches_ns,
+        latencies_ns,
+        warmup_ns,
+        reuse,
+        batch_allocations,
+        batch_alloc_bytes,
+        echo_batch,
+        failures: &failures,
+    })?;
+
+    failures.into_result()
+}
+
+/// The untimed batch run before the others.
+#[derive(Debug, Clone, Copy)]
+struct Warmup {
+    count: usize,
+    /// The warm-up's batch index in stall reports, one past the timed batches.
+    index: usize,
+    /// Whether it reuses its buffers, like the timed batches.
+    reuse: bool,
+}
+
+/// Run the warm-up's echo calls of `payload` bytes as one batch, read in the order they were
+/// sent, and return their latencies.
+async fn warm_up(
+    echoer: &echo_capnp::echoer::Client,
+    warmup: Warmup,
+    payload: usize,
+    questions: &QuestionLimit,
+) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    if warmup.count == 0 {
+        return Ok(Vec::new());
+    }
+    log!("guest: warming up with {} calls", warmup.count);
+    let read = Reads {
+        order: ReadOrder::Fifo,
+        seed: None,
+        reuse: warmup.reuse,
+        on_error: OnError::Abort,
+    };
+    let shutdown = Shutdown::default();
+    let mut outcome = run_echo_batch(
+        echoer.clone(),
+        warmup.index,
+        warmup.count,
+        payload,
+        read,
+        questions.clone(),
+        &shutdown,
+    )
+    .await;
+    if let Some(e) = outcome.error.take() {
+        return Err(format!("warm-up failed: {e}").into());
+    }
+    Ok(outcome.latencies)
+}
+
+/// Tell the host a batch is starting. Progress is best-effort: hosts without a `Progress`
+/// capability fail these calls, and that shouldn't fail the workload.
+async fn report_started(progress: &echo_capnp::progress::Client, batch: usize, calls: usize) {
+    let mut request = progress.batch_started_request();
+    request.get().set_batch(batch as u32);
+    request.get().set_calls(calls as u32);
+    let _ = request.send().promise.await;
+}
+
+/// Tell the host a batch is done. Cancelled calls count as failed.
+async fn report_finished(
+    progress: &echo_capnp::progress::Client,
+    batch: usize,
+    outcome: &BatchOutcome,
+) {
+    let mut request = progress.batch_finished_request();
+    request.get().set_batch(batch as u32);
+    request.get().set_completed(outcome.completed as u32);
+    request
+        .get()
+        .set_failed((outcome.failed + outcome.cancelled) as u32);
+    let _ = request.send().promise.await;
+}
+
+/// What became of one batch's calls. Calls that were never sent count as cancelled.
+struct BatchOutcome {
+    /// Latencies of the completed calls, in nanoseconds.
+    latencies: Vec<u64>,
+    completed: usize,
+    failed: usize,
+    cancelled: usize,
+    error: Option<Box<dyn std::error::Error>>,
+}
+
+/// Shared by the batches of one run: set once one of them fails, so the rest stop early.
+#[derive(Clone, Default)]
+struct Shutdown {
+    state: Rc<RefCell<ShutdownState>>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: bool,
+    waiters: Vec<Waker>,
+}
+
+impl Shutdown {
+    fn request(&self) {
+        let waiters = {
+            let mut state = self.state.borrow_mut();
+            state.requested = true;
+            std::mem::take(&mut state.waiters)
+        };
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    fn requested(&self) -> bool {
+        self.state.borrow().requested
+    }
+
+    /// Run `fut` unless shutdown is requested first; `None` if it was.
+    async fn unless<F: Future>(&self, fut: F) -> Option<F::Output> {
+        let requested = future::poll_fn(|cx| {
+            let mut state = self.state.borrow_mut();
+            if state.requested {
+                return Poll::Ready(());
+            }
+            if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        });
+        pin_mut!(fut);
+        pin_mut!(requested);
+        match future::select(fut, requested).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+/// Submit `count` echo requests in order, then consume replies in the order `read` gives.
+/// Messages are padded to at least `payload` bytes.
+/// At most `questions` calls are outstanding at once across all batches.
+/// The batch stops at the first failed call, and requests `shutdown`; it stops as well once
+/// another batch has requested it. Calls still outstanding when it stops are cancelled. If
+/// `read.on_error` is [`OnError::Continue`], it counts failed calls instead and keeps the first
+/// error. The guest exits if the batch stalls (see `watchdog`). Both loops yield to the executor
+/// every `WETWARE_YIELD_EVERY` iterations, so replies are read off the connection while they run.
+async fn run_echo_batch(
+    echoer: echo_capnp::echoer::Client,
+    batch: usize,
+    count: usize,
+    payload: usize,
+    read: Reads,
+    questions: QuestionLimit,
+    shutdown: &Shutdown,
+) -> BatchOutcome {
+    let watch = BatchWatch::start(batch);
+    // Submit echo requests in order, store their promises by index.
+    let mut promises: Vec<Option<_>> = Vec::with_capacity(count);
+    // The call ID of each request, by index, to name a failed call as the host's logs do.
+    let mut call_ids: Vec<u64> = Vec::with_capacity(count);
+    // Only without reuse; otherwise each expected reply is rebuilt when it is checked.
+    let mut expected: Vec<String> = Vec::new();
+    let mut text = TextScratch::with_capacity(payload);
+    let yield_every = env_or(YIELD_EVERY_ENV, coop::DEFAULT_EVERY as usize) as u32;
+    let mut budget = Budget::new(yield_every);
+
+    for i in 0..count {
+        budget.tick().await;
+        if shutdown.requested() {
+            break;
+        }
+        let mut echo_request = echoer.echo_request();
+        if read.reuse {
+            echo_request
+                .get()
+                .set_msg(text.fill(|buf| echo_message(buf, i, payload)));
+        } else {
+            let mut msg = String::new();
+            echo_message(&mut msg, i, payload);
+            echo_request.get().set_msg(msg.as_str());
+            expected.push(msg);
+        }
+        let call_id = call_id::next();
+        echo_request.get().set_call_id(call_id);
+        log!("guest: submitting echo {} (call ID {})", i, call_id);
+        // Hold a question slot until the reply is in. Each call is awaited by its own task so the
+        // slot frees up as soon as the response arrives, whatever order we consume it in.
+        let Some(permit) = shutdown.unless(questions.acquire()).await else {
+            break;
+        };
+        let sent = timer::monotonic_now_ns();
+        let promise = echo_request.send().promise;
+        stats::request_started();
+        watch.sent(i);
+        let resolver = watch.resolver(i);
+        let (mut reply_tx, reply_rx) = oneshot::channel();
+        let call = async move {
+            // Dropping the receiver cancels the call.
+            let response = match future::select(promise, reply_tx.cancellation()).await {
+                Either::Left((response, _)) => response,
+                Either::Right(_) => {
+                    stats::request_finished();
+                    return;
+                }
+            };
+            let latency = timer::monotonic_now_ns().saturating_sub(sent);
+            resolver.resolved();
+            stats::request_finished();
+            drop(permit);
+            let _ = reply_tx.send((response, latency));
+        };
+        executor::spawn(stats::attribute(stats::AllocScope::Batch(batch), call));
+        promises.push(Some(reply_rx));
+        call_ids.push(call_id);
+    }
+
+    // Consume results in the chosen order.
+    let order = read.indices(promises.len());
+
+    let mut outcome = BatchOutcome {
+        latencies: Vec::with_capacity(count),
+        completed: 0,
+        failed: 0,
+        cancelled: 0,
+        error: None,
+    };
+    let mut budget = Budget::new(yield_every);
+    for idx in order {
+        budget.tick().await;
+        let promise = promises[idx].take().expect("promise should be present");
+        let Some(reply) = shutdown.unless(promise).await else {
+            break;
+        };
+        let checked = if read.reuse {
+            check_reply(reply, text.fill(|buf| echo_message(buf, idx, payload)))
+        } else {
+            check_reply(reply, &expected[idx])
+        };
+        match checked {
+            Ok(latency) => {
+                outcome.latencies.push(latency);
+                outcome.completed += 1;
+            }
+            Err(e) => {
+                outcome.failed += 1;
+                let call_id = call_ids[idx];
+                outcome
+                    .error
+                    .get_or_insert_with(|| format!("echo {idx} (call ID {call_id}): {e}").into());
+                if read.on_error == OnError::Abort {
+                    shutdown.request();
+                    break;
+                }
+            }
+        }
+    }
+    // Whatever wasn't consumed is dropped here, cancelling the calls still outstanding.
+    outcome.cancelled = count - outcome.completed - outcome.failed;
+
+    if outcome.error.is_none() && outcome.cancelled == 0 {
+        log!("guest: batch assertions passed");
+    }
+    outcome
+}
+
+/// Write echo `i`'s message, padded with dots to at least `payload` bytes, to `buf`.
+pub(crate) fn echo_message(buf: &mut String, i: usize, payload: usize) {
+    use std::fmt::Write;
+    let _ = write!(buf, "Hello from WASI! #{i}");
+    if buf.len() < payload {
+        buf.extend(std::iter::repeat_n('.', payload - buf.len()));
+    }
+}
+
+type Reply = (
+    capnp::Result<capnp::capability::Response<echo_capnp::echoer::echo_results::Owned>>,
+    u64,
+);
+
+/// Check one echo reply against the message sent; returns the call's latency.
+fn check_reply(
+    reply: Result<Reply, oneshot::Canceled>,
+    expected: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let (echo_response, latency) = reply?;
+    let echo_response = echo_response?;
+    let reply = echo_response.get()?.get_reply()?;
+    let reply_str = std::str::from_utf8(reply)?;
+    log!("guest: read echo => {}", reply_str);
+    if reply_str != expected {
+        return Err(format!("reply mismatch: expected {expected:?}, got {reply_str:?}").into());
+    }
+    Ok(latency)
+}
+
+/// The order a batch consumes its replies in, from `WETWARE_READ_ORDER`. Calls are always sent
+/// in index order, so each order holds a different set of replies unread for a different time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadOrder {
+    /// A seeded random order (`shuffle`, the default).
+    Shuffle,
+    /// Oldest call first (`fifo`): replies are read about as they arrive.
+    Fifo,
+    /// Newest call first (`lifo`, or `reverse`): every reply but the last waits unread until the
+    /// whole batch has been sent and answered.
+    Lifo,
+    /// Oldest and newest outstanding calls by turns (`interleave`): 0, n-1, 1, n-2 and so on, so
+    /// reads jump between the two ends of the question table.
+    Interleave,
+}
+
+impl ReadOrder {
+    fn from_env() -> Result<Self, String> {
+        let Ok(value) = std::env::var(READ_ORDER_ENV) else {
+            return Ok(Self::Shuffle);
+        };
+        let order = match value.as_str() {
+            "shuffle" => Self::Shuffle,
+            "fifo" => Self::Fifo,
+            "lifo" | "reverse" => Self::Lifo,
+            "interleave" => Self::Interleave,
+            _ => {
+                return Err(format!(
+                    "{READ_ORDER_ENV}: unknown read order {value:?} \
+                     (expected shuffle, fifo, lifo, reverse or interleave)"
+                ));
+            }
+        };
+        log!("guest: reading replies in {:?} order", order);
+        Ok(order)
+    }
+}
+
+/// How one batch reads its replies. If `seed` is provided, a shuffle is reproducible; otherwise a
+/// WASI random seed is used.
+#[derive(Debug, Clone, Copy)]
+struct Reads {
+    order: ReadOrder,
+    seed: Option<u64>,
+    /// Reuse one text buffer for every request and check (see `wetware_guest::reuse`).
+    reuse: bool,
+    /// Whether a failed call stops the batch.
+    on_error: OnError,
+}
+
+impl Reads {
+    /// The indices `0..len` in read order.
+    fn indices(self, len: usize) -> Vec<usize> {
+        match self.order {
+            ReadOrder::Shuffle => {
+                rng::shuffle_indices(len, self.seed.unwrap_or_else(rng::seed_from_wasi))
+            }
+            ReadOrder::Fifo => (0..len).collect(),
+            ReadOrder::Lifo => (0..len).rev().collect(),
+            ReadOrder::Interleave => {
+                let (mut low, mut high) = (0, len);
+                let mut order = Vec::with_capacity(len);
+                while low < high {
+                    order.push(low);
+                    low += 1;
+                    if low < high {
+                        high -= 1;
+                        order.push(high);
+                    }
+                }
+                order
+            }
+        }
+    }
+}
+
+/// What the workload does when a call or a stage fails, from `WETWARE_ON_ERROR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnError {
+    /// Stop at the first failure and return it (`abort`, the default).
+    Abort,
+    /// Record the failure and go on with the rest of the work (`continue`); the failures are
+    /// reported together once everything has run.
+    Continue,
+}
+
+impl OnError {
+    fn from_env() -> Result<Self, String> {
+        let Ok(value) = std::env::var(ON_ERROR_ENV) else {
+            return Ok(Self::Abort);
+        };
+        let policy = match value.as_str() {
+            "abort" => Self::Abort,
+            "continue" => Self::Continue,
+            _ => {
+                return Err(format!(
+                    "{ON_ERROR_ENV}: unknown policy {value:?} (expected abort or continue)"
+                ));
+            }
+        };
+        log!("guest: on error: {:?}", policy);
+        Ok(policy)
+    }
+}
+
+/// The failures recorded under [`OnError::Continue`].
+struct Failures {
+    policy: OnError,
+    /// Failed calls across the timed batches.
+    calls: usize,
+    /// The first error of each batch that had failed calls, and of each failed stage.
+    errors: Vec<String>,
+    stages: usize,
+}
+
+impl Failures {
+    fn new(policy: OnError) -> Self {
+        Self {
+            policy,
+            calls: 0,
+            errors: Vec::new(),
+            stages: 0,
+        }
+    }
+
+    /// Record `failed` calls of `batch`, the first of which failed with `error`.
+    fn batch(&mut self, batch: usize, failed: usize, error: Box<dyn std::error::Error>) {
+        self.calls += failed;
+        self.errors.push(format!("batch {batch}: {error}"));
+    }
+
+    /// Pass on the outcome of stage `name`. Under [`OnError::Continue`], a failure is recorded
+    /// and comes back as `None`.
+    fn stage<T>(
+        &mut self,
+        name: &str,
+        result: Result<T, Box<dyn std::error::Error>>,
+    ) -> Result<Option<T>, Box<dyn std::error::Error>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.policy == OnError::Continue => {
+                log!("guest: stage {} failed, continuing: {e}", name);
+                self.stages += 1;
+                self.errors.push(format!("{name}: {e}"));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Report every recorded failure, and fail if there were any.
+    fn into_result(self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        for error in &self.errors {
+            log!("guest: failure: {}", error);
+        }
+        Err(format!(
+            "{} failed calls in the batches and {} failed stages",
+            self.calls, self.stages
+        )
+        .into())
+    }
+}
+
+// Environment variable naming a preopened directory for run artifacts.
+const ARTIFACTS_ENV: &str = "WETWARE_ARTIFACTS";
+
+// Environment variable naming a file to read and echo before the workload.
+const ECHO_FILE_ENV: &str = "WETWARE_ECHO_FILE";
+
+// Workload knobs; keep them in sync with the load-test driver.
+const BATCHES_ENV: &str = "WETWARE_BATCHES";
+const CALLS_ENV: &str = "WETWARE_CALLS";
+const PAYLOAD_ENV: &str = "WETWARE_PAYLOAD";
+const WARMUP_ENV: &str = "WETWARE_WARMUP";
+const REUSE_ENV: &str = "WETWARE_REUSE";
+
+// Order replies are read in; see `ReadOrder`.
+const READ_ORDER_ENV: &str = "WETWARE_READ_ORDER";
+
+// Loop iterations between yields to the executor in a batch; 0 never yields.
+const YIELD_EVERY_ENV: &str = "WETWARE_YIELD_EVERY";
+
+// What to do when a call or a stage fails; see `OnError`.
+const ON_ERROR_ENV: &str = "WETWARE_ON_ERROR";
+
+pub fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Echo the contents of the file named by `WETWARE_ECHO_FILE`, if any, and check the reply.
+async fn echo_file(echoer: &echo_capnp::echoer::Client) -> Result<(), Box<dyn std::error::Error>> {
+    let Ok(path) = std::env::var(ECHO_FILE_ENV) else {
+        return Ok(());
+    };
+    let msg = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {path}: {e}"))?;
+    let mut request = echoer.echo_request();
+    request.get().set_msg(msg.as_str());
+    request.get().set_call_id(call_id::next());
+    let response = request.send().promise.await?;
+    if response.get()?.get_reply()? != msg.as_bytes() {
+        return Err(format!("reply mismatch echoing {path}").into());
+    }
+    log!("guest: echoed {} bytes from {path}", msg.len());
+    Ok(())
+}
+
+/// What the batch stage did, for `summary.txt`.
+struct Summary<'a> {
+    batch_count: usize,
+    call_count: usize,
+    payload: usize,
+    batches_ns: u64,
+    /// Latencies of the timed batches only.
+    latencies_ns: Vec<u64>,
+    warmup_ns: Vec<u64>,
+    reuse: bool,
+    /// Allocations charged to the timed batches; zero without `alloc-stats`.
+    batch_allocations: u64,
+    batch_alloc_bytes: u64,
+    /// The batched-echo stage, if it ran.
+    echo_batch: Option<batched::Batched>,
+    failures: &'a Failures,
+}
+
+/// Write `summary.txt` as `key: value` lines; the load-test driver parses it.
+fn write_summary(summary: &Summary) -> std::io::Result<()> {
+    let Ok(dir) = std::env::var(ARTIFACTS_ENV) else {
+        return Ok(());
+    };
+    let latency = Percentiles::of(&summary.latencies_ns);
+    let warmup = Percentiles::of(&summary.warmup_ns);
+    let (echo_batch_size, echo_batch_ns) = summary
+        .echo_batch
+        .as_ref()
+        .map_or((0, 0), |b| (b.size, b.elapsed_ns));
+    let path = std::path::Path::new(&dir).join("summary.txt");
+    std::fs::write(
+        &path,
+        format!(
+            "batches: {}\ncalls per batch: {}\npayload bytes: {}\nbatch stage us: {}\n\
+             latency p50 us: {}\nlatency p90 us: {}\nlatency p99 us: {}\nlatency max us: {}\n\
+             warm-up calls: {}\nwarm-up p50 us: {}\nwarm-up p99 us: {}\nwarm-up max us: {}\n\
+             buffer reuse: {}\nbatch allocations: {}\nbatch alloc bytes: {}\n\
+             echo batch size: {}\necho batch stage us: {}\n\
+             failed calls: {}\nfailed stages: {}\nstatus: {}\n",
+            summary.batch_count,
+            summary.call_count,
+            summary.payload,
+            summary.batches_ns / 1_000,
+            latency.us(50),
+            latency.us(90),
+            latency.us(99),
+            latency.us(100),
+            summary.warmup_ns.len(),
+            warmup.us(50),
+            warmup.us(99),
+            warmup.us(100),
+            u8::from(summary.reuse),
+            summary.batch_allocations,
+            summary.batch_alloc_bytes,
+            echo_batch_size,
+            echo_batch_ns / 1_000,
+            summary.failures.calls,
+            summary.failures.stages,
+            if summary.failures.errors.is_empty() {
+                "ok"
+            } else {
+                "failed"
+            },
+        ),
+    )?;
+    log!("guest: wrote {}", path.display());
+    Ok(())
+}
+
+/// Sorted latencies, for percentiles.
+struct Percentiles(Vec<u64>);
+
+impl Percentiles {
+    fn of(latencies_ns: &[u64]) -> Self {
+        let mut sorted = latencies_ns.to_vec();
+        sorted.sort_unstable();
+        Self(sorted)
+    }
+
+    /// The `p`th percentile in microseconds; 0 if there are no latencies.
+    fn us(&self, p: usize) -> u64 {
+        match self.0.len() {
+            0 => 0,
+            n => self.0[(n - 1) * p / 100] / 1_000,
+        }
+    }
+}
+
+// Environment variable carrying the seed the host derived for us; keep it in sync with
+// `SEED_ENV` in the host.
+const SEED_ENV: &str = "WETWARE_SEED";
+
+fn seed_from_env() -> Option<u64> {
+    let seed = std::env::var(SEED_ENV).ok()?.parse().ok()?;
+    log!("guest: using seed {seed} from {SEED_ENV}");
+    Some(seed)
+}
@@ -0,0 +1,127 @@
+//! Scoped background tasks.
+//!
+//! A task spawned onto the guest executor runs detached: nothing waits for it, and if it is still
+//! pending when `block_on` returns, it is dropped without a word. [`scope`] gives tasks an owner
+//! instead. Tasks spawned with [`Scope::spawn_local`] run alongside the scope's body, may borrow
+//! from outside the scope, and are all finished before `scope` returns: once the body is done,
+//! the scope waits for the tasks still running. A task that would never finish on its own is
+//! cancelled through its [`Task`] handle first. Dropping the scope's future drops every task with
+//! it, so no task outlives its scope either way.
+//!
+//! The scope polls its tasks itself, from whichever executor polls the scope, so it works the
+//! same on every executor the guest can be built with.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::{Pin, pin};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::oneshot;
+use futures::future::{self, AbortHandle, Abortable, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+
+struct Shared<'a> {
+    /// Spawned since the scope last polled its tasks.
+    spawned: Vec<LocalBoxFuture<'a, ()>>,
+    /// Wakes the scope when a task is spawned from outside its own poll.
+    waker: Option<Waker>,
+}
+
+/// Spawns tasks into a [`scope`]. Clones spawn into the same scope.
+#[derive(Clone)]
+pub struct Scope<'a> {
+    shared: Rc<RefCell<Shared<'a>>>,
+}
+
+impl<'a> Scope<'a> {
+    /// Run `fut` alongside the scope's body. The returned handle awaits its output, or cancels it.
+    pub fn spawn_local<T: 'a>(&self, fut: impl Future<Output = T> + 'a) -> Task<T> {
+        let (output_tx, output) = oneshot::channel();
+        let (abort, registration) = AbortHandle::new_pair();
+        let task = Abortable::new(fut, registration);
+        let mut shared = self.shared.borrow_mut();
+        shared.spawned.push(Box::pin(async move {
+            if let Ok(value) = task.await {
+                let _ = output_tx.send(value);
+            }
+        }));
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        Task { output, abort }
+    }
+}
+
+/// A task in a [`scope`]. Awaiting it yields its output, or [`Cancelled`] if it was cancelled.
+/// Dropping the handle leaves the task running.
+pub struct Task<T> {
+    output: oneshot::Receiver<T>,
+    abort: AbortHandle,
+}
+
+impl<T> Task<T> {
+    /// Stop the task at its next await point; its scope no longer waits for it.
+    pub fn cancel(&self) {
+        self.abort.abort();
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.output).poll(cx).map_err(|_| Cancelled)
+    }
+}
+
+/// The task was cancelled before it finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Run `body` with a [`Scope`] to spawn tasks into, and return its output once it and every task
+/// spawned into the scope have finished.
+pub async fn scope<'a, T, Fut>(body: impl FnOnce(Scope<'a>) -> Fut) -> T
+where
+    Fut: Future<Output = T> + 'a,
+{
+    let scope = Scope {
+        shared: Rc::new(RefCell::new(Shared {
+            spawned: Vec::new(),
+            waker: None,
+        })),
+    };
+    let mut body = pin!(body(scope.clone()));
+    let mut output = None;
+    let mut tasks = FuturesUnordered::new();
+    future::poll_fn(|cx| {
+        loop {
+            if output.is_none()
+                && let Poll::Ready(value) = body.as_mut().poll(cx)
+            {
+                output = Some(value);
+            }
+            tasks.extend(std::mem::take(&mut scope.shared.borrow_mut().spawned));
+            while let Poll::Ready(Some(())) = tasks.poll_next_unpin(cx) {}
+            // Tasks may have spawned more while they ran.
+            if !scope.shared.borrow().spawned.is_empty() {
+                continue;
+            }
+            if output.is_some() && tasks.is_empty() {
+                return Poll::Ready(output.take().unwrap());
+            }
+            scope.shared.borrow_mut().waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+    })
+    .await
+}
@@ -0,0 +1,75 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A future that resolves once `duration` has elapsed on the WASI monotonic clock.
+///
+/// Like `Wasip2Stdin`, this checks its pollable without blocking and, while it is not ready,
+/// registers it with the executor's batched waits (or self-wakes outside them), so it never
+/// stalls the single-threaded executor.
+#[cfg(not(feature = "wasip1"))]
+pub struct Sleep {
+    pollable: std::rc::Rc<wasip2::io::poll::Pollable>,
+}
+
+#[cfg(not(feature = "wasip1"))]
+pub fn sleep(duration: Duration) -> Sleep {
+    let nanos = duration.as_nanos() as u64;
+    Sleep {
+        pollable: std::rc::Rc::new(wasip2::clocks::monotonic_clock::subscribe_duration(nanos)),
+    }
+}
+
+#[cfg(not(feature = "wasip1"))]
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.pollable.ready() {
+            return Poll::Ready(());
+        }
+        if !crate::reactor::register(&self.pollable, cx.waker()) {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+/// WASIp1 has no pollables to hold on to, so the deadline is compared against
+/// `clock_time_get` on every poll instead.
+#[cfg(feature = "wasip1")]
+pub struct Sleep {
+    deadline_ns: u64,
+}
+
+/// Nanoseconds on the WASI monotonic clock.
+#[cfg(not(feature = "wasip1"))]
+pub fn monotonic_now_ns() -> u64 {
+    wasip2::clocks::monotonic_clock::now()
+}
+
+#[cfg(feature = "wasip1")]
+pub fn monotonic_now_ns() -> u64 {
+    unsafe { wasip1::clock_time_get(wasip1::CLOCKID_MONOTONIC, 1) }.unwrap_or(0)
+}
+
+#[cfg(feature = "wasip1")]
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline_ns: monotonic_now_ns().saturating_add(duration.as_nanos() as u64),
+    }
+}
+
+#[cfg(feature = "wasip1")]
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if monotonic_now_ns() >= self.deadline_ns {
+            return Poll::Ready(());
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
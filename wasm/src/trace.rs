@@ -0,0 +1,203 @@
+//! A `tracing` subscriber that forwards the guest's spans and events to the host.
+//!
+//! Each span and event becomes one tab-separated stderr line starting with `guest:trace`, which the
+//! host's stderr reader turns back into real `tracing` spans and events, nested under the guest's
+//! connection span. Span IDs are assigned sequentially, so the same run gives the same IDs. The
+//! line format must match the host's `guest_trace` module:
+//!
+//! ```text
+//! guest:trace  new    <id> <parent> <level> <target> <name> <fields>
+//! guest:trace  event  <parent> <level> <target> <message> <fields>
+//! guest:trace  close  <id>
+//! ```
+//!
+//! `<parent>` is `0` for spans and events outside any span.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Prefix of every trace line; keep it in sync with `TRACE_PREFIX` in the host.
+pub const TRACE_PREFIX: &str = "guest:trace";
+
+/// Install the stderr subscriber as the global default, forwarding everything at `max_level` or
+/// more severe. Fails if a global subscriber is already set.
+pub fn init(max_level: Level) -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+    tracing::subscriber::set_global_default(StderrSubscriber::new(max_level))
+}
+
+/// Subscriber writing `guest:trace` lines to stderr.
+pub struct StderrSubscriber {
+    max_level: Level,
+    next_id: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Reference counts of open spans.
+    refs: HashMap<u64, usize>,
+    // Spans entered and not yet exited, innermost last.
+    stack: Vec<u64>,
+}
+
+impl StderrSubscriber {
+    pub fn new(max_level: Level) -> Self {
+        Self {
+            max_level,
+            next_id: AtomicU64::new(1),
+            state: Mutex::default(),
+        }
+    }
+
+    fn current(&self) -> u64 {
+        self.state
+            .lock()
+            .unwrap()
+            .stack
+            .last()
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl Subscriber for StderrSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let parent = match attrs.parent() {
+            Some(parent) => parent.into_u64(),
+            None if attrs.is_contextual() => self.current(),
+            None => 0,
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let metadata = attrs.metadata();
+        write_line(format_args!(
+            "new\t{id}\t{parent}\t{}\t{}\t{}\t{}",
+            metadata.level(),
+            clean(metadata.target()),
+            clean(metadata.name()),
+            fields.rest(),
+        ));
+        self.state.lock().unwrap().refs.insert(id, 1);
+        Id::from_u64(id)
+    }
+
+    // Fields recorded after a span is created aren't forwarded.
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = match event.parent() {
+            Some(parent) => parent.into_u64(),
+            None if event.is_contextual() => self.current(),
+            None => 0,
+        };
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        write_line(format_args!(
+            "event\t{parent}\t{}\t{}\t{}\t{}",
+            metadata.level(),
+            clean(metadata.target()),
+            clean(&fields.message),
+            fields.rest(),
+        ));
+    }
+
+    fn enter(&self, span: &Id) {
+        self.state.lock().unwrap().stack.push(span.into_u64());
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pos) = state.stack.iter().rposition(|id| *id == span.into_u64()) {
+            state.stack.remove(pos);
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(refs) = self.state.lock().unwrap().refs.get_mut(&span.into_u64()) {
+            *refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let id = span.into_u64();
+        let closed = {
+            let mut state = self.state.lock().unwrap();
+            match state.refs.get_mut(&id) {
+                Some(refs) if *refs > 1 => {
+                    *refs -= 1;
+                    false
+                }
+                Some(_) => {
+                    state.refs.remove(&id);
+                    true
+                }
+                None => false,
+            }
+        };
+        if closed {
+            write_line(format_args!("close\t{id}"));
+        }
+        closed
+    }
+}
+
+/// The `message` field, and every other field as space-separated `name=value` pairs.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    others: String,
+}
+
+impl Fields {
+    fn rest(&self) -> String {
+        clean(&self.others)
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            if !self.others.is_empty() {
+                self.others.push(' ');
+            }
+            let _ = write!(self.others, "{}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+}
+
+/// Tabs and newlines would break the line format.
+fn clean(text: &str) -> String {
+    text.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Write one trace line with a single write, so it can't interleave with other stderr output.
+fn write_line(body: fmt::Arguments<'_>) {
+    let line = format!("{TRACE_PREFIX}\t{body}\n");
+    let _ = std::io::stderr().write_all(line.as_bytes());
+}
@@ -0,0 +1,20 @@
+// Stream adapters carrying the Cap'n Proto transport. The WASIp2 backend is the default; the
+// `wasip1` feature swaps in a backend built on `fd_read`/`fd_write` + `poll_oneoff` for runtimes
+// that haven't adopted the component model, and the `wasip3` feature one built on WASI 0.3's
+// native `stream<u8>` stdio. All of them only depend on the `futures-io` traits that capnp-rpc
+// consumes, not on the rest of `futures`.
+
+#[cfg(not(any(feature = "wasip1", feature = "wasip3")))]
+mod wasip2;
+#[cfg(not(any(feature = "wasip1", feature = "wasip3")))]
+pub use wasip2::rpc_streams;
+
+#[cfg(feature = "wasip1")]
+mod wasip1;
+#[cfg(feature = "wasip1")]
+pub use wasip1::rpc_streams;
+
+#[cfg(all(feature = "wasip3", not(feature = "wasip1")))]
+mod wasip3;
+#[cfg(all(feature = "wasip3", not(feature = "wasip1")))]
+pub use wasip3::rpc_streams;
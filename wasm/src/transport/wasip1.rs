@@ -0,0 +1,133 @@
+use std::io;
+use std::mem::MaybeUninit;
+use std::task::{Context, Poll};
+
+// WASIp1 flavour of the stream adapters, for runtimes without the component model. Same
+// contract as the WASIp2 ones: reads never block (readiness is checked with a zero-timeout
+// `poll_oneoff` first), and writes commit the whole buffer before returning so capnp frames
+// aren't truncated.
+
+const STDIN_FD: wasip1::Fd = 0;
+const STDOUT_FD: wasip1::Fd = 1;
+
+fn errno_to_io(errno: wasip1::Errno) -> io::Error {
+    io::Error::from_raw_os_error(errno.raw() as i32)
+}
+
+/// Check whether `fd` has data to read, without blocking.
+fn fd_readable(fd: wasip1::Fd) -> io::Result<bool> {
+    let subscriptions = [
+        wasip1::Subscription {
+            userdata: 0,
+            u: wasip1::SubscriptionU {
+                tag: wasip1::EVENTTYPE_FD_READ.raw(),
+                u: wasip1::SubscriptionUU {
+                    fd_read: wasip1::SubscriptionFdReadwrite {
+                        file_descriptor: fd,
+                    },
+                },
+            },
+        },
+        // A relative zero timeout makes `poll_oneoff` return immediately.
+        wasip1::Subscription {
+            userdata: 1,
+            u: wasip1::SubscriptionU {
+                tag: wasip1::EVENTTYPE_CLOCK.raw(),
+                u: wasip1::SubscriptionUU {
+                    clock: wasip1::SubscriptionClock {
+                        id: wasip1::CLOCKID_MONOTONIC,
+                        timeout: 0,
+                        precision: 0,
+                        flags: 0,
+                    },
+                },
+            },
+        },
+    ];
+    let mut events = [MaybeUninit::<wasip1::Event>::uninit(); 2];
+    let n = unsafe {
+        wasip1::poll_oneoff(
+            subscriptions.as_ptr(),
+            events.as_mut_ptr().cast(),
+            subscriptions.len(),
+        )
+    }
+    .map_err(errno_to_io)?;
+    // Only the first `n` events were initialized.
+    let ready = events[..n]
+        .iter()
+        .map(|event| unsafe { event.assume_init() })
+        .any(|event| event.userdata == 0 && event.type_ == wasip1::EVENTTYPE_FD_READ);
+    Ok(ready)
+}
+
+pub struct Wasip1Stdin;
+
+impl futures_io::AsyncRead for Wasip1Stdin {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        // Non-blocking read: only call fd_read once poll_oneoff reports data; otherwise yield
+        // Pending and self-wake.
+        match fd_readable(STDIN_FD) {
+            Ok(true) => {}
+            Ok(false) => {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+        let iovs = [wasip1::Iovec {
+            buf: buf.as_mut_ptr(),
+            buf_len: buf.len(),
+        }];
+        Poll::Ready(unsafe { wasip1::fd_read(STDIN_FD, &iovs) }.map_err(errno_to_io))
+    }
+}
+
+pub struct Wasip1Stdout;
+
+impl futures_io::AsyncWrite for Wasip1Stdout {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // fd_write may commit fewer bytes than requested; loop until the whole buffer is out. A
+        // write that commits nothing would loop forever, so it fails the stream instead.
+        let mut written = 0;
+        while written < buf.len() {
+            let iovs = [wasip1::Ciovec {
+                buf: buf[written..].as_ptr(),
+                buf_len: buf.len() - written,
+            }];
+            match unsafe { wasip1::fd_write(STDOUT_FD, &iovs) } {
+                Ok(0) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "fd_write committed no bytes",
+                    )));
+                }
+                Ok(n) => written += n,
+                Err(e) => return Poll::Ready(Err(errno_to_io(e))),
+            }
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // fd_write has no buffering of its own to flush.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: std::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The RPC transport on WASIp1 is always stdin/stdout.
+pub fn rpc_streams() -> Result<(Wasip1Stdin, Wasip1Stdout), Box<dyn std::error::Error>> {
+    Ok((Wasip1Stdin, Wasip1Stdout))
+}
@@ -0,0 +1,361 @@
+use std::io;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use wasip2::filesystem::types::{ErrorCode, filesystem_error_code};
+use wasip2::io::poll::Pollable;
+use wasip2::io::streams::{self, StreamError};
+
+use crate::{host, reactor};
+
+// Trying to use Cap'n Proto over the raw wasi:io/streams will not deadlock at some
+// point and will not work. We need to implement non-blocking reads (return Pending
+// when no bytes are ready) and flush-safe writes streams so capnp frames aren't truncated.
+
+// The calls the adapters make on the WASI streams. The tests drive the adapters with scripted
+// streams instead.
+pub trait Input {
+    fn read(&self, len: u64) -> Result<Vec<u8>, StreamError>;
+    // Have `waker` woken once the stream may be readable; false if nothing will wake it.
+    fn wake_when_readable(&self, waker: &Waker) -> bool;
+}
+
+pub trait Output {
+    fn blocking_write_and_flush(&self, buf: &[u8]) -> Result<(), StreamError>;
+    fn blocking_flush(&self) -> Result<(), StreamError>;
+}
+
+pub struct WasiInput {
+    // Declared first so it is dropped before the stream it was subscribed from.
+    readable: Rc<Pollable>,
+    stream: streams::InputStream,
+}
+
+impl Input for WasiInput {
+    fn read(&self, len: u64) -> Result<Vec<u8>, StreamError> {
+        self.stream.read(len)
+    }
+
+    fn wake_when_readable(&self, waker: &Waker) -> bool {
+        // Batched with the executor's other waits.
+        reactor::register(&self.readable, waker)
+    }
+}
+
+impl Output for streams::OutputStream {
+    fn blocking_write_and_flush(&self, buf: &[u8]) -> Result<(), StreamError> {
+        streams::OutputStream::blocking_write_and_flush(self, buf)
+    }
+
+    fn blocking_flush(&self) -> Result<(), StreamError> {
+        streams::OutputStream::blocking_flush(self)
+    }
+}
+
+pub struct Wasip2Stdin<I = WasiInput> {
+    input: I,
+}
+
+impl Wasip2Stdin {
+    pub fn new(stream: streams::InputStream) -> Self {
+        Self {
+            input: WasiInput {
+                readable: Rc::new(stream.subscribe()),
+                stream,
+            },
+        }
+    }
+}
+
+impl<I: Input + Unpin> futures_io::AsyncRead for Wasip2Stdin<I> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        // Non-blocking read: try to read available bytes; if none, yield Pending and self-wake.
+        let len = buf.len() as u64;
+        match self.input.read(len) {
+            Ok(bytes) => {
+                let n = bytes.len();
+                if n == 0 {
+                    // No data ready yet: wait for the stream to become readable, or yield and
+                    // try again later.
+                    if !self.input.wake_when_readable(cx.waker()) {
+                        cx.waker().wake_by_ref();
+                    }
+                    return Poll::Pending;
+                }
+                buf[..n].copy_from_slice(&bytes);
+                Poll::Ready(Ok(n))
+            }
+            // The host closed its end: report EOF so the RpcSystem shuts down cleanly instead of
+            // failing with an opaque error.
+            Err(StreamError::Closed) => Poll::Ready(Ok(0)),
+            Err(e) => {
+                let err = stream_error(e);
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                ) {
+                    // Transient; retry like an empty read.
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+/// Convert a stream error to an `io::Error`, keeping its kind where the host reports one.
+/// Writing to or flushing a closed stream is a broken pipe.
+fn stream_error(e: StreamError) -> io::Error {
+    match e {
+        StreamError::Closed => io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"),
+        StreamError::LastOperationFailed(err) => {
+            let kind = filesystem_error_code(&err).map_or(io::ErrorKind::Other, error_kind);
+            io::Error::new(kind, err.to_debug_string())
+        }
+    }
+}
+
+fn error_kind(code: ErrorCode) -> io::ErrorKind {
+    match code {
+        ErrorCode::Access | ErrorCode::NotPermitted => io::ErrorKind::PermissionDenied,
+        ErrorCode::WouldBlock => io::ErrorKind::WouldBlock,
+        ErrorCode::Interrupted => io::ErrorKind::Interrupted,
+        ErrorCode::Pipe => io::ErrorKind::BrokenPipe,
+        ErrorCode::Invalid => io::ErrorKind::InvalidInput,
+        ErrorCode::InsufficientMemory => io::ErrorKind::OutOfMemory,
+        ErrorCode::Unsupported => io::ErrorKind::Unsupported,
+        ErrorCode::NoEntry => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+pub struct Wasip2Stdout<O = streams::OutputStream> {
+    // `None` once closed.
+    stream: Option<O>,
+}
+
+impl Wasip2Stdout {
+    pub fn new(stream: streams::OutputStream) -> Self {
+        Self {
+            stream: Some(stream),
+        }
+    }
+}
+
+impl<O> Wasip2Stdout<O> {
+    fn stream(&self) -> io::Result<&O> {
+        self.stream
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"))
+    }
+}
+
+impl<O: Output + Unpin> futures_io::AsyncWrite for Wasip2Stdout<O> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Ensure we don't misreport partial writes: use blocking_write_and_flush so the
+        // entire buffer is committed before returning. This avoids frame truncation that can
+        // deadlock Cap'n Proto RPC on subsequent reads. blocking_write_and_flush chunks by
+        // `check-write` itself; a non-blocking path has to do the same, as the host's tests in
+        // `src/half_close.rs` do under write budgets down to one byte.
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let stream = self.stream()?;
+        match stream.blocking_write_and_flush(buf) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(e) => Poll::Ready(Err(stream_error(e))),
+        }
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Ensure any pending output is committed before proceeding. Nothing is pending once
+        // closed.
+        let Some(stream) = &self.stream else {
+            return Poll::Ready(Ok(()));
+        };
+        match stream.blocking_flush() {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(stream_error(e))),
+        }
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Commit all pending output, then drop the stream: the host shuts down its end of the
+        // pipe, so the other side reads EOF while replies can still arrive on stdin.
+        let Some(stream) = self.stream.take() else {
+            return Poll::Ready(Ok(()));
+        };
+        let flushed = stream.blocking_flush();
+        drop(stream);
+        match flushed {
+            Ok(()) | Err(StreamError::Closed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(stream_error(e))),
+        }
+    }
+}
+
+/// Get the RPC transport streams from the host. These share the pipes behind wasi:cli
+/// stdin/stdout, which older guests use directly.
+pub fn rpc_streams() -> Result<(Wasip2Stdin, Wasip2Stdout), Box<dyn std::error::Error>> {
+    let (rpc_in, rpc_out) = host::transport::rpc_streams()?;
+    Ok((Wasip2Stdin::new(rpc_in), Wasip2Stdout::new(rpc_out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+
+    use futures_io::{AsyncRead, AsyncWrite};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // What a scripted input stream does on one `read`.
+    #[derive(Debug, Clone)]
+    enum Step {
+        // Hand over at most this many bytes.
+        Bytes(usize),
+        // Have nothing ready yet.
+        NotReady,
+    }
+
+    // An input stream that hands over `data` as `steps` say, then reports itself closed. Once the
+    // steps run out it hands over whatever was asked for.
+    struct ScriptedInput {
+        data: RefCell<VecDeque<u8>>,
+        steps: RefCell<VecDeque<Step>>,
+        wakes: bool,
+    }
+
+    impl Input for ScriptedInput {
+        fn read(&self, len: u64) -> Result<Vec<u8>, StreamError> {
+            let mut data = self.data.borrow_mut();
+            if data.is_empty() {
+                return Err(StreamError::Closed);
+            }
+            let step = self.steps.borrow_mut().pop_front();
+            let n = match step.unwrap_or(Step::Bytes(usize::MAX)) {
+                Step::Bytes(n) => n.min(len as usize).min(data.len()),
+                Step::NotReady => 0,
+            };
+            Ok(data.drain(..n).collect())
+        }
+
+        fn wake_when_readable(&self, _waker: &Waker) -> bool {
+            self.wakes
+        }
+    }
+
+    // An output stream that takes every write whole, and closes after `accepts` of them.
+    struct ScriptedOutput {
+        written: Rc<RefCell<Vec<u8>>>,
+        accepts: RefCell<usize>,
+    }
+
+    impl Output for ScriptedOutput {
+        fn blocking_write_and_flush(&self, buf: &[u8]) -> Result<(), StreamError> {
+            let mut accepts = self.accepts.borrow_mut();
+            if *accepts == 0 {
+                return Err(StreamError::Closed);
+            }
+            *accepts -= 1;
+            self.written.borrow_mut().extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn blocking_flush(&self) -> Result<(), StreamError> {
+            Ok(())
+        }
+    }
+
+    fn step() -> impl Strategy<Value = Step> {
+        prop_oneof![(1usize..64).prop_map(Step::Bytes), Just(Step::NotReady)]
+    }
+
+    proptest! {
+        #[test]
+        fn reads_deliver_every_byte_once_in_order(
+            data in prop::collection::vec(any::<u8>(), 0..4096),
+            steps in prop::collection::vec(step(), 0..256),
+            sizes in prop::collection::vec(1usize..128, 1..16),
+            wakes in any::<bool>(),
+        ) {
+            let mut stdin = Wasip2Stdin {
+                input: ScriptedInput {
+                    data: RefCell::new(data.iter().copied().collect()),
+                    steps: RefCell::new(steps.iter().cloned().collect()),
+                    wakes,
+                },
+            };
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut received = Vec::new();
+            let mut buf = [0u8; 128];
+            // Every poll either moves a byte, uses up a step or ends the stream.
+            let mut polls = 0;
+            for size in sizes.iter().cycle() {
+                polls += 1;
+                prop_assert!(polls <= data.len() + steps.len() + 1, "reader stalled");
+                match Pin::new(&mut stdin).poll_read(&mut cx, &mut buf[..*size]) {
+                    Poll::Ready(Ok(0)) => break,
+                    Poll::Ready(Ok(n)) => {
+                        prop_assert!(n <= *size);
+                        received.extend_from_slice(&buf[..n]);
+                    }
+                    Poll::Ready(Err(e)) => return Err(TestCaseError::fail(e.to_string())),
+                    Poll::Pending => {}
+                }
+            }
+            prop_assert_eq!(received, data);
+        }
+
+        #[test]
+        fn writes_commit_whole_buffers_in_order(
+            writes in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..512), 0..32),
+            accepts in 0usize..40,
+        ) {
+            let written = Rc::new(RefCell::new(Vec::new()));
+            let mut stdout = Wasip2Stdout {
+                stream: Some(ScriptedOutput {
+                    written: written.clone(),
+                    accepts: RefCell::new(accepts),
+                }),
+            };
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut expected = Vec::new();
+            let mut failed = false;
+            for write in &writes {
+                match Pin::new(&mut stdout).poll_write(&mut cx, write) {
+                    Poll::Ready(Ok(n)) => {
+                        // An empty write never reaches the stream.
+                        prop_assert!(
+                            !failed || write.is_empty(),
+                            "write accepted after the stream closed"
+                        );
+                        prop_assert_eq!(n, write.len());
+                        expected.extend_from_slice(write);
+                    }
+                    Poll::Ready(Err(e)) => {
+                        prop_assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+                        failed = true;
+                    }
+                    Poll::Pending => return Err(TestCaseError::fail("write returned Pending")),
+                }
+            }
+            prop_assert!(Pin::new(&mut stdout).poll_close(&mut cx).is_ready());
+            prop_assert_eq!(&*written.borrow(), &expected);
+        }
+    }
+}
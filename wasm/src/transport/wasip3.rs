@@ -0,0 +1,222 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use wetware_guest::buffers;
+use wit_bindgen_p3::rt::async_support::{StreamReader, StreamResult, StreamWriter};
+
+use crate::executor;
+
+// WASI 0.3 flavour of the stream adapters, built on the native component-model `stream<u8>`
+// instead of hand-rolled pollable checks: reads and writes are real async operations that wake
+// the executor when the host makes progress, so there is no self-waking busy loop.
+//
+// The read/write futures borrow the stream handle, so the handle is moved into the in-flight
+// future and handed back when it completes.
+
+type ReadFuture = Pin<Box<dyn Future<Output = (StreamReader<u8>, StreamResult, Vec<u8>)>>>;
+type WriteFuture = Pin<Box<dyn Future<Output = (StreamWriter<u8>, StreamResult, usize)>>>;
+
+enum ReadState {
+    Idle(StreamReader<u8>),
+    Reading(ReadFuture),
+    Closed,
+}
+
+pub struct Wasip3Stdin {
+    state: ReadState,
+    // Bytes a finished read returned beyond what the caller's buffer could take, and how many of
+    // them were handed out since. A read is sized by the buffer of the call that started it, and
+    // a later call may come with a smaller one.
+    leftover: Vec<u8>,
+    taken: usize,
+}
+
+impl Wasip3Stdin {
+    // Copy what fits of `bytes` into `buf`, keeping the rest for the next read.
+    fn hand_out(&mut self, buf: &mut [u8], bytes: Vec<u8>) -> usize {
+        let n = buf.len().min(bytes.len());
+        buf[..n].copy_from_slice(&bytes[..n]);
+        if n < bytes.len() {
+            buffers::hold(bytes.len() - n);
+            self.leftover = bytes;
+            self.taken = n;
+        }
+        n
+    }
+}
+
+impl futures_io::AsyncRead for Wasip3Stdin {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.taken < self.leftover.len() {
+            let rest = &self.leftover[self.taken..];
+            let n = buf.len().min(rest.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            buffers::release(n);
+            self.taken += n;
+            if self.taken == self.leftover.len() {
+                self.leftover = Vec::new();
+                self.taken = 0;
+            }
+            return Poll::Ready(Ok(n));
+        }
+        loop {
+            match std::mem::replace(&mut self.state, ReadState::Closed) {
+                ReadState::Idle(mut reader) => {
+                    let capacity = buf.len();
+                    self.state = ReadState::Reading(Box::pin(async move {
+                        let (result, bytes) = reader.read(Vec::with_capacity(capacity)).await;
+                        (reader, result, bytes)
+                    }));
+                }
+                ReadState::Reading(mut fut) => match fut.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        self.state = ReadState::Reading(fut);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((reader, StreamResult::Complete(_), bytes)) => {
+                        self.state = ReadState::Idle(reader);
+                        return Poll::Ready(Ok(self.hand_out(buf, bytes)));
+                    }
+                    // The host dropped its end: report EOF.
+                    Poll::Ready((_, StreamResult::Dropped, _)) => return Poll::Ready(Ok(0)),
+                    Poll::Ready((reader, StreamResult::Cancelled, _)) => {
+                        self.state = ReadState::Idle(reader);
+                    }
+                },
+                ReadState::Closed => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
+
+enum WriteState {
+    Idle(StreamWriter<u8>),
+    Writing(WriteFuture),
+    Closed,
+}
+
+pub struct Wasip3Stdout {
+    state: WriteState,
+    // The host side of stdout, handed to `write-via-stream` on first use. Spawning needs a
+    // running executor, which doesn't exist yet when `rpc_streams` is called.
+    sink: Option<StreamReader<u8>>,
+    // Bytes copied into the in-flight write.
+    in_flight: usize,
+}
+
+impl Wasip3Stdout {
+    fn start_sink(&mut self) {
+        if let Some(sink) = self.sink.take() {
+            executor::spawn(async move {
+                let _ = wasip3::cli::stdout::write_via_stream(sink).await;
+            });
+        }
+    }
+
+    // Complete the in-flight write, if any, returning how many bytes it committed.
+    fn poll_pending_write(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<usize>>> {
+        match std::mem::replace(&mut self.state, WriteState::Closed) {
+            WriteState::Writing(mut fut) => match fut.as_mut().poll(cx) {
+                Poll::Pending => {
+                    self.state = WriteState::Writing(fut);
+                    Poll::Pending
+                }
+                Poll::Ready((writer, StreamResult::Dropped, _)) => {
+                    buffers::release(std::mem::take(&mut self.in_flight));
+                    drop(writer);
+                    Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+                }
+                Poll::Ready((writer, _, written)) => {
+                    buffers::release(std::mem::take(&mut self.in_flight));
+                    self.state = WriteState::Idle(writer);
+                    Poll::Ready(Ok(Some(written)))
+                }
+            },
+            state => {
+                self.state = state;
+                Poll::Ready(Ok(None))
+            }
+        }
+    }
+}
+
+impl futures_io::AsyncWrite for Wasip3Stdout {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.start_sink();
+        // A write started by a previous call owns a copy of that call's buffer; per the
+        // `AsyncWrite` contract the caller retries with the same data, so report it here.
+        if let Some(written) = std::task::ready!(self.poll_pending_write(cx))? {
+            return Poll::Ready(Ok(written));
+        }
+        match std::mem::replace(&mut self.state, WriteState::Closed) {
+            WriteState::Idle(mut writer) => {
+                let values = buf.to_vec();
+                buffers::hold(values.len());
+                self.in_flight = values.len();
+                self.state = WriteState::Writing(Box::pin(async move {
+                    let len = values.len();
+                    let remaining = writer.write_all(values).await;
+                    let written = len - remaining.len();
+                    let result = if remaining.is_empty() {
+                        StreamResult::Complete(written)
+                    } else {
+                        StreamResult::Dropped
+                    };
+                    (writer, result, written)
+                }));
+                match std::task::ready!(self.poll_pending_write(cx))? {
+                    Some(written) => Poll::Ready(Ok(written)),
+                    None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                }
+            }
+            _ => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        std::task::ready!(self.poll_pending_write(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        std::task::ready!(self.poll_pending_write(cx))?;
+        // Dropping the writer closes the stream on our side.
+        self.state = WriteState::Closed;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The RPC transport over WASI 0.3 stdio streams. Writing to stdout is itself an async call that
+/// completes once the stream is closed, so it is driven as a background task.
+pub fn rpc_streams() -> Result<(Wasip3Stdin, Wasip3Stdout), Box<dyn std::error::Error>> {
+    let (reader, _stdin_done) = wasip3::cli::stdin::read_via_stream();
+    let (writer, sink) = wasip3::wit_stream::new::<u8>();
+    Ok((
+        Wasip3Stdin {
+            state: ReadState::Idle(reader),
+            leftover: Vec::new(),
+            taken: 0,
+        },
+        Wasip3Stdout {
+            state: WriteState::Idle(writer),
+            sink: Some(sink),
+            in_flight: 0,
+        },
+    ))
+}
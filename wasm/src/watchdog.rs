@@ -0,0 +1,128 @@
+//! Per-batch stall detection for the stress workload.
+//!
+//! A [`BatchWatch`] tracks a batch's unresolved calls and when one last resolved. Its watchdog
+//! task checks on it with a WASI timer, and if calls are outstanding and none has resolved for
+//! the stall timeout, it writes the batch's unresolved indices to stderr and exits the guest
+//! with [`STALL_EXIT_CODE`]. A hung transport is then reported from inside the guest, with the
+//! requests it is stuck on, instead of the host killing a silent guest. The report goes to stderr
+//! whether or not the `logging` feature is on.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::{executor, timer};
+
+// Seconds a batch may go without a reply; the default is below the host's 30 s liveness timeout,
+// which the guest's heartbeat keeps from firing while the executor is alive.
+const STALL_TIMEOUT_ENV: &str = "WETWARE_STALL_TIMEOUT";
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Exit code of a guest that gave up on a stalled batch. WASIp2's `exit` only carries success or
+/// failure, so hosts may see just the failure; WASIp1 passes the code through.
+pub const STALL_EXIT_CODE: i32 = 3;
+
+// Unresolved indices listed in a stall report; the count is always given.
+const REPORTED_INDICES: usize = 32;
+
+struct State {
+    batch: usize,
+    pending: BTreeSet<usize>,
+    last_progress_ns: u64,
+    done: bool,
+}
+
+/// One batch's outstanding calls, watched until the `BatchWatch` is dropped.
+pub struct BatchWatch {
+    state: Rc<RefCell<State>>,
+}
+
+impl BatchWatch {
+    /// Start watching batch `batch`.
+    pub fn start(batch: usize) -> Self {
+        let state = Rc::new(RefCell::new(State {
+            batch,
+            pending: BTreeSet::new(),
+            last_progress_ns: timer::monotonic_now_ns(),
+            done: false,
+        }));
+        executor::spawn(watch(state.clone(), stall_timeout()));
+        Self { state }
+    }
+
+    /// Call `index` was sent.
+    pub fn sent(&self, index: usize) {
+        self.state.borrow_mut().pending.insert(index);
+    }
+
+    /// A handle for marking call `index` resolved from the task awaiting it.
+    pub fn resolver(&self, index: usize) -> Resolver {
+        Resolver {
+            state: self.state.clone(),
+            index,
+        }
+    }
+}
+
+impl Drop for BatchWatch {
+    fn drop(&mut self) {
+        self.state.borrow_mut().done = true;
+    }
+}
+
+pub struct Resolver {
+    state: Rc<RefCell<State>>,
+    index: usize,
+}
+
+impl Resolver {
+    pub fn resolved(self) {
+        let mut state = self.state.borrow_mut();
+        state.pending.remove(&self.index);
+        state.last_progress_ns = timer::monotonic_now_ns();
+    }
+}
+
+fn stall_timeout() -> Duration {
+    std::env::var(STALL_TIMEOUT_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_STALL_TIMEOUT)
+}
+
+async fn watch(state: Rc<RefCell<State>>, timeout: Duration) {
+    let check = (timeout / 4).max(Duration::from_millis(100));
+    loop {
+        timer::sleep(check).await;
+        let watched = state.borrow();
+        if watched.done {
+            return;
+        }
+        let idle_ns = timer::monotonic_now_ns().saturating_sub(watched.last_progress_ns);
+        if watched.pending.is_empty() || idle_ns < timeout.as_nanos() as u64 {
+            continue;
+        }
+        let listed: Vec<String> = watched
+            .pending
+            .iter()
+            .take(REPORTED_INDICES)
+            .map(usize::to_string)
+            .collect();
+        let more = watched.pending.len().saturating_sub(REPORTED_INDICES);
+        crate::log_stderr(&format!(
+            "guest: batch {} stalled: no reply for {}s; {} unresolved: [{}]{}",
+            watched.batch,
+            idle_ns / 1_000_000_000,
+            watched.pending.len(),
+            listed.join(", "),
+            if more > 0 {
+                format!(" and {more} more")
+            } else {
+                String::new()
+            },
+        ));
+        std::process::exit(STALL_EXIT_CODE);
+    }
+}